@@ -29,15 +29,11 @@ use tempfile::tempdir;
 use librad::{
     git::{local::url::LocalUrl, storage::Storage},
     keys::{PublicKey, SecretKey},
-    meta::entity::Signatory,
     paths::Paths,
     peer::PeerId,
     uri::RadUrn,
 };
-use librad_test::{
-    logging,
-    rad::entity::{Alice, Radicle},
-};
+use librad_test::{logging, rad::identities::create_test_project};
 
 const PASSPHRASE: &str = "123";
 
@@ -94,17 +90,8 @@ fn smoke() {
 }
 
 fn setup_entity(paths: &Paths, key: SecretKey) -> anyhow::Result<RadUrn> {
-    let mut alice = Alice::new(key.public());
-    let mut radicle = Radicle::new(&alice);
-    {
-        let resolves_to_alice = alice.clone();
-        alice.sign(&key, &Signatory::OwnedKey, &resolves_to_alice)?;
-        radicle.sign(&key, &Signatory::User(alice.urn()), &resolves_to_alice)?;
-
-        let store = Storage::open_or_init(&paths, key)?;
-        store.create_repo(&alice)?;
-        store.create_repo(&radicle)?;
-    }
+    let store = Storage::open_or_init(&paths, key)?;
+    let radicle = create_test_project(&store, &key)?;
 
     Ok(radicle.urn())
 }
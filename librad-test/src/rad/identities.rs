@@ -0,0 +1,90 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use either::Either;
+
+use librad::{
+    git::storage::{self, Storage},
+    identities::{
+        delegation::Indirect,
+        git::{Project, User, VerifiedProject, VerifiedUser},
+        payload,
+    },
+    keys::{self, SecretKey},
+    signer::Signer,
+    uri::RadUrn,
+};
+
+/// The project `radicle`, delegating to the user `alice`.
+pub struct TestProject {
+    pub owner: VerifiedUser,
+    pub project: VerifiedProject,
+}
+
+impl TestProject {
+    /// The namespace of the project in [`Storage`].
+    pub fn urn(&self) -> RadUrn {
+        storage::rad_urn(&self.project.urn())
+    }
+
+    /// The namespace of the owner in [`Storage`].
+    pub fn owner_urn(&self) -> RadUrn {
+        storage::rad_urn(&self.owner.urn())
+    }
+}
+
+/// Create the user `alice` with the single key `key`, and make it available
+/// for replication.
+pub fn create_test_user<S>(storage: &Storage<S>, key: &SecretKey) -> anyhow::Result<VerifiedUser>
+where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    let alice = storage.identities::<User>().create(
+        payload::User {
+            name: "alice".into(),
+        }
+        .into(),
+        Some(key.public()).into_iter().collect(),
+        key,
+    )?;
+
+    Ok(storage.create_user_repo(&alice)?)
+}
+
+/// Create the user `alice` and the project `radicle` she maintains, and make
+/// both available for replication.
+pub fn create_test_project<S>(storage: &Storage<S>, key: &SecretKey) -> anyhow::Result<TestProject>
+where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    let owner = create_test_user(storage, key)?;
+    let radicle = storage.identities::<Project>().create(
+        payload::Project {
+            name: "radicle".into(),
+            description: None,
+            default_branch: None,
+        }
+        .into(),
+        Indirect::try_from_iter(Some(Either::Right(owner.clone().into_inner())))?,
+        key,
+    )?;
+    let project = storage.create_project_repo(&radicle)?;
+
+    Ok(TestProject { owner, project })
+}
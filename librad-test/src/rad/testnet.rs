@@ -73,6 +73,7 @@ async fn boot(seeds: Vec<(PeerId, SocketAddr)>) -> anyhow::Result<TestPeer> {
     let gossip_params = Default::default();
     let disco = discovery::Static::new(seeds);
     let storage_config = Default::default();
+    let sync_params = Default::default();
 
     git::storage::Storage::init(&paths, key)?;
 
@@ -83,6 +84,8 @@ async fn boot(seeds: Vec<(PeerId, SocketAddr)>) -> anyhow::Result<TestPeer> {
         gossip_params,
         disco,
         storage_config,
+        sync_params,
+        accept_pushes: false,
     };
 
     config
@@ -26,3 +26,5 @@ pub mod trailer;
 pub mod types;
 
 pub(crate) mod header;
+pub(crate) mod pkt_line;
+pub(crate) mod upload_pack;
@@ -34,6 +34,19 @@ pub(crate) struct Header {
     pub service: Service,
     pub repo: RadUrn,
     pub peer: PeerId,
+    pub version: Version,
+}
+
+/// The git wire protocol version requested by the client.
+///
+/// As with [`git-daemon`], it is passed as an extra parameter after the
+/// `host`, which servers not supporting it ignore.
+///
+/// [`git-daemon`]: https://git-scm.com/docs/pack-protocol#_git_transport
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Version {
+    V0,
+    V2,
 }
 
 impl Header {
@@ -42,28 +55,34 @@ impl Header {
             service: Service(service),
             repo,
             peer,
+            version: Version::V0,
         }
     }
+
+    pub fn with_version(self, version: Version) -> Self {
+        Self { version, ..self }
+    }
 }
 
 impl Display for Header {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.service.0 {
-            GitService::UploadPackLs => {
-                writeln!(f, "git-upload-pack {}\0host={}\0ls\0", self.repo, self.peer)
-            },
-            GitService::UploadPack => {
-                writeln!(f, "git-upload-pack {}\0host={}\0", self.repo, self.peer)
-            },
-            GitService::ReceivePackLs => writeln!(
-                f,
-                "git-receive-pack {}\0host={}\0ls\0",
-                self.repo, self.peer
-            ),
-            GitService::ReceivePack => {
-                writeln!(f, "git-receive-pack {}\0host={}\0", self.repo, self.peer)
-            },
-        }
+        let (service, mode) = match self.service.0 {
+            GitService::UploadPackLs => ("git-upload-pack", "ls\0"),
+            GitService::UploadPack => ("git-upload-pack", ""),
+            GitService::ReceivePackLs => ("git-receive-pack", "ls\0"),
+            GitService::ReceivePack => ("git-receive-pack", ""),
+        };
+        let version = match self.version {
+            Version::V0 => "",
+            Version::V2 if mode.is_empty() => "\0version=2\0",
+            Version::V2 => "version=2\0",
+        };
+
+        writeln!(
+            f,
+            "{} {}\0host={}\0{}{}",
+            service, self.repo, self.peer, mode, version
+        )
     }
 }
 
@@ -108,6 +127,11 @@ impl FromStr for Header {
             .ok_or_else(|| ParseError::MissingHost)
             .and_then(|peer| peer.parse::<PeerId>().map_err(|e| e.into()))?;
         let mode = parts.next().unwrap_or("");
+        let version = if parts.any(|param| param.trim_end() == "version=2") {
+            Version::V2
+        } else {
+            Version::V0
+        };
 
         let service = match service {
             "git-upload-pack" => match mode {
@@ -125,7 +149,7 @@ impl FromStr for Header {
             unknown => Err(ParseError::InvalidService(unknown.to_owned())),
         }?;
 
-        Ok(Self::new(service, repo, peer))
+        Ok(Self::new(service, repo, peer).with_version(version))
     }
 }
 
@@ -184,4 +208,39 @@ mod tests {
 
         assert_eq!(hdr, hdr.to_string().parse::<Header>().unwrap())
     }
+
+    #[test]
+    fn test_version_roundtrip() {
+        let urn = RadUrn {
+            id: Hash::hash(b"linux"),
+            proto: uri::Protocol::Git,
+            path: uri::Path::empty(),
+        };
+        let peer = PeerId::from(SecretKey::new());
+
+        for service in &[GitService::UploadPackLs, GitService::UploadPack] {
+            for version in &[Version::V0, Version::V2] {
+                let hdr = Header::new(*service, urn.clone(), peer.clone()).with_version(*version);
+                assert_eq!(hdr, hdr.to_string().parse::<Header>().unwrap())
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let hdr = format!(
+            "git-upload-pack {}\0host={}\0ls\0version=3\0\n",
+            RadUrn {
+                id: Hash::hash(b"linux"),
+                proto: uri::Protocol::Git,
+                path: uri::Path::empty(),
+            },
+            PeerId::from(SecretKey::new())
+        )
+        .parse::<Header>()
+        .unwrap();
+
+        assert_eq!(Version::V0, hdr.version);
+        assert_eq!(Service(GitService::UploadPackLs), hdr.service)
+    }
 }
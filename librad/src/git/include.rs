@@ -28,7 +28,7 @@ use crate::{
         local::url::LocalUrl,
         types::{remote::Remote, AsRefspec, FlatRef, Force},
     },
-    meta::user::User,
+    identities::git::VerifiedUser,
     peer::PeerId,
 };
 
@@ -125,13 +125,18 @@ impl<Path> Include<Path> {
     ///
     /// The tracked users are expected to be retrieved by talking to the
     /// [`crate::git::storage::Storage`].
-    pub fn from_tracked_users<S>(
+    pub fn from_tracked_users(
         path: Path,
         local_url: LocalUrl,
-        tracked: impl Iterator<Item = (User<S>, PeerId)>,
+        tracked: impl Iterator<Item = (VerifiedUser, PeerId)>,
     ) -> Self {
         let remotes = tracked
-            .map(|(user, peer)| Remote::new(local_url.clone(), format!("{}@{}", user.name(), peer)))
+            .map(|(user, peer)| {
+                Remote::new(
+                    local_url.clone(),
+                    format!("{}@{}", user.doc.payload.subject.name, peer),
+                )
+            })
             .collect();
         Self {
             remotes,
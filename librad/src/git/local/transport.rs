@@ -32,6 +32,7 @@ use crate::{
         ext::{into_git_err, RECEIVE_PACK_HEADER, UPLOAD_PACK_HEADER},
         local::{self, url::LocalUrl},
         storage::{self, Storage},
+        upload_pack,
     },
     paths::Paths,
     peer::PeerId,
@@ -128,6 +129,9 @@ pub enum Error {
     #[error(transparent)]
     Storage(#[from] storage::Error),
 
+    #[error(transparent)]
+    UploadPack(#[from] upload_pack::Error),
+
     #[error("child exited unsuccessfully")]
     Child(ExitStatus),
 
@@ -267,22 +271,51 @@ impl Localio {
 #[derive(Clone)]
 pub struct LocalTransport {
     storage: Arc<Mutex<Storage<BoxedSigner>>>,
+    settings: Settings,
 }
 
 impl LocalTransport {
     pub fn new(settings: Settings) -> Result<Self, Error> {
-        let storage = Storage::open(&settings.paths)?.with_signer(settings.signer)?;
+        let storage = open_storage(&settings)?;
         Ok(LocalTransport {
             storage: Arc::new(Mutex::new(storage)),
+            settings,
         })
     }
 
+    /// Open a stream to `service` for use as a `git2` transport.
+    ///
+    /// Fetches are served in-process, see [`upload_pack`], pushes by
+    /// connecting to `git receive-pack`.
     pub fn stream(
         &mut self,
         url: LocalUrl,
         service: Service,
         stdio: Localio,
     ) -> Result<LocalStream, Error> {
+        if let Service::UploadPackLs | Service::UploadPack = service {
+            let urn = url.into();
+            self.guard_has_urn(&urn)?;
+
+            let response = match service {
+                Service::UploadPackLs => {
+                    let mut adv = UPLOAD_PACK_HEADER.to_vec();
+                    upload_pack::advertise(&self.visible_refs(&urn)?, &mut adv)?;
+                    Some(Cursor::new(adv))
+                },
+                _ => None,
+            };
+
+            return Ok(LocalStream {
+                inner: Inner::UploadPack(UploadPack {
+                    transport: self.clone(),
+                    urn,
+                    request: Vec::new(),
+                    response,
+                }),
+            });
+        }
+
         let mut child = self.connect(url, service, Mode::Stateless, stdio)?;
 
         let stdin = child.process.stdin.take().unwrap();
@@ -295,13 +328,15 @@ impl LocalTransport {
         };
 
         Ok(LocalStream {
-            read: LocalRead {
-                header,
-                inner: stdout,
+            inner: Inner::Child {
+                read: LocalRead {
+                    header,
+                    inner: stdout,
+                },
+                write: stdin,
+
+                _connected_to: child,
             },
-            write: stdin,
-
-            _connected_to: child,
         })
     }
 
@@ -400,6 +435,30 @@ impl LocalTransport {
             .map_err(Error::from)
     }
 
+    /// The refs of `urn` which may be fetched, relative to its namespace.
+    fn visible_refs(&self, urn: &RadUrn) -> Result<Vec<(String, git2::Oid)>, Error> {
+        const GLOBS: &[&str] = &[
+            "refs/heads/*",
+            "refs/tags/*",
+            "refs/remotes/*/heads/*",
+            "refs/remotes/*/tags/*",
+        ];
+
+        let storage = self.storage.lock().unwrap();
+        let mut refs = storage.references_glob(urn, GLOBS)?.collect::<Vec<_>>();
+        refs.sort();
+
+        let head = storage
+            .as_raw()
+            .find_reference(&format!("refs/namespaces/{}/HEAD", urn.id))
+            .and_then(|head| head.resolve());
+        if let Some(oid) = head.ok().and_then(|head| head.target()) {
+            refs.insert(0, ("HEAD".to_owned(), oid));
+        }
+
+        Ok(refs)
+    }
+
     fn repo_path(&self) -> PathBuf {
         self.storage.lock().unwrap().path().to_path_buf()
     }
@@ -414,33 +473,121 @@ impl LocalTransport {
 }
 
 pub struct LocalStream {
-    read: LocalRead,
-    write: ChildStdin,
+    inner: Inner,
+}
 
-    /// The child process we're connected to.
-    ///
-    /// Tied to the lifetime of this stream, such that the process is
-    /// a-`wait(2)`-ed when it gets dropped (see [`Connected`]).
-    //
-    // NOTE: that we're implicitly relying on the drop order of struct fields
-    // being in declaration order: stdio of the child _should_ already be
-    // closed, so we have a guarantee that it's not waiting for `stdin`.
-    _connected_to: Connected,
+enum Inner {
+    Child {
+        read: LocalRead,
+        write: ChildStdin,
+
+        /// The child process we're connected to.
+        ///
+        /// Tied to the lifetime of this stream, such that the process is
+        /// a-`wait(2)`-ed when it gets dropped (see [`Connected`]).
+        //
+        // NOTE: that we're implicitly relying on the drop order of struct
+        // fields being in declaration order: stdio of the child _should_
+        // already be closed, so we have a guarantee that it's not waiting for
+        // `stdin`.
+        _connected_to: Connected,
+    },
+    UploadPack(UploadPack),
 }
 
 impl Read for LocalStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.read.read(buf)
+        match &mut self.inner {
+            Inner::Child { read, .. } => read.read(buf),
+            Inner::UploadPack(upload_pack) => upload_pack.read(buf),
+        }
     }
 }
 
 impl Write for LocalStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.write.write(buf)
+        match &mut self.inner {
+            Inner::Child { write, .. } => write.write(buf),
+            Inner::UploadPack(upload_pack) => upload_pack.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Child { write, .. } => write.flush(),
+            Inner::UploadPack(upload_pack) => upload_pack.flush(),
+        }
+    }
+}
+
+/// Open the [`Storage`] described by `settings`.
+fn open_storage(settings: &Settings) -> Result<Storage<BoxedSigner>, Error> {
+    Ok(Storage::open(&settings.paths)?.with_signer(settings.signer.clone())?)
+}
+
+/// `upload-pack`, served in-process.
+///
+/// The request is buffered until the response is first read, which is then
+/// computed in full.
+struct UploadPack {
+    transport: LocalTransport,
+    urn: RadUrn,
+    request: Vec<u8>,
+    response: Option<Cursor<Vec<u8>>>,
+}
+
+impl UploadPack {
+    /// Compute the response.
+    ///
+    /// If the storage is a partial clone, the objects it is missing are
+    /// fetched first.
+    fn respond(&self) -> Result<Vec<u8>, Error> {
+        let request = upload_pack::Request::parse(&self.request)?;
+        let refs = self.transport.visible_refs(&self.urn)?;
+
+        let mut out = Vec::new();
+        let missing = {
+            let storage = self.transport.storage.lock().unwrap();
+            match upload_pack::respond(storage.as_raw(), &refs, &request, |_| false, &mut out) {
+                Err(upload_pack::Error::Missing(oids)) => Some(oids),
+                res => res.map(|()| None)?,
+            }
+        };
+
+        if let Some(oids) = missing {
+            // Fetching goes over the network, so don't hold the lock on the
+            // shared storage meanwhile
+            open_storage(&self.transport.settings)?.fetch_objects(&self.urn, &oids)?;
+            out.clear();
+            let storage = self.transport.storage.lock().unwrap();
+            upload_pack::respond(storage.as_raw(), &refs, &request, |_| false, &mut out)?;
+        }
+
+        Ok(out)
+    }
+}
+
+impl Read for UploadPack {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.response {
+            Some(response) => response.read(buf),
+            None => {
+                let response = self
+                    .respond()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                self.response.get_or_insert(Cursor::new(response)).read(buf)
+            },
+        }
+    }
+}
+
+impl Write for UploadPack {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.request.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.write.flush()
+        Ok(())
     }
 }
 
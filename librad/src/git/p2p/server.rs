@@ -20,68 +20,114 @@
 //! This implements the same protocol as [`git-daemon`], with a minor adjustment
 //! due to a (possible) bug in `libgit2` or `git2-rs`, which prevents us from
 //! registering a stateful transport: when parsing the header line, we look for
-//! a null-terminated string "advertise" to decide whether we should wait for
-//! data to be fed into `stdin` of `git upload-pack` or not.
+//! a null-terminated string "advertise" to decide whether to advertise the
+//! refs, or to wait for the client's request.
+//!
+//! Fetches are served in-process, see [`crate::git::upload_pack`]. If the
+//! client asks for protocol v2 when requesting the refs advertisement, we
+//! advertise our v2 capabilities instead, and answer an `ls-refs` command with
+//! the refs matching its `ref-prefix`es.
+//!
+//! If enabled, peers may also push to us, see [`receive_pack`].
 //!
 //! [`git-daemon`]: https://git-scm.com/docs/git-daemon
 
 use std::{
     io,
+    ops::Deref,
     path::{Path, PathBuf},
-    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use futures::{
     self,
+    channel::mpsc,
     io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sink::SinkExt,
+    stream::StreamExt,
 };
 use git2::transport::Service;
-use tokio::process::{self, Command};
-use tokio_util::compat::{Tokio02AsyncReadCompatExt, Tokio02AsyncWriteCompatExt};
+use tokio::{process::Command, task::spawn_blocking, time::timeout};
 
 use crate::{
     git::{
-        ext::{into_io_err, References, UPLOAD_PACK_HEADER},
-        header::{self, Header},
+        ext::{into_io_err, is_not_found_err, References, UPLOAD_PACK_HEADER},
+        header::{self, Header, Version},
+        pkt_line::{self, Pkt},
         types::Namespace,
+        upload_pack,
     },
     paths::Paths,
+    peer::PeerId,
 };
 
+pub mod receive_pack;
+pub use receive_pack::Pushed;
+
+/// How long to wait for the client to send its `upload-pack` request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many chunks of the packfile may be buffered before sending them.
+const PACK_BUFFER: usize = 16;
+const PACK_CHUNK_SIZE: usize = 64 * 1024;
+/// Upper bound on the number of unused monorepo handles kept around.
+const MAX_IDLE_REPOS: usize = 8;
+/// Upper bounds on the number of lines of an `ls-refs` command, and on their
+/// total size in bytes.
+const MAX_LS_REFS_ARGS: usize = 256;
+const MAX_LS_REFS_SIZE: usize = 64 * 1024;
+
 #[derive(Clone)]
 pub struct GitServer {
-    monorepo: PathBuf,
+    repos: Repos,
+    receive_pack: bool,
 }
 
 impl GitServer {
     pub fn new(paths: &Paths) -> Self {
         Self {
-            monorepo: paths.git_dir().to_path_buf(),
+            repos: Repos::new(paths.git_dir().to_path_buf()),
+            receive_pack: false,
+        }
+    }
+
+    /// Whether to accept pushes from other peers. Disabled by default.
+    pub fn with_receive_pack(self, enabled: bool) -> Self {
+        Self {
+            receive_pack: enabled,
+            ..self
         }
     }
 }
 
 impl GitServer {
-    pub async fn invoke_service<R, W>(&self, (recv, mut send): (R, W)) -> io::Result<()>
+    /// Serve the git service requested by `remote_peer`.
+    ///
+    /// If `remote_peer` pushed to us, the refs it updated are returned.
+    pub async fn invoke_service<R, W>(
+        &self,
+        remote_peer: &PeerId,
+        (recv, mut send): (R, W),
+    ) -> io::Result<Option<Pushed>>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        let span = tracing::trace_span!("GitServer::invoke_service", git.server.path = %self.monorepo.display());
+        let span = tracing::trace_span!("GitServer::invoke_service", git.server.path = %self.repos.path().display());
         let _guard = span.enter();
 
         let mut recv = BufReader::new(recv);
         let mut hdr_buf = String::with_capacity(256);
         if let Err(e) = recv.read_line(&mut hdr_buf).await {
             tracing::error!("Error reading git service header: {}", e);
-            return send_err(&mut send, "garbage header").await;
+            return send_err(&mut send, "garbage header").await.map(|()| None);
         }
 
         let header = match hdr_buf.parse::<Header>() {
             Ok(hdr) => hdr,
             Err(e) => {
                 tracing::error!("Error parsing git service header: {}", e);
-                return send_err(&mut send, "invalid header").await;
+                return send_err(&mut send, "invalid header").await.map(|()| None);
             },
         };
 
@@ -91,124 +137,323 @@ impl GitServer {
         );
 
         match *header.service {
-            Service::UploadPack => {
-                UploadPack::upload_pack(&self.monorepo)?
-                    .run(recv, send)
+            Service::UploadPack => self
+                .upload_pack(&header.repo.id, recv, send)
+                .await
+                .map(|()| None),
+            Service::UploadPackLs if header.version == Version::V2 => self
+                .ls_refs(&header.repo.id, recv, send)
+                .await
+                .map(|()| None),
+            Service::UploadPackLs => self.advertise(&header.repo.id, send).await.map(|()| None),
+            Service::ReceivePackLs if self.receive_pack => {
+                receive_pack::advertise(&self.repos, &header.repo, remote_peer, send)
                     .await
+                    .map(|()| None)
             },
-            Service::UploadPackLs => {
-                UploadPack::advertise(&self.monorepo, &header.repo.id)?
-                    .run(recv, send)
-                    .await
+            Service::ReceivePack if self.receive_pack => {
+                receive_pack::receive(&self.repos, &header.repo, remote_peer, recv, send).await
             },
             service => {
                 tracing::error!("Invalid git service: {:?}", header::Service(service));
-                send_err(&mut send, "service not enabled").await
+                send_err(&mut send, "service not enabled")
+                    .await
+                    .map(|()| None)
             },
         }
     }
 }
 
-enum UploadPack {
-    AdvertiseRefs(process::Child),
-    UploadPack(process::Child),
-}
+impl GitServer {
+    async fn advertise<W>(&self, namespace: &Namespace, mut send: W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut adv = UPLOAD_PACK_HEADER.to_vec();
+        {
+            let repo = self.repos.get().map_err(into_io_err)?;
+            let refs = visible_refs(&repo, namespace, &[]).map_err(into_io_err)?;
+            upload_pack::advertise(&refs, &mut adv)?;
+        }
 
-impl UploadPack {
-    fn advertise(repo_path: &Path, namespace: &Namespace) -> io::Result<Self> {
-        let mut git = Command::new("git");
+        send.write_all(&adv).await
+    }
 
-        git.args(&["-c", "uploadpack.hiderefs=refs/"])
-            .arg("-c")
-            .arg(format!(
-                "uploadpack.hiderefs=!refs/namespaces/{}",
-                namespace
-            ));
+    /// Advertise our protocol v2 capabilities, and answer an `ls-refs`
+    /// command, if the client sends one.
+    async fn ls_refs<R, W>(&self, namespace: &Namespace, mut recv: R, mut send: W) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut capabilities = Vec::new();
+        upload_pack::advertise_v2(&mut capabilities)?;
+        send.write_all(&capabilities).await?;
 
-        // FIXME: we should probably keep one git2::Repository around, but
-        // `GitServer` needs to be `Sync`
-        let repo = git2::Repository::open_bare(repo_path).map_err(into_io_err)?;
-        let mut refs = References::from_globs(
-            &repo,
-            &[
-                format!("refs/namespaces/{}/refs/rad/ids/*", namespace),
-                format!("refs/namespaces/{}/refs/remotes/**/rad/ids/*", namespace),
-            ],
-        )
-        .map_err(into_io_err)?;
-
-        for id_ref in refs.names() {
-            if let Some(id) = id_ref.ok().and_then(|name| name.split('/').next_back()) {
-                git.arg("-c").arg(format!(
-                    "uploadpack.hiderefs=!refs/namespaces/{}/rad/id",
-                    id
-                ));
+        let mut pkts = Vec::new();
+        let read = async {
+            let mut size = 0;
+            loop {
+                match pkt_line::read_async(&mut recv).await? {
+                    Pkt::Flush => break Ok::<_, io::Error>(true),
+                    pkt => {
+                        if let Pkt::Data(line) = &pkt {
+                            size += line.len();
+                        }
+                        pkts.push(pkt);
+                        if pkts.len() > MAX_LS_REFS_ARGS || size > MAX_LS_REFS_SIZE {
+                            break Ok(false);
+                        }
+                    },
+                }
             }
+        };
+        let res = timeout(REQUEST_TIMEOUT, read).await;
+        match res {
+            Ok(Ok(true)) => {},
+            Ok(Ok(false)) => return send_err(&mut send, "command too large").await,
+            // The client only wanted to know our capabilities
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof && pkts.is_empty() => {
+                return Ok(())
+            },
+            Ok(Err(e)) => {
+                tracing::error!("Error reading ls-refs command: {}", e);
+                return send_err(&mut send, "invalid command").await;
+            },
+            Err(_) => return send_err(&mut send, "timed out").await,
         }
 
-        git_tracing(&mut git);
-        git.args(&[
-            "upload-pack",
-            "--strict",
-            "--timeout=5",
-            "--stateless-rpc",
-            "--advertise-refs",
-            ".",
-        ])
-        .current_dir(repo_path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map(Self::AdvertiseRefs)
-    }
+        let cmd = match upload_pack::LsRefs::from_pkts(pkts.iter().map(Pkt::as_deref)) {
+            Ok(cmd) => cmd,
+            Err(e) => return send_err(&mut send, &e.to_string()).await,
+        };
 
-    fn upload_pack(repo_path: &Path) -> io::Result<Self> {
-        let mut git = Command::new("git");
-        git_tracing(&mut git);
-        git.args(&[
-            "upload-pack",
-            "--strict",
-            "--timeout=5",
-            "--stateless-rpc",
-            ".",
-        ])
-        .current_dir(repo_path)
-        .stdout(Stdio::piped())
-        .stdin(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map(Self::UploadPack)
+        let mut out = Vec::new();
+        {
+            let repo = self.repos.get().map_err(into_io_err)?;
+            let refs = visible_refs(&repo, namespace, &cmd.prefixes).map_err(into_io_err)?;
+            upload_pack::ls_refs(&refs, &mut out)?;
+        }
+
+        send.write_all(&out).await
     }
 
-    async fn run<R, W>(self, mut recv: R, mut send: W) -> io::Result<()>
+    async fn upload_pack<R, W>(
+        &self,
+        namespace: &Namespace,
+        mut recv: R,
+        mut send: W,
+    ) -> io::Result<()>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        match self {
-            Self::AdvertiseRefs(mut child) => {
-                let mut stdout = child.stdout.take().unwrap().compat();
+        let mut request = upload_pack::Request::default();
+        let read = async {
+            loop {
+                let pkt = pkt_line::read_async(&mut recv).await?;
+                let complete = request
+                    .push(pkt.as_deref())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if complete {
+                    break Ok::<_, io::Error>(());
+                }
+            }
+        };
+        let res = timeout(REQUEST_TIMEOUT, read).await;
+        match res {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => {
+                tracing::error!("Error reading upload-pack request: {}", e);
+                return send_err(&mut send, "invalid request").await;
+            },
+            Err(_) => return send_err(&mut send, "timed out").await,
+        }
 
-                spawn_child(child);
+        let repo = self.repos.get().map_err(into_io_err)?;
+        let namespace = namespace.clone();
+        let (tx, mut rx) = mpsc::channel(PACK_BUFFER);
+        let respond = spawn_blocking(move || {
+            let refs = visible_refs(&repo, &namespace, &[])?;
+            let mut out = io::BufWriter::with_capacity(PACK_CHUNK_SIZE, ChannelWriter(tx));
+            upload_pack::respond(&repo, &refs, &request, is_rad_ref, &mut out)
+        });
 
-                send.write_all(UPLOAD_PACK_HEADER).await?;
-                futures::io::copy(&mut stdout, &mut send).await.map(|_| ())
+        while let Some(chunk) = rx.next().await {
+            send.write_all(&chunk).await?;
+        }
+
+        let respond = respond
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        match respond {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                tracing::error!("upload-pack error: {}", e);
+                send_err(&mut send, &e.to_string()).await
             },
+        }
+    }
+}
 
-            Self::UploadPack(mut child) => {
-                let mut stdin = child.stdin.take().unwrap().compat_write();
-                let mut stdout = child.stdout.take().unwrap().compat();
+/// A pool of handles to the monorepo, so we don't have to open it for every
+/// request.
+///
+/// `git2::Repository` is not `Sync`, so a handle is taken out of the pool for
+/// the duration of a request, and put back when done.
+#[derive(Clone)]
+struct Repos {
+    path: PathBuf,
+    idle: Arc<Mutex<Vec<git2::Repository>>>,
+}
 
-                spawn_child(child);
+impl Repos {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn get(&self) -> Result<Pooled, git2::Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let repo = match idle {
+            Some(repo) => repo,
+            None => git2::Repository::open_bare(&self.path)?,
+        };
 
-                futures::try_join!(
-                    futures::io::copy(&mut recv, &mut stdin),
-                    futures::io::copy(&mut stdout, &mut send),
-                )
-                .map(|_| ())
+        Ok(Pooled {
+            repo: Some(repo),
+            idle: Arc::clone(&self.idle),
+        })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+struct Pooled {
+    repo: Option<git2::Repository>,
+    idle: Arc<Mutex<Vec<git2::Repository>>>,
+}
+
+impl Deref for Pooled {
+    type Target = git2::Repository;
+
+    fn deref(&self) -> &Self::Target {
+        self.repo.as_ref().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let (Some(repo), Ok(mut idle)) = (self.repo.take(), self.idle.lock()) {
+            if idle.len() < MAX_IDLE_REPOS {
+                idle.push(repo)
+            }
+        }
+    }
+}
+
+/// The refs of `namespace` which may be fetched, starting with one of
+/// `prefixes`, or all of them if there are none.
+///
+/// Besides the namespace itself, these are the `rad/id` refs of the
+/// certifiers of the identity, so they can be fetched along with it. As the
+/// fetching side can't know them in advance, they are included regardless of
+/// `prefixes`, if any of them matches the namespace.
+///
+/// Only the refs under the namespace, and those of the certifiers, are
+/// visited, so this doesn't get more expensive with the size of the monorepo.
+fn visible_refs(
+    repo: &git2::Repository,
+    namespace: &Namespace,
+    prefixes: &[String],
+) -> Result<Vec<(String, git2::Oid)>, git2::Error> {
+    let namespace_prefix = format!("refs/namespaces/{}/", namespace);
+    let globs = if prefixes.is_empty() {
+        vec![format!("{}*", namespace_prefix)]
+    } else {
+        prefixes
+            .iter()
+            .filter_map(|prefix| {
+                if prefix.starts_with(&namespace_prefix) {
+                    Some(format!("{}*", prefix))
+                } else if namespace_prefix.starts_with(prefix.as_str()) {
+                    Some(format!("{}*", namespace_prefix))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    };
+    if globs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut refs = upload_pack::visible_refs(repo, globs)?;
+
+    let certifiers = {
+        let mut refs = References::from_globs(
+            repo,
+            &[
+                format!("{}refs/rad/ids/*", namespace_prefix),
+                format!("{}refs/remotes/**/rad/ids/*", namespace_prefix),
+            ],
+        )?;
+        refs.names()
+            .filter_map(|name| {
+                name.ok()
+                    .and_then(|name| name.split('/').next_back())
+                    .map(|id| format!("refs/namespaces/{}/refs/rad/id", id))
+            })
+            .collect::<Vec<_>>()
+    };
+    for name in certifiers {
+        match repo.find_reference(&name).and_then(|r| r.resolve()) {
+            Ok(reference) => {
+                if let Some(oid) = reference.target() {
+                    refs.push((name, oid))
+                }
             },
+            Err(e) if is_not_found_err(&e) => {},
+            Err(e) => return Err(e),
         }
     }
+    refs.sort();
+    refs.dedup();
+
+    Ok(refs)
+}
+
+/// Whether `name` is one of the `rad/*` refs of a namespace, or a remote
+/// within it.
+///
+/// Their history is needed in full to verify the identities, so shallow and
+/// partial clones only apply to the other refs.
+fn is_rad_ref(name: &str) -> bool {
+    let mut parts = name.split('/').skip(3);
+    match (parts.next(), parts.next()) {
+        (Some("refs"), Some("rad")) => true,
+        (Some("refs"), Some("remotes")) => parts.nth(1) == Some("rad"),
+        _ => false,
+    }
+}
+
+/// Blocking [`io::Write`] handing chunks over to the async side.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures::executor::block_on(self.0.send(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 fn git_tracing(git: &mut Command) {
@@ -224,17 +469,19 @@ where
         .await
 }
 
-fn spawn_child(child: process::Child) {
-    tokio::spawn(async {
-        let _ = child
-            .await
-            .map(|status| {
-                if !status.success() {
-                    tracing::error!("upload-pack exited non-zero: {:?}", status)
-                }
-            })
-            .map_err(|e| tracing::error!("upload-pack error: {}", e));
-    });
+/// Read a pkt-line, returning `None` for a flush packet.
+async fn read_pkt_line<R>(recv: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    match pkt_line::read_async(recv).await? {
+        Pkt::Flush => Ok(None),
+        Pkt::Delim => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected delim-pkt",
+        )),
+        Pkt::Data(line) => Ok(Some(line)),
+    }
 }
 
 fn pkt_line(msg: &str) -> String {
@@ -250,6 +497,41 @@ fn pkt_line(msg: &str) -> String {
 mod tests {
     use super::*;
 
+    use futures::io::Cursor;
+
+    use crate::hash::Hash;
+
+    async fn ls_refs(prefixes: Vec<String>) -> String {
+        let tmp = tempfile::tempdir().unwrap();
+        let server = GitServer::new(&Paths::from_root(tmp.path()).unwrap());
+
+        let mut cmd = Vec::new();
+        upload_pack::LsRefs { prefixes }.encode(&mut cmd).unwrap();
+        let mut out = Vec::new();
+        server
+            .ls_refs(&Hash::hash(b"project"), Cursor::new(cmd), &mut out)
+            .await
+            .unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ls_refs_rejects_too_many_args() {
+        let prefixes = (0..MAX_LS_REFS_ARGS)
+            .map(|i| format!("refs/heads/{}", i))
+            .collect();
+        assert!(ls_refs(prefixes).await.ends_with("ERR command too large"));
+    }
+
+    #[tokio::test]
+    async fn ls_refs_rejects_oversized_args() {
+        let prefixes = (0..MAX_LS_REFS_SIZE / 1024)
+            .map(|_| format!("refs/heads/{}", "x".repeat(1024)))
+            .collect();
+        assert!(ls_refs(prefixes).await.ends_with("ERR command too large"));
+    }
+
     #[test]
     fn test_pkt_line() {
         assert_eq!("0006a\n", pkt_line("a\n"));
@@ -257,4 +539,18 @@ mod tests {
         assert_eq!("000bfoobar\n", pkt_line("foobar\n"));
         assert_eq!("0004", pkt_line(""));
     }
+
+    #[test]
+    fn test_is_rad_ref() {
+        assert!(is_rad_ref("refs/namespaces/hnr/refs/rad/id"));
+        assert!(is_rad_ref("refs/namespaces/hnr/refs/rad/ids/hnd"));
+        assert!(is_rad_ref(
+            "refs/namespaces/hnr/refs/remotes/hyn/rad/signed_refs"
+        ));
+        assert!(!is_rad_ref("refs/namespaces/hnr/refs/heads/rad"));
+        assert!(!is_rad_ref(
+            "refs/namespaces/hnr/refs/remotes/hyn/heads/master"
+        ));
+        assert!(!is_rad_ref("refs/namespaces/hnr/refs/remotes/rad"));
+    }
 }
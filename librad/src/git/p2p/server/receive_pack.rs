@@ -0,0 +1,708 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Accepting pushes from other peers
+//!
+//! A peer may only push to its own remote tracking branches of a repo we
+//! have, and only if we track it for that repo:
+//!
+//! * `refs/namespaces/<urn>/refs/remotes/<peer>/heads/*`
+//! * `refs/namespaces/<urn>/refs/remotes/<peer>/rad/signed_refs`
+//!
+//! The refs are only updated if the pushed `rad/signed_refs` are signed by the
+//! peer, and every pushed head is at the revision it signed. The updates of a
+//! push are applied as a whole, or not at all.
+//!
+//! Only the packfile is handed to `git index-pack`: the refs advertisement,
+//! the ref updates, and the status report are handled here, as this is where
+//! the checks need to go.
+
+use std::{io, path::Path, process::Stdio, str};
+
+use futures::{
+    future::{self, Either},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    pin_mut,
+};
+use thiserror::Error;
+use tokio::process::Command;
+use tokio_util::compat::Tokio02AsyncWriteCompatExt;
+
+use crate::{
+    git::{
+        ext::{into_io_err, is_not_found_err, References, RECEIVE_PACK_HEADER},
+        refs::{self, Refs},
+        storage,
+        upload_pack::write_advertisement,
+    },
+    peer::PeerId,
+    uri::{self, RadUrn},
+};
+
+use super::{git_tracing, pkt_line, read_pkt_line, send_err, Repos};
+
+const CAPABILITIES: &str = "report-status ofs-delta";
+
+/// The refs a peer successfully pushed, see
+/// [`super::GitServer::invoke_service`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pushed {
+    /// The peer which pushed.
+    pub peer: PeerId,
+    /// The repo pushed to.
+    pub urn: RadUrn,
+    /// The updated branches, as `refs/heads/<name>`, and their new tips.
+    pub heads: Vec<(String, git2::Oid)>,
+}
+
+#[derive(Debug, Error)]
+enum Error {
+    #[error("no such repo")]
+    NoSuchRepo,
+
+    #[error("pushes from {0} are not accepted")]
+    Untracked(PeerId),
+
+    #[error("may not push to {0}")]
+    Forbidden(String),
+
+    #[error("deleting refs is not supported")]
+    Delete,
+
+    #[error("missing rad/signed_refs")]
+    MissingSignedRefs,
+
+    #[error("{0} is not at the signed revision")]
+    Unsigned(String),
+
+    #[error("{0} is not at the expected revision")]
+    Stale(String),
+
+    #[error(transparent)]
+    Refsig(#[from] refs::signed::Error),
+
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+}
+
+/// A ref update requested by the pushing peer.
+#[derive(Debug, PartialEq)]
+struct Update {
+    old: git2::Oid,
+    new: git2::Oid,
+    name: String,
+}
+
+impl Update {
+    /// Parse a command line of the form `<old> <new> <name>`. The first one is
+    /// followed by the peer's capabilities, which we ignore: we only support
+    /// the ones we advertised.
+    fn parse(line: &[u8]) -> Option<Self> {
+        let line = str::from_utf8(line).ok()?;
+        let line = line.trim_end_matches('\n').split('\0').next()?;
+        let mut parts = line.splitn(3, ' ');
+        let mut oid = || {
+            parts
+                .next()
+                .filter(|hex| hex.len() == 40)
+                .and_then(|hex| git2::Oid::from_str(hex).ok())
+        };
+        let old = oid()?;
+        let new = oid()?;
+        let name = parts.next().filter(|name| !name.is_empty())?;
+
+        Some(Self {
+            old,
+            new,
+            name: name.to_owned(),
+        })
+    }
+
+    /// Check that the update stays within `prefix`, and is one we support.
+    fn check(&self, prefix: &str) -> Result<(), Error> {
+        if self.new.is_zero() {
+            return Err(Error::Delete);
+        }
+
+        let allowed = git2::Reference::is_valid_name(&self.name)
+            && self
+                .name
+                .strip_prefix(prefix)
+                .map(|name| name == "rad/signed_refs" || name.starts_with("heads/"))
+                .unwrap_or(false);
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(self.name.clone()))
+        }
+    }
+
+    /// The name of the branch updated, if any.
+    fn head(&self, prefix: &str) -> Option<String> {
+        self.name
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_prefix("heads/"))
+            .map(|name| format!("refs/heads/{}", name))
+    }
+
+    /// Check that the ref is still at `old`, where a zero `old` means it must
+    /// not exist.
+    fn check_old(&self, repo: &git2::Repository) -> Result<(), Error> {
+        let current = match repo.refname_to_id(&self.name) {
+            Ok(oid) => oid,
+            Err(e) if is_not_found_err(&e) => git2::Oid::zero(),
+            Err(e) => return Err(e.into()),
+        };
+
+        if current == self.old {
+            Ok(())
+        } else {
+            Err(Error::Stale(self.name.clone()))
+        }
+    }
+
+    /// Undo the update, unless the ref was changed by someone else meanwhile.
+    fn revert(&self, repo: &git2::Repository) -> Result<(), git2::Error> {
+        if self.old.is_zero() {
+            match repo.find_reference(&self.name) {
+                Ok(mut r) if r.target() == Some(self.new) => r.delete(),
+                Ok(_) => Ok(()),
+                Err(e) if is_not_found_err(&e) => Ok(()),
+                Err(e) => Err(e),
+            }
+        } else {
+            repo.reference_matching(&self.name, self.old, true, self.new, "reverted push")
+                .map(|_| ())
+        }
+    }
+}
+
+/// Advertise the refs `peer` may push to.
+pub(super) async fn advertise<W>(
+    repos: &Repos,
+    urn: &RadUrn,
+    peer: &PeerId,
+    mut send: W,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let refs = {
+        let repo = repos.get().map_err(into_io_err)?;
+        match authorise(&repo, urn, peer) {
+            Err(e) => Err(e.to_string()),
+            Ok(()) => Ok(
+                References::from_globs(&repo, &[format!("{}*", prefix(urn, peer))])
+                    .map_err(into_io_err)?
+                    .peeled()
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    };
+
+    match refs {
+        Err(e) => send_err(&mut send, &e).await,
+        Ok(refs) => {
+            let mut adv = RECEIVE_PACK_HEADER.to_vec();
+            write_advertisement(&refs, CAPABILITIES, &mut adv)?;
+            send.write_all(&adv).await
+        },
+    }
+}
+
+/// Receive the ref updates and packfile pushed by `peer`, and apply the
+/// updates if they check out.
+pub(super) async fn receive<R, W>(
+    repos: &Repos,
+    urn: &RadUrn,
+    peer: &PeerId,
+    mut recv: R,
+    mut send: W,
+) -> io::Result<Option<Pushed>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut updates = Vec::new();
+    while let Some(line) = read_pkt_line(&mut recv).await? {
+        match Update::parse(&line) {
+            Some(update) => updates.push(update),
+            None => return send_err(&mut send, "invalid command").await.map(|()| None),
+        }
+    }
+    // Nothing to do, and no packfile follows
+    if updates.is_empty() {
+        return Ok(None);
+    }
+
+    let prefix = prefix(urn, peer);
+    let checked = repos
+        .get()
+        .map_err(Error::from)
+        .and_then(|repo| authorise(&repo, urn, peer))
+        .and_then(|()| updates.iter().try_for_each(|update| update.check(&prefix)));
+    if let Err(e) = checked {
+        return send_err(&mut send, &e.to_string()).await.map(|()| None);
+    }
+
+    if let Err(e) = index_pack(repos.path(), &mut recv).await {
+        tracing::warn!("Failed to receive packfile: {}", e);
+        let mut report = pkt_line(&format!("unpack {}\n", e));
+        for update in &updates {
+            report.push_str(&pkt_line(&format!("ng {} unpacker error\n", update.name)));
+        }
+        report.push_str("0000");
+        return send.write_all(report.as_bytes()).await.map(|()| None);
+    }
+
+    let applied = {
+        let repo = repos.get().map_err(into_io_err)?;
+        apply(&repo, peer, &prefix, &updates)
+    };
+    if let Err(e) = &applied {
+        tracing::warn!(peer = %peer, "Rejecting push: {}", e);
+    }
+
+    let mut report = pkt_line("unpack ok\n");
+    let mut heads = Vec::new();
+    for update in &updates {
+        match &applied {
+            Ok(()) => {
+                report.push_str(&pkt_line(&format!("ok {}\n", update.name)));
+                if let Some(head) = update.head(&prefix) {
+                    heads.push((head, update.new))
+                }
+            },
+            Err(e) => report.push_str(&pkt_line(&format!(
+                "ng {} {}\n",
+                update.name,
+                e.to_string().replace('\n', " ")
+            ))),
+        }
+    }
+    report.push_str("0000");
+    send.write_all(report.as_bytes()).await?;
+
+    Ok(if heads.is_empty() {
+        None
+    } else {
+        Some(Pushed {
+            peer: peer.clone(),
+            urn: RadUrn::new(urn.id.clone(), uri::Protocol::Git, uri::Path::empty()),
+            heads,
+        })
+    })
+}
+
+/// The refs `peer` may push to in `urn`.
+fn prefix(urn: &RadUrn, peer: &PeerId) -> String {
+    format!("refs/namespaces/{}/refs/remotes/{}/", urn.id, peer)
+}
+
+/// Check that we have `urn`, and track `peer` for it.
+fn authorise(repo: &git2::Repository, urn: &RadUrn, peer: &PeerId) -> Result<(), Error> {
+    match repo.find_reference(&format!("refs/namespaces/{}/refs/rad/id", urn.id)) {
+        Ok(_) => {},
+        Err(e) if is_not_found_err(&e) => return Err(Error::NoSuchRepo),
+        Err(e) => return Err(e.into()),
+    }
+
+    match repo.find_remote(&storage::tracking_remote_name(urn, peer)) {
+        Ok(_) => Ok(()),
+        Err(e) if is_not_found_err(&e) => Err(Error::Untracked(peer.clone())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Apply `updates` as a whole, or not at all.
+///
+/// All refs are locked before they are checked, and the heads are written
+/// before `rad/signed_refs`, so the latter never refer to heads we don't have.
+/// If writing fails half-way, what was written is reverted.
+fn apply(
+    repo: &git2::Repository,
+    peer: &PeerId,
+    prefix: &str,
+    updates: &[Update],
+) -> Result<(), Error> {
+    const MSG: &str = "pushed by peer";
+
+    let (heads, signed_refs): (Vec<&Update>, Vec<&Update>) = updates
+        .iter()
+        .partition(|update| update.head(prefix).is_some());
+
+    let mut heads_tx = repo.transaction()?;
+    for update in &heads {
+        heads_tx.lock_ref(&update.name)?;
+    }
+    let mut signed_refs_tx = repo.transaction()?;
+    for update in &signed_refs {
+        signed_refs_tx.lock_ref(&update.name)?;
+    }
+
+    for update in updates {
+        update.check_old(repo)?;
+    }
+    verify(repo, peer, prefix, updates)?;
+
+    for update in &heads {
+        heads_tx.set_target(&update.name, update.new, None, MSG)?;
+    }
+    for update in &signed_refs {
+        signed_refs_tx.set_target(&update.name, update.new, None, MSG)?;
+    }
+
+    heads_tx
+        .commit()
+        .and_then(|()| signed_refs_tx.commit())
+        .map_err(|e| {
+            for update in &heads {
+                if let Err(e) = update.revert(repo) {
+                    tracing::warn!("Failed to revert {}: {}", update.name, e);
+                }
+            }
+            e.into()
+        })
+}
+
+/// Verify that `updates` leave the refs under `prefix` in the state `peer`
+/// signed.
+fn verify(
+    repo: &git2::Repository,
+    peer: &PeerId,
+    prefix: &str,
+    updates: &[Update],
+) -> Result<(), Error> {
+    let signed_refs = format!("{}rad/signed_refs", prefix);
+    let tip = match updates.iter().find(|update| update.name == signed_refs) {
+        Some(update) => update.new,
+        None => match repo.refname_to_id(&signed_refs) {
+            Ok(oid) => oid,
+            Err(e) if is_not_found_err(&e) => return Err(Error::MissingSignedRefs),
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    let refs = {
+        let blob = repo
+            .find_commit(tip)?
+            .tree()?
+            .get_path(Path::new("refs"))?
+            .to_object(repo)?
+            .peel_to_blob()?;
+        Refs::from(refs::Signed::from_json(blob.content(), peer)?)
+    };
+
+    for update in updates {
+        if let Some(head) = update.head(prefix) {
+            // Also make sure it's a commit we have
+            repo.find_commit(update.new)?;
+            if refs.heads.get(&head).map(|oid| **oid) != Some(update.new) {
+                return Err(Error::Unsigned(head));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the packfile following the commands into the object database.
+///
+/// `index-pack` stops reading after the packfile, but the pushing peer keeps
+/// the stream open to wait for our report, so we stop feeding it when it
+/// exits.
+async fn index_pack<R>(repo_path: &Path, recv: &mut R) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut git = Command::new("git");
+    git_tracing(&mut git);
+    let mut child = git
+        .args(&["index-pack", "--stdin", "--fix-thin", "--strict"])
+        .current_dir(repo_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "stdin of index-pack not captured"))?
+        .compat_write();
+    pin_mut!(child);
+
+    let status = {
+        let copy = futures::io::copy(recv, &mut stdin);
+        pin_mut!(copy);
+        match future::select(copy, child.as_mut()).await {
+            Either::Left((copied, _)) => {
+                copied?;
+                None
+            },
+            Either::Right((status, _)) => Some(status?),
+        }
+    };
+    let status = match status {
+        Some(status) => status,
+        // The peer closed the stream, so `index-pack` has all there is
+        None => {
+            drop(stdin);
+            child.await?
+        },
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("index-pack exited with {}", status),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{BTreeMap, HashMap};
+
+    use tempfile::tempdir;
+
+    use crate::{git::refs::Remotes, hash::Hash, keys::SecretKey};
+
+    const OLD: &str = "0000000000000000000000000000000000000000";
+    const NEW: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+    fn prefix() -> String {
+        let urn = RadUrn::new(Hash::hash(b"geez"), uri::Protocol::Git, uri::Path::empty());
+        super::prefix(&urn, &PeerId::from(SecretKey::new()))
+    }
+
+    fn update(name: &str) -> Update {
+        Update {
+            old: git2::Oid::zero(),
+            new: git2::Oid::from_str(NEW).unwrap(),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse_update() {
+        let name = "refs/namespaces/geez/refs/remotes/peer/heads/master";
+        let expected = Some(update(name));
+
+        assert_eq!(
+            expected,
+            Update::parse(format!("{} {} {}\n", OLD, NEW, name).as_bytes())
+        );
+        assert_eq!(
+            expected,
+            Update::parse(format!("{} {} {}\0report-status\n", OLD, NEW, name).as_bytes())
+        );
+        assert_eq!(None, Update::parse(format!("{} {}\n", OLD, NEW).as_bytes()));
+        assert_eq!(
+            None,
+            Update::parse(format!("{} {} {}\n", &OLD[..7], NEW, name).as_bytes())
+        );
+    }
+
+    #[test]
+    fn check_update() {
+        let prefix = prefix();
+
+        assert!(update(&format!("{}heads/master", prefix))
+            .check(&prefix)
+            .is_ok());
+        assert!(update(&format!("{}rad/signed_refs", prefix))
+            .check(&prefix)
+            .is_ok());
+        assert!(update(&format!("{}rad/id", prefix)).check(&prefix).is_err());
+        assert!(update(&format!("{}heads/../../rad/id", prefix))
+            .check(&prefix)
+            .is_err());
+        assert!(update("refs/heads/master").check(&prefix).is_err());
+
+        let delete = Update {
+            new: git2::Oid::zero(),
+            ..update(&format!("{}heads/master", prefix))
+        };
+        assert!(delete.check(&prefix).is_err());
+    }
+
+    #[test]
+    fn update_head() {
+        let prefix = prefix();
+
+        assert_eq!(
+            Some("refs/heads/next".to_owned()),
+            update(&format!("{}heads/next", prefix)).head(&prefix)
+        );
+        assert_eq!(
+            None,
+            update(&format!("{}rad/signed_refs", prefix)).head(&prefix)
+        );
+    }
+
+    struct Pushable {
+        repo: git2::Repository,
+        peer: PeerId,
+        prefix: String,
+        head: git2::Oid,
+        signed_refs: git2::Oid,
+    }
+
+    /// A repo with a commit, and `rad/signed_refs` of a peer which signed that
+    /// commit as its `master` branch.
+    fn pushable(path: &Path) -> Pushable {
+        let key = SecretKey::new();
+        let peer = PeerId::from(&key);
+        let urn = RadUrn::new(Hash::hash(b"geez"), uri::Protocol::Git, uri::Path::empty());
+        let repo = git2::Repository::init_bare(path).unwrap();
+
+        let sig = git2::Signature::now("geez", "geez@acme.com").unwrap();
+        let commit = |repo: &git2::Repository, tree: git2::Oid, msg: &str| {
+            let tree = repo.find_tree(tree).unwrap();
+            repo.commit(None, &sig, &sig, msg, &tree, &[]).unwrap()
+        };
+
+        let empty = repo.treebuilder(None).unwrap().write().unwrap();
+        let head = commit(&repo, empty, "head");
+
+        let signed = Refs {
+            heads: Some(("refs/heads/master".to_owned(), head.into()))
+                .into_iter()
+                .collect::<BTreeMap<_, _>>(),
+            issues: BTreeMap::new(),
+            remotes: Remotes::from_map(HashMap::new()),
+        }
+        .sign(&key)
+        .unwrap();
+        let blob = repo.blob(&serde_json::to_vec(&signed).unwrap()).unwrap();
+        let tree = {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("refs", blob, 0o100_644).unwrap();
+            builder.write().unwrap()
+        };
+        let signed_refs = commit(&repo, tree, "signed refs");
+
+        Pushable {
+            repo,
+            prefix: super::prefix(&urn, &peer),
+            peer,
+            head,
+            signed_refs,
+        }
+    }
+
+    #[test]
+    fn apply_updates() {
+        let tmp = tempdir().unwrap();
+        let pushable = pushable(tmp.path());
+        let updates = vec![
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.signed_refs,
+                name: format!("{}rad/signed_refs", pushable.prefix),
+            },
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.head,
+                name: format!("{}heads/master", pushable.prefix),
+            },
+        ];
+
+        apply(&pushable.repo, &pushable.peer, &pushable.prefix, &updates).unwrap();
+        for update in updates {
+            assert_eq!(
+                pushable.repo.refname_to_id(&update.name).unwrap(),
+                update.new
+            )
+        }
+    }
+
+    #[test]
+    fn apply_all_or_nothing() {
+        let tmp = tempdir().unwrap();
+        let pushable = pushable(tmp.path());
+        let updates = vec![
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.signed_refs,
+                name: format!("{}rad/signed_refs", pushable.prefix),
+            },
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.head,
+                name: format!("{}heads/master", pushable.prefix),
+            },
+            // Claims to update a branch we don't have
+            Update {
+                old: pushable.head,
+                new: pushable.head,
+                name: format!("{}heads/next", pushable.prefix),
+            },
+        ];
+
+        assert_matches!(
+            apply(&pushable.repo, &pushable.peer, &pushable.prefix, &updates),
+            Err(Error::Stale(name)) if name == updates[2].name
+        );
+        for update in updates {
+            assert!(pushable.repo.find_reference(&update.name).is_err())
+        }
+    }
+
+    #[test]
+    fn apply_rejects_unsigned() {
+        let tmp = tempdir().unwrap();
+        let pushable = pushable(tmp.path());
+        let updates = vec![
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.signed_refs,
+                name: format!("{}rad/signed_refs", pushable.prefix),
+            },
+            Update {
+                old: git2::Oid::zero(),
+                new: pushable.head,
+                name: format!("{}heads/next", pushable.prefix),
+            },
+        ];
+
+        assert_matches!(
+            apply(&pushable.repo, &pushable.peer, &pushable.prefix, &updates),
+            Err(Error::Unsigned(_))
+        );
+        for update in updates {
+            assert!(pushable.repo.find_reference(&update.name).is_err())
+        }
+    }
+
+    #[tokio::test]
+    async fn read_pkt_lines() {
+        let mut recv = futures::io::Cursor::new(b"0006a\n00000003".to_vec());
+
+        assert_eq!(
+            Some(b"a\n".to_vec()),
+            read_pkt_line(&mut recv).await.unwrap()
+        );
+        assert_eq!(None, read_pkt_line(&mut recv).await.unwrap());
+        assert!(read_pkt_line(&mut recv).await.is_err());
+    }
+}
@@ -43,13 +43,19 @@
 //! transport incompatible with [`git-daemon`] for now, so the other side
 //! needs to run our own [`GitServer`].
 //!
+//! When asked for the refs advertisement, we try to use protocol v2 to only
+//! list the refs of the requested namespace, see [`RadSubTransport::ls_refs`].
+//!
+//! If the URL carries a `filter`, it is added to the `upload-pack` requests
+//! `libgit2` sends, which doesn't know about partial clones itself.
+//!
 //! [`git-daemon`]: https://git-scm.com/docs/git-daemon
 //! [`GitServer`]: ../server/struct.GitServer.html
 
 use std::{
     collections::HashMap,
     fmt::Display,
-    io::{self, Read, Write},
+    io::{self, Cursor, Read, Write},
     net::SocketAddr,
     sync::{Arc, Once, RwLock, Weak},
 };
@@ -61,7 +67,13 @@ use futures::{
 use git2::transport::{Service, SmartSubtransport, SmartSubtransportStream, Transport};
 
 use crate::{
-    git::{ext::into_git_err, header::Header, p2p::url::GitUrl},
+    git::{
+        ext::{into_git_err, UPLOAD_PACK_HEADER},
+        header::{Header, Version},
+        p2p::url::GitUrl,
+        pkt_line::{self, Pkt},
+        upload_pack,
+    },
     peer::PeerId,
     uri::{self, RadUrn},
 };
@@ -134,12 +146,17 @@ impl RadTransport {
         self.fac.write().unwrap().insert(peer_id.clone(), fac);
     }
 
-    fn open_stream(
+    pub(crate) fn open_stream(
         &self,
         from: &PeerId,
         to: &PeerId,
         addr_hints: &[SocketAddr],
     ) -> Option<Box<dyn GitStream>> {
+        block_on(self.stream_factory(from)?.open_stream(to, addr_hints))
+    }
+
+    /// The [`GitStreamFactory`] registered for `from`, if it is still alive.
+    fn stream_factory(&self, from: &PeerId) -> Option<Arc<Box<dyn GitStreamFactory>>> {
         let fac = self.fac.read().unwrap();
         match fac.get(from) {
             None => None,
@@ -154,7 +171,7 @@ impl RadTransport {
                     fac.remove(from);
                     None
                 },
-                Some(fac) => block_on(fac.open_stream(to, addr_hints)),
+                Some(fac) => Some(fac),
             },
         }
     }
@@ -171,11 +188,19 @@ impl SmartSubtransport for RadTransport {
             .open_stream(&url.local_peer, &url.remote_peer, &url.addr_hints)
             .ok_or_else(|| into_git_err(format!("No connection to {}", url.remote_peer)))?;
 
+        let request = match (service, url.filter) {
+            (Service::UploadPack, Some(_)) => Some(Vec::new()),
+            _ => None,
+        };
+
         Ok(Box::new(RadSubTransport {
+            transport: self.clone(),
             header_sent: false,
             url,
             service,
             stream,
+            advertisement: None,
+            request,
         }))
     }
 
@@ -185,35 +210,178 @@ impl SmartSubtransport for RadTransport {
 }
 
 struct RadSubTransport {
+    /// To open another stream, if the remote turns out not to support
+    /// `ls-refs`.
+    transport: RadTransport,
     header_sent: bool,
     url: GitUrl,
     service: Service,
     stream: Box<dyn GitStream>,
+    /// The refs advertisement, as handed to `libgit2`.
+    advertisement: Option<Cursor<Vec<u8>>>,
+    /// The `upload-pack` request, buffered until it is complete so we can add
+    /// the filter.
+    request: Option<Vec<u8>>,
 }
 
 impl RadSubTransport {
+    fn header(&self) -> Header {
+        Header::new(
+            self.service,
+            RadUrn::new(
+                self.url.repo.clone(),
+                uri::Protocol::Git,
+                uri::Path::empty(),
+            ),
+            self.url.remote_peer.clone(),
+        )
+    }
+
     async fn ensure_header_sent(&mut self) -> io::Result<()> {
         if !self.header_sent {
             self.header_sent = true;
-            let header = Header::new(
-                self.service,
-                RadUrn::new(
-                    self.url.repo.clone(),
-                    uri::Protocol::Git,
-                    uri::Path::empty(),
-                ),
-                self.url.remote_peer.clone(),
-            );
+            let header = self.header();
             self.stream.write_all(header.to_string().as_bytes()).await
         } else {
             Ok(())
         }
     }
+
+    /// Get the refs advertisement.
+    ///
+    /// We ask for protocol v2, and if the remote supports it, send an
+    /// `ls-refs` command for only the refs of the requested namespace. As
+    /// `libgit2` only speaks protocol v0, the result is turned into a v0
+    /// advertisement. Otherwise, the remote responds with a v0 advertisement
+    /// right away, which is passed on as is. If the remote speaks v2, but
+    /// doesn't support `ls-refs`, we ask again for a v0 advertisement on a new
+    /// stream.
+    async fn ls_refs(&mut self) -> io::Result<Vec<u8>> {
+        self.header_sent = true;
+        let header = self.header().with_version(Version::V2);
+        self.stream.write_all(header.to_string().as_bytes()).await?;
+
+        let mut capabilities = Vec::new();
+        let mut v0 = Vec::new();
+        loop {
+            let pkt = pkt_line::read_async(&mut self.stream).await?;
+            pkt.encode(&mut v0)?;
+            match pkt {
+                Pkt::Data(line) if capabilities.is_empty() && line != b"version 2\n" => {
+                    return if line.starts_with(b"ERR ") {
+                        Ok(v0)
+                    } else {
+                        self.read_v0(v0).await
+                    }
+                },
+                Pkt::Data(line) => capabilities.push(line),
+                Pkt::Flush => break,
+                Pkt::Delim => return Err(io_error("unexpected delim-pkt")),
+            }
+        }
+
+        if !upload_pack::supports_ls_refs(capabilities.iter().map(|cap| cap.as_slice())) {
+            self.reconnect().await?;
+            let header = self.header();
+            self.stream.write_all(header.to_string().as_bytes()).await?;
+            return self.read_v0(Vec::new()).await;
+        }
+
+        let mut cmd = Vec::new();
+        upload_pack::LsRefs {
+            prefixes: vec![format!("refs/namespaces/{}/", self.url.repo)],
+        }
+        .encode(&mut cmd)?;
+        self.stream.write_all(&cmd).await?;
+        self.stream.flush().await?;
+
+        let mut refs = Vec::new();
+        loop {
+            match pkt_line::read_async(&mut self.stream).await? {
+                Pkt::Flush => break,
+                Pkt::Data(line) if line.starts_with(b"ERR ") => {
+                    return Err(io_error(String::from_utf8_lossy(&line).trim_end()))
+                },
+                Pkt::Data(line) => refs.push(upload_pack::parse_ref(&line).map_err(io_error)?),
+                Pkt::Delim => return Err(io_error("unexpected delim-pkt")),
+            }
+        }
+
+        let mut adv = UPLOAD_PACK_HEADER.to_vec();
+        upload_pack::advertise(&refs, &mut adv)?;
+        Ok(adv)
+    }
+
+    /// Close the stream, and open a new one to the same remote.
+    async fn reconnect(&mut self) -> io::Result<()> {
+        self.stream.close().await?;
+        let stream = match self.transport.stream_factory(&self.url.local_peer) {
+            Some(fac) => {
+                fac.open_stream(&self.url.remote_peer, &self.url.addr_hints)
+                    .await
+            },
+            None => None,
+        };
+        self.stream =
+            stream.ok_or_else(|| io_error(format!("No connection to {}", self.url.remote_peer)))?;
+
+        Ok(())
+    }
+
+    /// Read the rest of a v0 advertisement, of which we've read `adv` so far:
+    /// the service line, a flush packet, and the refs up to another flush
+    /// packet.
+    async fn read_v0(&mut self, mut adv: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut flushes = 0;
+        while flushes < 2 {
+            let pkt = pkt_line::read_async(&mut self.stream).await?;
+            pkt.encode(&mut adv)?;
+            if let Pkt::Flush = pkt {
+                flushes += 1
+            }
+        }
+
+        Ok(adv)
+    }
+
+    /// Send the buffered `upload-pack` request, with the `filter` line added
+    /// to the wants.
+    async fn send_request(&mut self, request: Vec<u8>) -> io::Result<()> {
+        let mut filtered = Vec::with_capacity(request.len() + 32);
+        let mut filter = self.url.filter;
+        let mut rest = &request[..];
+        while !rest.is_empty() {
+            let pkt = pkt_line::read(&mut rest)?;
+            if let Pkt::Flush = pkt {
+                if let Some(filter) = filter.take() {
+                    pkt_line::write(&mut filtered, format!("filter {}\n", filter).as_bytes())?;
+                }
+            }
+            pkt.encode(&mut filtered)?;
+        }
+
+        self.ensure_header_sent().await?;
+        self.stream.write_all(&filtered).await?;
+        self.stream.flush().await
+    }
 }
 
 impl Read for RadSubTransport {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         block_on(async {
+            if let Service::UploadPackLs = self.service {
+                if self.advertisement.is_none() {
+                    let adv = self.ls_refs().await?;
+                    self.advertisement = Some(Cursor::new(adv));
+                }
+                if let Some(adv) = self.advertisement.as_mut() {
+                    return Read::read(adv, buf);
+                }
+            }
+            if let Some(request) = self.request.take() {
+                self.send_request(request).await?;
+            }
+
             self.ensure_header_sent().await?;
             self.stream.read(buf).await.map_err(io_error)
         })
@@ -222,6 +390,10 @@ impl Read for RadSubTransport {
 
 impl Write for RadSubTransport {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(request) = self.request.as_mut() {
+            return Write::write(request, buf);
+        }
+
         block_on(async {
             self.ensure_header_sent().await?;
             self.stream.write(buf).await.map_err(io_error)
@@ -229,6 +401,10 @@ impl Write for RadSubTransport {
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if self.request.is_some() {
+            return Ok(());
+        }
+
         block_on(async {
             self.ensure_header_sent().await?;
             self.stream.flush().await.map_err(io_error)
@@ -239,3 +415,92 @@ impl Write for RadSubTransport {
 fn io_error<E: Display>(err: E) -> io::Error {
     io::Error::new(io::ErrorKind::Other, err.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::io::{AsyncBufReadExt, BufReader};
+    use tokio::{runtime::Handle, task::spawn_blocking};
+
+    use crate::{hash::Hash, keys::SecretKey, net::connection::mock::MockStream};
+
+    /// A remote which speaks protocol v2, but doesn't support `ls-refs`.
+    struct NoLsRefs {
+        local: PeerId,
+        refs: Vec<(String, git2::Oid)>,
+        runtime: Handle,
+    }
+
+    #[async_trait]
+    impl GitStreamFactory for NoLsRefs {
+        async fn open_stream(
+            &self,
+            to: &PeerId,
+            _addr_hints: &[SocketAddr],
+        ) -> Option<Box<dyn GitStream>> {
+            let (ours, theirs) = MockStream::pair(to.clone(), self.local.clone(), 64 * 1024);
+            let refs = self.refs.clone();
+            self.runtime.spawn(async move {
+                let (recv, mut send) = theirs.split();
+                let mut recv = BufReader::new(recv);
+                let mut header = String::new();
+                recv.read_line(&mut header).await.unwrap();
+
+                let mut out = Vec::new();
+                if header.parse::<Header>().unwrap().version == Version::V2 {
+                    pkt_line::write(&mut out, b"version 2\n").unwrap();
+                    pkt_line::write(&mut out, b"agent=git/2.28.0\n").unwrap();
+                    out.extend_from_slice(pkt_line::FLUSH);
+                } else {
+                    out.extend_from_slice(UPLOAD_PACK_HEADER);
+                    upload_pack::advertise(&refs, &mut out).unwrap();
+                }
+                send.write_all(&out).await.unwrap();
+            });
+
+            Some(Box::new(ours))
+        }
+    }
+
+    #[tokio::test]
+    async fn ls_refs_falls_back_to_v0() {
+        let local = PeerId::from(SecretKey::new());
+        let remote = PeerId::from(SecretKey::new());
+        let repo = Hash::hash(b"leboeuf");
+        let refs = vec![(
+            format!("refs/namespaces/{}/refs/heads/master", repo),
+            git2::Oid::from_str("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391").unwrap(),
+        )];
+
+        let fac: Arc<Box<dyn GitStreamFactory>> = Arc::new(Box::new(NoLsRefs {
+            local: local.clone(),
+            refs: refs.clone(),
+            runtime: Handle::current(),
+        }));
+        let transport = register();
+        transport.register_stream_factory(&local, Arc::downgrade(&fac));
+
+        let url = GitUrl {
+            local_peer: local,
+            remote_peer: remote,
+            addr_hints: vec![],
+            repo,
+            filter: None,
+        };
+        let adv = spawn_blocking(move || {
+            let mut stream = transport
+                .action(&url.to_string(), Service::UploadPackLs)
+                .unwrap();
+            let mut adv = Vec::new();
+            stream.read_to_end(&mut adv).unwrap();
+            adv
+        })
+        .await
+        .unwrap();
+
+        let mut expected = UPLOAD_PACK_HEADER.to_vec();
+        upload_pack::advertise(&refs, &mut expected).unwrap();
+        assert_eq!(adv, expected);
+    }
+}
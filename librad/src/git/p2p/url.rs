@@ -24,7 +24,10 @@ use std::{
 use thiserror::Error;
 use url::Url;
 
+pub use crate::git::upload_pack::Filter;
+
 use crate::{
+    git::upload_pack,
     hash::{self, Hash},
     peer::{self, PeerId},
     uri::{self, RadUrl, RadUrlRef, RadUrn},
@@ -36,6 +39,8 @@ pub struct GitUrl {
     pub remote_peer: PeerId,
     pub addr_hints: Vec<SocketAddr>,
     pub repo: Hash,
+    /// Fetch a partial clone, omitting the objects matched by the filter.
+    pub filter: Option<Filter>,
 }
 
 impl GitUrl {
@@ -60,6 +65,14 @@ impl GitUrl {
             remote_peer,
             addr_hints: addrs.into_iter().collect(),
             repo: urn.id,
+            filter: None,
+        }
+    }
+
+    pub fn with_filter(self, filter: Filter) -> Self {
+        Self {
+            filter: Some(filter),
+            ..self
         }
     }
 
@@ -69,6 +82,7 @@ impl GitUrl {
             remote_peer: &self.remote_peer,
             addr_hints: &self.addr_hints,
             repo: &self.repo,
+            filter: self.filter,
         }
     }
 
@@ -106,6 +120,9 @@ pub enum ParseError {
 
     #[error(transparent)]
     Addr(#[from] AddrParseError),
+
+    #[error(transparent)]
+    Filter(#[from] upload_pack::Error),
 }
 
 impl FromStr for GitUrl {
@@ -140,12 +157,18 @@ impl FromStr for GitUrl {
             .query_pairs()
             .filter_map(|(k, v)| if k == "addr" { v.parse().ok() } else { None })
             .collect();
+        let filter = url
+            .query_pairs()
+            .find(|(k, _)| k == "filter")
+            .map(|(_, v)| v.parse())
+            .transpose()?;
 
         Ok(Self {
             local_peer,
             remote_peer,
             addr_hints,
             repo,
+            filter,
         })
     }
 }
@@ -169,6 +192,7 @@ pub struct GitUrlRef<'a> {
     pub remote_peer: &'a PeerId,
     pub addr_hints: &'a [SocketAddr],
     pub repo: &'a Hash,
+    pub filter: Option<Filter>,
 }
 
 impl<'a> GitUrlRef<'a> {
@@ -208,6 +232,7 @@ impl<'a> GitUrlRef<'a> {
             remote_peer,
             addr_hints: addr_hints.as_ref(),
             repo: &urn.id,
+            filter: None,
         }
     }
 
@@ -217,6 +242,7 @@ impl<'a> GitUrlRef<'a> {
             remote_peer: self.remote_peer.clone(),
             addr_hints: self.addr_hints.to_vec(),
             repo: self.repo.clone(),
+            filter: self.filter,
         }
     }
 }
@@ -236,6 +262,10 @@ impl<'a> Display for GitUrlRef<'a> {
                 .iter()
                 .map(|addr| ("addr", addr.to_string())),
         );
+        if let Some(filter) = self.filter {
+            url.query_pairs_mut()
+                .append_pair("filter", &filter.to_string());
+        }
         url.set_path(&format!("/{}.git", self.repo));
 
         f.write_str(url.as_str())
@@ -266,6 +296,20 @@ mod tests {
                 )),
             ],
             repo: Hash::hash(b"leboeuf"),
+            filter: None,
+        };
+
+        str_roundtrip(url)
+    }
+
+    #[test]
+    fn test_str_roundtrip_filter() {
+        let url = GitUrl {
+            local_peer: PeerId::from(SecretKey::new()),
+            remote_peer: PeerId::from(SecretKey::new()),
+            addr_hints: vec![],
+            repo: Hash::hash(b"leboeuf"),
+            filter: Some(Filter::BlobNone),
         };
 
         str_roundtrip(url)
@@ -0,0 +1,157 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The [pkt-line] framing of the git wire protocol
+//!
+//! [pkt-line]: https://git-scm.com/docs/protocol-common#_pkt_line_format
+
+use std::{
+    io::{self, Write},
+    str,
+};
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+pub const FLUSH: &[u8] = b"0000";
+pub const DELIM: &[u8] = b"0001";
+
+/// The maximum length of a pkt-line, including the length prefix.
+pub const MAX_LEN: usize = 65520;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pkt<T> {
+    Flush,
+    /// Separates sections in protocol v2.
+    Delim,
+    Data(T),
+}
+
+impl<T: AsRef<[u8]>> Pkt<T> {
+    pub fn as_deref(&self) -> Pkt<&[u8]> {
+        match self {
+            Self::Flush => Pkt::Flush,
+            Self::Delim => Pkt::Delim,
+            Self::Data(data) => Pkt::Data(data.as_ref()),
+        }
+    }
+
+    pub fn encode<W>(&self, out: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Self::Flush => out.write_all(FLUSH),
+            Self::Delim => out.write_all(DELIM),
+            Self::Data(data) => write(out, data.as_ref()),
+        }
+    }
+}
+
+/// Read a pkt-line from the front of `buf`.
+pub fn read<'a>(buf: &mut &'a [u8]) -> io::Result<Pkt<&'a [u8]>> {
+    if buf.len() < 4 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let len = parse_len(&buf[..4])?;
+    let pkt = match len {
+        0 => Pkt::Flush,
+        1 => Pkt::Delim,
+        len if len > buf.len() => return Err(io::ErrorKind::UnexpectedEof.into()),
+        len => Pkt::Data(&buf[4..len]),
+    };
+    *buf = &buf[len.max(4)..];
+
+    Ok(pkt)
+}
+
+/// Read a pkt-line from `recv`.
+pub async fn read_async<R>(recv: &mut R) -> io::Result<Pkt<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0; 4];
+    recv.read_exact(&mut len).await?;
+
+    match parse_len(&len)? {
+        0 => Ok(Pkt::Flush),
+        1 => Ok(Pkt::Delim),
+        len => {
+            let mut buf = vec![0; len - 4];
+            recv.read_exact(&mut buf).await?;
+            Ok(Pkt::Data(buf))
+        },
+    }
+}
+
+pub fn write<W>(out: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    assert!(
+        data.len() + 4 <= MAX_LEN,
+        "pkt-line data must not exceed {} bytes",
+        MAX_LEN - 4
+    );
+
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+/// Parse the length prefix, which must be either that of a special packet, or
+/// denote a non-empty data packet.
+fn parse_len(len: &[u8]) -> io::Result<usize> {
+    str::from_utf8(len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .filter(|len| *len <= 1 || (*len > 4 && *len <= MAX_LEN))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pkt-line length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let pkts = vec![
+            Pkt::Data(&b"command=ls-refs\n"[..]),
+            Pkt::Delim,
+            Pkt::Data(&b"ref-prefix refs/heads/\n"[..]),
+            Pkt::Flush,
+        ];
+
+        let mut buf = Vec::new();
+        for pkt in &pkts {
+            pkt.encode(&mut buf).unwrap();
+        }
+
+        let mut rest = &buf[..];
+        for pkt in pkts {
+            assert_eq!(pkt, read(&mut rest).unwrap())
+        }
+        assert!(rest.is_empty())
+    }
+
+    #[test]
+    fn invalid_len() {
+        assert!(read(&mut &b"0004"[..]).is_err());
+        assert!(read(&mut &b"000"[..]).is_err());
+        assert!(read(&mut &b"zzzz"[..]).is_err());
+        assert!(read(&mut &b"0009abc"[..]).is_err());
+    }
+}
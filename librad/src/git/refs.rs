@@ -114,10 +114,15 @@ pub mod signing {
     }
 }
 
-/// The current `refs/heads` and [`Remotes`] (transitive tracking graph)
+/// The current `refs/heads`, `refs/issues` and [`Remotes`] (transitive
+/// tracking graph)
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Refs {
     pub heads: BTreeMap<String, Oid>,
+    /// Omitted from the signed form if empty, so refs signed before issues
+    /// were included still verify.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub issues: BTreeMap<String, Oid>,
     pub remotes: Remotes<PeerId>,
 }
 
@@ -15,7 +15,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::{collections::HashSet, net::SocketAddr};
+use std::{collections::BTreeSet, net::SocketAddr};
 
 use thiserror::Error;
 
@@ -25,8 +25,11 @@ use crate::{
         storage::{self, RadSelfSpec, Storage},
         types::Namespace,
     },
+    identities::{
+        git::{Revision, VerifiedUser},
+        urn::Urn,
+    },
     keys,
-    meta::{entity::Draft, user::User},
     peer::PeerId,
     signer::Signer,
     uri::{RadUrl, RadUrn},
@@ -78,12 +81,12 @@ impl<S: Clone> Repo<'_, S> {
     }
 
     /// Get `rad/self` identity for this repo.
-    pub fn get_rad_self(&self) -> Result<User<Draft>, Error> {
+    pub fn get_rad_self(&self) -> Result<VerifiedUser, Error> {
         self.get_rad_self_of(None)
     }
 
     /// Get the `rad/self` identity for the remote `peer` under the `urn`.
-    pub fn get_rad_self_of<P>(&self, peer: P) -> Result<User<Draft>, Error>
+    pub fn get_rad_self_of<P>(&self, peer: P) -> Result<VerifiedUser, Error>
     where
         P: Into<Option<PeerId>>,
     {
@@ -92,8 +95,8 @@ impl<S: Clone> Repo<'_, S> {
             .map_err(Error::from)
     }
 
-    /// Retrieve the certifier URNs of this repo's identity
-    pub fn certifiers(&self) -> Result<HashSet<RadUrn>, Error> {
+    /// Retrieve the URNs of the user delegations of this repo's identity
+    pub fn certifiers(&self) -> Result<BTreeSet<Urn<Revision>>, Error> {
         self.storage.certifiers(&self.urn).map_err(Error::from)
     }
 
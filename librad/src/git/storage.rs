@@ -27,7 +27,6 @@ use std::{
     path::Path,
 };
 
-use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
 
 use crate::{
@@ -39,29 +38,18 @@ use crate::{
             Oid,
             References,
         },
-        p2p::url::{GitUrl, GitUrlRef},
+        p2p::url::{Filter, GitUrl, GitUrlRef},
         refs::{self, Refs},
         repo::Repo,
         types::{Force, Multiple, NamespacedRef, Single},
     },
     hash::Hash,
+    identities::{self, git::Revision, urn::Urn},
     internal::{
         canonical::{Cjson, CjsonError},
         result::ResultExt,
     },
     keys,
-    meta::{
-        entity::{
-            self,
-            data::EntityInfoExt,
-            Draft,
-            Entity,
-            GenericDraftEntity,
-            Signatory,
-            Verified,
-        },
-        user::User,
-    },
     paths::Paths,
     peer::{self, PeerId},
     signer::Signer,
@@ -73,6 +61,10 @@ pub use pool::{Pool, Pooled};
 
 mod config;
 mod fetch;
+mod identity;
+
+pub use fetch::{CancelToken, FetchHooks, FetchProgress, FetchStage};
+pub use identity::{rad_urn, SomeVerifiedIdentity};
 
 #[cfg(test)]
 mod test;
@@ -93,8 +85,13 @@ pub enum Error {
     )]
     RootHashMismatch { expected: Hash, actual: Hash },
 
-    #[error("metadata is not signed")]
-    UnsignedMetadata,
+    #[error(
+        "identity root doesn't match the requested URN. Expected {expected}, actual: {actual}"
+    )]
+    IdentityRootMismatch {
+        expected: Revision,
+        actual: Revision,
+    },
 
     #[error("signer key does not match key used at initialisation")]
     SignerKeyMismatch,
@@ -102,15 +99,30 @@ pub enum Error {
     #[error("can't refer to the local key for this operation")]
     SelfReferential,
 
-    #[error("metadata must be signed by local key")]
+    #[error("identity must be signed by local key")]
     NotSignedBySelf,
 
-    #[error("local key certifier not found: {0}")]
-    NoSelf(NamespacedRef<Single>),
-
     #[error("missing certifier {certifier} of {urn}")]
     MissingCertifier { certifier: RadUrn, urn: RadUrn },
 
+    #[error("none of the tracked peers of {0} provided the missing objects")]
+    NoProvider(RadUrn),
+
+    #[error("{peer} rejected the push of {refname}: {reason}")]
+    PushRejected {
+        peer: PeerId,
+        refname: String,
+        reason: String,
+    },
+
+    #[error("rejected identity {urn} as seen by {peer}")]
+    Rejected {
+        urn: RadUrn,
+        peer: PeerId,
+        #[source]
+        reason: Box<Error>,
+    },
+
     #[error(transparent)]
     PeerId(#[from] peer::conversion::Error),
 
@@ -118,7 +130,13 @@ pub enum Error {
     Urn(#[from] uri::rad_urn::ParseError),
 
     #[error(transparent)]
-    Entity(#[from] entity::Error),
+    Identity(#[from] identities::git::error::Load),
+
+    #[error(transparent)]
+    VerifyUser(#[from] identities::git::error::VerifyUser),
+
+    #[error(transparent)]
+    VerifyProject(#[from] identities::git::error::VerifyProject<git2::Error>),
 
     #[error(transparent)]
     Fetch(#[from] fetch::Error),
@@ -148,7 +166,7 @@ pub enum Error {
 #[derive(Clone, Debug)]
 pub enum RadSelfSpec {
     Default,
-    Urn(RadUrn),
+    Urn(Urn<Revision>),
 }
 
 pub type NoSigner = PhantomData<!>;
@@ -180,83 +198,6 @@ impl<S: Clone> Storage<S> {
         })
     }
 
-    /// Get the [`Entity`] metadata found at the provided [`RadUrn`].
-    pub fn metadata<T>(&self, urn: &RadUrn) -> Result<Entity<T, Draft>, Error>
-    where
-        T: Clone + Serialize + DeserializeOwned + EntityInfoExt,
-    {
-        self.metadata_of(urn, None)
-    }
-
-    /// Get the [`Entity`] metadata of the tracked `peer` at the provided
-    /// [`RadUrn`].
-    ///
-    /// Note that "tracked" here refers to the transitive tracking graph. That
-    /// is, the metadata will resolve if, and only if, it has been fetched from
-    /// the network acc. to the replication rules prior to calling this method.
-    pub fn metadata_of<T, P>(&self, urn: &RadUrn, peer: P) -> Result<Entity<T, Draft>, Error>
-    where
-        T: Clone + Serialize + DeserializeOwned + EntityInfoExt,
-        P: Into<Option<PeerId>>,
-    {
-        self.metadata_from_reference(
-            NamespacedRef::rad_id(urn.id.clone())
-                .set_remote(peer.into())
-                .borrow(),
-        )
-    }
-
-    /// Like [`Storage::metadata`], but for situations where the type is not
-    /// statically known.
-    pub fn some_metadata(&self, urn: &RadUrn) -> Result<GenericDraftEntity, Error> {
-        self.some_metadata_of(urn, None)
-    }
-
-    /// Like [`Storage::metadata_of`], but for situations where the type is not
-    /// statically known.
-    pub fn some_metadata_of<P>(&self, urn: &RadUrn, peer: P) -> Result<GenericDraftEntity, Error>
-    where
-        P: Into<Option<PeerId>>,
-    {
-        self.metadata_from_reference(
-            NamespacedRef::rad_id(urn.id.clone())
-                .set_remote(peer.into())
-                .borrow(),
-        )
-    }
-
-    /// Get all the [`Entity`] data in this `Storage`.
-    ///
-    /// The caller has the choice to filter on the [`EntityInfo`], which is
-    /// useful when the you want a list of a specific kind of `Entity`.
-    pub fn all_metadata<'a>(
-        &'a self,
-    ) -> Result<impl Iterator<Item = Result<GenericDraftEntity, Error>> + 'a, Error> {
-        let iter = References::from_globs(&self.backend, &["refs/namespaces/*/refs/rad/id"])?;
-
-        Ok(iter.map(move |reference| self.metadata_from_reference(reference?)))
-    }
-
-    /// Retrieve the `rad/self` identity configured via
-    /// [`Storage::set_default_rad_self`].
-    pub fn default_rad_self(&self) -> Result<User<Draft>, Error> {
-        let urn = Config::try_from(&self.backend)?.user()?;
-        self.metadata(&urn)
-    }
-
-    /// Get the `rad/self` identity for `urn`.
-    pub fn get_rad_self(&self, urn: &RadUrn) -> Result<User<Draft>, Error> {
-        self.get_rad_self_of(urn, None)
-    }
-
-    /// Get the `rad/self` identity for the remote `peer` under the `urn`.
-    pub fn get_rad_self_of<P>(&self, urn: &RadUrn, peer: P) -> Result<User<Draft>, Error>
-    where
-        P: Into<Option<PeerId>>,
-    {
-        self.metadata_from_reference(NamespacedRef::rad_self(urn.id.clone(), peer).borrow())
-    }
-
     pub fn certifiers_of(&self, urn: &RadUrn, peer: &PeerId) -> Result<HashSet<RadUrn>, Error> {
         let mut refs = References::from_globs(
             &self.backend,
@@ -344,6 +285,12 @@ impl<S: Clone> Storage<S> {
 
         tracing::debug!(heads = ?heads);
 
+        // Collect refs/issues (our issues) at their current state
+        let issues = self.references_glob(urn, Some("refs/issues/*"))?;
+        let issues: BTreeMap<String, Oid> = issues.map(|(name, oid)| (name, Oid(oid))).collect();
+
+        tracing::debug!(issues = ?issues);
+
         // Get 1st degree tracked peers from the remotes configured in .git/config
         let tracked = self.tracked(urn)?;
         let mut remotes: HashMap<PeerId, HashMap<PeerId, HashSet<PeerId>>> =
@@ -366,6 +313,7 @@ impl<S: Clone> Storage<S> {
 
         Ok(Refs {
             heads,
+            issues,
             remotes: remotes.into(),
         })
     }
@@ -384,6 +332,20 @@ impl<S: Clone> Storage<S> {
         Ok(Refs::from(signed))
     }
 
+    /// Read our signed [`Refs`] as stored, ie. in their signed JSON form.
+    ///
+    /// This is what other peers can verify using [`refs::Signed::from_json`].
+    pub fn rad_signed_refs_json(&self, urn: &RadUrn) -> Result<Vec<u8>, Error> {
+        let refs = NamespacedRef::rad_signed_refs(urn.id.clone(), None);
+        let blob = Blob::Tip {
+            branch: refs.borrow().into(),
+            path: Path::new("refs"),
+        }
+        .get(&self.backend)?;
+
+        Ok(blob.content().to_vec())
+    }
+
     /// Get the [`NamespacedRef`] provided, if it exists.
     pub fn reference<'a>(
         &'a self,
@@ -400,19 +362,6 @@ impl<S: Clone> Storage<S> {
         reference.references(&self.backend).map_err(Error::from)
     }
 
-    /// The set of all certifiers of the given identity, transitively
-    pub fn certifiers(&self, urn: &RadUrn) -> Result<HashSet<RadUrn>, Error> {
-        let mut refs = References::from_globs(
-            &self.backend,
-            &[
-                format!("refs/namespaces/{}/refs/rad/ids/*", &urn.id),
-                format!("refs/namespaces/{}/refs/remotes/**/rad/ids/*", &urn.id),
-            ],
-        )?;
-        let refnames = refs.names();
-        Ok(urns_from_refs(refnames).collect())
-    }
-
     pub(crate) fn references_glob<'a>(
         &'a self,
         urn: &RadUrn,
@@ -433,23 +382,13 @@ impl<S: Clone> Storage<S> {
         }))
     }
 
-    fn metadata_from_reference<'a, R, T>(&'a self, reference: R) -> Result<Entity<T, Draft>, Error>
-    where
-        R: Into<blob::Branch<'a>>,
-        T: Clone + Serialize + DeserializeOwned + EntityInfoExt,
-    {
-        let blob = Blob::Tip {
-            branch: reference.into(),
-            path: Path::new("id"),
-        }
-        .get(&self.backend)?;
-
-        Entity::<T, Draft>::from_json_slice(blob.content()).map_err(Error::from)
-    }
-
     pub(crate) fn path(&self) -> &Path {
         self.backend.path()
     }
+
+    pub(crate) fn as_raw(&self) -> &git2::Repository {
+        &self.backend
+    }
 }
 
 impl Storage<NoSigner> {
@@ -545,88 +484,77 @@ where
         }
     }
 
-    pub fn create_repo<'a, T>(&'a self, meta: &Entity<T, Draft>) -> Result<Repo<'a, S>, Error>
+    /// Attempt to clone the designated repo from the network.
+    ///
+    /// The identity found in the repo must be the one its namespace is derived
+    /// from, and pass verification as seen by the remote peer.
+    ///
+    /// `addr_hints` may be supplied for the networking layer to establish a new
+    /// connection to the peer specified in the `url` if none is currently
+    /// active.
+    ///
+    /// Note that this method **must** be spawned on a `async` runtime, where
+    /// currently the only supported method is [`tokio::task::spawn_blocking`].
+    pub fn clone_repo<Addrs>(&self, url: RadUrl, addr_hints: Addrs) -> Result<Repo<S>, Error>
     where
-        T: Serialize + DeserializeOwned + Clone + EntityInfoExt,
+        Addrs: IntoIterator<Item = SocketAddr>,
     {
-        let span = tracing::info_span!("Storage::create_repo", local.id = %self.peer_id);
-        let _guard = span.enter();
-
-        // FIXME: properly verify meta
-
-        if meta.signatures().is_empty() {
-            return Err(Error::UnsignedMetadata);
-        }
-
-        let urn = RadUrn::new(
-            meta.root_hash().to_owned(),
-            uri::Protocol::Git,
-            uri::Path::empty(),
-        );
+        self.clone_repo_with(url, addr_hints, None, FetchHooks::default())
+    }
 
-        if self.has_urn(&urn)? {
-            return Err(Error::AlreadyExists(urn));
-        }
+    /// Like [`Storage::clone_repo`], but omit the objects matched by `filter`.
+    ///
+    /// The `rad/*` refs are always fetched in full, as they are needed to
+    /// verify the repo. The filter is remembered for subsequent fetches of
+    /// the repo, and objects are fetched from the tracked peers as they are
+    /// needed by the local transport (see [`Storage::fetch_objects`]). Note
+    /// that this requires the remote peers to support partial clones.
+    pub fn clone_repo_partial<Addrs>(
+        &self,
+        url: RadUrl,
+        addr_hints: Addrs,
+        filter: Filter,
+    ) -> Result<Repo<S>, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        self.clone_repo_with(url, addr_hints, Some(filter), FetchHooks::default())
+    }
 
-        let self_sig = meta
-            .signatures()
-            .get(&self.signer.public_key().into())
-            .ok_or(Error::NotSignedBySelf)?;
+    /// Like [`Storage::clone_repo_partial`], but the clone doesn't need to be
+    /// partial, and `hooks` are called with its progress.
+    ///
+    /// If the clone is cancelled via `hooks`, what was fetched so far is
+    /// deleted before the error is returned.
+    pub fn clone_repo_with<Addrs>(
+        &self,
+        url: RadUrl,
+        addr_hints: Addrs,
+        filter: Option<Filter>,
+        hooks: FetchHooks,
+    ) -> Result<Repo<S>, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        let span = tracing::info_span!("Storage::clone_repo", local.id = %self.peer_id, url = %url);
+        let _guard = span.enter();
 
-        let rad_id = NamespacedRef::rad_id(meta.urn().id);
-        let rad_self = NamespacedRef::rad_self(meta.urn().id, None);
-        let rad_self_target = match &self_sig.by {
-            Signatory::OwnedKey => rad_id.clone(),
-            Signatory::User(urn) => NamespacedRef::rad_id(urn.id.clone()),
+        let urn = RadUrn {
+            path: uri::Path::empty(),
+            ..url.urn.clone()
         };
 
-        // Invariants
-        {
-            // Check if `rad/self` has a valid target
-            if rad_id != rad_self_target && !self.has_ref(&rad_self_target)? {
-                return Err(Error::NoSelf(rad_self_target));
-            }
+        let mut git_url = GitUrl::from_rad_url(url, self.peer_id.clone(), addr_hints);
+        git_url.filter = filter;
+        self.clone_identity(git_url, hooks, |this, peer| this.verify_remote(&urn, peer))?;
 
-            // Check if `rad/ids/*` have valid targets
-            for certifier in meta.certifiers() {
-                if !self.has_urn(certifier)? {
-                    let certifier = certifier.clone();
-                    return Err(Error::MissingCertifier { certifier, urn });
-                }
-            }
-        }
-
-        self.commit_initial_meta(&meta)?;
-
-        // self and certifier symrefs
-        {
-            let res = iter::once((rad_self, rad_self_target))
-                .chain(meta.certifiers().iter().map(|certifier| {
-                    (
-                        NamespacedRef::rad_certifier(meta.urn().id, certifier),
-                        NamespacedRef::rad_id(certifier.id.clone()),
-                    )
-                }))
-                .try_for_each(|(src, target)| {
-                    target
-                        .symbolic_ref(src, Force::True)
-                        .create(&self.backend)
-                        .and(Ok(()))
-                });
-
-            if let Err(err) = res {
-                self.delete_repo(&urn)?;
-                return Err(err.into());
-            }
-        }
-
-        self.track_signers(&meta)?;
-        self.update_refs(&urn)?;
-
-        Ok(Repo { urn, storage: self })
+        Ok(Repo {
+            urn,
+            storage: &self,
+        })
     }
 
-    /// Attempt to clone the designated repo from the network.
+    /// Attempt to fetch updates for the designated repo from the network.
     ///
     /// `addr_hints` may be supplied for the networking layer to establish a new
     /// connection to the peer specified in the `url` if none is currently
@@ -634,107 +562,198 @@ where
     ///
     /// Note that this method **must** be spawned on a `async` runtime, where
     /// currently the only supported method is [`tokio::task::spawn_blocking`].
-    pub fn clone_repo<T, Addrs>(&self, url: RadUrl, addr_hints: Addrs) -> Result<Repo<S>, Error>
+    pub fn fetch_repo<Addrs>(&self, url: RadUrl, addr_hints: Addrs) -> Result<(), Error>
     where
-        T: Serialize + DeserializeOwned + Clone + EntityInfoExt,
         Addrs: IntoIterator<Item = SocketAddr>,
     {
-        let span = tracing::info_span!("Storage::clone_repo", local.id = %self.peer_id, url = %url);
-        let _guard = span.enter();
+        self.fetch_repo_with(url, addr_hints, FetchHooks::default())
+    }
 
-        let remote_peer = url.authority.clone();
+    /// Like [`Storage::fetch_repo`], but `hooks` are called with the progress
+    /// of the fetch.
+    ///
+    /// If the fetch is cancelled via `hooks`, the remote tracking refs are
+    /// restored to what they were before the error is returned.
+    pub fn fetch_repo_with<Addrs>(
+        &self,
+        url: RadUrl,
+        addr_hints: Addrs,
+        hooks: FetchHooks,
+    ) -> Result<(), Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        let span = tracing::info_span!("Storage::fetch", local.id = %self.peer_id, url = %url);
+        let _guard = span.enter();
 
         let urn = RadUrn {
             path: uri::Path::empty(),
             ..url.urn.clone()
         };
+        let mut git_url = GitUrl::from_rad_url(url, self.peer_id.clone(), addr_hints);
+        git_url.filter = Config::try_from(&self.backend)?.partial_clone_filter(&urn.id)?;
+        let fetcher = Fetcher::new(&self.backend, git_url, hooks)?;
+        self.fetch_internal(fetcher, |peer| self.verify_remote(&urn, peer).map(|_| ()))
+    }
 
-        if self.has_urn(&urn)? {
-            return Err(Error::AlreadyExists(urn));
-        }
+    /// Fetch the objects `oids` of the designated repo, which are missing
+    /// because it is a partial clone.
+    ///
+    /// The tracked peers are asked in turn, until one of them has all the
+    /// objects. Only peers we are currently connected to are considered.
+    ///
+    /// Like [`Storage::fetch_repo`], this method **must** be spawned on a
+    /// `async` runtime.
+    pub fn fetch_objects(&self, urn: &RadUrn, oids: &[git2::Oid]) -> Result<(), Error> {
+        let span =
+            tracing::info_span!("Storage::fetch_objects", local.id = %self.peer_id, urn = %urn);
+        let _guard = span.enter();
 
-        // Fetch the identity first
-        let git_url = GitUrl::from_rad_url(url, self.peer_id.clone(), addr_hints);
-        let mut fetcher = Fetcher::new(&self.backend, git_url)?;
-        fetcher.prefetch()?;
-
-        let meta = self.some_metadata_of(&urn, remote_peer.clone())?;
-
-        // TODO: properly verify
-        let valid: Result<(), Error> = {
-            if meta.signatures().is_empty() {
-                Err(Error::UnsignedMetadata)
-            } else if meta.root_hash() != &urn.id {
-                Err(Error::RootHashMismatch {
-                    expected: urn.id.clone(),
-                    actual: meta.root_hash().to_owned(),
-                })
-            } else {
-                Ok(())
+        let mut err = None;
+        for peer in self.tracked(urn)? {
+            let url = GitUrl::from_rad_urn(urn.clone(), self.peer_id.clone(), peer.clone(), None);
+            match fetch::objects(&self.backend, &url, oids) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    tracing::warn!(peer = %peer, "Failed to fetch objects: {}", e);
+                    err = Some(e);
+                },
             }
-        };
+        }
+
+        Err(err.map_or_else(|| Error::NoProvider(urn.clone()), Error::from))
+    }
 
-        if let Err(invalid) = valid {
-            self.delete_repo(&urn)?;
-            return Err(invalid);
+    /// Turn a partial clone of the designated repo into a full one.
+    ///
+    /// All objects reachable from the refs of the repo, which were omitted so
+    /// far, are fetched, and subsequent fetches will no longer apply a filter.
+    pub fn complete_repo(&self, urn: &RadUrn) -> Result<(), Error> {
+        let mut config = Config::try_from(&self.backend)?;
+        if config.partial_clone_filter(&urn.id)?.is_none() {
+            return Ok(());
         }
 
-        // We determined that `remote_peer`'s view of the identity is valid, so
-        // we can adopt it as our own (ie. make `refs/rad/id` point to what
-        // `remote_peer` said)
-        {
-            let local_id = NamespacedRef::rad_id(urn.id.clone());
-            let remote_id = local_id.set_remote(remote_peer);
-            let remote_id_head = self.reference(&remote_id).and_then(|reference| {
-                reference
-                    .target()
-                    .ok_or_else(|| {
-                        git2::Error::from_str(&format!(
-                            "We just read `{}`, but now it's gone",
-                            remote_id
-                        ))
-                    })
-                    .map_err(Error::from)
-            })?;
-            self.backend.reference(
-                &local_id.to_string(),
-                remote_id_head,
-                /* force */ false,
-                &format!("Adopted `{}` as ours", remote_id),
-            )?;
+        let missing = self.missing_blobs(urn)?;
+        tracing::debug!(urn = %urn, "Completing partial clone, {} blobs missing", missing.len());
+        if !missing.is_empty() {
+            self.fetch_objects(urn, &missing)?;
         }
 
-        self.track_signers(&meta)?;
-        self.update_refs(&urn)?;
-        self.fetch_internal(fetcher)?;
+        config.set_partial_clone_filter(&urn.id, None)?;
+        Ok(())
+    }
 
-        Ok(Repo {
-            urn,
-            storage: &self,
-        })
+    /// The blobs reachable from the refs of `urn`, which we don't have.
+    fn missing_blobs(&self, urn: &RadUrn) -> Result<Vec<git2::Oid>, Error> {
+        let odb = self.backend.odb()?;
+        let mut walk = self.backend.revwalk()?;
+        walk.push_glob(&format!("refs/namespaces/{}/*", urn.id))?;
+
+        let mut seen = HashSet::new();
+        let mut missing = Vec::new();
+        for oid in walk {
+            let tree = self.backend.find_commit(oid?)?.tree()?;
+            if !seen.insert(tree.id()) {
+                continue;
+            }
+            tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+                if !seen.insert(entry.id()) {
+                    return git2::TreeWalkResult::Skip;
+                }
+                if entry.kind() == Some(git2::ObjectType::Blob) && !odb.exists(entry.id()) {
+                    missing.push(entry.id());
+                }
+                git2::TreeWalkResult::Ok
+            })?;
+        }
+
+        Ok(missing)
     }
 
-    /// Attempt to fetch updates for the designated repo from the network.
+    /// Push our branches and `rad/signed_refs` of the designated repo to the
+    /// peer in `url`.
     ///
-    /// `addr_hints` may be supplied for the networking layer to establish a new
-    /// connection to the peer specified in the `url` if none is currently
-    /// active.
+    /// They end up in the remote tracking branches for our [`PeerId`] on the
+    /// other side, provided it accepts pushes from us: it needs to have the
+    /// repo, track us, and have enabled receiving pushes.
     ///
-    /// Note that this method **must** be spawned on a `async` runtime, where
-    /// currently the only supported method is [`tokio::task::spawn_blocking`].
-    pub fn fetch_repo<Addrs>(&self, url: RadUrl, addr_hints: Addrs) -> Result<(), Error>
+    /// Like [`Storage::fetch_repo`], this method **must** be spawned on a
+    /// `async` runtime.
+    pub fn push_repo<Addrs>(&self, url: RadUrl, addr_hints: Addrs) -> Result<(), Error>
     where
         Addrs: IntoIterator<Item = SocketAddr>,
     {
-        let span = tracing::info_span!("Storage::fetch", local.id = %self.peer_id, url = %url);
+        let span = tracing::info_span!("Storage::push", local.id = %self.peer_id, url = %url);
         let _guard = span.enter();
 
-        let git_url = GitUrl::from_rad_url(url, self.peer_id.clone(), addr_hints);
-        let fetcher = Fetcher::new(&self.backend, git_url)?;
-        self.fetch_internal(fetcher)
+        let urn = RadUrn {
+            path: uri::Path::empty(),
+            ..url.urn.clone()
+        };
+        let remote_peer = url.authority.clone();
+
+        // Make sure what we sign is what we push
+        self.update_refs(&urn)?;
+
+        let ours = self.peer_id.clone();
+        let refspecs = self
+            .references_glob(&urn, Some("refs/heads/*"))?
+            .filter_map(|(name, _)| {
+                name.strip_prefix("refs/heads/").map(|name| {
+                    NamespacedRef::head(urn.id.clone(), ours.clone(), name)
+                        .refspec(NamespacedRef::head(urn.id.clone(), None, name), Force::True)
+                        .to_string()
+                })
+            })
+            .chain(iter::once(
+                NamespacedRef::rad_signed_refs(urn.id.clone(), ours.clone())
+                    .refspec(
+                        NamespacedRef::rad_signed_refs(urn.id.clone(), None),
+                        Force::True,
+                    )
+                    .to_string(),
+            ))
+            .collect::<Vec<_>>();
+        tracing::debug!(refspecs = ?refspecs, "Pushing");
+
+        let git_url = GitUrl::from_rad_url(url, ours, addr_hints);
+        let mut remote = self.backend.remote_anonymous(&git_url.to_string())?;
+
+        let mut rejected = None;
+        {
+            let mut cbs = git2::RemoteCallbacks::new();
+            cbs.push_update_reference(|refname, status| {
+                if let Some(reason) = status {
+                    rejected.get_or_insert_with(|| (refname.to_owned(), reason.to_owned()));
+                }
+                Ok(())
+            });
+            let mut opts = git2::PushOptions::new();
+            opts.remote_callbacks(cbs);
+            remote.push(&refspecs, Some(&mut opts))?;
+        }
+
+        match rejected {
+            None => Ok(()),
+            Some((refname, reason)) => Err(Error::PushRejected {
+                peer: remote_peer,
+                refname,
+                reason,
+            }),
+        }
     }
 
-    fn fetch_internal(&self, mut fetcher: Fetcher<'_>) -> Result<(), Error> {
+    /// Fetch from the remote designated by `fetcher`, and run `verify` for
+    /// every remote whose refs were updated.
+    ///
+    /// The remote tracking refs of a rejected peer are rolled back to what
+    /// they were before the fetch, and the first rejection is returned as an
+    /// error after the remaining remotes have been processed.
+    fn fetch_internal<F>(&self, mut fetcher: Fetcher<'_>, verify: F) -> Result<(), Error>
+    where
+        F: Fn(&PeerId) -> Result<(), Error>,
+    {
         let url = fetcher.url();
         let urn = url.clone().into_rad_url().urn;
 
@@ -746,11 +765,46 @@ where
             .flatten()
             .collect::<HashSet<&PeerId>>();
 
-        fetcher.fetch(
+        let before = self.remote_heads(&urn)?;
+        let fetched = fetcher.fetch(
             transitively_tracked,
             |peer| self.rad_signed_refs_of(&urn, peer),
             |peer| self.certifiers_of(&urn, peer),
-        )?;
+        );
+        let after = self.remote_heads(&urn)?;
+        if let Err(e) = fetched {
+            // Don't leave a partial update behind if we were asked to stop
+            if let Error::Fetch(fetch::Error::Cancelled) = e {
+                let none = HashMap::new();
+                let peers = after.keys().chain(before.keys()).collect::<HashSet<_>>();
+                for peer in peers {
+                    let heads = after.get(peer).unwrap_or(&none);
+                    let prev = before.get(peer);
+                    if prev != Some(heads) {
+                        self.rollback_remote(heads, prev)?;
+                    }
+                }
+            }
+            return Err(e);
+        }
+
+        // Verify every remote which changed, and restore the previous state of
+        // the remotes we reject. Note that a remote may have updated its
+        // branches, but not its `rad/id`: those are only acceptable if the
+        // identity still checks out.
+        let mut rejected = None;
+        for (peer, heads) in &after {
+            let prev = before.get(peer);
+            if prev == Some(heads) {
+                continue;
+            }
+
+            if let Err(e) = verify(peer) {
+                tracing::warn!(peer = %peer, "rejecting fetched identity: {}", e);
+                self.rollback_remote(heads, prev)?;
+                rejected.get_or_insert(e);
+            }
+        }
 
         // Symref any certifiers from `remote_peer`, ie. for all valid refs in
         // the remotes's `rad/ids/*`, create a symref in the _local_ `rad/ids/*`
@@ -791,69 +845,82 @@ where
         // update the refs, but don't recurse here for now (we could, if
         // we reload `self.rad_signed_refs()` and compare to the value we had
         // before fetching).
-        self.update_refs(&urn)
-    }
+        self.update_refs(&urn)?;
 
-    // DO NOT MAKE THIS PUBLIC YET
-    fn delete_repo(&self, urn: &RadUrn) -> Result<(), Error> {
-        References::from_globs(&self.backend, &[format!("refs/namespaces/{}/*", urn.id)])?
-            .try_for_each(|reference| reference?.delete())
-            .map_err(Error::from)
+        match rejected {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
     }
 
-    /// Persist [`User`] `id` as the default `rad/self` identity
-    pub fn set_default_rad_self(&self, id: User<Verified>) -> Result<(), Error> {
-        let urn = id.urn();
-        if !self.has_urn(&urn)? {
-            return Err(Error::NoSuchUrn(urn));
+    /// Snapshot the remote tracking refs of `urn`, grouped by remote peer.
+    fn remote_heads(
+        &self,
+        urn: &RadUrn,
+    ) -> Result<HashMap<PeerId, HashMap<String, git2::Oid>>, Error> {
+        let prefix = format!("refs/namespaces/{}/refs/remotes/", urn.id);
+        let mut heads: HashMap<PeerId, HashMap<String, git2::Oid>> = HashMap::new();
+        let refs = References::from_globs(&self.backend, &[format!("{}*", prefix)])?;
+        for (name, oid) in refs.peeled() {
+            let peer = name
+                .strip_prefix(&prefix)
+                .and_then(|suffix| suffix.split('/').next())
+                .and_then(|peer| peer.parse::<PeerId>().ok());
+            if let Some(peer) = peer {
+                heads.entry(peer).or_default().insert(name, oid);
+            }
         }
 
-        Config::try_from(&self.backend)?
-            .set_user(Some(id))
-            .map_err(Error::from)
+        Ok(heads)
     }
 
-    /// Set the `rad/self` identity for `urn`
-    ///
-    /// [`None`] removes `rad/self`, if present.
-    pub fn set_rad_self<Spec>(&self, urn: &RadUrn, spec: Spec) -> Result<(), Error>
-    where
-        Spec: Into<Option<RadSelfSpec>>,
-    {
-        match spec.into() {
-            None => {
-                let have = self.reference(&NamespacedRef::rad_self(urn.id.clone(), None));
-                match have {
-                    Err(_) => Ok(()),
-                    Ok(mut reference) => reference.delete().map_err(Error::from),
-                }
-            },
+    /// Reset the refs in `heads` to their value in `prev`, deleting the ones
+    /// which didn't exist before, and restoring the ones which were deleted.
+    fn rollback_remote(
+        &self,
+        heads: &HashMap<String, git2::Oid>,
+        prev: Option<&HashMap<String, git2::Oid>>,
+    ) -> Result<(), Error> {
+        for name in heads.keys() {
+            if prev.map_or(true, |prev| !prev.contains_key(name)) {
+                self.backend.find_reference(name)?.delete()?;
+            }
+        }
+        for (name, oid) in prev.into_iter().flatten() {
+            if heads.get(name) != Some(oid) {
+                self.backend.reference(
+                    name,
+                    *oid,
+                    /* force */ true,
+                    "rolled back rejected fetch",
+                )?;
+            }
+        }
 
-            Some(spec) => {
-                let src = NamespacedRef::rad_self(urn.id.clone(), None);
-                let target = match spec {
-                    RadSelfSpec::Default => {
-                        let id = self.default_rad_self()?;
-                        Ok::<_, Error>(NamespacedRef::rad_id(id.urn().id))
-                    },
+        Ok(())
+    }
 
-                    RadSelfSpec::Urn(self_urn) => {
-                        let meta: User<Draft> = self.metadata(&self_urn)?;
-                        Config::try_from(&self.backend)?.guard_user_valid(&meta)?;
-                        Ok(NamespacedRef::rad_id(self_urn.id))
-                    },
-                }?;
+    /// Remove the namespace `urn` after a failed clone, returning the error
+    /// which caused the clone to fail.
+    fn rollback_clone(&self, urn: &RadUrn, err: Error) -> Error {
+        tracing::info!(urn = %urn, "Clone failed, rolling back: {}", err);
+        let rollback = self.delete_repo(urn).and_then(|()| {
+            Config::try_from(&self.backend)?
+                .set_partial_clone_filter(&urn.id, None)
+                .map_err(Error::from)
+        });
+        if let Err(e) = rollback {
+            tracing::warn!(urn = %urn, "Failed to roll back clone: {}", e);
+        }
 
-                let sym_log_msg = &format!("{} -> {}", src, target);
-                tracing::info!("creating symbolic link: {}", sym_log_msg);
+        err
+    }
 
-                target
-                    .symbolic_ref(src, Force::True)
-                    .create(&self.backend)
-                    .and(Ok(()))
-                    .map_err(Error::from)
-            },
-        }
+    // DO NOT MAKE THIS PUBLIC YET
+    fn delete_repo(&self, urn: &RadUrn) -> Result<(), Error> {
+        References::from_globs(&self.backend, &[format!("refs/namespaces/{}/*", urn.id)])?
+            .try_for_each(|reference| reference?.delete())
+            .map_err(Error::from)
     }
 
     pub fn track(&self, urn: &RadUrn, peer: &PeerId) -> Result<(), Error> {
@@ -878,86 +945,6 @@ where
 
     // Helpers
 
-    fn commit_initial_meta<T>(&self, meta: &Entity<T, Draft>) -> Result<git2::Oid, Error>
-    where
-        T: Serialize + DeserializeOwned + Clone + EntityInfoExt,
-    {
-        let canonical_data = Cjson(meta).canonical_form()?;
-        let blob = self.backend.blob(&canonical_data)?;
-        let tree = {
-            let mut builder = self.backend.treebuilder(None)?;
-            builder.insert("id", blob, 0o100_644)?;
-            let oid = builder.write()?;
-            self.backend.find_tree(oid)
-        }?;
-        let author = self.backend.signature()?;
-
-        let branch_name = NamespacedRef::rad_id(meta.urn().id);
-
-        let oid = self.backend.commit(
-            Some(&branch_name.to_string()),
-            &author,
-            &author,
-            &format!("Initialised with identity {}", meta.root_hash()),
-            &tree,
-            &[],
-        )?;
-
-        tracing::debug!(
-            repo.urn = %meta.urn(),
-            repo.id.branch = %branch_name,
-            repo.id.oid = %oid,
-            "Initial metadata committed"
-        );
-
-        Ok(oid)
-    }
-
-    // FIXME: decide if we want to require verified entities
-    // FIXME: yes, we want this
-    fn track_signers<T>(&self, meta: &Entity<T, Draft>) -> Result<(), Error>
-    where
-        T: Serialize + DeserializeOwned + Clone + EntityInfoExt,
-    {
-        let meta_urn = meta.urn();
-
-        tracing::debug!(
-            local.id = %self.peer_id,
-            meta.urn = %meta_urn,
-            "Storage::track_signers",
-        );
-
-        meta.signatures()
-            .iter()
-            .map(|(pk, sig)| {
-                let peer_id = PeerId::from(pk.clone());
-                match &sig.by {
-                    Signatory::User(urn) => (peer_id, Some(urn)),
-                    Signatory::OwnedKey => (peer_id, None),
-                }
-            })
-            .filter(|(peer, _)| peer != self.peer_id())
-            .try_for_each(|(peer, urn)| {
-                tracing::debug!(
-                    tracked.peer = %peer,
-                    tracked.urn =
-                        %urn.map(|urn| urn.to_string()).unwrap_or_else(|| "None".to_owned()),
-                    "Tracking signer of {}",
-                    meta.urn()
-                );
-
-                // Track the signer's version of this repo (if any)
-                self.track(&meta_urn, &peer)?;
-                // Track the signer's version of the identity she used for
-                // signing (if any)
-                if let Some(urn) = urn {
-                    self.track(urn, &peer)?;
-                }
-
-                Ok(())
-            })
-    }
-
     pub(crate) fn update_refs(&self, urn: &RadUrn) -> Result<(), Error> {
         tracing::debug!("Storage::update_refs");
 
@@ -1061,7 +1048,7 @@ impl Iterator for Tracked {
     }
 }
 
-fn tracking_remote_name(urn: &RadUrn, peer: &PeerId) -> String {
+pub(crate) fn tracking_remote_name(urn: &RadUrn, peer: &PeerId) -> String {
     format!("{}/{}", urn.id, peer)
 }
 
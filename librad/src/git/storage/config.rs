@@ -24,15 +24,18 @@ use thiserror::Error;
 use keystore::sign;
 
 use crate::{
-    git::ext::is_not_found_err,
+    git::{
+        ext::{self, is_not_found_err},
+        upload_pack::{self, Filter},
+    },
+    hash::Hash,
+    identities::{
+        git::{Revision, VerifiedUser},
+        urn::{self, Urn},
+    },
     internal::result::ResultExt,
     keys::SecretKey,
-    meta::{
-        entity::{Signatory, Verified},
-        user::User,
-    },
     peer::{self, PeerId},
-    uri::{self, RadUrn},
 };
 
 const CONFIG_USER_NAME: &str = "user.name";
@@ -42,11 +45,11 @@ const CONFIG_RAD_PEER_ID: &str = "rad.peerid";
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("supplied user entity is not signed by the local key")]
+    #[error("supplied user identity is not signed by the local key")]
     NotSignedBySelf,
 
-    #[error("entity must be  signed with an owned key")]
-    OwnedKeyRequired,
+    #[error("supplied user identity does not delegate to the local key")]
+    NotDelegate,
 
     #[error("configuration key {config_key} is not set")]
     Unset { config_key: &'static str },
@@ -55,7 +58,10 @@ pub enum Error {
     Peer(#[from] peer::conversion::Error),
 
     #[error(transparent)]
-    Urn(#[from] uri::rad_urn::ParseError),
+    Urn(#[from] urn::ParseError<ext::oid::FromMultihashError>),
+
+    #[error(transparent)]
+    Filter(#[from] upload_pack::Error),
 
     #[error(transparent)]
     Git(#[from] git2::Error),
@@ -78,21 +84,20 @@ impl<'a> TryFrom<&'a git2::Repository> for Config {
 }
 
 impl Config {
-    pub(super) fn init<U>(
+    pub(super) fn init(
         repo: &mut git2::Repository,
         signer: &impl sign::Signer,
-        user: U,
-    ) -> Result<Self, Error>
-    where
-        U: Into<Option<User<Verified>>>,
-    {
+        user: Option<&VerifiedUser>,
+    ) -> Result<Self, Error> {
         let peer_id = PeerId::from_signer(signer);
         let mut config = repo.config()?;
-        let user = user.into();
 
         let mut this = Config { inner: config };
         this.set_peer_id(&peer_id)?;
-        this.set_user_info(user.as_ref().map(|u| u.name()).unwrap_or("radicle"))?;
+        this.set_user_info(
+            user.map(|u| u.doc.payload.subject.name.as_str())
+                .unwrap_or("radicle"),
+        )?;
         this.set_user(user)?;
 
         Ok(this)
@@ -144,25 +149,22 @@ impl Config {
     ///
     /// An error is returned if:
     ///
-    /// * The [`User`] is not signed by the configured [`PeerId`]'s key
-    /// * The signature of the configured key is not owned by the [`User`] (ie.
-    ///   the local key refers to a different entity)
-    pub fn set_user<U>(&mut self, user: U) -> Result<(), Error>
-    where
-        U: Into<Option<User<Verified>>>,
-    {
-        match user.into() {
+    /// * The [`VerifiedUser`] is not signed by the configured [`PeerId`]'s key
+    /// * The [`VerifiedUser`] does not delegate to the configured key (ie. the
+    ///   local key refers to a different identity)
+    pub fn set_user(&mut self, user: Option<&VerifiedUser>) -> Result<(), Error> {
+        match user {
             None => self
                 .inner
                 .remove(CONFIG_RAD_SELF)
                 .or_matches(is_not_found_err, || Ok(())),
 
             Some(user) => {
-                self.guard_user_valid(&user)?;
+                self.guard_user_valid(user)?;
                 self.inner
                     .set_str(CONFIG_RAD_SELF, &user.urn().to_string())
                     .map_err(Error::from)?;
-                self.set_user_info(user.name())?;
+                self.set_user_info(user.doc.payload.subject.name.as_str())?;
 
                 Ok(())
             },
@@ -170,18 +172,19 @@ impl Config {
     }
 
     /// Validation rules as described for [`Config::set_user`]
-    pub fn guard_user_valid<S>(&self, user: &User<S>) -> Result<(), Error> {
+    pub fn guard_user_valid(&self, user: &VerifiedUser) -> Result<(), Error> {
         let peer_id = self.peer_id()?;
-        user.signatures()
-            .get(peer_id.as_public_key())
-            .ok_or(Error::NotSignedBySelf)
-            .and_then(|sig| match sig.by {
-                Signatory::User(_) => Err(Error::OwnedKeyRequired),
-                Signatory::OwnedKey => Ok(()),
-            })
+        let key = peer_id.as_public_key();
+        if !user.signatures.contains_key(key) {
+            Err(Error::NotSignedBySelf)
+        } else if !(&user.doc.delegations).into_iter().any(|k| k == key) {
+            Err(Error::NotDelegate)
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn user(&self) -> Result<RadUrn, Error> {
+    pub fn user(&self) -> Result<Urn<Revision>, Error> {
         let urn = self
             .inner
             .get_string(CONFIG_RAD_SELF)
@@ -193,6 +196,42 @@ impl Config {
 
         urn.parse().map_err(Error::from)
     }
+
+    /// The [`Filter`] the repo `id` was cloned with, if it is a partial clone.
+    pub fn partial_clone_filter(&self, id: &Hash) -> Result<Option<Filter>, Error> {
+        self.inner
+            .get_string(&partial_clone_filter_key(id))
+            .map(Some)
+            .or_matches::<Error, _, _>(is_not_found_err, || Ok(None))?
+            .map(|filter| filter.parse().map_err(Error::from))
+            .transpose()
+    }
+
+    /// Set the [`Filter`] to use when fetching the repo `id`.
+    ///
+    /// Passing [`Option::None`] removes the setting, ie. the repo is no longer
+    /// considered a partial clone.
+    pub fn set_partial_clone_filter(
+        &mut self,
+        id: &Hash,
+        filter: Option<Filter>,
+    ) -> Result<(), Error> {
+        let key = partial_clone_filter_key(id);
+        match filter {
+            None => self
+                .inner
+                .remove(&key)
+                .or_matches(is_not_found_err, || Ok(())),
+            Some(filter) => self
+                .inner
+                .set_str(&key, &filter.to_string())
+                .map_err(Error::from),
+        }
+    }
+}
+
+fn partial_clone_filter_key(id: &Hash) -> String {
+    format!("rad.{}.partialclonefilter", id)
 }
 
 #[cfg(test)]
@@ -203,7 +242,13 @@ mod tests {
 
     use tempfile::tempdir;
 
-    use crate::{keys::SecretKey, meta::entity::Draft, test::ConstResolver};
+    use crate::{
+        identities::{
+            git::{Git, User},
+            payload,
+        },
+        keys::SecretKey,
+    };
     use librad_test::tempdir::WithTmpDir;
 
     struct TmpConfig {
@@ -244,12 +289,27 @@ mod tests {
         ))
     }
 
+    fn create_user(repo: &git2::Repository, key: &SecretKey) -> VerifiedUser {
+        let git = Git::<User>::new(repo);
+        let user = git
+            .create(
+                payload::User {
+                    name: "alice".into(),
+                }
+                .into(),
+                Some(key.public()).into_iter().collect(),
+                key,
+            )
+            .unwrap();
+        git.verify(*user.content_id).unwrap()
+    }
+
     #[test]
-    fn test_guard_user_unsigned() {
+    fn test_guard_user_foreign() {
         let key = SecretKey::new();
         let config = setup(&key);
 
-        let alice = User::<Draft>::create("alice".to_owned(), key.public()).unwrap();
+        let alice = create_user(&config.repo, &SecretKey::new());
         assert!(matches!(
             config.guard_user_valid(&alice),
             Err(Error::NotSignedBySelf)
@@ -257,40 +317,49 @@ mod tests {
     }
 
     #[test]
-    fn test_guard_user_not_self_signed() {
+    fn test_guard_user_valid() {
         let key = SecretKey::new();
         let config = setup(&key);
 
-        let mut alice = User::<Draft>::create("alice".to_owned(), key.public()).unwrap();
-        {
-            let bob = User::<Draft>::create("bob".to_owned(), key.public()).unwrap();
-            alice
-                .sign(&key, &Signatory::User(bob.urn()), &ConstResolver::new(bob))
-                .unwrap();
-        }
+        let alice = create_user(&config.repo, &key);
+        assert!(matches!(config.guard_user_valid(&alice), Ok(())))
+    }
 
+    #[test]
+    fn test_set_user() {
+        let key = SecretKey::new();
+        let mut config = setup(&key);
+
+        let alice = create_user(&config.repo, &key);
+        config.config.set_user(Some(&alice)).unwrap();
+        assert_eq!(config.user().unwrap(), alice.urn());
+        assert_eq!(config.user_name().unwrap(), "alice");
+
+        config.config.set_user(None).unwrap();
         assert!(matches!(
-            config.guard_user_valid(&alice),
-            Err(Error::OwnedKeyRequired)
+            config.user(),
+            Err(Error::Unset {
+                config_key: CONFIG_RAD_SELF
+            })
         ))
     }
 
     #[test]
-    fn test_guard_user_valid() {
+    fn test_partial_clone_filter() {
         let key = SecretKey::new();
-        let config = setup(&key);
-
-        let mut alice = User::<Draft>::create("alice".to_owned(), key.public()).unwrap();
-        {
-            alice
-                .sign(
-                    &key,
-                    &Signatory::OwnedKey,
-                    &ConstResolver::new(alice.clone()),
-                )
-                .unwrap();
-        }
-
-        assert!(matches!(config.guard_user_valid(&alice), Ok(())))
+        let mut config = setup(&key);
+        let id = Hash::hash(b"leboeuf");
+
+        assert_eq!(config.partial_clone_filter(&id).unwrap(), None);
+        config
+            .config
+            .set_partial_clone_filter(&id, Some(Filter::BlobNone))
+            .unwrap();
+        assert_eq!(
+            config.partial_clone_filter(&id).unwrap(),
+            Some(Filter::BlobNone)
+        );
+        config.config.set_partial_clone_filter(&id, None).unwrap();
+        assert_eq!(config.partial_clone_filter(&id).unwrap(), None);
     }
 }
@@ -15,37 +15,154 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+use futures::{
+    executor::block_on,
+    io::{AsyncRead, AsyncWriteExt},
+};
+use git2::transport::Service;
 use thiserror::Error;
 
 use crate::{
     git::{
-        p2p::url::GitUrl,
+        header::Header,
+        p2p::{transport, url::GitUrl},
+        pkt_line::{self, Pkt},
         refs::Refs,
         types::{Force, Reference, Refspec},
     },
     peer::PeerId,
-    uri::RadUrn,
+    uri::{self, RadUrn},
 };
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("fetch was cancelled")]
+    Cancelled,
+
+    #[error("no connection to {0}")]
+    NoConnection(PeerId),
+
+    #[error("remote error: {0}")]
+    Remote(String),
+
+    #[error("unexpected response: {0}")]
+    Unexpected(String),
+
     #[error(transparent)]
     Git(#[from] git2::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The refs being fetched, see [`Fetcher::prefetch`] and [`Fetcher::fetch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchStage {
+    /// The refs needed to verify the identity, when cloning.
+    Prefetch,
+    /// The `rad/signed_refs` of the tracked peers.
+    SignedRefs,
+    /// The heads, as per the signed refs.
+    Heads,
+}
+
+/// Transfer progress of a fetch from `peer`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchProgress {
+    pub urn: RadUrn,
+    pub peer: PeerId,
+    pub stage: FetchStage,
+    pub total_objects: usize,
+    pub received_objects: usize,
+    pub indexed_objects: usize,
+    pub received_bytes: usize,
+}
+
+/// Aborts in-flight fetches when cancelled.
+///
+/// Clones of a token share its state, so one can be handed to a fetch while
+/// the other is kept to cancel it.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Callbacks to observe, and cancel, a fetch.
+#[derive(Clone, Default)]
+pub struct FetchHooks {
+    progress: Option<Arc<dyn Fn(&FetchProgress) + Send + Sync>>,
+    cancel: CancelToken,
+}
+
+impl FetchHooks {
+    /// Call `f` whenever more of the packfile was received or indexed.
+    pub fn on_progress<F>(self, f: F) -> Self
+    where
+        F: Fn(&FetchProgress) + Send + Sync + 'static,
+    {
+        Self {
+            progress: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Abort the fetch once `cancel` is cancelled.
+    pub fn with_cancel(self, cancel: CancelToken) -> Self {
+        Self { cancel, ..self }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+impl fmt::Debug for FetchHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FetchHooks")
+            .field("progress", &self.progress.as_ref().map(|_| ".."))
+            .field("cancel", &self.cancel)
+            .finish()
+    }
 }
 
 pub struct Fetcher<'a> {
     url: GitUrl,
     remote: git2::Remote<'a>,
+    hooks: FetchHooks,
 }
 
 impl<'a> Fetcher<'a> {
-    pub fn new(repo: &'a git2::Repository, url: GitUrl) -> Result<Self, Error> {
+    pub fn new(repo: &'a git2::Repository, url: GitUrl, hooks: FetchHooks) -> Result<Self, Error> {
+        if hooks.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
         let mut remote = repo.remote_anonymous(&url.to_string())?;
         remote.connect(git2::Direction::Fetch)?;
 
-        Ok(Self { url, remote })
+        Ok(Self { url, remote, hooks })
     }
 
     pub fn url(&self) -> &GitUrl {
@@ -90,13 +207,7 @@ impl<'a> Fetcher<'a> {
         .collect::<Vec<String>>();
 
         tracing::trace!(repo.clone.refspecs = ?refspecs);
-        {
-            let mut fetch_options = self.fetch_options();
-            self.remote
-                .fetch(&refspecs, Some(&mut fetch_options), None)?;
-        }
-
-        Ok(())
+        self.fetch_stage(FetchStage::Prefetch, &refspecs)
     }
 
     /// Fetch remote heads according to the remote's signed `rad/signed_refs`
@@ -116,12 +227,10 @@ impl<'a> Fetcher<'a> {
     where
         F: Fn(PeerId) -> Result<Refs, E>,
         G: Fn(&PeerId) -> Result<HashSet<RadUrn>, E>,
-        E: From<git2::Error>,
+        E: From<git2::Error> + From<Error>,
     {
-        let namespace = &self.url.repo;
-        let remote_peer = &self.url.remote_peer;
-
-        let mut fetch_opts = self.fetch_options();
+        let namespace = self.url.repo.clone();
+        let remote_peer = self.url.remote_peer.clone();
 
         // Fetch `rad/signed_refs` first
         {
@@ -134,8 +243,7 @@ impl<'a> Fetcher<'a> {
             .collect::<Vec<String>>();
 
             tracing::debug!(refspecs = ?refspecs, "Fetching rad/refs");
-
-            self.remote.fetch(&refspecs, Some(&mut fetch_opts), None)?;
+            self.fetch_stage(FetchStage::SignedRefs, &refspecs)?;
         }
 
         // Calculate the fetch heads based on the signed `rad/refs` -- any
@@ -153,7 +261,7 @@ impl<'a> Fetcher<'a> {
                 .collect();
 
             let refspecs = Refspec::fetch_heads(
-                namespace.clone(),
+                namespace,
                 remote_heads,
                 transitively_tracked.iter().cloned(),
                 &remote_peer,
@@ -164,18 +272,46 @@ impl<'a> Fetcher<'a> {
             .collect::<Vec<String>>();
 
             tracing::debug!(refspecs = ?refspecs, "Fetching refs/heads");
-            self.remote.fetch(&refspecs, Some(&mut fetch_opts), None)?;
+            self.fetch_stage(FetchStage::Heads, &refspecs)?;
         }
 
         Ok(())
     }
 
-    // TODO: allow users to supply callbacks
-    fn fetch_options(&self) -> git2::FetchOptions<'a> {
+    fn fetch_stage(&mut self, stage: FetchStage, refspecs: &[String]) -> Result<(), Error> {
+        if self.hooks.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let mut fetch_options = self.fetch_options(stage);
+        match self.remote.fetch(refspecs, Some(&mut fetch_options), None) {
+            Err(_) if self.hooks.is_cancelled() => Err(Error::Cancelled),
+            res => res.map_err(Error::from),
+        }
+    }
+
+    /// The options for fetching `stage`, reporting progress to, and checking
+    /// for cancellation with our [`FetchHooks`].
+    fn fetch_options(&self, stage: FetchStage) -> git2::FetchOptions<'a> {
+        let hooks = self.hooks.clone();
+        let urn = self.url.clone().into_rad_url().urn;
+        let peer = self.url.remote_peer.clone();
+
         let mut cbs = git2::RemoteCallbacks::new();
-        cbs.transfer_progress(|prog| {
+        cbs.transfer_progress(move |prog| {
             tracing::trace!("Fetch: received {} bytes", prog.received_bytes());
-            true
+            if let Some(progress) = &hooks.progress {
+                progress(&FetchProgress {
+                    urn: urn.clone(),
+                    peer: peer.clone(),
+                    stage,
+                    total_objects: prog.total_objects(),
+                    received_objects: prog.received_objects(),
+                    indexed_objects: prog.indexed_objects(),
+                    received_bytes: prog.received_bytes(),
+                })
+            }
+            !hooks.is_cancelled()
         })
         .update_tips(|name, old, new| {
             tracing::debug!("Fetch: updating tip {}: {} -> {}", name, old, new);
@@ -191,3 +327,221 @@ impl<'a> Fetcher<'a> {
         fos
     }
 }
+
+/// Fetch the objects `oids` from the peer in `url`, regardless of which refs
+/// they are reachable from.
+///
+/// This is how the objects omitted from a partial clone are fetched once they
+/// are needed. `libgit2` can only fetch refs, so we speak just enough of the
+/// protocol ourselves: the wants are sent as a single `done` request, and the
+/// packfile is written to the object database of `repo`.
+pub fn objects(repo: &git2::Repository, url: &GitUrl, oids: &[git2::Oid]) -> Result<(), Error> {
+    if oids.is_empty() {
+        return Ok(());
+    }
+
+    tracing::debug!("Fetching {} objects from {}", oids.len(), url.remote_peer);
+
+    let mut stream = transport::register()
+        .open_stream(&url.local_peer, &url.remote_peer, &url.addr_hints)
+        .ok_or_else(|| Error::NoConnection(url.remote_peer.clone()))?;
+
+    let header = Header::new(
+        Service::UploadPack,
+        RadUrn::new(url.repo.clone(), uri::Protocol::Git, uri::Path::empty()),
+        url.remote_peer.clone(),
+    );
+    let mut request = header.to_string().into_bytes();
+    for (i, oid) in oids.iter().enumerate() {
+        let want = if i == 0 {
+            format!("want {} side-band-64k ofs-delta\n", oid)
+        } else {
+            format!("want {}\n", oid)
+        };
+        pkt_line::write(&mut request, want.as_bytes())?;
+    }
+    request.extend_from_slice(pkt_line::FLUSH);
+    pkt_line::write(&mut request, b"done\n")?;
+
+    block_on(async {
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+        receive_pack(repo, &mut stream).await
+    })
+}
+
+/// Read the response to a `done` request, and write the packfile to `repo`.
+async fn receive_pack<R>(repo: &git2::Repository, recv: &mut R) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    match pkt_line::read_async(recv).await? {
+        Pkt::Data(line) if line == b"NAK\n" => {},
+        Pkt::Data(line) => return Err(unexpected(&line)),
+        Pkt::Flush | Pkt::Delim => return Err(Error::Unexpected("special packet".to_owned())),
+    }
+
+    let odb = repo.odb()?;
+    let mut writer = odb.packwriter()?;
+    loop {
+        match pkt_line::read_async(recv).await? {
+            Pkt::Flush => break,
+            Pkt::Delim => return Err(Error::Unexpected("delim-pkt".to_owned())),
+            Pkt::Data(data) => match data.split_first() {
+                Some((1, pack)) => writer.write_all(pack)?,
+                Some((2, progress)) => {
+                    tracing::trace!("Fetch: {}", String::from_utf8_lossy(progress).trim_end())
+                },
+                Some((3, err)) => {
+                    return Err(Error::Remote(
+                        String::from_utf8_lossy(err).trim_end().to_owned(),
+                    ))
+                },
+                _ => return Err(unexpected(&data)),
+            },
+        }
+    }
+    writer.commit()?;
+
+    Ok(())
+}
+
+fn unexpected(line: &[u8]) -> Error {
+    let line = String::from_utf8_lossy(line);
+    match line.strip_prefix("ERR ") {
+        Some(err) => Error::Remote(err.trim_end().to_owned()),
+        None => Error::Unexpected(line.trim_end().to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::{runtime::Handle, task::spawn_blocking};
+
+    use crate::{
+        git::{
+            p2p::{
+                server::GitServer,
+                transport::{GitStream, GitStreamFactory},
+            },
+            storage::{self, Storage},
+        },
+        hash::Hash,
+        keys::SecretKey,
+        net::connection::{mock::MockStream, Stream as _},
+        paths::Paths,
+        uri::RadUrl,
+    };
+
+    /// Serves the git requests of `local` from `server`, in-process.
+    struct Loopback {
+        local: PeerId,
+        server: GitServer,
+        runtime: Handle,
+    }
+
+    #[async_trait]
+    impl GitStreamFactory for Loopback {
+        async fn open_stream(
+            &self,
+            to: &PeerId,
+            _addr_hints: &[SocketAddr],
+        ) -> Option<Box<dyn GitStream>> {
+            let (ours, theirs) = MockStream::pair(to.clone(), self.local.clone(), 64 * 1024);
+            let server = self.server.clone();
+            let local = self.local.clone();
+            self.runtime.spawn(async move {
+                if let Err(e) = server.invoke_service(&local, theirs.split()).await {
+                    tracing::warn!("Loopback git service failed: {}", e)
+                }
+            });
+
+            Some(Box::new(ours))
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_fetch_rolls_back_remotes() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let remote_paths = Paths::from_root(remote_dir.path()).unwrap();
+        let remote = Storage::init(&remote_paths, SecretKey::new()).unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let local = Storage::init(
+            &Paths::from_root(local_dir.path()).unwrap(),
+            SecretKey::new(),
+        )
+        .unwrap();
+
+        let urn = RadUrn::new(
+            Hash::hash(b"leboeuf"),
+            uri::Protocol::Git,
+            uri::Path::empty(),
+        );
+
+        // A signed branch for `local` to fetch
+        {
+            let repo = &remote.backend;
+            let sig = git2::Signature::now("leboeuf", "leboeuf@acme.com").unwrap();
+            let blob = repo.blob(b"bugs in our boeuf").unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("README", blob, 0o100_644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            repo.commit(
+                Some(&format!("refs/namespaces/{}/refs/heads/master", urn.id)),
+                &sig,
+                &sig,
+                "initial",
+                &tree,
+                &[],
+            )
+            .unwrap();
+            remote.update_refs(&urn).unwrap();
+        }
+        local.track(&urn, remote.peer_id()).unwrap();
+
+        let loopback: Arc<Box<dyn GitStreamFactory>> = Arc::new(Box::new(Loopback {
+            local: local.peer_id().clone(),
+            server: GitServer::new(&remote_paths),
+            runtime: Handle::current(),
+        }));
+        transport::register().register_stream_factory(local.peer_id(), Arc::downgrade(&loopback));
+
+        // Cancel once the signed refs were fetched, while the heads are coming in
+        let cancel = CancelToken::new();
+        let cancelled_heads = Arc::new(AtomicBool::new(false));
+        let hooks = {
+            let cancel = cancel.clone();
+            let cancelled_heads = cancelled_heads.clone();
+            FetchHooks::default()
+                .with_cancel(cancel.clone())
+                .on_progress(move |progress| {
+                    if progress.stage == FetchStage::Heads {
+                        cancelled_heads.store(true, Ordering::SeqCst);
+                        cancel.cancel()
+                    }
+                })
+        };
+
+        let url = RadUrl {
+            authority: remote.peer_id().clone(),
+            urn: urn.clone(),
+        };
+        spawn_blocking(move || {
+            let before = local.remote_heads(&urn).unwrap();
+            assert_matches!(
+                local.fetch_repo_with(url, None::<SocketAddr>, hooks),
+                Err(storage::Error::Fetch(Error::Cancelled))
+            );
+            assert_eq!(local.remote_heads(&urn).unwrap(), before);
+        })
+        .await
+        .unwrap();
+
+        assert!(cancel.is_cancelled());
+        assert!(cancelled_heads.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,859 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! [`Storage`] operations driven by the git-backed identities model of
+//! [`crate::identities::git`].
+//!
+//! The `rad/id` branch of a namespace managed through this module points to
+//! the head commit of an identity history as created by
+//! [`crate::identities::git::Git`]. Identities read from `rad/id` are only
+//! ever handed out after successful verification.
+
+use std::{collections::BTreeSet, convert::TryFrom, iter, net::SocketAddr};
+
+use either::Either;
+
+use crate::{
+    git::{
+        ext::{is_not_found_err, References},
+        p2p::url::GitUrl,
+        types::{Force, NamespacedRef},
+    },
+    hash::Hash,
+    identities::{
+        git::{Git, Project, Revision, SomeIdentity, User, VerifiedProject, VerifiedUser},
+        urn::Urn,
+    },
+    keys::{self, PublicKey},
+    peer::PeerId,
+    signer::Signer,
+    uri::{self, RadUrn},
+};
+
+use super::{
+    config::Config,
+    fetch::{FetchHooks, Fetcher},
+    Error,
+    RadSelfSpec,
+    Storage,
+};
+
+/// The [`RadUrn`] of the namespace under which the identity `urn` is stored.
+///
+/// The stable identifier of an identity is its root [`Revision`], ie. the
+/// content address (git tree oid) of its initial document. [`Storage`]
+/// namespaces are keyed by [`Hash`], which is a Blake2b-256 multihash and thus
+/// can't represent a SHA-1 oid as is. The namespace is therefore the [`Hash`]
+/// of the raw bytes of the root revision:
+///
+/// * it is the same for all revisions of an identity, as they share the root
+/// * distinct roots map to distinct namespaces, barring a Blake2b collision
+/// * `urn.path` is not part of the mapping
+///
+/// Changing this mapping changes where every identity is stored.
+pub fn rad_urn(urn: &Urn<Revision>) -> RadUrn {
+    RadUrn::new(
+        Hash::hash(urn.id.as_bytes()),
+        uri::Protocol::Git,
+        uri::Path::empty(),
+    )
+}
+
+/// Verified identities which delegate trust to a set of keys.
+pub(super) trait Delegating {
+    /// The head commit of the verified identity history.
+    fn head(&self) -> git2::Oid;
+
+    /// The root revision of the identity.
+    fn root(&self) -> Revision;
+
+    /// Whether the verified revision carries a signature by `key`.
+    fn is_signed_by(&self, key: &PublicKey) -> bool;
+
+    /// All keys, direct or indirect, the identity delegates to.
+    fn delegate_keys(&self) -> BTreeSet<&PublicKey>;
+
+    /// The user identities delegated to, if any.
+    fn certifiers(&self) -> Vec<&User>;
+
+    /// The user identity through which `key` is delegated to, if any.
+    fn delegate_of(&self, key: &PublicKey) -> Option<Urn<Revision>>;
+}
+
+impl Delegating for VerifiedUser {
+    fn head(&self) -> git2::Oid {
+        *self.content_id
+    }
+
+    fn root(&self) -> Revision {
+        self.root
+    }
+
+    fn is_signed_by(&self, key: &PublicKey) -> bool {
+        self.signatures.contains_key(key)
+    }
+
+    fn delegate_keys(&self) -> BTreeSet<&PublicKey> {
+        (&self.doc.delegations).into_iter().collect()
+    }
+
+    fn certifiers(&self) -> Vec<&User> {
+        vec![]
+    }
+
+    fn delegate_of(&self, key: &PublicKey) -> Option<Urn<Revision>> {
+        (&self.doc.delegations)
+            .into_iter()
+            .any(|k| k == key)
+            .then_some(self.urn())
+    }
+}
+
+impl Delegating for VerifiedProject {
+    fn head(&self) -> git2::Oid {
+        *self.content_id
+    }
+
+    fn root(&self) -> Revision {
+        self.root
+    }
+
+    fn is_signed_by(&self, key: &PublicKey) -> bool {
+        self.signatures.contains_key(key)
+    }
+
+    fn delegate_keys(&self) -> BTreeSet<&PublicKey> {
+        self.doc
+            .delegations
+            .iter()
+            .flat_map(|delegation| match delegation {
+                Either::Left(key) => vec![key],
+                Either::Right(user) => (&user.doc.delegations).into_iter().collect(),
+            })
+            .collect()
+    }
+
+    fn certifiers(&self) -> Vec<&User> {
+        self.doc
+            .delegations
+            .iter()
+            .filter_map(|delegation| delegation.right())
+            .collect()
+    }
+
+    fn delegate_of(&self, key: &PublicKey) -> Option<Urn<Revision>> {
+        self.doc.delegations.iter().find_map(|delegation| {
+            delegation.right().and_then(|user| {
+                (&user.doc.delegations)
+                    .into_iter()
+                    .any(|k| k == key)
+                    .then_some(user.urn())
+            })
+        })
+    }
+}
+
+/// A verified identity whose type is not known statically.
+#[derive(Clone, Debug)]
+pub enum SomeVerifiedIdentity {
+    User(VerifiedUser),
+    Project(VerifiedProject),
+}
+
+impl SomeVerifiedIdentity {
+    /// The stable identifier of this identity.
+    pub fn urn(&self) -> Urn<Revision> {
+        match self {
+            Self::User(user) => user.urn(),
+            Self::Project(project) => project.urn(),
+        }
+    }
+}
+
+impl Delegating for SomeVerifiedIdentity {
+    fn head(&self) -> git2::Oid {
+        match self {
+            Self::User(user) => user.head(),
+            Self::Project(project) => project.head(),
+        }
+    }
+
+    fn root(&self) -> Revision {
+        match self {
+            Self::User(user) => Delegating::root(user),
+            Self::Project(project) => Delegating::root(project),
+        }
+    }
+
+    fn is_signed_by(&self, key: &PublicKey) -> bool {
+        match self {
+            Self::User(user) => user.is_signed_by(key),
+            Self::Project(project) => project.is_signed_by(key),
+        }
+    }
+
+    fn delegate_keys(&self) -> BTreeSet<&PublicKey> {
+        match self {
+            Self::User(user) => user.delegate_keys(),
+            Self::Project(project) => project.delegate_keys(),
+        }
+    }
+
+    fn certifiers(&self) -> Vec<&User> {
+        match self {
+            Self::User(user) => user.certifiers(),
+            Self::Project(project) => project.certifiers(),
+        }
+    }
+
+    fn delegate_of(&self, key: &PublicKey) -> Option<Urn<Revision>> {
+        match self {
+            Self::User(user) => user.delegate_of(key),
+            Self::Project(project) => project.delegate_of(key),
+        }
+    }
+}
+
+impl<S: Clone> Storage<S> {
+    /// Access the identities stored in this `Storage`.
+    ///
+    /// Note that identities created via the returned [`Git`] are not
+    /// referenced by any branch. Use [`Storage::create_user_repo`] or
+    /// [`Storage::create_project_repo`] to make them available for
+    /// replication.
+    pub fn identities<'a, T: 'a>(&'a self) -> Git<'a, T> {
+        Git::new(&self.backend)
+    }
+
+    /// The head commit of `rad/id` in the namespace of `urn`, or of the
+    /// tracked `peer`'s view of it.
+    pub fn identity_head<P>(&self, urn: &Urn<Revision>, peer: P) -> Result<git2::Oid, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        Ok(self.identity_head_git(urn, peer)?)
+    }
+
+    /// Read the identity found at `rad/id` of `urn`, without verification.
+    pub fn some_identity<P>(&self, urn: &Urn<Revision>, peer: P) -> Result<SomeIdentity, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        let head = self.identity_head(urn, peer)?;
+        Ok(self.identities::<User>().some_identity(head)?)
+    }
+
+    /// Get the verified [`User`] identity of `urn`.
+    pub fn user(&self, urn: &Urn<Revision>) -> Result<VerifiedUser, Error> {
+        self.user_of(urn, None)
+    }
+
+    /// Get the verified [`User`] identity of `urn` as seen by the tracked
+    /// `peer`.
+    pub fn user_of<P>(&self, urn: &Urn<Revision>, peer: P) -> Result<VerifiedUser, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        let head = self.identity_head(urn, peer)?;
+        let user = self.identities::<User>().verify(head)?;
+        ensure_root(urn, &user)?;
+
+        Ok(user)
+    }
+
+    /// Get the verified [`Project`] identity of `urn`.
+    pub fn project(&self, urn: &Urn<Revision>) -> Result<VerifiedProject, Error> {
+        self.project_of(urn, None)
+    }
+
+    /// Get the verified [`Project`] identity of `urn` as seen by the tracked
+    /// `peer`.
+    ///
+    /// The latest heads of the project's user delegations are resolved from
+    /// `peer`'s view first, falling back to our own.
+    pub fn project_of<P>(&self, urn: &Urn<Revision>, peer: P) -> Result<VerifiedProject, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        let peer = peer.into();
+        let head = self.identity_head(urn, peer.clone())?;
+        let project = self.verify_project(&rad_urn(urn), head, peer)?;
+        ensure_root(urn, &project)?;
+
+        Ok(project)
+    }
+
+    /// Get the verified identity stored in the namespace `urn`, whichever type
+    /// it is.
+    pub fn identity(&self, urn: &RadUrn) -> Result<SomeVerifiedIdentity, Error> {
+        self.identity_of(urn, None)
+    }
+
+    /// Get the verified identity stored in the namespace `urn` as seen by the
+    /// tracked `peer`, whichever type it is.
+    ///
+    /// The identity must be the one the namespace is derived from, as per
+    /// [`rad_urn`].
+    pub fn identity_of<P>(&self, urn: &RadUrn, peer: P) -> Result<SomeVerifiedIdentity, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        let peer = peer.into();
+        let head = NamespacedRef::rad_id(urn.id.clone())
+            .set_remote(peer.clone())
+            .find(&self.backend)?
+            .peel_to_commit()?
+            .id();
+        let verified = match self.identities::<User>().some_identity(head)? {
+            SomeIdentity::User(_) => {
+                SomeVerifiedIdentity::User(self.identities::<User>().verify(head)?)
+            },
+            SomeIdentity::Project(_) => {
+                SomeVerifiedIdentity::Project(self.verify_project(urn, head, peer)?)
+            },
+        };
+
+        let actual = rad_urn(&verified.urn()).id;
+        if actual != urn.id {
+            return Err(Error::RootHashMismatch {
+                expected: urn.id.clone(),
+                actual,
+            });
+        }
+
+        Ok(verified)
+    }
+
+    /// Get all the verified identities in this `Storage`, as per our own
+    /// `rad/id`s.
+    pub fn all_identities<'a>(
+        &'a self,
+    ) -> Result<impl Iterator<Item = Result<SomeVerifiedIdentity, Error>> + 'a, Error> {
+        let namespaces = self.namespaces()?;
+        Ok(namespaces.into_iter().map(move |namespace| {
+            self.identity(&RadUrn::new(
+                namespace,
+                uri::Protocol::Git,
+                uri::Path::empty(),
+            ))
+        }))
+    }
+
+    /// The namespaces in this `Storage` which have a `rad/id` of our own.
+    ///
+    /// Unlike [`Storage::all_identities`], the identities are not verified,
+    /// which makes this cheap enough to call often.
+    pub fn namespaces(&self) -> Result<Vec<Hash>, Error> {
+        Ok(
+            References::from_globs(&self.backend, &["refs/namespaces/*/refs/rad/id"])?
+                .names()
+                .filter_map(|name| {
+                    name.ok()
+                        .and_then(|name| name.strip_prefix("refs/namespaces/"))
+                        .and_then(|name| name.split('/').next())
+                        .and_then(|namespace| namespace.parse::<Hash>().ok())
+                })
+                .collect(),
+        )
+    }
+
+    /// The user delegations of the identity `urn`, as seen by us and any of
+    /// the tracked peers.
+    pub fn certifiers(&self, urn: &RadUrn) -> Result<BTreeSet<Urn<Revision>>, Error> {
+        let mut certifiers = BTreeSet::new();
+        for peer in iter::once(None).chain(self.tracked(urn)?.map(Some)) {
+            match self.identity_of(urn, peer) {
+                Ok(verified) => {
+                    certifiers.extend(verified.certifiers().into_iter().map(|user| user.urn()))
+                },
+                Err(Error::Git(e)) if is_not_found_err(&e) => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(certifiers)
+    }
+
+    /// Retrieve the `rad/self` identity configured via
+    /// [`Storage::set_default_rad_self`].
+    pub fn default_rad_self(&self) -> Result<VerifiedUser, Error> {
+        let urn = Config::try_from(&self.backend)?.user()?;
+        self.user(&urn)
+    }
+
+    /// Get the `rad/self` identity for `urn`.
+    pub fn get_rad_self(&self, urn: &RadUrn) -> Result<VerifiedUser, Error> {
+        self.get_rad_self_of(urn, None)
+    }
+
+    /// Get the `rad/self` identity for the remote `peer` under the `urn`.
+    pub fn get_rad_self_of<P>(&self, urn: &RadUrn, peer: P) -> Result<VerifiedUser, Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        let head = NamespacedRef::rad_self(urn.id.clone(), peer)
+            .find(&self.backend)?
+            .peel_to_commit()?
+            .id();
+        Ok(self.identities::<User>().verify(head)?)
+    }
+
+    /// Persist the [`User`] identity `user` as the default `rad/self`
+    /// identity.
+    pub fn set_default_rad_self(&self, user: &VerifiedUser) -> Result<(), Error> {
+        let urn = rad_urn(&user.urn());
+        if !self.has_urn(&urn)? {
+            return Err(Error::NoSuchUrn(urn));
+        }
+
+        Config::try_from(&self.backend)?
+            .set_user(Some(user))
+            .map_err(Error::from)
+    }
+
+    /// Verify the project history with head commit `head`, stored in the
+    /// namespace `urn`.
+    ///
+    /// The latest heads of the user delegations are looked up in their own
+    /// namespaces first, then in the `rad/ids/*` of `urn`, preferring `peer`'s
+    /// view over ours. The latter is what a clone fetches along with the
+    /// project.
+    fn verify_project(
+        &self,
+        urn: &RadUrn,
+        head: git2::Oid,
+        peer: Option<PeerId>,
+    ) -> Result<VerifiedProject, Error> {
+        let certifier_head = |user: &Urn<Revision>, peer: Option<PeerId>| {
+            NamespacedRef::rad_certifier(urn.id.clone(), &rad_urn(user))
+                .set_remote(peer)
+                .find(&self.backend)?
+                .peel_to_commit()
+                .map(|commit| commit.id())
+        };
+
+        Ok(self
+            .identities::<Project>()
+            .verify(head, |user: Urn<Revision>| {
+                self.identity_head_git(&user, peer.clone())
+                    .or_else(|_| certifier_head(&user, peer.clone()))
+                    .or_else(|_| self.identity_head_git(&user, None))
+                    .or_else(|_| certifier_head(&user, None))
+            })?)
+    }
+
+    pub(super) fn identity_head_git<P>(
+        &self,
+        urn: &Urn<Revision>,
+        peer: P,
+    ) -> Result<git2::Oid, git2::Error>
+    where
+        P: Into<Option<PeerId>>,
+    {
+        NamespacedRef::rad_id(rad_urn(urn).id)
+            .set_remote(peer)
+            .find(&self.backend)?
+            .peel_to_commit()
+            .map(|commit| commit.id())
+    }
+}
+
+impl<S> Storage<S>
+where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    /// Set the `rad/self` identity for `urn`
+    ///
+    /// [`None`] removes `rad/self`, if present.
+    pub fn set_rad_self<Spec>(&self, urn: &RadUrn, spec: Spec) -> Result<(), Error>
+    where
+        Spec: Into<Option<RadSelfSpec>>,
+    {
+        let src = NamespacedRef::rad_self(urn.id.clone(), None);
+        match spec.into() {
+            None => match self.reference(&src) {
+                Err(_) => Ok(()),
+                Ok(mut reference) => reference.delete().map_err(Error::from),
+            },
+
+            Some(spec) => {
+                let user = match spec {
+                    RadSelfSpec::Default => self.default_rad_self()?,
+                    RadSelfSpec::Urn(self_urn) => {
+                        let user = self.user(&self_urn)?;
+                        Config::try_from(&self.backend)?.guard_user_valid(&user)?;
+                        user
+                    },
+                };
+
+                self.link_self(urn, &user.urn())
+            },
+        }
+    }
+
+    /// Make the [`User`] identity available for replication.
+    ///
+    /// The identity must have been created through [`Storage::identities`],
+    /// pass verification, and be signed by the local key.
+    pub fn create_user_repo(&self, user: &User) -> Result<VerifiedUser, Error> {
+        let span = tracing::info_span!("Storage::create_user_repo", local.id = %self.peer_id);
+        let _guard = span.enter();
+
+        let verified = self.identities::<User>().verify(*user.content_id)?;
+        self.create_identity_repo(&user.urn(), &verified)?;
+
+        Ok(verified)
+    }
+
+    /// Make the [`Project`] identity available for replication.
+    ///
+    /// The identity must have been created through [`Storage::identities`],
+    /// pass verification, and be signed by the local key. All user delegations
+    /// of the project must already be present in this `Storage`.
+    pub fn create_project_repo(&self, project: &Project) -> Result<VerifiedProject, Error> {
+        let span = tracing::info_span!("Storage::create_project_repo", local.id = %self.peer_id);
+        let _guard = span.enter();
+
+        let urn = project.urn();
+        for user in project.doc.delegations.iter().filter_map(|d| d.right()) {
+            let certifier = rad_urn(&user.urn());
+            if !self.has_urn(&certifier)? {
+                return Err(Error::MissingCertifier {
+                    certifier,
+                    urn: rad_urn(&urn),
+                });
+            }
+        }
+
+        let verified = self
+            .identities::<Project>()
+            .verify(*project.content_id, |user: Urn<Revision>| {
+                self.identity_head_git(&user, None)
+            })?;
+        self.create_identity_repo(&urn, &verified)?;
+
+        Ok(verified)
+    }
+
+    /// Attempt to clone the [`User`] identity `urn` from the peer `from`.
+    ///
+    /// The identity is only adopted as our own if `from`'s view of it passes
+    /// verification.
+    ///
+    /// Note that this method **must** be spawned on a `async` runtime, where
+    /// currently the only supported method is [`tokio::task::spawn_blocking`].
+    pub fn clone_user<Addrs>(
+        &self,
+        urn: &Urn<Revision>,
+        from: PeerId,
+        addr_hints: Addrs,
+    ) -> Result<VerifiedUser, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        let span = tracing::info_span!("Storage::clone_user", local.id = %self.peer_id, urn = %urn);
+        let _guard = span.enter();
+
+        let git_url = GitUrl::from_rad_urn(rad_urn(urn), self.peer_id.clone(), from, addr_hints);
+        self.clone_identity(git_url, FetchHooks::default(), |this, peer| {
+            this.user_of(urn, peer.clone())
+        })
+    }
+
+    /// Attempt to clone the [`Project`] identity `urn` from the peer `from`.
+    ///
+    /// Like [`Storage::clone_user`], but for projects. The user delegations of
+    /// the project are fetched along with it.
+    ///
+    /// Note that this method **must** be spawned on a `async` runtime, where
+    /// currently the only supported method is [`tokio::task::spawn_blocking`].
+    pub fn clone_project<Addrs>(
+        &self,
+        urn: &Urn<Revision>,
+        from: PeerId,
+        addr_hints: Addrs,
+    ) -> Result<VerifiedProject, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        let span =
+            tracing::info_span!("Storage::clone_project", local.id = %self.peer_id, urn = %urn);
+        let _guard = span.enter();
+
+        let git_url = GitUrl::from_rad_urn(rad_urn(urn), self.peer_id.clone(), from, addr_hints);
+        self.clone_identity(git_url, FetchHooks::default(), |this, peer| {
+            this.project_of(urn, peer.clone())
+        })
+    }
+
+    /// Fetch updates of the [`User`] identity `urn` from the peer `from`.
+    ///
+    /// Returns the verified view of `from`. If it is a descendant of our
+    /// `rad/id`, our `rad/id` is fast-forwarded to it.
+    pub fn fetch_user<Addrs>(
+        &self,
+        urn: &Urn<Revision>,
+        from: PeerId,
+        addr_hints: Addrs,
+    ) -> Result<VerifiedUser, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        self.fetch_identity(urn, from, addr_hints, |this, peer| {
+            this.user_of(urn, peer.clone())
+        })
+    }
+
+    /// Fetch updates of the [`Project`] identity `urn` from the peer `from`.
+    ///
+    /// Like [`Storage::fetch_user`], but for projects.
+    pub fn fetch_project<Addrs>(
+        &self,
+        urn: &Urn<Revision>,
+        from: PeerId,
+        addr_hints: Addrs,
+    ) -> Result<VerifiedProject, Error>
+    where
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        self.fetch_identity(urn, from, addr_hints, |this, peer| {
+            this.project_of(urn, peer.clone())
+        })
+    }
+
+    // Helpers
+
+    fn create_identity_repo<V>(&self, urn: &Urn<Revision>, verified: &V) -> Result<(), Error>
+    where
+        V: Delegating,
+    {
+        let rad_urn = rad_urn(urn);
+        if self.has_urn(&rad_urn)? {
+            return Err(Error::AlreadyExists(rad_urn));
+        }
+
+        let local_key: PublicKey = self.signer.public_key().into();
+        if !verified.is_signed_by(&local_key) {
+            return Err(Error::NotSignedBySelf);
+        }
+
+        self.backend.reference(
+            &NamespacedRef::rad_id(rad_urn.id.clone()).to_string(),
+            verified.head(),
+            /* force */ false,
+            &format!("Initialised with identity {}", urn),
+        )?;
+
+        let linked = verified
+            .delegate_of(&local_key)
+            .map_or(Ok(()), |user| self.link_self(&rad_urn, &user))
+            .and_then(|()| self.link_identity(&rad_urn, verified));
+        if let Err(e) = linked {
+            self.delete_repo(&rad_urn)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Clone the namespace designated by `git_url`, and adopt the view of its
+    /// remote peer if it passes `verify`.
+    ///
+    /// If any step fails, the namespace is removed again, so a failed clone
+    /// can be retried.
+    pub(super) fn clone_identity<V, F>(
+        &self,
+        git_url: GitUrl,
+        hooks: FetchHooks,
+        verify: F,
+    ) -> Result<V, Error>
+    where
+        V: Delegating,
+        F: Fn(&Self, &PeerId) -> Result<V, Error>,
+    {
+        let rad_urn = RadUrn::new(git_url.repo.clone(), uri::Protocol::Git, uri::Path::empty());
+        if self.has_urn(&rad_urn)? {
+            return Err(Error::AlreadyExists(rad_urn));
+        }
+
+        self.clone_identity_internal(&rad_urn, git_url, hooks, verify)
+            .map_err(|e| self.rollback_clone(&rad_urn, e))
+    }
+
+    fn clone_identity_internal<V, F>(
+        &self,
+        rad_urn: &RadUrn,
+        git_url: GitUrl,
+        hooks: FetchHooks,
+        verify: F,
+    ) -> Result<V, Error>
+    where
+        V: Delegating,
+        F: Fn(&Self, &PeerId) -> Result<V, Error>,
+    {
+        let from = git_url.remote_peer.clone();
+        let filter = git_url.filter;
+        let mut fetcher = Fetcher::new(&self.backend, git_url, hooks)?;
+        fetcher.prefetch()?;
+
+        let verified = verify(self, &from)?;
+
+        // Adopt the most recent verified identity of `from` as our own -- which
+        // is not necessarily the head of `from`'s `rad/id`.
+        self.backend.reference(
+            &NamespacedRef::rad_id(rad_urn.id.clone()).to_string(),
+            verified.head(),
+            /* force */ false,
+            &format!(
+                "Adopted `{}` of {} as ours",
+                Urn::new(verified.root()),
+                from
+            ),
+        )?;
+
+        self.link_identity(rad_urn, &verified)?;
+        if filter.is_some() {
+            Config::try_from(&self.backend)?.set_partial_clone_filter(&rad_urn.id, filter)?;
+        }
+        self.fetch_internal(fetcher, |peer| verify(self, peer).map(|_| ()))?;
+
+        Ok(verified)
+    }
+
+    /// Verify the identity in the namespace `urn` as seen by `peer`, such that
+    /// what we fetched from it can be accepted.
+    pub(super) fn verify_remote(
+        &self,
+        urn: &RadUrn,
+        peer: &PeerId,
+    ) -> Result<SomeVerifiedIdentity, Error> {
+        self.identity_of(urn, peer.clone())
+            .map_err(|reason| Error::Rejected {
+                urn: urn.clone(),
+                peer: peer.clone(),
+                reason: Box::new(reason),
+            })
+    }
+
+    fn fetch_identity<V, F, Addrs>(
+        &self,
+        urn: &Urn<Revision>,
+        from: PeerId,
+        addr_hints: Addrs,
+        verify: F,
+    ) -> Result<V, Error>
+    where
+        V: Delegating,
+        F: Fn(&Self, &PeerId) -> Result<V, Error>,
+        Addrs: IntoIterator<Item = SocketAddr>,
+    {
+        let rad_urn = rad_urn(urn);
+        let git_url = GitUrl::from_rad_urn(
+            rad_urn.clone(),
+            self.peer_id.clone(),
+            from.clone(),
+            addr_hints,
+        );
+        let fetcher = Fetcher::new(&self.backend, git_url, FetchHooks::default())?;
+        self.fetch_internal(fetcher, |peer| verify(self, peer).map(|_| ()))?;
+
+        let verified = verify(self, &from)?;
+
+        let ours = self.identity_head(urn, None)?;
+        let theirs = verified.head();
+        if ours != theirs && self.backend.graph_descendant_of(theirs, ours)? {
+            self.backend.reference(
+                &NamespacedRef::rad_id(rad_urn.id.clone()).to_string(),
+                theirs,
+                /* force */ true,
+                &format!("Fast-forwarded `{}` to {}'s view", urn, from),
+            )?;
+            self.link_identity(&rad_urn, &verified)?;
+        }
+
+        Ok(verified)
+    }
+
+    /// Symref `rad/self` of `urn` to the `rad/id` of the [`User`] identity
+    /// `user`.
+    fn link_self(&self, urn: &RadUrn, user: &Urn<Revision>) -> Result<(), Error> {
+        let src = NamespacedRef::rad_self(urn.id.clone(), None);
+        let target = NamespacedRef::rad_id(rad_urn(user).id);
+        tracing::info!("creating symbolic link: {} -> {}", src, target);
+
+        target
+            .symbolic_ref(src, Force::True)
+            .create(&self.backend)
+            .and(Ok(()))
+            .map_err(Error::from)
+    }
+
+    /// Symref the `rad/ids/*` of `verified`, track its delegates, and update
+    /// `rad/signed_refs`.
+    ///
+    /// Certifiers we don't have yet are adopted as verified along with
+    /// `verified`, so that `rad/ids/*` always resolve.
+    fn link_identity<V>(&self, urn: &RadUrn, verified: &V) -> Result<(), Error>
+    where
+        V: Delegating,
+    {
+        for user in verified.certifiers() {
+            let certifier = rad_urn(&user.urn());
+            if !self.has_urn(&certifier)? {
+                self.backend.reference(
+                    &NamespacedRef::rad_id(certifier.id.clone()).to_string(),
+                    *user.content_id,
+                    /* force */ false,
+                    &format!("Adopted certifier `{}` of {}", user.urn(), urn),
+                )?;
+                let user = self.user(&user.urn())?;
+                self.link_identity(&certifier, &user)?;
+            }
+
+            NamespacedRef::rad_id(certifier.id.clone())
+                .symbolic_ref(
+                    NamespacedRef::rad_certifier(urn.id.clone(), &certifier),
+                    Force::True,
+                )
+                .create(&self.backend)?;
+        }
+
+        for peer in verified
+            .delegate_keys()
+            .into_iter()
+            .map(|key| PeerId::from(key.clone()))
+            .filter(|peer| peer != self.peer_id())
+        {
+            tracing::debug!(tracked.peer = %peer, "Tracking delegate of {}", urn);
+            self.track(urn, &peer)?;
+        }
+
+        self.update_refs(urn)
+    }
+}
+
+fn ensure_root<V: Delegating>(urn: &Urn<Revision>, verified: &V) -> Result<(), Error> {
+    if verified.root() == urn.id {
+        Ok(())
+    } else {
+        Err(Error::IdentityRootMismatch {
+            expected: urn.id,
+            actual: verified.root(),
+        })
+    }
+}
@@ -15,17 +15,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::*;
+use super::{identity::Delegating, *};
 
-use std::str::FromStr;
+use std::collections::BTreeSet;
+
+use either::Either;
 
 use crate::{
     git::types::NamespacedRef,
     hash::Hash,
+    identities::{
+        delegation::Indirect,
+        git::{Project, User, Verifying},
+        payload,
+    },
     keys::SecretKey,
-    meta::{entity::Draft, Project, User},
     paths::Paths,
-    test::ConstResolver,
     uri::{self, RadUrn},
 };
 use librad_test::tempdir::WithTmpDir;
@@ -40,16 +45,48 @@ fn storage(key: SecretKey) -> TmpStorage {
     .unwrap()
 }
 
-fn urn_from_idref(refname: &str) -> Option<RadUrn> {
-    refname
-        .strip_suffix("/refs/rad/id")
-        .and_then(|namespace_root| {
-            namespace_root
-                .split('/')
-                .next_back()
-                .and_then(|namespace| Hash::from_str(namespace).ok())
-                .map(|hash| RadUrn::new(hash, uri::Protocol::Git, uri::Path::empty()))
-        })
+fn create_user(store: &TmpStorage, key: &SecretKey) -> User {
+    store
+        .identities::<User>()
+        .create(
+            payload::User {
+                name: "dylan".into(),
+            }
+            .into(),
+            Some(key.public()).into_iter().collect(),
+            key,
+        )
+        .unwrap()
+}
+
+fn create_project(store: &TmpStorage, key: &SecretKey, name: &str, user: User) -> Project {
+    store
+        .identities::<Project>()
+        .create(
+            payload::Project {
+                name: name.into(),
+                description: None,
+                default_branch: None,
+            }
+            .into(),
+            Indirect::try_from_iter(Some(Either::Right(user))).unwrap(),
+            key,
+        )
+        .unwrap()
+}
+
+/// Pretend `peer` has the same view of `urn` as we do.
+fn copy_view_to(store: &TmpStorage, urn: &RadUrn, peer: &PeerId) -> Result<(), Error> {
+    let id = NamespacedRef::rad_id(urn.id.clone());
+    let head = store.reference(&id)?.peel_to_commit()?.id();
+    store.backend.reference(
+        &id.set_remote(peer.clone()).to_string(),
+        head,
+        false,
+        "test",
+    )?;
+
+    Ok(())
 }
 
 #[test]
@@ -100,95 +137,57 @@ fn test_untrack() {
 }
 
 #[test]
-fn test_all_metadata_heads() {
+fn test_all_identities() -> Result<(), Error> {
     let key = SecretKey::new();
     let store = storage(key);
 
-    // Create signed and verified user
-    let mut user = User::<Draft>::create("user".to_owned(), key.public()).unwrap();
-    user.sign_owned(&key).unwrap();
-    let user_resolver = ConstResolver::new(user.clone());
-    let verified_user = user
-        .clone()
-        .check_history_status(&user_resolver, &user_resolver)
-        .unwrap();
-
-    // Create and sign two projects
-    let mut project_foo = Project::<Draft>::create("foo".to_owned(), user.urn()).unwrap();
-    let mut project_bar = Project::<Draft>::create("bar".to_owned(), user.urn()).unwrap();
-    project_foo.sign_by_user(&key, &verified_user).unwrap();
-    project_bar.sign_by_user(&key, &verified_user).unwrap();
-
-    // Store the three entities in their respective namespaces
-    store.create_repo(&user).unwrap();
-    store.create_repo(&project_foo).unwrap();
-    store.create_repo(&project_bar).unwrap();
-
-    let mut ids = HashSet::new();
-    let mut urns = HashMap::new();
-    ids.insert(user.hash());
-    ids.insert(project_foo.hash());
-    ids.insert(project_bar.hash());
-
-    // Iterate ove all namespaces
-    let all_metadata_heads =
-        References::from_globs(&store.backend, &["refs/namespaces/*/refs/rad/id"])
-            .unwrap()
-            .peeled()
-            .filter_map(|(refname, oid)| {
-                urn_from_idref(&refname).and_then(|urn| {
-                    store
-                        .backend
-                        .find_commit(oid)
-                        .map(|commit| (urn, commit))
-                        .ok()
-                })
-            });
-    for (urn, commit) in all_metadata_heads {
-        // Check that we found one of our IDs
-        let id = &urn.id;
-        assert!(ids.contains(id));
-
-        // Check that we can use the URN to find the same commit
-        let commit_from_urn = NamespacedRef::rad_id(urn.id.clone())
-            .find(&store.backend)
-            .unwrap()
-            .target()
-            .unwrap();
-        assert_eq!(commit_from_urn, commit.id());
+    let user = create_user(&store, &key);
+    store.create_user_repo(&user)?;
+    let kalt = create_project(&store, &key, "kalt", user.clone());
+    store.create_project_repo(&kalt)?;
+    let kolt = create_project(&store, &key, "kolt", user.clone());
+    store.create_project_repo(&kolt)?;
+
+    let all = store
+        .all_identities()?
+        .map(|id| id.map(|id| (id.urn(), id.head())))
+        .collect::<Result<BTreeSet<_>, _>>()?;
+    assert_eq!(
+        all,
+        vec![
+            (user.urn(), *user.content_id),
+            (kalt.urn(), *kalt.content_id),
+            (kolt.urn(), *kolt.content_id),
+        ]
+        .into_iter()
+        .collect()
+    );
+    // The namespaces are those of the identities
+    assert_eq!(
+        store.namespaces()?.into_iter().collect::<BTreeSet<_>>(),
+        all.iter().map(|(urn, _)| rad_urn(urn).id).collect()
+    );
 
-        // Bookkeeping for more tests
-        ids.remove(id);
-        urns.insert(id.to_owned(), urn);
+    // The URN finds the same commit
+    for (urn, head) in all {
+        assert_eq!(
+            NamespacedRef::rad_id(rad_urn(&urn).id)
+                .find(&store.backend)?
+                .target(),
+            Some(head)
+        );
     }
 
-    // Check that we found all the entities that we saved
-    assert!(ids.is_empty());
-
-    // Pull out user blob and deserialize
-    assert_eq!(user, store.metadata(&user.urn()).unwrap());
-    let generic_user = store.some_metadata(&user.urn()).unwrap();
-    assert_eq!(generic_user.kind(), user.kind());
-    assert_eq!(generic_user.hash(), user.hash());
-
-    // Pull out foo blob and deserialize
-    assert_eq!(project_foo, store.metadata(&project_foo.urn()).unwrap());
-    let generic_foo = store.some_metadata(&project_foo.urn()).unwrap();
-    assert_eq!(generic_foo.kind(), project_foo.kind());
-    assert_eq!(generic_foo.hash(), project_foo.hash());
-
-    // Check user commit history length
-    let user_history = {
-        let rad_id = NamespacedRef::rad_id(user.urn().id);
-
-        let mut revwalk = store.backend.revwalk().unwrap();
-        revwalk.set_sorting(git2::Sort::TOPOLOGICAL).unwrap();
-        revwalk.simplify_first_parent().unwrap();
-        revwalk.push_ref(&rad_id.to_string()).unwrap();
+    assert_matches!(
+        store.identity(&rad_urn(&user.urn()))?,
+        SomeVerifiedIdentity::User(verified) if *verified == user
+    );
+    assert_matches!(
+        store.identity(&rad_urn(&kalt.urn()))?,
+        SomeVerifiedIdentity::Project(verified) if verified.content_id == kalt.content_id
+    );
 
-        revwalk.collect::<Vec<_>>()
-    };
-    assert_eq!(user_history.len(), 1);
+    Ok(())
 }
 
 #[test]
@@ -196,32 +195,34 @@ fn set_and_get_rad_self() -> Result<(), Error> {
     let key = SecretKey::new();
     let store = storage(key);
 
-    // Create signed and verified user
-    let mut user = User::<Draft>::create("user".to_owned(), key.public())?;
-    user.sign_owned(&key)?;
-    let user_resolver = ConstResolver::new(user.clone());
-    let verified_user = user
-        .clone()
-        .check_history_status(&user_resolver, &user_resolver)
-        .unwrap();
-    store.create_repo(&user)?;
-    store.set_default_rad_self(verified_user.clone())?;
-
-    let mut project = Project::<Draft>::create("banana".to_owned(), user.urn())?;
-    project.sign_by_user(&key, &verified_user)?;
-
-    let repo = store.create_repo(&project)?;
+    let user = create_user(&store, &key);
+    let verified_user = store.create_user_repo(&user)?;
+    store.set_default_rad_self(&verified_user)?;
+    assert_eq!(
+        store.default_rad_self()?.content_id,
+        verified_user.content_id
+    );
+
+    let project = create_project(&store, &key, "banana", user);
+    store.create_project_repo(&project)?;
+    let repo = store.open_repo(rad_urn(&project.urn()))?;
+    repo.set_rad_self(None).expect("repo error:");
+    assert_matches!(
+        store.get_rad_self(&repo.urn),
+        Err(Error::Git(e)) if is_not_found_err(&e)
+    );
+
     repo.set_rad_self(RadSelfSpec::Default)
         .expect("repo error:");
-
     assert_eq!(
-        repo.get_rad_self().expect("repo error:"),
-        store.default_rad_self()?
+        repo.get_rad_self().expect("repo error:").content_id,
+        verified_user.content_id
     );
     assert_eq!(
-        store.get_rad_self(&project.urn())?,
-        store.default_rad_self()?
+        store.get_rad_self(&repo.urn)?.content_id,
+        verified_user.content_id
     );
+
     Ok(())
 }
 
@@ -230,45 +231,45 @@ fn project_has_rad_self() -> Result<(), Error> {
     let key = SecretKey::new();
     let store = storage(key);
 
-    // Create signed and verified user
-    let mut user = User::<Draft>::create("user".to_owned(), key.public())?;
-    user.sign_owned(&key)?;
-    let user_resolver = ConstResolver::new(user.clone());
-    let verified_user = user
-        .clone()
-        .check_history_status(&user_resolver, &user_resolver)
-        .unwrap();
-    store.create_repo(&user)?;
-    store.set_default_rad_self(verified_user.clone())?;
-
-    let mut project = Project::<Draft>::create("banana".to_owned(), user.urn())?;
-    project.sign_by_user(&key, &verified_user)?;
+    let user = create_user(&store, &key);
+    let verified_user = store.create_user_repo(&user)?;
 
-    let repo = store.create_repo(&project)?;
+    let project = create_project(&store, &key, "banana", user);
+    store.create_project_repo(&project)?;
 
     assert_eq!(
-        repo.get_rad_self().expect("repo error:"),
-        store.default_rad_self()?
+        store.get_rad_self(&rad_urn(&project.urn()))?.content_id,
+        verified_user.content_id
     );
     assert_eq!(
-        store.get_rad_self(&project.urn())?,
-        store.default_rad_self()?
+        store
+            .get_rad_self(&rad_urn(&verified_user.urn()))?
+            .content_id,
+        verified_user.content_id
     );
+
     Ok(())
 }
 
 #[test]
-fn cannot_create_twice() -> Result<(), Error> {
-    let key = SecretKey::new();
-    let store = storage(key);
+fn clone_cancelled() -> Result<(), Error> {
+    let store = storage(SecretKey::new());
+    let urn = RadUrn::new(
+        Hash::hash(b"leboeuf"),
+        uri::Protocol::Git,
+        uri::Path::empty(),
+    );
+    let url = RadUrl {
+        authority: PeerId::from(SecretKey::new()),
+        urn: urn.clone(),
+    };
 
-    // Create signed and verified user
-    let mut user = User::<Draft>::create("user".to_owned(), key.public())?;
-    user.sign_owned(&key)?;
-    store.create_repo(&user)?;
-    let repo = store.create_repo(&user);
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let repo = store.clone_repo_with(url, None, None, FetchHooks::default().with_cancel(cancel));
 
-    assert_matches!(repo.err(), Some(Error::AlreadyExists(_)));
+    assert_matches!(repo.err(), Some(Error::Fetch(fetch::Error::Cancelled)));
+    assert!(!store.has_urn(&urn)?);
 
     Ok(())
 }
@@ -282,43 +283,18 @@ fn test_structure_of_refs() -> Result<(), Error> {
     let store = storage(key);
     let mut refs = vec![];
 
-    // Create signed and verified user
-    let mut user = User::<Draft>::create("user".to_owned(), key.public())?;
-    user.sign_owned(&key)?;
-    let user_resolver = ConstResolver::new(user.clone());
-    let verified_user = user
-        .clone()
-        .check_history_status(&user_resolver, &user_resolver)
-        .unwrap();
-    refs.push(NamespacedRef::rad_id(user.urn().id));
-    refs.push(NamespacedRef::rad_signed_refs(user.urn().id, None));
-    store.create_repo(&user)?;
-    store.set_default_rad_self(verified_user)?;
-
-    // Set up project banana
-    {
-        let mut project = Project::<Draft>::create("banana".to_owned(), user.urn())?
-            .to_builder()
-            .add_key(key.public())
-            .build()?;
-        project.sign_owned(&key)?;
-        let namespace = project.urn().id;
-        refs.push(NamespacedRef::rad_id(namespace.clone()));
-        refs.push(NamespacedRef::rad_signed_refs(namespace, None));
-        let _repo = store.create_repo(&project)?;
-    }
+    let user = create_user(&store, &key);
+    let user_ns = rad_urn(&user.urn()).id;
+    refs.push(NamespacedRef::rad_id(user_ns.clone()));
+    refs.push(NamespacedRef::rad_signed_refs(user_ns, None));
+    store.create_user_repo(&user)?;
 
-    // Set up project pineapple
-    {
-        let mut project = Project::<Draft>::create("pineapple".to_owned(), user.urn())?
-            .to_builder()
-            .add_key(key.public())
-            .build()?;
-        project.sign_owned(&key)?;
-        let namespace = project.urn().id;
+    for name in &["banana", "pineapple"] {
+        let project = create_project(&store, &key, name, user.clone());
+        let namespace = rad_urn(&project.urn()).id;
         refs.push(NamespacedRef::rad_id(namespace.clone()));
         refs.push(NamespacedRef::rad_signed_refs(namespace, None));
-        let _repo = store.create_repo(&project)?;
+        store.create_project_repo(&project)?;
     }
 
     // Ensure that we can find all the references
@@ -330,3 +306,304 @@ fn test_structure_of_refs() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn signed_refs_include_issues() -> Result<(), Error> {
+    let key = SecretKey::new();
+    let store = storage(key);
+
+    let user = create_user(&store, &key);
+    store.create_user_repo(&user)?;
+    let project = create_project(&store, &key, "banana", user);
+    store.create_project_repo(&project)?;
+    let urn = rad_urn(&project.urn());
+
+    // Without issues, the signed form is the same as before issues were
+    // included
+    let json: serde_json::Value =
+        serde_json::from_slice(&store.rad_signed_refs_json(&urn)?).unwrap();
+    assert_eq!(json["refs"].get("issues"), None);
+
+    let target = store
+        .backend
+        .refname_to_id(&NamespacedRef::rad_id(urn.id.clone()).to_string())?;
+    store.backend.reference(
+        &NamespacedRef::issue(urn.id.clone(), None, "1").to_string(),
+        target,
+        false,
+        "open issue",
+    )?;
+    store.update_refs(&urn)?;
+
+    let signed = refs::Signed::from_json(&store.rad_signed_refs_json(&urn)?, store.peer_id())?;
+    assert_eq!(
+        Refs::from(signed)
+            .issues
+            .get("refs/issues/1")
+            .map(|oid| **oid),
+        Some(target)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verify_remote_of_peer() -> Result<(), Error> {
+    let key = SecretKey::new();
+    let store = storage(key);
+    let peer = PeerId::from(SecretKey::new());
+
+    let user = create_user(&store, &key);
+    store.create_user_repo(&user)?;
+    let project = create_project(&store, &key, "banana", user.clone());
+    store.create_project_repo(&project)?;
+
+    for urn in &[rad_urn(&user.urn()), rad_urn(&project.urn())] {
+        copy_view_to(&store, urn, &peer)?;
+    }
+
+    assert_matches!(
+        store.verify_remote(&rad_urn(&project.urn()), &peer)?,
+        SomeVerifiedIdentity::Project(verified) if verified.content_id == project.content_id
+    );
+    Ok(())
+}
+
+#[test]
+fn verify_remote_rejects_foreign_namespace() -> Result<(), Error> {
+    let key = SecretKey::new();
+    let store = storage(key);
+    let peer = PeerId::from(SecretKey::new());
+
+    let user = create_user(&store, &key);
+    store.create_user_repo(&user)?;
+
+    // `peer` claims `user` lives in a namespace not derived from it
+    let forged = RadUrn::new(
+        Hash::hash(b"forged"),
+        uri::Protocol::Git,
+        uri::Path::empty(),
+    );
+    store.backend.reference(
+        &NamespacedRef::rad_id(forged.id.clone())
+            .set_remote(peer.clone())
+            .to_string(),
+        *user.content_id,
+        false,
+        "test",
+    )?;
+
+    assert_matches!(
+        store.verify_remote(&forged, &peer),
+        Err(Error::Rejected { reason, .. }) if matches!(*reason, Error::RootHashMismatch { .. })
+    );
+    Ok(())
+}
+
+#[test]
+fn rollback_remote_restores_previous_heads() -> Result<(), Error> {
+    let store = storage(SecretKey::new());
+    let urn = RadUrn::new(Hash::hash(b"lala"), uri::Protocol::Git, uri::Path::empty());
+    let peer = PeerId::from(SecretKey::new());
+    let stranger = PeerId::from(SecretKey::new());
+
+    let sig = git2::Signature::now("leboeuf", "leboeuf@acme.com")?;
+    let tree = store
+        .backend
+        .find_tree(store.backend.treebuilder(None)?.write()?)?;
+    let one = store.backend.commit(None, &sig, &sig, "one", &tree, &[])?;
+    let two = store.backend.commit(None, &sig, &sig, "two", &tree, &[])?;
+    let remote_ref = |peer: &PeerId, name: &str| {
+        format!(
+            "refs/namespaces/{}/refs/remotes/{}/heads/{}",
+            urn.id, peer, name
+        )
+    };
+
+    store
+        .backend
+        .reference(&remote_ref(&peer, "master"), one, false, "test")?;
+    store
+        .backend
+        .reference(&remote_ref(&peer, "gone"), one, false, "test")?;
+    let before = store.remote_heads(&urn)?;
+
+    // Pretend a fetch updated, created and deleted refs
+    store
+        .backend
+        .reference(&remote_ref(&peer, "master"), two, true, "test")?;
+    store
+        .backend
+        .reference(&remote_ref(&peer, "dev"), two, false, "test")?;
+    store
+        .backend
+        .find_reference(&remote_ref(&peer, "gone"))?
+        .delete()?;
+    store
+        .backend
+        .reference(&remote_ref(&stranger, "master"), two, false, "test")?;
+    let after = store.remote_heads(&urn)?;
+
+    store.rollback_remote(&after[&peer], before.get(&peer))?;
+    store.rollback_remote(&after[&stranger], before.get(&stranger))?;
+    assert_eq!(store.remote_heads(&urn)?, before);
+
+    Ok(())
+}
+
+mod rad_urn {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::identities::git::Revision;
+
+    fn root(hex: &str) -> Urn<Revision> {
+        Urn::new(Revision::from(git2::Oid::from_str(hex).unwrap()))
+    }
+
+    #[test]
+    fn is_hash_of_root() {
+        let urn = root("e24124b7538658220b5aaf3b6ef53758f0a106dc");
+        assert_eq!(
+            rad_urn(&urn),
+            RadUrn::new(
+                Hash::from_str("hwd1yrerf8k84ksyju6kgbm7sxyw83cjya9wt3sx1irf19wiaqdiecjdcqe")
+                    .unwrap(),
+                uri::Protocol::Git,
+                uri::Path::empty(),
+            )
+        )
+    }
+
+    #[test]
+    fn ignores_path() {
+        let urn = root("e24124b7538658220b5aaf3b6ef53758f0a106dc");
+        let with_path = Urn {
+            path: Some("refs/heads/master".parse().unwrap()),
+            ..urn
+        };
+        assert_eq!(rad_urn(&urn), rad_urn(&with_path))
+    }
+
+    #[test]
+    fn distinct_roots() {
+        assert_ne!(
+            rad_urn(&root("e24124b7538658220b5aaf3b6ef53758f0a106dc")),
+            rad_urn(&root("e24124b7538658220b5aaf3b6ef53758f0a106dd"))
+        )
+    }
+
+    #[test]
+    fn stable_across_revisions() -> Result<(), Error> {
+        let key = SecretKey::new();
+        let store = storage(key);
+
+        let user = create_user(&store, &key);
+        let verified = store.create_user_repo(&user)?;
+        let payload: payload::UserPayload = payload::User {
+            name: "dylan-thomas".into(),
+        }
+        .into();
+        let updated = store
+            .identities::<User>()
+            .update(
+                Verifying::from(verified.into_inner()).signed().unwrap(),
+                payload,
+                None,
+                &key,
+            )
+            .unwrap();
+
+        assert_ne!(updated.content_id, user.content_id);
+        assert_eq!(rad_urn(&updated.urn()), rad_urn(&user.urn()));
+        assert!(store.has_urn(&rad_urn(&updated.urn()))?);
+
+        Ok(())
+    }
+}
+
+mod identities {
+    use super::*;
+
+    #[test]
+    fn create_user_repo_verifies() -> Result<(), Error> {
+        let key = SecretKey::new();
+        let store = storage(key);
+
+        let user = create_user(&store, &key);
+        store.create_user_repo(&user)?;
+
+        assert_eq!(store.user(&user.urn())?.into_inner(), user);
+        assert!(store.has_ref(&NamespacedRef::rad_signed_refs(
+            rad_urn(&user.urn()).id,
+            None
+        ))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cannot_create_user_repo_twice() -> Result<(), Error> {
+        let key = SecretKey::new();
+        let store = storage(key);
+
+        let user = create_user(&store, &key);
+        store.create_user_repo(&user)?;
+
+        assert_matches!(
+            store.create_user_repo(&user).err(),
+            Some(Error::AlreadyExists(_))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cannot_create_foreign_user_repo() {
+        let store = storage(SecretKey::new());
+
+        let user = create_user(&store, &SecretKey::new());
+
+        assert_matches!(
+            store.create_user_repo(&user).err(),
+            Some(Error::NotSignedBySelf)
+        )
+    }
+
+    #[test]
+    fn create_project_repo_links_certifiers() -> Result<(), Error> {
+        let key = SecretKey::new();
+        let store = storage(key);
+
+        let user = create_user(&store, &key);
+        store.create_user_repo(&user)?;
+        let project = create_project(&store, &key, "haskell-emoji", user.clone());
+        store.create_project_repo(&project)?;
+
+        assert_eq!(
+            store.project(&project.urn())?.content_id,
+            project.content_id
+        );
+        assert_eq!(
+            store.certifiers(&rad_urn(&project.urn()))?,
+            Some(user.urn()).into_iter().collect::<BTreeSet<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_project_repo_requires_certifiers() {
+        let key = SecretKey::new();
+        let store = storage(key);
+
+        let user = create_user(&store, &key);
+        let project = create_project(&store, &key, "haskell-emoji", user);
+
+        assert_matches!(
+            store.create_project_repo(&project).err(),
+            Some(Error::MissingCertifier { .. })
+        )
+    }
+}
@@ -210,18 +210,36 @@ impl Refspec<PeerId, PeerId> {
         let mut refspecs = Vec::new();
 
         for tracked_peer in tracked_peers {
-            // Heads
+            // Heads and issues
             //
             // `+refs/namespaces/<namespace>/refs[/remotes/<peer>]/heads/* \
             // :refs/namespaces/<namespace>/refs/remotes/<peer>/heads/*`
+            //
+            // and
+            //
+            // `+refs/namespaces/<namespace>/refs[/remotes/<peer>]/issues/* \
+            // :refs/namespaces/<namespace>/refs/remotes/<peer>/issues/*`
+            //
+            // limited to the refs the peer signed, at the signed targets.
             {
                 let their_singed_rad_refs = rad_signed_refs_of(tracked_peer.clone())?;
-                for (name, target) in their_singed_rad_refs.heads {
+                let signed = their_singed_rad_refs
+                    .heads
+                    .into_iter()
+                    .map(|head| (RefsCategory::Heads, head))
+                    .chain(
+                        their_singed_rad_refs
+                            .issues
+                            .into_iter()
+                            .map(|issue| (RefsCategory::Issues, issue)),
+                    );
+                for (category, (name, target)) in signed {
                     let name_namespaced = format!("refs/namespaces/{}/{}", namespace, name);
-                    if let Some(name) = name.strip_prefix("refs/heads/") {
+                    let category_prefix = format!("refs/{}/", category);
+                    if let Some(name) = name.strip_prefix(category_prefix.as_str()) {
                         let name_namespaced_remote = format!(
-                            "refs/namespaces/{}/refs/remotes/{}/heads/{}",
-                            namespace, tracked_peer, name
+                            "refs/namespaces/{}/refs/remotes/{}/{}/{}",
+                            namespace, tracked_peer, category, name
                         );
                         let targets_match = remote_heads
                             .get(name_namespaced.as_str())
@@ -230,8 +248,14 @@ impl Refspec<PeerId, PeerId> {
                             .unwrap_or(false);
 
                         if targets_match {
-                            let local =
-                                Reference::head(namespace.clone(), tracked_peer.clone(), &name);
+                            let local = match category {
+                                RefsCategory::Issues => {
+                                    Reference::issue(namespace.clone(), tracked_peer.clone(), &name)
+                                },
+                                _ => {
+                                    Reference::head(namespace.clone(), tracked_peer.clone(), &name)
+                                },
+                            };
                             let remote = if tracked_peer == remote_peer {
                                 local.set_remote(None)
                             } else {
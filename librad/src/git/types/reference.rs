@@ -43,6 +43,7 @@ pub type Namespace = Hash;
 pub enum RefsCategory {
     Heads,
     Rad,
+    Issues,
 }
 
 impl Display for RefsCategory {
@@ -50,6 +51,7 @@ impl Display for RefsCategory {
         match self {
             Self::Heads => f.write_str("heads"),
             Self::Rad => f.write_str("rad"),
+            Self::Issues => f.write_str("issues"),
         }
     }
 }
@@ -64,7 +66,7 @@ pub trait ReferenceInfo {
 pub struct Reference<Namespaced, Remote, Cardinality> {
     /// The remote portion of this reference.
     pub remote: Option<Remote>,
-    /// Where this reference falls under, i.e. `rad` or `heads`.
+    /// Where this reference falls under, i.e. `rad`, `heads` or `issues`.
     pub category: RefsCategory,
     /// The path of the reference, e.g. `feature/123`, `dev`.
     pub name: String,
@@ -290,6 +292,19 @@ impl<N, R> Reference<N, R, Single> {
             _cardinality: PhantomData,
         }
     }
+
+    /// Build a reference that points to:
+    ///     * `refs/namespaces/<namespace>/refs/issues/<name>`
+    ///     * `refs/namespaces/<namespace>/refs/remote/<peer_id>/issues/<name>
+    pub fn issue(namespace: N, remote: impl Into<Option<R>>, name: &str) -> Self {
+        Self {
+            remote: remote.into(),
+            category: RefsCategory::Issues,
+            name: name.to_owned(),
+            _namespace: namespace,
+            _cardinality: PhantomData,
+        }
+    }
 }
 
 // References with a Multiple cardinality
@@ -328,6 +343,18 @@ impl<N, R> Reference<N, R, Multiple> {
             _cardinality: PhantomData,
         }
     }
+
+    /// Build a reference that points to
+    /// `refs/namespaces/<namespace>/refs/[remotes/<peer_id>/]issues/*`
+    pub fn issues(namespace: N, remote: impl Into<Option<R>>) -> Self {
+        Self {
+            remote: remote.into(),
+            category: RefsCategory::Issues,
+            name: "*".to_owned(),
+            _namespace: namespace,
+            _cardinality: PhantomData,
+        }
+    }
 }
 
 impl<'a, N, R> Into<ext::blob::Branch<'a>> for &'a Reference<N, R, Single>
@@ -0,0 +1,1253 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! In-process `upload-pack`
+//!
+//! Serves fetches from a [`git2::Repository`] without spawning `git
+//! upload-pack`. Only what our transports need is supported: the stateless
+//! variant of protocol v0, with the `multi_ack_detailed`, `side-band-64k`,
+//! `ofs-delta`, `shallow`, and `filter` capabilities. Of protocol v2, only the
+//! `ls-refs` command is supported, see [`LsRefs`].
+//!
+//! Which refs are advertised is up to the caller. Only the objects reachable
+//! from those refs can be fetched, and blobs by their id, so that partial
+//! clones can fetch the blobs they are missing.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{self, Display},
+    io::{self, Write},
+    str::{self, FromStr},
+};
+
+use thiserror::Error;
+
+use crate::git::{
+    ext::{is_not_found_err, References},
+    pkt_line::{self, Pkt},
+};
+
+const CAPABILITIES: &str = "multi_ack_detailed side-band-64k ofs-delta shallow filter";
+const CAPABILITIES_V2: &[&str] = &["version 2", "ls-refs"];
+
+/// Upper bound on the number of haves of a request.
+const MAX_HAVES: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid pkt-line")]
+    PktLine,
+
+    #[error("unexpected line in request: {0}")]
+    Unexpected(String),
+
+    #[error("unknown command: {0}")]
+    UnknownCommand(String),
+
+    #[error("{0} is not advertised")]
+    NotAdvertised(git2::Oid),
+
+    #[error("unsupported filter: {0}")]
+    UnsupportedFilter(String),
+
+    #[error("too many haves")]
+    TooManyHaves,
+
+    /// Objects needed for the packfile are missing, because the repo is a
+    /// partial clone itself.
+    #[error("{} objects are missing", .0.len())]
+    Missing(Vec<git2::Oid>),
+
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Wants,
+    Haves,
+    Complete,
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Self::Wants
+    }
+}
+
+/// An object filter, as used by partial clones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Omit all blobs.
+    BlobNone,
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BlobNone => f.write_str("blob:none"),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blob:none" => Ok(Self::BlobNone),
+            _ => Err(Error::UnsupportedFilter(s.to_owned())),
+        }
+    }
+}
+
+/// A request sent by the fetching side.
+///
+/// In stateless mode, every request carries all the wants, and the haves found
+/// to be common so far. Unless it is `done`, we only tell which of the haves
+/// we have in common. Otherwise, we send the packfile.
+#[derive(Debug, Default, PartialEq)]
+pub struct Request {
+    pub wants: Vec<git2::Oid>,
+    pub haves: Vec<git2::Oid>,
+    pub done: bool,
+    /// The commits the fetching side has without their parents.
+    pub shallow: Vec<git2::Oid>,
+    /// The number of commits to send, counting from the wants.
+    pub depth: Option<u32>,
+    pub filter: Option<Filter>,
+    side_band: bool,
+    stage: Stage,
+}
+
+impl Request {
+    /// Parse a complete request.
+    pub fn parse(mut buf: &[u8]) -> Result<Self, Error> {
+        let mut request = Self::default();
+        loop {
+            let pkt = pkt_line::read(&mut buf)?;
+            if request.push(pkt)? {
+                return Ok(request);
+            }
+        }
+    }
+
+    /// Add the next pkt-line.
+    ///
+    /// Returns `true` if the request is complete.
+    pub fn push(&mut self, pkt: Pkt<&[u8]>) -> Result<bool, Error> {
+        let line = match pkt {
+            Pkt::Flush => None,
+            Pkt::Delim => return Err(Error::Unexpected("delim-pkt".to_owned())),
+            Pkt::Data(line) => Some(line),
+        };
+
+        match (self.stage, line) {
+            (Stage::Complete, _) => {
+                return Err(Error::Unexpected("data after end of request".to_owned()))
+            },
+
+            (Stage::Wants, None) if self.wants.is_empty() => self.stage = Stage::Complete,
+            (Stage::Wants, None) => self.stage = Stage::Haves,
+            (Stage::Wants, Some(line)) => {
+                let line = text(line)?;
+                if line.starts_with("shallow ") {
+                    self.shallow.push(parse_oid(line, "shallow ")?.0);
+                } else if let Some(depth) = line.strip_prefix("deepen ") {
+                    let depth = depth
+                        .parse()
+                        .ok()
+                        .filter(|depth| *depth > 0)
+                        .ok_or_else(|| Error::Unexpected(line.to_owned()))?;
+                    self.depth = Some(depth);
+                } else if let Some(filter) = line.strip_prefix("filter ") {
+                    self.filter = Some(filter.parse()?);
+                } else {
+                    let (want, caps) = parse_oid(line, "want ")?;
+                    if self.wants.is_empty() {
+                        self.side_band = caps.split(' ').any(|cap| cap == "side-band-64k");
+                    }
+                    self.wants.push(want);
+                }
+            },
+
+            (Stage::Haves, None) => self.stage = Stage::Complete,
+            (Stage::Haves, Some(line)) => {
+                let line = text(line)?;
+                if line == "done" {
+                    self.done = true;
+                    self.stage = Stage::Complete;
+                } else if self.haves.len() < MAX_HAVES {
+                    self.haves.push(parse_oid(line, "have ")?.0);
+                } else {
+                    return Err(Error::TooManyHaves);
+                }
+            },
+        }
+
+        Ok(self.stage == Stage::Complete)
+    }
+}
+
+/// The refs matching `globs`, and their targets. Symbolic refs are resolved.
+pub fn visible_refs(
+    repo: &git2::Repository,
+    globs: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<Vec<(String, git2::Oid)>, git2::Error> {
+    let mut refs = References::from_globs(repo, globs)?
+        .filter_map(|reference| {
+            reference
+                .and_then(|reference| {
+                    let name = reference.name().map(|name| name.to_owned());
+                    let target = reference.resolve()?.target();
+                    Ok(name.and_then(|name| target.map(|target| (name, target))))
+                })
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    refs.sort();
+    refs.dedup();
+
+    Ok(refs)
+}
+
+/// Write the advertisement of `refs`.
+pub fn advertise<W>(refs: &[(String, git2::Oid)], out: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    write_advertisement(refs, CAPABILITIES, out)
+}
+
+/// Write the advertisement of `refs`, with `capabilities` on the first line.
+pub fn write_advertisement<W>(
+    refs: &[(String, git2::Oid)],
+    capabilities: &str,
+    out: &mut W,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut lines = refs.iter().map(|(name, oid)| format!("{} {}", oid, name));
+    let first = lines
+        .next()
+        .unwrap_or_else(|| format!("{} capabilities^{{}}", git2::Oid::zero()));
+
+    pkt_line::write(out, format!("{}\0{}\n", first, capabilities).as_bytes())?;
+    for line in lines {
+        pkt_line::write(out, format!("{}\n", line).as_bytes())?;
+    }
+    out.write_all(pkt_line::FLUSH)
+}
+
+/// Answer `request`, given we advertised `refs`.
+///
+/// Neither `deepen` nor `filter` apply to the history of the refs selected by
+/// `unlimited`, eg. because the fetching side needs to verify it.
+pub fn respond<W, F>(
+    repo: &git2::Repository,
+    refs: &[(String, git2::Oid)],
+    request: &Request,
+    unlimited: F,
+    out: &mut W,
+) -> Result<(), Error>
+where
+    W: Write,
+    F: Fn(&str) -> bool,
+{
+    let odb = repo.odb()?;
+    let tips = refs.iter().map(|(_, oid)| oid).collect::<HashSet<_>>();
+    let mut blobs = Vec::new();
+    for want in &request.wants {
+        if tips.contains(want) {
+            continue;
+        }
+        if !is_blob(&odb, *want)? {
+            return Err(Error::NotAdvertised(*want));
+        }
+        blobs.push(*want);
+    }
+    // Blobs are requested by partial clones filling in what they omitted, but
+    // must not give access to other namespaces of the monorepo
+    if let Some(blob) = unreachable_blob(repo, refs.iter().map(|(_, oid)| *oid), blobs)? {
+        return Err(Error::NotAdvertised(blob));
+    }
+    let unlimited = refs
+        .iter()
+        .filter(|(name, _)| unlimited(name))
+        .map(|(_, oid)| *oid)
+        .collect::<HashSet<_>>();
+
+    // Like blobs, the haves we have in common must not reveal what is in other
+    // namespaces
+    let common = reachable_haves(repo, refs.iter().map(|(_, oid)| *oid), &request.haves)?;
+
+    // The shallow update is part of every response, so we need to know what
+    // to send even if we're not sending it yet
+    let pack = if request.done || request.depth.is_some() {
+        Some(Pack::build(repo, request, &common, &unlimited)?)
+    } else {
+        None
+    };
+
+    if request.depth.is_some() {
+        if let Some(pack) = &pack {
+            for oid in &pack.shallow {
+                pkt_line::write(out, format!("shallow {}\n", oid).as_bytes())?;
+            }
+            for oid in &pack.unshallow {
+                pkt_line::write(out, format!("unshallow {}\n", oid).as_bytes())?;
+            }
+            out.write_all(pkt_line::FLUSH)?;
+        }
+    }
+
+    let mut pack = match pack {
+        Some(pack) if request.done => pack,
+        _ => {
+            for oid in &common {
+                pkt_line::write(out, format!("ACK {} common\n", oid).as_bytes())?;
+            }
+            pkt_line::write(out, b"NAK\n")?;
+            return out.flush().map_err(Error::from);
+        },
+    };
+
+    match common.last() {
+        Some(oid) => pkt_line::write(out, format!("ACK {}\n", oid).as_bytes())?,
+        None => pkt_line::write(out, b"NAK\n")?,
+    }
+
+    let mut written = Ok(());
+    let res = pack.builder.foreach(|chunk| {
+        written = if request.side_band {
+            write_band(out, 1, chunk)
+        } else {
+            out.write_all(chunk)
+        };
+        written.is_ok()
+    });
+    written?;
+    res?;
+
+    if request.side_band {
+        out.write_all(pkt_line::FLUSH)?;
+    }
+    out.flush().map_err(Error::from)
+}
+
+/// The objects to send in response to a [`Request`].
+struct Pack<'r> {
+    builder: git2::PackBuilder<'r>,
+    /// Commits we send without their parents.
+    shallow: Vec<git2::Oid>,
+    /// Commits the fetching side had without their parents, which we send.
+    unshallow: Vec<git2::Oid>,
+}
+
+impl<'r> Pack<'r> {
+    fn build(
+        repo: &'r git2::Repository,
+        request: &Request,
+        common: &[git2::Oid],
+        unlimited: &HashSet<git2::Oid>,
+    ) -> Result<Self, Error> {
+        let mut pack = Self {
+            builder: repo.packbuilder()?,
+            shallow: Vec::new(),
+            unshallow: Vec::new(),
+        };
+
+        // Let libgit2 figure it out, unless we're missing objects
+        if request.depth.is_none() && request.filter.is_none() && request.shallow.is_empty() {
+            match insert_walk(repo, &mut pack.builder, &request.wants, common) {
+                Ok(()) => return Ok(pack),
+                Err(e) if is_not_found_err(&e) => pack.builder = repo.packbuilder()?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut objects = Objects {
+            repo,
+            odb: repo.odb()?,
+            builder: &mut pack.builder,
+            seen: HashSet::new(),
+            missing: Vec::new(),
+        };
+        // Don't send what the fetching side has
+        for have in common {
+            let tree = repo.find_commit(*have)?.tree_id();
+            objects.tree(tree, true, false)?;
+        }
+
+        let mut full = Vec::new();
+        let mut limited = Vec::new();
+        for want in &request.wants {
+            let object = repo.find_object(*want, None)?;
+            match object.kind() {
+                Some(git2::ObjectType::Blob) => objects.blob(*want)?,
+                Some(git2::ObjectType::Tree) => objects.tree(*want, true, true)?,
+                _ => {
+                    let commit = object.peel_to_commit()?;
+                    // Annotated tags
+                    if commit.id() != *want {
+                        objects.builder.insert_object(*want, None)?;
+                    }
+                    if unlimited.contains(want) {
+                        full.push(commit.id())
+                    } else {
+                        limited.push(commit.id())
+                    }
+                },
+            }
+        }
+
+        for commit in revwalk(repo, &full, common)? {
+            objects.commit(commit, true)?;
+        }
+
+        let blobs = request.filter.is_none();
+        let limited = match request.depth {
+            None => revwalk(repo, &limited, common)?,
+            Some(depth) => {
+                let walk = shallow_walk(repo, &limited, common, &request.shallow, depth)?;
+                pack.shallow = walk.shallow;
+                pack.unshallow = walk.unshallow;
+                walk.commits
+            },
+        };
+        for commit in limited {
+            objects.commit(commit, blobs)?;
+        }
+
+        if objects.missing.is_empty() {
+            Ok(pack)
+        } else {
+            Err(Error::Missing(objects.missing))
+        }
+    }
+}
+
+/// Adds commits, trees, and blobs to a [`git2::PackBuilder`], skipping the
+/// ones already added, and remembering the ones we don't have.
+struct Objects<'a, 'r> {
+    repo: &'r git2::Repository,
+    odb: git2::Odb<'r>,
+    builder: &'a mut git2::PackBuilder<'r>,
+    seen: HashSet<git2::Oid>,
+    missing: Vec<git2::Oid>,
+}
+
+impl<'a, 'r> Objects<'a, 'r> {
+    fn commit(&mut self, oid: git2::Oid, blobs: bool) -> Result<(), git2::Error> {
+        if self.seen.insert(oid) {
+            let tree = self.repo.find_commit(oid)?.tree_id();
+            self.builder.insert_object(oid, None)?;
+            self.tree(tree, blobs, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the tree `oid` recursively, including blobs if `blobs`. Unless
+    /// `insert`, the objects are only marked as seen.
+    fn tree(&mut self, oid: git2::Oid, blobs: bool, insert: bool) -> Result<(), git2::Error> {
+        if !self.seen.insert(oid) {
+            return Ok(());
+        }
+
+        let tree = match self.repo.find_tree(oid) {
+            Ok(tree) => tree,
+            Err(e) if is_not_found_err(&e) => {
+                if insert {
+                    self.missing.push(oid);
+                }
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        if insert {
+            self.builder.insert_object(oid, None)?;
+        }
+
+        for entry in tree.iter() {
+            match entry.kind() {
+                Some(git2::ObjectType::Tree) => self.tree(entry.id(), blobs, insert)?,
+                Some(git2::ObjectType::Blob) if blobs && insert => self.blob(entry.id())?,
+                Some(git2::ObjectType::Blob) if blobs => {
+                    self.seen.insert(entry.id());
+                },
+                // Omitted blobs, and submodule commits
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn blob(&mut self, oid: git2::Oid) -> Result<(), git2::Error> {
+        if self.seen.insert(oid) {
+            if self.odb.exists(oid) {
+                self.builder.insert_object(oid, None)?;
+            } else {
+                self.missing.push(oid);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn insert_walk(
+    repo: &git2::Repository,
+    builder: &mut git2::PackBuilder,
+    wants: &[git2::Oid],
+    common: &[git2::Oid],
+) -> Result<(), git2::Error> {
+    let mut walk = repo.revwalk()?;
+    for want in wants {
+        let object = repo.find_object(*want, None)?;
+        match object.peel_to_commit() {
+            Ok(commit) => {
+                walk.push(commit.id())?;
+                // Annotated tags
+                if commit.id() != *want {
+                    builder.insert_object(*want, None)?;
+                }
+            },
+            Err(_) => builder.insert_recursive(*want, None)?,
+        }
+    }
+    for have in common {
+        walk.hide(*have)?;
+    }
+
+    builder.insert_walk(&mut walk)
+}
+
+/// The commits reachable from `tips`, but not from `common`.
+fn revwalk(
+    repo: &git2::Repository,
+    tips: &[git2::Oid],
+    common: &[git2::Oid],
+) -> Result<Vec<git2::Oid>, git2::Error> {
+    if tips.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut walk = repo.revwalk()?;
+    for tip in tips {
+        walk.push(*tip)?;
+    }
+    for have in common {
+        walk.hide(*have)?;
+    }
+
+    walk.collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ShallowWalk {
+    commits: Vec<git2::Oid>,
+    shallow: Vec<git2::Oid>,
+    unshallow: Vec<git2::Oid>,
+}
+
+/// The commits at most `depth` commits away from `tips`, which the fetching
+/// side doesn't have.
+///
+/// The history of the commits in `common` is assumed to be present on the
+/// fetching side, unless it told us it is `shallow`.
+fn shallow_walk(
+    repo: &git2::Repository,
+    tips: &[git2::Oid],
+    common: &[git2::Oid],
+    shallow: &[git2::Oid],
+    depth: u32,
+) -> Result<ShallowWalk, git2::Error> {
+    let common = common.iter().collect::<HashSet<_>>();
+    let shallow = shallow.iter().collect::<HashSet<_>>();
+
+    let mut walk = ShallowWalk::default();
+    let mut seen = HashSet::new();
+    let mut queue = tips.iter().map(|tip| (*tip, 1)).collect::<VecDeque<_>>();
+    while let Some((oid, distance)) = queue.pop_front() {
+        if !seen.insert(oid) {
+            continue;
+        }
+
+        // If the fetching side is shallow, the history of what it has may be
+        // incomplete, so we can't stop there
+        let have = common.contains(&oid);
+        let is_shallow = shallow.contains(&oid);
+        if have && shallow.is_empty() {
+            continue;
+        }
+
+        let commit = repo.find_commit(oid)?;
+        if !have {
+            walk.commits.push(oid);
+        }
+        if commit.parent_count() == 0 {
+            continue;
+        }
+
+        if distance >= depth {
+            if !have {
+                walk.shallow.push(oid)
+            }
+        } else {
+            if is_shallow {
+                walk.unshallow.push(oid)
+            }
+            queue.extend(commit.parent_ids().map(|parent| (parent, distance + 1)));
+        }
+    }
+
+    Ok(walk)
+}
+
+/// The commits among `haves` which are reachable from any of the commits
+/// `tips` point to, in the order they were given.
+fn reachable_haves(
+    repo: &git2::Repository,
+    tips: impl IntoIterator<Item = git2::Oid>,
+    haves: &[git2::Oid],
+) -> Result<Vec<git2::Oid>, git2::Error> {
+    let mut unseen = haves.iter().copied().collect::<HashSet<_>>();
+    if !unseen.is_empty() {
+        for commit in walk_from(repo, tips)? {
+            unseen.remove(&commit?);
+            if unseen.is_empty() {
+                break;
+            }
+        }
+    }
+
+    Ok(haves
+        .iter()
+        .filter(|have| !unseen.contains(have))
+        .copied()
+        .collect())
+}
+
+/// Walk the history of the commits `tips` point to.
+fn walk_from(
+    repo: &git2::Repository,
+    tips: impl IntoIterator<Item = git2::Oid>,
+) -> Result<git2::Revwalk, git2::Error> {
+    let mut walk = repo.revwalk()?;
+    for tip in tips {
+        if let Ok(commit) = repo.find_object(tip, None)?.peel_to_commit() {
+            walk.push(commit.id())?;
+        }
+    }
+
+    Ok(walk)
+}
+
+/// Find one of `blobs` which is not reachable from any of the commits `tips`
+/// point to, if any.
+fn unreachable_blob(
+    repo: &git2::Repository,
+    tips: impl IntoIterator<Item = git2::Oid>,
+    blobs: impl IntoIterator<Item = git2::Oid>,
+) -> Result<Option<git2::Oid>, git2::Error> {
+    let mut missing = blobs.into_iter().collect::<HashSet<_>>();
+    if missing.is_empty() {
+        return Ok(None);
+    }
+
+    let walk = walk_from(repo, tips)?;
+    let mut seen = HashSet::new();
+    for commit in walk {
+        let mut trees = vec![repo.find_commit(commit?)?.tree_id()];
+        while let Some(tree) = trees.pop() {
+            if !seen.insert(tree) {
+                continue;
+            }
+            for entry in repo.find_tree(tree)?.iter() {
+                match entry.kind() {
+                    Some(git2::ObjectType::Tree) => trees.push(entry.id()),
+                    Some(git2::ObjectType::Blob) => {
+                        missing.remove(&entry.id());
+                    },
+                    _ => {},
+                }
+            }
+            if missing.is_empty() {
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(missing.into_iter().next())
+}
+
+fn is_blob(odb: &git2::Odb, oid: git2::Oid) -> Result<bool, git2::Error> {
+    match odb.read_header(oid) {
+        Ok((_, kind)) => Ok(kind == git2::ObjectType::Blob),
+        Err(e) if is_not_found_err(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write the protocol v2 capability advertisement.
+pub fn advertise_v2<W>(out: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    for capability in CAPABILITIES_V2 {
+        pkt_line::write(out, format!("{}\n", capability).as_bytes())?;
+    }
+    out.write_all(pkt_line::FLUSH)
+}
+
+/// Whether `capabilities`, as advertised by [`advertise_v2`], are those of a
+/// server supporting [`LsRefs`].
+pub fn supports_ls_refs<'a>(capabilities: impl IntoIterator<Item = &'a [u8]>) -> bool {
+    let mut version_2 = false;
+    let mut ls_refs = false;
+    for capability in capabilities {
+        match text(capability) {
+            Ok("version 2") => version_2 = true,
+            Ok(capability) => {
+                ls_refs |= capability == "ls-refs" || capability.starts_with("ls-refs=")
+            },
+            Err(_) => {},
+        }
+    }
+
+    version_2 && ls_refs
+}
+
+/// The protocol v2 `ls-refs` command.
+///
+/// Of its arguments, only `ref-prefix` is supported: the others only add
+/// information, which may be omitted.
+#[derive(Debug, Default, PartialEq)]
+pub struct LsRefs {
+    /// Only list the refs starting with one of these. If empty, list all refs.
+    pub prefixes: Vec<String>,
+}
+
+impl LsRefs {
+    /// Parse the command from its pkt-lines, up to the terminating flush
+    /// packet.
+    pub fn from_pkts<'a>(pkts: impl IntoIterator<Item = Pkt<&'a [u8]>>) -> Result<Self, Error> {
+        let mut pkts = pkts.into_iter();
+        match pkts.next() {
+            Some(Pkt::Data(line)) => match text(line)?.strip_prefix("command=") {
+                Some("ls-refs") => {},
+                Some(command) => return Err(Error::UnknownCommand(command.to_owned())),
+                None => return Err(Error::Unexpected("missing command".to_owned())),
+            },
+            _ => return Err(Error::Unexpected("missing command".to_owned())),
+        }
+
+        let mut prefixes = Vec::new();
+        let mut args = false;
+        for pkt in pkts {
+            match pkt {
+                Pkt::Flush => break,
+                Pkt::Delim => args = true,
+                // Capabilities
+                Pkt::Data(_) if !args => {},
+                Pkt::Data(line) => {
+                    if let Some(prefix) = text(line)?.strip_prefix("ref-prefix ") {
+                        prefixes.push(prefix.to_owned())
+                    }
+                },
+            }
+        }
+
+        Ok(Self { prefixes })
+    }
+
+    pub fn encode<W>(&self, out: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        pkt_line::write(out, b"command=ls-refs\n")?;
+        out.write_all(pkt_line::DELIM)?;
+        for prefix in &self.prefixes {
+            pkt_line::write(out, format!("ref-prefix {}\n", prefix).as_bytes())?;
+        }
+        out.write_all(pkt_line::FLUSH)
+    }
+}
+
+/// Write the response to an [`LsRefs`] command, listing `refs`.
+pub fn ls_refs<W>(refs: &[(String, git2::Oid)], out: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    for (name, oid) in refs {
+        pkt_line::write(out, format!("{} {}\n", oid, name).as_bytes())?;
+    }
+    out.write_all(pkt_line::FLUSH)
+}
+
+/// Parse a line of the response to an [`LsRefs`] command.
+pub fn parse_ref(line: &[u8]) -> Result<(String, git2::Oid), Error> {
+    let line = text(line)?;
+    let (oid, rest) = parse_oid(line, "")?;
+    let name = rest
+        .split(' ')
+        .next()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| Error::Unexpected(line.to_owned()))?;
+
+    Ok((name.to_owned(), oid))
+}
+
+/// Write `data` to side-band `band`, split into as many pkt-lines as needed.
+fn write_band<W>(out: &mut W, band: u8, data: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    for chunk in data.chunks(pkt_line::MAX_LEN - 5) {
+        write!(out, "{:04x}", chunk.len() + 5)?;
+        out.write_all(&[band])?;
+        out.write_all(chunk)?;
+    }
+
+    Ok(())
+}
+
+fn text(line: &[u8]) -> Result<&str, Error> {
+    str::from_utf8(line)
+        .map(|line| line.trim_end_matches('\n'))
+        .map_err(|_| Error::PktLine)
+}
+
+/// Parse `<prefix><oid>[ <rest>]`, returning the oid and the rest.
+fn parse_oid<'a>(line: &'a str, prefix: &str) -> Result<(git2::Oid, &'a str), Error> {
+    let unexpected = || Error::Unexpected(line.to_owned());
+
+    let rest = line.strip_prefix(prefix).ok_or_else(unexpected)?;
+    let mut parts = rest.splitn(2, ' ');
+    let oid = parts
+        .next()
+        .filter(|hex| hex.len() == 40)
+        .and_then(|hex| git2::Oid::from_str(hex).ok())
+        .ok_or_else(unexpected)?;
+
+    Ok((oid, parts.next().unwrap_or("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+    const B: &str = "4b825dc642cb6eb9a060e54bf8d69288fbc4904e";
+
+    fn oid(hex: &str) -> git2::Oid {
+        git2::Oid::from_str(hex).unwrap()
+    }
+
+    fn pkt_lines(lines: &[Option<&str>]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for line in lines {
+            match line {
+                Some(line) => pkt_line::write(&mut buf, line.as_bytes()).unwrap(),
+                None => buf.extend_from_slice(pkt_line::FLUSH),
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_request() {
+        let request = Request::parse(&pkt_lines(&[
+            Some(&format!("want {} multi_ack_detailed side-band-64k\n", A)),
+            Some(&format!("want {}\n", B)),
+            None,
+            Some(&format!("have {}\n", B)),
+            Some("done\n"),
+        ]))
+        .unwrap();
+
+        assert_eq!(request.wants, vec![oid(A), oid(B)]);
+        assert_eq!(request.haves, vec![oid(B)]);
+        assert!(request.done);
+        assert!(request.side_band);
+    }
+
+    #[test]
+    fn parse_negotiation_round() {
+        let request = Request::parse(&pkt_lines(&[
+            Some(&format!("want {}\n", A)),
+            None,
+            Some(&format!("have {}\n", B)),
+            None,
+        ]))
+        .unwrap();
+
+        assert_eq!(request.haves, vec![oid(B)]);
+        assert!(!request.done);
+        assert!(!request.side_band);
+    }
+
+    #[test]
+    fn parse_shallow_request() {
+        let request = Request::parse(&pkt_lines(&[
+            Some(&format!("want {} side-band-64k shallow filter\n", A)),
+            Some(&format!("shallow {}\n", B)),
+            Some("deepen 1\n"),
+            Some("filter blob:none\n"),
+            None,
+            Some("done\n"),
+        ]))
+        .unwrap();
+
+        assert_eq!(request.wants, vec![oid(A)]);
+        assert_eq!(request.shallow, vec![oid(B)]);
+        assert_eq!(request.depth, Some(1));
+        assert_eq!(request.filter, Some(Filter::BlobNone));
+        assert!(request.done);
+    }
+
+    #[test]
+    fn reject_too_many_haves() {
+        let mut lines = vec![Some(format!("want {}\n", A)), None];
+        lines.extend((0..=MAX_HAVES).map(|_| Some(format!("have {}\n", B))));
+        lines.push(None);
+        let lines = lines.iter().map(Option::as_deref).collect::<Vec<_>>();
+
+        assert_matches!(Request::parse(&pkt_lines(&lines)), Err(Error::TooManyHaves));
+    }
+
+    #[test]
+    fn reject_unsupported_shallow_request() {
+        assert!(matches!(
+            Request::parse(&pkt_lines(&[Some("filter tree:0\n"), None])),
+            Err(Error::UnsupportedFilter(_))
+        ));
+        assert!(Request::parse(&pkt_lines(&[Some("deepen 0\n"), None])).is_err());
+        assert!(Request::parse(&pkt_lines(&[Some("deepen-since 42\n"), None])).is_err());
+    }
+
+    #[test]
+    fn parse_empty_request() {
+        let request = Request::parse(&pkt_lines(&[None])).unwrap();
+        assert!(request.wants.is_empty());
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(Request::parse(&pkt_lines(&[Some("want 123\n"), None])).is_err());
+        assert!(Request::parse(&pkt_lines(&[Some(&format!("have {}\n", A)), None])).is_err());
+        assert!(Request::parse(&pkt_lines(&[Some(&format!("want {}\n", A))])).is_err());
+        assert!(Request::parse(b"00zz").is_err());
+    }
+
+    #[test]
+    fn advertisement() {
+        let mut buf = Vec::new();
+        advertise(&[("refs/heads/master".to_owned(), oid(A))], &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!(
+                "{:04x}{} refs/heads/master\0{}\n0000",
+                4 + 40 + 19 + CAPABILITIES.len() + 1,
+                A,
+                CAPABILITIES
+            )
+        );
+
+        let mut buf = Vec::new();
+        advertise(&[], &mut buf).unwrap();
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .contains(&format!("{} capabilities^{{}}\0", git2::Oid::zero())));
+    }
+
+    #[test]
+    fn ls_refs_roundtrip() {
+        let cmd = LsRefs {
+            prefixes: vec!["refs/namespaces/geez/".to_owned(), "refs/tags/".to_owned()],
+        };
+        let mut buf = Vec::new();
+        cmd.encode(&mut buf).unwrap();
+
+        let mut rest = &buf[..];
+        let mut pkts = Vec::new();
+        while !rest.is_empty() {
+            pkts.push(pkt_line::read(&mut rest).unwrap());
+        }
+        assert_eq!(cmd, LsRefs::from_pkts(pkts).unwrap());
+    }
+
+    #[test]
+    fn ls_refs_ignores_capabilities_and_other_args() {
+        let pkts = vec![
+            Pkt::Data(&b"command=ls-refs\n"[..]),
+            Pkt::Data(&b"agent=git/2.28.0\n"[..]),
+            Pkt::Delim,
+            Pkt::Data(&b"peel\n"[..]),
+            Pkt::Data(&b"ref-prefix refs/heads/\n"[..]),
+            Pkt::Flush,
+        ];
+        assert_eq!(
+            vec!["refs/heads/".to_owned()],
+            LsRefs::from_pkts(pkts).unwrap().prefixes
+        );
+
+        assert_matches!(
+            LsRefs::from_pkts(vec![Pkt::Data(&b"command=fetch\n"[..]), Pkt::Flush]),
+            Err(Error::UnknownCommand(cmd)) if cmd == "fetch"
+        );
+    }
+
+    #[test]
+    fn parse_ls_refs_response() {
+        let mut buf = Vec::new();
+        ls_refs(&[("refs/heads/master".to_owned(), oid(A))], &mut buf).unwrap();
+
+        let mut rest = &buf[..];
+        match pkt_line::read(&mut rest).unwrap() {
+            Pkt::Data(line) => assert_eq!(
+                ("refs/heads/master".to_owned(), oid(A)),
+                parse_ref(line).unwrap()
+            ),
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+        assert_eq!(Pkt::Flush, pkt_line::read(&mut rest).unwrap());
+
+        assert_eq!(
+            ("refs/tags/v1".to_owned(), oid(A)),
+            parse_ref(format!("{} refs/tags/v1 peeled:{}\n", A, B).as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn v2_capabilities() {
+        let mut buf = Vec::new();
+        advertise_v2(&mut buf).unwrap();
+
+        let mut rest = &buf[..];
+        let mut capabilities = Vec::new();
+        while let Pkt::Data(line) = pkt_line::read(&mut rest).unwrap() {
+            capabilities.push(line)
+        }
+        assert!(supports_ls_refs(capabilities));
+        assert!(!supports_ls_refs(vec![
+            &b"version 2\n"[..],
+            &b"fetch\n"[..]
+        ]));
+    }
+
+    #[test]
+    fn side_band_chunks() {
+        let data = vec![0; pkt_line::MAX_LEN];
+        let mut buf = Vec::new();
+        write_band(&mut buf, 1, &data).unwrap();
+
+        let mut rest = &buf[..];
+        match pkt_line::read(&mut rest).unwrap() {
+            Pkt::Data(first) => {
+                assert_eq!(first.len(), pkt_line::MAX_LEN - 4);
+                assert_eq!(first[0], 1);
+            },
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+        assert_eq!(
+            Pkt::Data(&[1, 0, 0, 0, 0, 0][..]),
+            pkt_line::read(&mut rest).unwrap()
+        );
+        assert!(rest.is_empty());
+    }
+
+    /// A linear history of `n` commits, returned newest first.
+    fn history(repo: &git2::Repository, n: usize) -> Vec<git2::Oid> {
+        let sig = git2::Signature::now("leboeuf", "leboeuf@acme.com").unwrap();
+        let mut commits: Vec<git2::Oid> = Vec::new();
+        for i in 0..n {
+            let blob = repo.blob(format!("{}", i).as_bytes()).unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("file", blob, 0o100_644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let parent = commits.last().map(|oid| repo.find_commit(*oid).unwrap());
+            let commit = repo
+                .commit(
+                    None,
+                    &sig,
+                    &sig,
+                    "msg",
+                    &tree,
+                    parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+                )
+                .unwrap();
+            commits.push(commit);
+        }
+        commits.reverse();
+        commits
+    }
+
+    #[test]
+    fn shallow_walk_depth() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let commits = history(&repo, 4);
+
+        let walk = shallow_walk(&repo, &commits[..1], &[], &[], 2).unwrap();
+        assert_eq!(
+            walk,
+            ShallowWalk {
+                commits: commits[..2].to_vec(),
+                shallow: vec![commits[1]],
+                unshallow: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn shallow_walk_deepen() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let commits = history(&repo, 4);
+
+        // The fetching side has the first two commits, and asks for one more
+        let walk = shallow_walk(&repo, &commits[..1], &commits[..2], &commits[1..2], 3).unwrap();
+        assert_eq!(
+            walk,
+            ShallowWalk {
+                commits: vec![commits[2]],
+                shallow: vec![commits[2]],
+                unshallow: vec![commits[1]],
+            }
+        );
+    }
+
+    #[test]
+    fn respond_filtered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let commits = history(&repo, 2);
+        let refs = vec![("refs/heads/master".to_owned(), commits[0])];
+
+        let request = Request::parse(&pkt_lines(&[
+            Some(&format!("want {}\n", commits[0])),
+            Some("filter blob:none\n"),
+            None,
+            Some("done\n"),
+        ]))
+        .unwrap();
+        let mut out = Vec::new();
+        respond(&repo, &refs, &request, |_| false, &mut out).unwrap();
+
+        let mut rest = &out[..];
+        assert_eq!(Pkt::Data(&b"NAK\n"[..]), pkt_line::read(&mut rest).unwrap());
+        // 2 commits and 2 trees, no blobs
+        assert_eq!(&rest[8..12], &[0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn respond_only_reachable_blobs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let commits = history(&repo, 2);
+        let refs = vec![("refs/namespaces/a/refs/heads/master".to_owned(), commits[0])];
+
+        // A blob only reachable from another namespace
+        let sig = git2::Signature::now("leboeuf", "leboeuf@acme.com").unwrap();
+        let secret = repo.blob(b"secret").unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("secret", secret, 0o100_644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        repo.commit(
+            Some("refs/namespaces/b/refs/heads/master"),
+            &sig,
+            &sig,
+            "msg",
+            &tree,
+            &[],
+        )
+        .unwrap();
+
+        let want = |blob: git2::Oid| {
+            Request::parse(&pkt_lines(&[
+                Some(&format!("want {}\n", blob)),
+                None,
+                Some("done\n"),
+            ]))
+            .unwrap()
+        };
+
+        // The blob of the first commit, reachable from the advertised tip
+        let own = repo
+            .find_commit(commits[1])
+            .unwrap()
+            .tree()
+            .unwrap()
+            .get_name("file")
+            .unwrap()
+            .id();
+        assert!(respond(&repo, &refs, &want(own), |_| false, &mut Vec::new()).is_ok());
+
+        assert_matches!(
+            respond(&repo, &refs, &want(secret), |_| false, &mut Vec::new()),
+            Err(Error::NotAdvertised(oid)) if oid == secret
+        );
+    }
+
+    #[test]
+    fn respond_acks_only_reachable_haves() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let commits = history(&repo, 2);
+        let refs = vec![("refs/namespaces/a/refs/heads/master".to_owned(), commits[0])];
+
+        // A commit only reachable from another namespace
+        let sig = git2::Signature::now("leboeuf", "leboeuf@acme.com").unwrap();
+        let tree = repo.find_commit(commits[1]).unwrap().tree().unwrap();
+        let other = repo
+            .commit(
+                Some("refs/namespaces/b/refs/heads/master"),
+                &sig,
+                &sig,
+                "other",
+                &tree,
+                &[],
+            )
+            .unwrap();
+
+        let request = Request::parse(&pkt_lines(&[
+            Some(&format!("want {}\n", commits[0])),
+            None,
+            Some(&format!("have {}\n", other)),
+            Some(&format!("have {}\n", commits[1])),
+            None,
+        ]))
+        .unwrap();
+        let mut out = Vec::new();
+        respond(&repo, &refs, &request, |_| false, &mut out).unwrap();
+
+        let mut rest = &out[..];
+        assert_eq!(
+            Pkt::Data(format!("ACK {} common\n", commits[1]).as_bytes()),
+            pkt_line::read(&mut rest).unwrap()
+        );
+        assert_eq!(Pkt::Data(&b"NAK\n"[..]), pkt_line::read(&mut rest).unwrap());
+        assert!(rest.is_empty());
+    }
+}
@@ -40,9 +40,6 @@ pub mod peer;
 pub mod signer;
 pub mod uri;
 
-#[cfg(test)]
-mod test;
-
 #[cfg(test)]
 #[macro_use]
 extern crate futures_await_test;
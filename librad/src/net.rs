@@ -15,12 +15,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod addrbook;
 pub mod connection;
 pub mod discovery;
 pub mod gossip;
 pub mod peer;
 pub mod protocol;
 pub mod quic;
+pub mod reputation;
+pub mod rpc;
 pub mod tls;
 pub mod upgrade;
 
@@ -0,0 +1,219 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Persistent record of the peers we know about.
+//!
+//! The passive view of the membership protocol only lives in memory. Saving it
+//! to an [`AddressBook`] allows a restarted node to rejoin the network through
+//! the peers it knew before, without being given seeds again (see
+//! [`crate::net::discovery::Persisted`]).
+
+use std::{
+    fs,
+    hash::Hash,
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use minicbor::{Decode, Encode};
+use thiserror::Error;
+
+use crate::{net::gossip::PeerInfo, paths::Paths};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Encode(#[from] minicbor::encode::Error<io::Error>),
+
+    #[error(transparent)]
+    Decode(#[from] minicbor::decode::Error),
+}
+
+/// The location of the address book within `paths`.
+pub fn default_path(paths: &Paths) -> PathBuf {
+    paths.net_dir().join("peers.cbor")
+}
+
+/// A known peer, along with when we last heard of it, and when we last
+/// talked to it.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+#[cbor(array)]
+pub struct Entry<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    #[n(0)]
+    pub info: PeerInfo<Addr>,
+    #[n(1)]
+    last_seen: u64,
+    #[n(2)]
+    last_success: Option<u64>,
+}
+
+impl<Addr> Entry<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    pub fn new(
+        info: PeerInfo<Addr>,
+        last_seen: SystemTime,
+        last_success: Option<SystemTime>,
+    ) -> Self {
+        Self {
+            info,
+            last_seen: to_secs(last_seen),
+            last_success: last_success.map(to_secs),
+        }
+    }
+
+    /// When we last heard of the peer, directly or through others.
+    pub fn last_seen(&self) -> SystemTime {
+        from_secs(self.last_seen)
+    }
+
+    /// When we last were connected to the peer, if ever.
+    pub fn last_success(&self) -> Option<SystemTime> {
+        self.last_success.map(from_secs)
+    }
+}
+
+// Timestamps are stored with second precision, which is plenty for judging
+// how stale an entry is.
+fn to_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+pub struct AddressBook<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    path: Option<PathBuf>,
+    entries: Vec<Entry<Addr>>,
+}
+
+impl<Addr> AddressBook<Addr>
+where
+    for<'de> Addr: Encode + Decode<'de> + Clone + PartialEq + Eq + Hash,
+{
+    /// An [`AddressBook`] which is not persisted.
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// An [`AddressBook`] persisted to the file at `path`.
+    ///
+    /// If the file exists, the entries are loaded from it.
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let entries = match fs::read(&path) {
+            Ok(cbor) => minicbor::decode(&cbor)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry<Addr>] {
+        &self.entries
+    }
+
+    /// Replace all entries, and write them to disk if we have a `path`.
+    pub fn save(&mut self, entries: Vec<Entry<Addr>>) -> Result<(), Error> {
+        self.entries = entries;
+
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, minicbor::to_vec(&self.entries)?)?;
+            fs::rename(tmp, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<Addr> Default for AddressBook<Addr>
+where
+    for<'de> Addr: Encode + Decode<'de> + Clone + PartialEq + Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tempfile::tempdir;
+
+    use crate::{keys::SecretKey, net::gossip::PeerAdvertisement, peer::PeerId};
+
+    #[test]
+    fn persist_entries() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("peers.cbor");
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let entries = vec![
+            Entry::new(
+                PeerInfo {
+                    peer_id: PeerId::from(SecretKey::new()),
+                    advertised_info: PeerAdvertisement::new(addr, 12345),
+                    seen_addrs: vec![addr].into_iter().collect(),
+                },
+                now,
+                Some(now),
+            ),
+            Entry::new(
+                PeerInfo {
+                    peer_id: PeerId::from(SecretKey::new()),
+                    advertised_info: PeerAdvertisement::new(addr, 12346),
+                    seen_addrs: Default::default(),
+                },
+                now,
+                None,
+            ),
+        ];
+
+        {
+            let mut book = AddressBook::load(path.clone()).unwrap();
+            assert!(book.entries().is_empty());
+            book.save(entries.clone()).unwrap();
+        }
+
+        let book = AddressBook::<IpAddr>::load(path).unwrap();
+        assert_eq!(book.entries(), entries.as_slice());
+        assert_eq!(book.entries()[0].last_success(), Some(now));
+        assert_eq!(book.entries()[1].last_success(), None);
+    }
+}
@@ -63,6 +63,7 @@ pub enum CloseReason {
     InternalError = 4,
     ServerShutdown = 5,
     InvalidUpgrade = 6,
+    Banned = 7,
 }
 
 impl CloseReason {
@@ -74,6 +75,7 @@ impl CloseReason {
             Self::InternalError => b"internal server error",
             Self::ServerShutdown => b"server shutdown",
             Self::InvalidUpgrade => b"invalid or unsupported protocol upgrade",
+            Self::Banned => b"banned",
         }
     }
 }
@@ -117,12 +119,77 @@ pub(crate) mod mock {
         }
     }
 
+    impl crate::git::p2p::transport::GitStream for MockStream {}
+
     impl Stream for MockStream {
-        type Read = ReadHalf<Endpoint>;
-        type Write = WriteHalf<Endpoint>;
+        type Read = MockHalf<ReadHalf<Endpoint>>;
+        type Write = MockHalf<WriteHalf<Endpoint>>;
 
         fn split(self) -> (Self::Read, Self::Write) {
-            AsyncReadExt::split(self.inner)
+            let (read, write) = AsyncReadExt::split(self.inner);
+            (
+                MockHalf {
+                    id: self.id.clone(),
+                    inner: read,
+                },
+                MockHalf {
+                    id: self.id,
+                    inner: write,
+                },
+            )
+        }
+    }
+
+    /// Either half of a split [`MockStream`], which still knows who's on the
+    /// other end.
+    pub struct MockHalf<H> {
+        id: PeerId,
+        inner: H,
+    }
+
+    impl<H> RemoteInfo for MockHalf<H> {
+        type Addr = PeerId;
+
+        fn remote_peer_id(&self) -> &PeerId {
+            &self.id
+        }
+
+        fn remote_addr(&self) -> Self::Addr {
+            self.id.clone()
+        }
+    }
+
+    impl<H: AsyncRead + Unpin> AsyncRead for MockHalf<H> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            AsyncRead::poll_read(Pin::new(&mut self.get_mut().inner), cx, buf)
+        }
+    }
+
+    impl<H: AsyncWrite + Unpin> AsyncWrite for MockHalf<H> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            AsyncWrite::poll_write(Pin::new(&mut self.get_mut().inner), cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_flush(Pin::new(&mut self.get_mut().inner), cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_close(Pin::new(&mut self.get_mut().inner), cx)
+        }
+    }
+
+    impl AsAddr<PeerId> for PeerId {
+        fn as_addr(&self) -> PeerId {
+            self.clone()
         }
     }
 
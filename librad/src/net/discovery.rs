@@ -18,16 +18,32 @@
 //! Discovery of peers during bootstrap, or out-of-band
 
 use std::{
+    cmp::Reverse,
     marker::PhantomData,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    vec,
 };
 
-use crate::peer::PeerId;
+use crate::{
+    net::addrbook::{self, AddressBook},
+    peer::PeerId,
+};
+
+pub mod local;
+pub use local::Local;
 
 pub trait Discovery {
     type Addr;
     type Stream: futures::Stream<Item = (PeerId, Vec<Self::Addr>)> + Send;
 
+    /// Tell the discovery mechanism who we are, and where we're listening.
+    ///
+    /// This is called once the local endpoint is bound, before
+    /// [`Discovery::discover`]. Mechanisms which announce the local peer to
+    /// others need this, the default implementation ignores it.
+    fn local_peer(&mut self, _peer_id: &PeerId, _listen_addr: Self::Addr) {}
+
     fn discover(self) -> Self::Stream;
 }
 
@@ -85,3 +101,52 @@ where
         self.into_stream()
     }
 }
+
+/// Discovery of the peers recorded in an [`AddressBook`].
+///
+/// Allows a node to rejoin the network via the peers it knew before a
+/// restart. Peers we have been connected to most recently are yielded first.
+pub struct Persisted {
+    peers: Vec<(PeerId, Vec<SocketAddr>)>,
+}
+
+impl Persisted {
+    pub fn new(book: &AddressBook<IpAddr>) -> Self {
+        let mut entries = book.entries().iter().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| Reverse((entry.last_success(), entry.last_seen())));
+
+        let peers = entries
+            .into_iter()
+            .map(|entry| {
+                let port = entry.info.advertised_info.listen_port;
+                let mut addrs = vec![SocketAddr::new(
+                    entry.info.advertised_info.listen_addr,
+                    port,
+                )];
+                for addr in &entry.info.seen_addrs {
+                    let addr = SocketAddr::new(*addr, port);
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr)
+                    }
+                }
+                (entry.info.peer_id.clone(), addrs)
+            })
+            .collect();
+
+        Self { peers }
+    }
+
+    /// Load the [`AddressBook`] at `path`, see [`addrbook::default_path`].
+    pub fn load(path: PathBuf) -> Result<Self, addrbook::Error> {
+        AddressBook::load(path).map(|book| Self::new(&book))
+    }
+}
+
+impl Discovery for Persisted {
+    type Addr = SocketAddr;
+    type Stream = futures::stream::Iter<vec::IntoIter<(PeerId, Vec<SocketAddr>)>>;
+
+    fn discover(self) -> Self::Stream {
+        futures::stream::iter(self.peers)
+    }
+}
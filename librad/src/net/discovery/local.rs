@@ -0,0 +1,309 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Discovery of peers on the local network segment via UDP multicast.
+//!
+//! Every peer periodically announces its [`PeerId`] and listen address to a
+//! multicast group, and yields the peers it hears from.
+//! Announcements are not authenticated -- this is fine, as the TLS handshake
+//! verifies the [`PeerId`] when connecting.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use futures::{
+    future,
+    stream::{self, BoxStream, StreamExt},
+};
+use futures_timer::Delay;
+use minicbor::{Decode, Encode};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{
+    udp::{RecvHalf, SendHalf},
+    UdpSocket,
+};
+
+use crate::{net::discovery::Discovery, peer::PeerId};
+
+/// Announcements are tiny, anything larger is garbage.
+const MAX_ANNOUNCEMENT_SIZE: usize = 512;
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Whether to announce ourselves and discover others at all.
+    pub enabled: bool,
+    /// The multicast group to announce to, and listen on.
+    pub group: SocketAddrV4,
+    /// The local interface to join the group on. If unspecified, the system
+    /// chooses one.
+    pub interface: Ipv4Addr,
+    /// Interval in which to announce ourselves.
+    pub interval: Duration,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 82, 76), 45454),
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+#[cbor(array)]
+struct Announcement {
+    #[n(0)]
+    peer_id: PeerId,
+    #[n(1)]
+    listen_addr: IpAddr,
+    #[n(2)]
+    listen_port: u16,
+}
+
+impl Announcement {
+    /// The address to connect to the announced peer on.
+    ///
+    /// If the peer listens on all interfaces, the address the announcement was
+    /// sent from is used.
+    fn addr(&self, from: IpAddr) -> SocketAddr {
+        let ip = if self.listen_addr.is_unspecified() {
+            from
+        } else {
+            self.listen_addr
+        };
+        SocketAddr::new(ip, self.listen_port)
+    }
+}
+
+/// Multicast [`Discovery`].
+///
+/// Needs to know the local peer (see [`Discovery::local_peer`]) before it can
+/// announce it. If it doesn't, or if [`Params::enabled`] is `false`, nothing is
+/// discovered.
+pub struct Local {
+    params: Params,
+    local: Option<Announcement>,
+}
+
+impl Local {
+    pub fn new(params: Params) -> Self {
+        Self {
+            params,
+            local: None,
+        }
+    }
+
+    fn bind(&self) -> io::Result<UdpSocket> {
+        let group = self.params.group;
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        // Allow multiple peers on the same machine to join the group
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()).into())?;
+        socket.join_multicast_v4(group.ip(), &self.params.interface)?;
+        socket.set_multicast_if_v4(&self.params.interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+
+        UdpSocket::from_std(socket.into_udp_socket())
+    }
+}
+
+impl Default for Local {
+    fn default() -> Self {
+        Self::new(Params::default())
+    }
+}
+
+impl Discovery for Local {
+    type Addr = SocketAddr;
+    type Stream = BoxStream<'static, (PeerId, Vec<SocketAddr>)>;
+
+    fn local_peer(&mut self, peer_id: &PeerId, listen_addr: SocketAddr) {
+        self.local = Some(Announcement {
+            peer_id: peer_id.clone(),
+            listen_addr: listen_addr.ip(),
+            listen_port: listen_addr.port(),
+        })
+    }
+
+    fn discover(self) -> Self::Stream {
+        if !self.params.enabled {
+            return stream::empty().boxed();
+        }
+
+        let local = match &self.local {
+            Some(local) => local.clone(),
+            None => {
+                tracing::warn!("Local peer unknown, not discovering peers on the local network");
+                return stream::empty().boxed();
+            },
+        };
+
+        let (recv, send) = match self.bind() {
+            Ok(socket) => socket.split(),
+            Err(e) => {
+                tracing::warn!("Failed to join multicast group: {}", e);
+                return stream::empty().boxed();
+            },
+        };
+
+        // Announcing yields nothing, it is merely driven by polling the
+        // discovered peers.
+        let announce = announce(send, self.params.group, self.params.interval, local.clone())
+            .filter_map(|()| future::ready(None::<(PeerId, Vec<SocketAddr>)>));
+        let discovered = receive(recv).filter_map(move |(ann, from)| {
+            future::ready(if ann.peer_id == local.peer_id {
+                None
+            } else {
+                let addr = ann.addr(from.ip());
+                Some((ann.peer_id, vec![addr]))
+            })
+        });
+
+        stream::select(discovered, announce).boxed()
+    }
+}
+
+fn announce(
+    send: SendHalf,
+    group: SocketAddrV4,
+    interval: Duration,
+    local: Announcement,
+) -> impl futures::Stream<Item = ()> {
+    let group = SocketAddr::V4(group);
+    stream::unfold((send, true), move |(mut send, first)| {
+        let local = local.clone();
+        async move {
+            if !first {
+                Delay::new(interval).await;
+            }
+            match minicbor::to_vec(&local) {
+                Ok(buf) => {
+                    if let Err(e) = send.send_to(&buf, &group).await {
+                        tracing::warn!("Failed to send local announcement: {}", e)
+                    }
+                },
+                Err(e) => tracing::warn!("Failed to encode local announcement: {}", e),
+            }
+            Some(((), (send, false)))
+        }
+    })
+}
+
+fn receive(recv: RecvHalf) -> impl futures::Stream<Item = (Announcement, SocketAddr)> {
+    stream::unfold(recv, |mut recv| async move {
+        let mut buf = [0; MAX_ANNOUNCEMENT_SIZE];
+        loop {
+            match recv.recv_from(&mut buf).await {
+                Ok((len, from)) => match minicbor::decode(&buf[..len]) {
+                    Ok(ann) => return Some(((ann, from), recv)),
+                    Err(e) => tracing::debug!("Invalid announcement from {}: {}", from, e),
+                },
+                Err(e) => {
+                    tracing::warn!("Failed to receive announcement: {}", e);
+                    return None;
+                },
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::keys::SecretKey;
+
+    #[test]
+    fn announced_addr() {
+        let peer_id = PeerId::from(SecretKey::new());
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 23));
+
+        let any = Announcement {
+            peer_id: peer_id.clone(),
+            listen_addr: Ipv4Addr::UNSPECIFIED.into(),
+            listen_port: 12345,
+        };
+        assert_eq!(any.addr(from), SocketAddr::new(from, 12345));
+
+        let loopback = Announcement {
+            peer_id,
+            listen_addr: Ipv4Addr::LOCALHOST.into(),
+            listen_port: 12345,
+        };
+        assert_eq!(
+            loopback.addr(from),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345)
+        );
+    }
+
+    #[tokio::test]
+    async fn discover_each_other() {
+        let params = Params {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 82, 77), 45455),
+            interval: Duration::from_millis(100),
+            ..Params::default()
+        };
+
+        let peers = (0..3)
+            .map(|i| {
+                let peer_id = PeerId::from(SecretKey::new());
+                let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345 + i);
+                (peer_id, addr)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // All peers need to be polled concurrently, as they only announce
+        // themselves while being polled.
+        let discovered = peers.iter().map(|(peer_id, addr)| {
+            let mut disco = Local::new(params.clone());
+            disco.local_peer(peer_id, *addr);
+
+            let expected = peers
+                .iter()
+                .filter(|(other, _)| *other != peer_id)
+                .map(|(other, addr)| (other.clone(), vec![*addr]))
+                .collect::<HashMap<_, _>>();
+            disco
+                .discover()
+                .scan(HashMap::new(), |discovered, (other, addrs)| {
+                    discovered.insert(other, addrs);
+                    future::ready(Some(discovered.clone()))
+                })
+                .filter(move |discovered| future::ready(*discovered == expected))
+                .into_future()
+        });
+        let discovered =
+            tokio::time::timeout(Duration::from_secs(10), future::join_all(discovered))
+                .await
+                .expect("peers were not discovered in time");
+
+        assert!(discovered
+            .into_iter()
+            .all(|(discovered, _)| discovered.is_some()))
+    }
+}
@@ -22,7 +22,7 @@
 //! [HyParView]: http://asc.di.fct.unl.pt/~jleitao/pdf/dsn07-leitao.pdf
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashSet,
     fmt::Debug,
     hash::Hash,
     iter,
@@ -32,7 +32,7 @@ use std::{
         atomic::{self, AtomicUsize},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::{
@@ -45,27 +45,41 @@ use futures::{
 use futures_codec::{Framed, FramedRead, FramedWrite};
 use futures_timer::Delay;
 use governor::{Quota, RateLimiter};
+use keystore::sign::Signer as _;
 use minicbor::{Decode, Encode};
-use rand::{seq::IteratorRandom, Rng};
 use rand_pcg::Pcg64Mcg;
 use tracing_futures::Instrument;
 
 use crate::{
     internal::channel::Fanout,
+    keys::Signature,
     net::{
-        codec::CborCodec,
+        addrbook::AddressBook,
+        codec::{CborCodec, CborCodecError},
         connection::{self, AsAddr, RemoteInfo},
-        gossip::error::Error,
+        gossip::{
+            error::Error,
+            peers::{ConnectedPeers, KnownPeers, Provenance},
+            seen::Seen,
+            tree::{Messages, Status},
+        },
+        reputation::Misbehaviour,
         upgrade::{self, Upgraded},
     },
     peer::PeerId,
+    signer::BoxedSigner,
 };
 
 pub mod error;
+pub mod interest;
+mod peers;
 pub mod rpc;
+mod seen;
 pub mod storage;
+mod tree;
 pub mod types;
 
+pub use interest::Interests;
 pub use rpc::*;
 pub use storage::*;
 pub use types::*;
@@ -93,6 +107,8 @@ where
         to: PeerInfo<Addr>,
     },
     Disconnect(PeerId),
+    /// The peer misbehaved, and should be penalised for it.
+    Penalise(PeerId, Misbehaviour),
 }
 
 #[derive(Clone, Debug)]
@@ -134,6 +150,29 @@ pub struct MembershipParams {
     pub shuffle_interval: Duration,
     /// Interval in which to attempt to promote a passive peer.
     pub promote_interval: Duration,
+    /// Maximum number of passive peers to remember.
+    pub max_passive: usize,
+    /// Maximum number of addresses to remember per passive peer.
+    pub max_addrs_per_peer: usize,
+    /// Maximum number of received messages to remember, eg. for answering a
+    /// [`Gossip::Graft`].
+    pub max_messages: usize,
+    /// How long to wait for a message announced via [`Gossip::IHave`] before
+    /// grafting onto the announcing peer.
+    pub graft_timeout: Duration,
+    /// How long to remember having seen a [`Gossip::Have`] or
+    /// [`Gossip::Want`], so as to not handle it again.
+    pub seen_expiry: Duration,
+    /// The initial `ttl` of [`Gossip`] messages originating from us.
+    pub max_hops: usize,
+    /// Whether to tell peers which gossip we're interested in (see
+    /// [`LocalStorage::interests`]), so they don't send us anything else.
+    ///
+    /// Nodes which replicate everything, such as seeds, should not.
+    pub advertise_interests: bool,
+    /// The number of peers to send [`Gossip`] to regardless of their
+    /// [`Interests`], in addition to the ones which may be interested.
+    pub interest_fanout: usize,
 }
 
 impl Default for MembershipParams {
@@ -144,133 +183,18 @@ impl Default for MembershipParams {
             shuffle_sample_size: 7,
             shuffle_interval: Duration::from_secs(30),
             promote_interval: Duration::from_secs(20),
+            max_passive: 128,
+            max_addrs_per_peer: 8,
+            max_messages: 1024,
+            graft_timeout: Duration::from_secs(3),
+            seen_expiry: Duration::from_secs(5 * 60),
+            max_hops: 8,
+            advertise_interests: true,
+            interest_fanout: 2,
         }
     }
 }
 
-/// Placeholder for a datastructure representing the currently connected-to
-/// peers
-///
-/// The number of peers is bounded -- when `insert`ing into an already full
-/// `ConnectedPeers`, an existing connection is chosen at random, its write
-/// stream is closed, and the corresponding `PeerId` is returned for upstream
-/// connection management.
-///
-/// The random choice should be replaced by a weighted selection, which takes
-/// metrics such as uptime, bandwidth, etc. into account.
-#[derive(Clone, Default)]
-struct ConnectedPeers<S, R> {
-    max_peers: usize,
-    rng: R,
-    peers: HashMap<PeerId, S>,
-}
-
-impl<S, R> ConnectedPeers<S, R>
-where
-    S: Unpin,
-    R: Rng,
-{
-    fn new(max_peers: usize, rng: R) -> Self {
-        Self {
-            max_peers,
-            rng,
-            peers: HashMap::default(),
-        }
-    }
-
-    fn insert(&mut self, peer_id: PeerId, sink: S) -> Option<(PeerId, S)> {
-        if !self.peers.contains_key(&peer_id) && self.peers.len() + 1 > self.max_peers {
-            self.peers.insert(peer_id, sink);
-
-            let eject = self
-                .peers
-                .keys()
-                .choose(&mut self.rng)
-                .expect("Iterator must contain at least 1 element, as per the if condition. qed")
-                .clone();
-            self.remove(&eject)
-        } else {
-            self.peers
-                .insert(peer_id.clone(), sink)
-                .map(|s| (peer_id, s))
-        }
-    }
-
-    fn remove(&mut self, peer_id: &PeerId) -> Option<(PeerId, S)> {
-        self.peers.remove(peer_id).map(|s| (peer_id.to_owned(), s))
-    }
-
-    fn random(&mut self) -> Option<(&PeerId, &mut S)> {
-        self.peers.iter_mut().choose(&mut self.rng)
-    }
-
-    fn contains(&self, peer_id: &PeerId) -> bool {
-        self.peers.contains_key(peer_id)
-    }
-
-    fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut S> {
-        self.peers.get_mut(peer_id)
-    }
-
-    fn iter_mut(&mut self) -> impl Iterator<Item = (&PeerId, &mut S)> {
-        self.peers.iter_mut()
-    }
-
-    fn len(&self) -> usize {
-        self.peers.len()
-    }
-}
-
-/// Placeholder for a datastructure with the following properties:
-///
-/// * Keeps a bounded number of `(PeerId, [SocketAddr])` pairs
-/// * The list of addresses is itself bounded, and keeps track of the `n` most
-///   recently seen "good" IP/port tuples
-/// * The list is prioritised by recently-seen, and treats peer info relayed by
-///   other peers (`Shuffle`d) with the least priority
-#[derive(Clone, Default)]
-struct KnownPeers<Addr, Rng>
-where
-    Addr: Clone + PartialEq + Eq + Hash,
-{
-    peers: HashMap<PeerId, PeerInfo<Addr>>,
-    rng: Rng,
-}
-
-impl<Addr, R> KnownPeers<Addr, R>
-where
-    Addr: Clone + PartialEq + Eq + Hash,
-    R: Rng,
-{
-    fn new(rng: R) -> Self {
-        Self {
-            peers: HashMap::default(),
-            rng,
-        }
-    }
-
-    fn insert<I: IntoIterator<Item = PeerInfo<Addr>>>(&mut self, peers: I) {
-        for info in peers {
-            let entry = self
-                .peers
-                .entry(info.peer_id.clone())
-                .or_insert_with(|| info.clone());
-            entry.seen_addrs = entry.seen_addrs.union(&info.seen_addrs).cloned().collect();
-        }
-    }
-
-    fn random(&mut self) -> Option<PeerInfo<Addr>> {
-        self.peers.values().cloned().choose(&mut self.rng)
-    }
-
-    fn sample(&mut self, n: usize) -> Vec<PeerInfo<Addr>> {
-        self.peers
-            .values()
-            .cloned()
-            .choose_multiple(&mut self.rng, n)
-    }
-}
-
 pub type Codec<A, P> = CborCodec<Rpc<A, P>, Rpc<A, P>>;
 type WriteStream<W, A, P> = FramedWrite<W, Codec<A, P>>;
 type ConnectedPeersImpl<W, A, P, R> = ConnectedPeers<WriteStream<W, A, P>, R>;
@@ -286,6 +210,7 @@ where
 {
     local_id: PeerId,
     local_ad: PeerAdvertisement<Addr>,
+    signer: BoxedSigner,
 
     mparams: MembershipParams,
 
@@ -296,6 +221,9 @@ where
 
     connected_peers: Arc<Mutex<ConnectedPeersImpl<W, Addr, Broadcast, Pcg64Mcg>>>,
     known_peers: Arc<Mutex<KnownPeers<Addr, Pcg64Mcg>>>,
+    addr_book: Arc<Mutex<AddressBook<Addr>>>,
+    messages: Arc<Mutex<Messages<Gossip<Addr, Broadcast>>>>,
+    seen: Arc<Mutex<Seen>>,
 
     ref_count: Arc<AtomicUsize>,
 
@@ -324,12 +252,16 @@ where
         Self {
             local_id: self.local_id.clone(),
             local_ad: self.local_ad.clone(),
+            signer: self.signer.clone(),
             mparams: self.mparams.clone(),
             storage: self.storage.clone(),
             storage_error_lim: self.storage_error_lim.clone(),
             subscribers: self.subscribers.clone(),
             connected_peers: self.connected_peers.clone(),
             known_peers: self.known_peers.clone(),
+            addr_book: self.addr_book.clone(),
+            messages: self.messages.clone(),
+            seen: self.seen.clone(),
             ref_count: self.ref_count.clone(),
             _marker: self._marker,
         }
@@ -353,8 +285,10 @@ where
     pub fn new(
         local_id: &PeerId,
         local_ad: PeerAdvertisement<Addr>,
+        signer: BoxedSigner,
         mparams: MembershipParams,
         storage: Storage,
+        addr_book: AddressBook<Addr>,
     ) -> Self {
         let span = tracing::trace_span!("Protocol", local.id = %local_id);
         let _guard = span.enter();
@@ -364,7 +298,18 @@ where
             mparams.max_active,
             prng.clone(),
         )));
-        let known_peers = Arc::new(Mutex::new(KnownPeers::new(prng)));
+        let known_peers = {
+            let mut known_peers =
+                KnownPeers::new(mparams.max_passive, mparams.max_addrs_per_peer, prng);
+            known_peers.restore(addr_book.entries().iter().cloned());
+            Arc::new(Mutex::new(known_peers))
+        };
+        let addr_book = Arc::new(Mutex::new(addr_book));
+        let messages = Arc::new(Mutex::new(Messages::new(mparams.max_messages)));
+        let seen = Arc::new(Mutex::new(Seen::new(
+            mparams.max_messages,
+            mparams.seen_expiry,
+        )));
 
         let storage_error_lim = Arc::new(RateLimiter::direct(Quota::per_second(unsafe {
             NonZeroU32::new_unchecked(5)
@@ -373,6 +318,7 @@ where
         let this = Self {
             local_id: local_id.clone(),
             local_ad,
+            signer,
 
             mparams,
 
@@ -383,6 +329,9 @@ where
 
             connected_peers,
             known_peers,
+            addr_book,
+            messages,
+            seen,
 
             ref_count: Arc::new(AtomicUsize::new(0)),
 
@@ -405,6 +354,9 @@ where
                     }
                     Delay::new(shuffle.mparams.shuffle_interval).await;
                     shuffle.shuffle().await;
+                    shuffle.save_known().await;
+                    // Our interests change as we replicate more (or less)
+                    shuffle.send_interests(None).await;
                 }
             });
             tokio::spawn(async move {
@@ -426,6 +378,15 @@ where
         &self.local_id
     }
 
+    /// The [`PeerAdvertisement`] we send to other peers.
+    pub fn local_advertisement(&self) -> &PeerAdvertisement<Addr> {
+        &self.local_ad
+    }
+
+    pub(super) fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
     pub async fn is_connected(&self) -> bool {
         self.connected_peers.lock().await.len() > 0
     }
@@ -433,29 +394,52 @@ where
     pub async fn announce(&self, have: Broadcast) {
         let span = tracing::trace_span!("Protocol::announce", local.id = %self.local_id);
 
-        self.broadcast(
-            Gossip::Have {
-                origin: self.local_peer_info(),
-                val: have,
-            },
-            None,
-        )
+        async move {
+            let id = MessageId::of(&have).map_err(|e| Error::Cbor(e.into()))?;
+            let have = self.local_have(have).await?;
+            self.messages
+                .lock()
+                .await
+                .received(id.clone(), have.clone(), Status::Delivered);
+            self.push(id, have, None).await;
+
+            Ok::<_, Error>(())
+        }
         .instrument(span)
         .await
+        .unwrap_or_else(|e| tracing::warn!("Failed to announce: {}", e))
     }
 
     pub async fn query(&self, want: Broadcast) {
         let span = tracing::trace_span!("Protocol::query", local.id = %self.local_id);
 
-        self.broadcast(
-            Gossip::Want {
-                origin: self.local_peer_info(),
-                val: want,
-            },
-            None,
-        )
+        async move {
+            self.see(Kind::Want, &self.local_id, &want).await?;
+            let want = self.local_want(want).await?;
+            self.broadcast(want, None).await;
+
+            Ok::<_, Error>(())
+        }
         .instrument(span)
         .await
+        .unwrap_or_else(|e| tracing::warn!("Failed to query: {}", e))
+    }
+
+    /// The [`Capability`]s `peer_id` advertised, as far as we know.
+    ///
+    /// This is empty if we know nothing about `peer_id`, in which case only
+    /// baseline features should be used with it.
+    pub async fn capabilities(&self, peer_id: &PeerId) -> HashSet<Capability> {
+        if let Some(conn) = self.connected_peers.lock().await.get_mut(peer_id) {
+            return conn.capabilities().clone();
+        }
+
+        self.known_peers
+            .lock()
+            .await
+            .advertisement(peer_id)
+            .map(|ad| ad.capabilities.clone())
+            .unwrap_or_default()
     }
 
     pub(super) async fn subscribe(
@@ -525,22 +509,46 @@ where
                     .emit(ProtocolEvent::Control(Control::Disconnect(ejected_peer)))
                     .await
             }
+            let advertised = {
+                let mut known_peers = self.known_peers.lock().await;
+                known_peers.connected(&remote_id);
+                known_peers
+                    .advertisement(&remote_id)
+                    .map(|ad| ad.capabilities.clone())
+            };
+            if let Some(capabilities) = advertised {
+                self.add_capabilities(&remote_id, capabilities).await
+            }
 
             while let Some(recvd) = recv.next().await {
                 match recvd {
-                    Ok(rpc) => match rpc {
-                        Rpc::Membership(msg) => {
-                            self.handle_membership(&remote_id, recv.remote_addr().as_addr(), msg)
+                    Ok(rpc) => {
+                        self.connected_peers.lock().await.received(&remote_id);
+                        match rpc {
+                            Rpc::Membership(msg) => {
+                                self.handle_membership(
+                                    &remote_id,
+                                    recv.remote_addr().as_addr(),
+                                    msg,
+                                )
                                 .await?
-                        },
+                            },
 
-                        Rpc::Gossip(msg) => self.handle_gossip(&remote_id, msg).await?,
+                            Rpc::Gossip(msg) => self.handle_gossip(&remote_id, msg).await?,
+                        }
                     },
 
-                    Err(e) => {
+                    Err(CborCodecError::Io(e)) => {
                         tracing::warn!("Recv error: {:?}", e);
                         break;
                     },
+
+                    // The remote sent garbage
+                    Err(e) => {
+                        tracing::warn!("Recv error: {:?}", e);
+                        self.remove_connected(&remote_id).await;
+                        return Err(e.into());
+                    },
                 }
             }
 
@@ -576,7 +584,11 @@ where
                     peer.info.addrs = ?peer_info.seen_addrs,
                 );
 
-                self.add_known(iter::once(peer_info.clone())).await;
+                self.add_capabilities(remote_id, ad.capabilities.iter().copied())
+                    .await;
+                self.add_known(iter::once(peer_info.clone()), Provenance::Direct)
+                    .await;
+                self.send_interests(remote_id).await;
                 self.broadcast(
                     ForwardJoin {
                         joined: peer_info,
@@ -609,7 +621,11 @@ where
 
             Neighbour(ad) => {
                 tracing::trace!(msg = "Neighbour advertisement", peer.info.advertised = ?ad);
-                self.add_known(iter::once(make_peer_info(ad.clone()))).await;
+                self.add_capabilities(remote_id, ad.capabilities.iter().copied())
+                    .await;
+                self.add_known(iter::once(make_peer_info(ad.clone())), Provenance::Direct)
+                    .await;
+                self.send_interests(remote_id).await;
 
                 self.subscribers
                     .emit(ProtocolEvent::Membership(MembershipInfo::Neighbour(ad)))
@@ -620,7 +636,7 @@ where
                 tracing::trace!(msg = "Shuffle", origin = ?origin, peer.neighbours = ?peers, peer.ttl = ttl);
                 // We're supposed to only remember shuffled peers at
                 // the end of the random walk. Do it anyway for now.
-                self.add_known(peers.clone()).await;
+                self.add_known(peers.clone(), Provenance::Relayed).await;
 
                 if ttl == 0 {
                     let sample = self.sample_known().await;
@@ -647,7 +663,24 @@ where
 
             ShuffleReply { peers } => {
                 tracing::trace!(msg = "ShuffleReply", peer.neighbours = ?peers);
-                self.add_known(peers).await
+                self.add_known(peers, Provenance::Relayed).await
+            },
+
+            Interests(interests) => {
+                tracing::trace!(msg = "Interests");
+                let first = match self.connected_peers.lock().await.get_mut(remote_id) {
+                    Some(conn) => {
+                        conn.add_capabilities(Some(Capability::Interests));
+                        let first = !conn.has_interests();
+                        conn.set_interests(interests);
+                        first
+                    },
+                    None => false,
+                };
+                // The peer may not know yet that we support interests, either
+                if first {
+                    self.send_interests(remote_id).await
+                }
             },
         }
 
@@ -664,47 +697,121 @@ where
         let span = tracing::trace_span!("Protocol::handle_gossip");
 
         async move {
+            if !msg.verify().map_err(|e| Error::Cbor(e.into()))? {
+                tracing::warn!(peer = %remote_id, "Dropping message with invalid signature");
+                self.misbehaved(remote_id, Misbehaviour::InvalidSignature)
+                    .await;
+                return Ok(());
+            }
+
             match msg {
-                Have { origin, val } => {
-                    tracing::trace!(origin.peer.id = %origin.peer_id, origin.value=?val, "Have");
-
-                    self.subscribers
-                        .emit(ProtocolEvent::Info(Info::Has(Has {
-                            provider: origin.clone(),
-                            val: val.clone(),
-                        })))
-                        .await;
+                Have {
+                    origin,
+                    val,
+                    ttl,
+                    signature,
+                } => {
+                    tracing::trace!(
+                        origin.peer.id = %origin.peer_id,
+                        origin.value = ?val,
+                        ttl = ttl,
+                        "Have"
+                    );
+
+                    if !self.storage.may_announce(&origin.peer_id, &val) {
+                        tracing::warn!(
+                            peer = %remote_id,
+                            origin.peer.id = %origin.peer_id,
+                            "Dropping Have making claims on behalf of another peer"
+                        );
+                        self.misbehaved(remote_id, Misbehaviour::InvalidSignature)
+                            .await;
+                        return Ok(());
+                    }
+
+                    let fresh = self.see(Kind::Have, &origin.peer_id, &val).await?;
+                    if fresh {
+                        self.subscribers
+                            .emit(ProtocolEvent::Info(Info::Has(Has {
+                                provider: origin.clone(),
+                                val: val.clone(),
+                            })))
+                            .await;
+                    }
+
+                    let id = MessageId::of(&val).map_err(|e| Error::Cbor(e.into()))?;
+                    let status = self.messages.lock().await.status(&id);
+                    // We've seen this before, so the link to `remote_id` is
+                    // redundant in the broadcast tree.
+                    if status == Some(Status::Delivered) {
+                        tracing::trace!(message.id = ?id, "Duplicate Have, pruning");
+                        self.prune(remote_id).await;
+                        return Ok(());
+                    }
+                    if !fresh {
+                        tracing::trace!(message.id = ?id, "Duplicate Have");
+                        return Ok(());
+                    }
+                    // If the message is pending, we already relayed it
+                    let relay = status.is_none() && ttl > 0;
+                    let ttl = ttl.saturating_sub(1);
 
-                    match self.storage.put(&remote_id, val.clone()).await {
+                    let res = self.storage.put(&remote_id, val.clone()).await;
+                    match res {
                         // `val` was new, and is now fetched to local storage.
                         // Let connected peers know they can now fetch it from
-                        // us.
+                        // us. The announcement stays signed by its origin, as
+                        // we can't vouch for `val` ourselves, but its `ttl` is
+                        // reset.
                         PutResult::Applied => {
                             tracing::info!(value = ?val, "Announcing applied value");
 
-                            self.broadcast(
-                                Have {
-                                    origin: self.local_peer_info(),
-                                    val,
-                                },
-                                remote_id,
-                            )
-                            .await
+                            let have = Have {
+                                origin,
+                                val,
+                                ttl: self.mparams.max_hops,
+                                signature,
+                            };
+                            self.messages.lock().await.received(
+                                id.clone(),
+                                have.clone(),
+                                Status::Delivered,
+                            );
+                            if status.is_none() {
+                                self.push(id, have, remote_id).await
+                            }
                         },
 
                         // Meh. Request retransmission.
-                        PutResult::Error => {
+                        PutResult::Error | PutResult::Invalid => {
                             tracing::info!(value = ?val, "Error applying value");
 
+                            if let PutResult::Invalid = res {
+                                self.subscribers
+                                    .emit(ProtocolEvent::Control(Control::Penalise(
+                                        remote_id.clone(),
+                                        Misbehaviour::Fetch,
+                                    )))
+                                    .await;
+                            }
+
                             // Forward in any case
-                            self.broadcast(
-                                Have {
+                            if status.is_none() {
+                                let have = Have {
                                     origin,
                                     val: val.clone(),
-                                },
-                                remote_id,
-                            )
-                            .await;
+                                    ttl,
+                                    signature,
+                                };
+                                self.messages.lock().await.received(
+                                    id.clone(),
+                                    have.clone(),
+                                    Status::Pending,
+                                );
+                                if relay {
+                                    self.push(id, have, remote_id).await;
+                                }
+                            }
                             // Exit if we're getting too many errors
                             self.storage_error_lim
                                 .check()
@@ -712,45 +819,129 @@ where
                             // Request retransmission
                             // This could be optimised be enqueuing `val`s and
                             // sending them in batch later (deduplicating)
-                            self.broadcast(
-                                Want {
-                                    origin: self.local_peer_info(),
-                                    val,
-                                },
-                                None,
-                            )
-                            .await
+                            self.see(Kind::Want, &self.local_id, &val).await?;
+                            let want = self.local_want(val).await?;
+                            self.broadcast(want, None).await
                         },
 
                         // Not interesting, forward to others
                         PutResult::Uninteresting => {
                             tracing::info!(value = ?val, "Value is uninteresting");
 
-                            self.broadcast(Have { origin, val }, remote_id).await
+                            let have = Have {
+                                origin,
+                                val,
+                                ttl,
+                                signature,
+                            };
+                            self.messages.lock().await.received(
+                                id.clone(),
+                                have.clone(),
+                                Status::Delivered,
+                            );
+                            if relay {
+                                self.push(id, have, remote_id).await
+                            }
                         },
 
                         // We are up-to-date, don't do anything
                         PutResult::Stale => {
                             tracing::info!(value = ?val, "Value is up to date");
+
+                            self.messages.lock().await.received(
+                                id,
+                                Have {
+                                    origin,
+                                    val,
+                                    ttl,
+                                    signature,
+                                },
+                                Status::Delivered,
+                            );
                         },
                     }
                 },
 
-                Want { origin, val } => {
-                    tracing::trace!(origin.peer.id = %origin.peer_id, origin.value = ?val, "Want");
+                Want {
+                    origin,
+                    val,
+                    ttl,
+                    signature,
+                } => {
+                    tracing::trace!(
+                        origin.peer.id = %origin.peer_id,
+                        origin.value = ?val,
+                        ttl = ttl,
+                        "Want"
+                    );
+
+                    if !self.see(Kind::Want, &origin.peer_id, &val).await? {
+                        tracing::trace!("Duplicate Want");
+                        return Ok(());
+                    }
 
                     let have = self.storage.ask(val.clone()).await;
                     if have {
-                        self.reply(
-                            &remote_id.clone(),
-                            Have {
-                                origin: self.local_peer_info(),
+                        // If we can't vouch for `val` ourselves, reply with
+                        // the announcement we received it by, if we still
+                        // have it
+                        let have = if self.storage.may_announce(&self.local_id, &val) {
+                            Some(self.local_have(val).await?)
+                        } else {
+                            let id = MessageId::of(&val).map_err(|e| Error::Cbor(e.into()))?;
+                            self.messages.lock().await.get(&id).cloned()
+                        };
+                        if let Some(have) = have {
+                            self.reply(remote_id, have).await
+                        }
+                    } else if ttl > 0 {
+                        self.broadcast(
+                            Want {
+                                origin,
                                 val,
+                                ttl: ttl - 1,
+                                signature,
                             },
+                            remote_id,
                         )
                         .await
-                    } else {
-                        self.broadcast(Want { origin, val }, remote_id).await
+                    }
+                },
+
+                IHave { id } => {
+                    tracing::trace!(message.id = ?id, "IHave");
+
+                    self.add_capabilities(remote_id, Some(Capability::BroadcastTree))
+                        .await;
+                    let missing = self
+                        .messages
+                        .lock()
+                        .await
+                        .announced(id.clone(), remote_id.clone());
+                    if missing {
+                        self.await_missing(id)
+                    }
+                },
+
+                Prune => {
+                    tracing::trace!("Prune");
+
+                    if let Some(conn) = self.connected_peers.lock().await.get_mut(remote_id) {
+                        conn.add_capabilities(Some(Capability::BroadcastTree));
+                        conn.set_eager(false)
+                    }
+                },
+
+                Graft { id } => {
+                    tracing::trace!(message.id = ?id, "Graft");
+
+                    if let Some(conn) = self.connected_peers.lock().await.get_mut(remote_id) {
+                        conn.add_capabilities(Some(Capability::BroadcastTree));
+                        conn.set_eager(true)
+                    }
+                    let have = self.messages.lock().await.get(&id).cloned();
+                    if let Some(have) = have {
+                        self.reply(remote_id, have).await
                     }
                 },
             }
@@ -761,6 +952,15 @@ where
         .await
     }
 
+    async fn add_capabilities<I>(&self, peer_id: &PeerId, capabilities: I)
+    where
+        I: IntoIterator<Item = Capability>,
+    {
+        if let Some(conn) = self.connected_peers.lock().await.get_mut(peer_id) {
+            conn.add_capabilities(capabilities)
+        }
+    }
+
     async fn add_connected(
         &self,
         peer_id: PeerId,
@@ -775,14 +975,36 @@ where
         }
     }
 
-    async fn add_known<I: IntoIterator<Item = PeerInfo<Addr>>>(&self, peers: I) {
+    async fn add_known<I>(&self, peers: I, provenance: Provenance)
+    where
+        I: IntoIterator<Item = PeerInfo<Addr>>,
+    {
         self.known_peers.lock().await.insert(
             peers
                 .into_iter()
                 .filter(|info| &info.peer_id != self.peer_id()),
+            provenance,
         )
     }
 
+    /// Record that an attempt to connect to `peer_id` has failed.
+    ///
+    /// Peers which repeatedly fail are less likely to be promoted, and are
+    /// eventually forgotten.
+    pub(super) async fn connection_failed(&self, peer_id: &PeerId) {
+        self.known_peers.lock().await.failed(peer_id)
+    }
+
+    /// Persist the known peers to the [`AddressBook`].
+    async fn save_known(&self) {
+        let entries = self.known_peers.lock().await.entries();
+        self.addr_book
+            .lock()
+            .await
+            .save(entries)
+            .unwrap_or_else(|e| tracing::warn!("Failed to save known peers: {}", e))
+    }
+
     async fn sample_known(&self) -> Vec<PeerInfo<Addr>> {
         self.known_peers
             .lock()
@@ -844,20 +1066,165 @@ where
         }
     }
 
-    /// Send an [`Rpc`] to all currently connected peers, except `excluding`
+    /// A signed `Have` of `val`, originating from us.
+    async fn local_have(&self, val: Broadcast) -> Result<Gossip<Addr, Broadcast>, Error> {
+        let signature = self.sign(Kind::Have, &val).await?;
+        Ok(Gossip::Have {
+            origin: self.local_peer_info(),
+            val,
+            ttl: self.mparams.max_hops,
+            signature,
+        })
+    }
+
+    /// A signed `Want` of `val`, originating from us.
+    async fn local_want(&self, val: Broadcast) -> Result<Gossip<Addr, Broadcast>, Error> {
+        let signature = self.sign(Kind::Want, &val).await?;
+        Ok(Gossip::Want {
+            origin: self.local_peer_info(),
+            val,
+            ttl: self.mparams.max_hops,
+            signature,
+        })
+    }
+
+    async fn sign(&self, kind: Kind, val: &Broadcast) -> Result<Signature, Error> {
+        let payload =
+            signed_payload(kind, &self.local_id, val).map_err(|e| Error::Cbor(e.into()))?;
+        let signature = self.signer.sign(&payload).await?;
+        Ok(signature.into())
+    }
+
+    /// Record that `peer_id` sent us a message it shouldn't have.
+    async fn misbehaved(&self, peer_id: &PeerId, misbehaviour: Misbehaviour) {
+        if let Some(conn) = self.connected_peers.lock().await.get_mut(peer_id) {
+            conn.misbehaved()
+        }
+        self.subscribers
+            .emit(ProtocolEvent::Control(Control::Penalise(
+                peer_id.clone(),
+                misbehaviour,
+            )))
+            .await
+    }
+
+    /// Record a `Have` or `Want` (denoted by `kind`) in the seen-set.
+    ///
+    /// Returns `false` if it was seen before.
+    async fn see(&self, kind: Kind, origin: &PeerId, val: &Broadcast) -> Result<bool, Error> {
+        let id = MessageId::of(&(kind.as_str(), origin, val)).map_err(|e| Error::Cbor(e.into()))?;
+        Ok(self.seen.lock().await.insert(id, Instant::now()))
+    }
+
+    /// Wait for a message announced by a lazy peer to arrive.
+    ///
+    /// If it doesn't arrive within `graft_timeout`, `Graft` onto the peer
+    /// which announced it first, making it eager. Repeat with the next
+    /// announcer until either the message arrives or we run out of announcers.
+    fn await_missing(&self, id: MessageId) {
+        let this = self.clone();
+        let span = tracing::trace_span!("Protocol::await_missing", message.id = ?id);
+        tokio::spawn(
+            async move {
+                loop {
+                    Delay::new(this.mparams.graft_timeout).await;
+                    let next = this.messages.lock().await.next_announcer(&id);
+                    match next {
+                        None => break,
+                        Some(peer) => {
+                            tracing::trace!(peer = %peer, "Grafting");
+                            if let Some(conn) = this.connected_peers.lock().await.get_mut(&peer) {
+                                conn.set_eager(true)
+                            }
+                            this.reply(&peer, Gossip::Graft { id: id.clone() }).await
+                        },
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Remove `peer` from the broadcast tree.
+    ///
+    /// Peers which don't support [`Capability::BroadcastTree`] always remain
+    /// in the tree.
+    async fn prune(&self, peer: &PeerId) {
+        match self.connected_peers.lock().await.get_mut(peer) {
+            Some(conn) if conn.supports(&Capability::BroadcastTree) => conn.set_eager(false),
+            _ => return,
+        }
+        self.reply(peer, Gossip::Prune).await
+    }
+
+    /// Disseminate a `Have` along the broadcast tree.
+    ///
+    /// The message is sent to eager peers, while lazy peers only receive an
+    /// `IHave`. Peers which don't support [`Capability::BroadcastTree`] are
+    /// treated as eager.
+    async fn push<'a, X>(&self, id: MessageId, have: Gossip<Addr, Broadcast>, excluding: X)
+    where
+        X: Into<Option<&'a PeerId>>,
+    {
+        let have: Rpc<Addr, Broadcast> = have.into();
+        let ihave: Rpc<Addr, Broadcast> = Gossip::IHave { id }.into();
+        let key = self.interest_key(&have);
+
+        let mut connected_peers = self.connected_peers.lock().await;
+        let recipients = connected_peers.recipients(
+            key.as_deref(),
+            self.mparams.interest_fanout,
+            excluding.into(),
+        );
+        futures::stream::iter(
+            connected_peers
+                .iter_mut()
+                .filter(|(peer_id, _)| recipients.contains(*peer_id)),
+        )
+        .for_each_concurrent(None, |(peer, out)| {
+            let rpc = if out.is_eager() || !out.supports(&Capability::BroadcastTree) {
+                have.clone()
+            } else {
+                ihave.clone()
+            };
+            async move {
+                tracing::trace!(msg = "Push", push.rpc = ?rpc, push.peer = %peer);
+                if let Err(e) = out.sink().send(rpc).await {
+                    tracing::warn!(
+                        "{}: Failed to push message to {}: {:?}",
+                        self.local_id,
+                        peer,
+                        e
+                    );
+                    out.failed()
+                }
+            }
+        })
+        .await
+    }
+
+    /// Send an [`Rpc`] to all currently connected peers, except `excluding`.
+    ///
+    /// [`Gossip`] is only sent to peers which may be interested in it, and a
+    /// few random others (see [`MembershipParams::interest_fanout`]).
     async fn broadcast<'a, M, X>(&self, rpc: M, excluding: X)
     where
         M: Into<Rpc<Addr, Broadcast>>,
         X: Into<Option<&'a PeerId>>,
     {
         let rpc = rpc.into();
-        let excluding = excluding.into();
+        let key = self.interest_key(&rpc);
 
         let mut connected_peers = self.connected_peers.lock().await;
+        let recipients = connected_peers.recipients(
+            key.as_deref(),
+            self.mparams.interest_fanout,
+            excluding.into(),
+        );
         futures::stream::iter(
             connected_peers
                 .iter_mut()
-                .filter(|(peer_id, _)| Some(*peer_id) != excluding),
+                .filter(|(peer_id, _)| recipients.contains(*peer_id)),
         )
         .for_each_concurrent(None, |(peer, out)| {
             let rpc = rpc.clone();
@@ -867,13 +1234,65 @@ where
                 // stopped working, too. Hence, we don't need to propagate
                 // errors here. This statement will need some empirical
                 // evidence.
-                if let Err(e) = out.send(rpc).await {
+                if let Err(e) = out.sink().send(rpc).await {
                     tracing::warn!(
                         "{}: Failed to send broadcast message to {}: {:?}",
                         self.local_id,
                         peer,
                         e
-                    )
+                    );
+                    out.failed()
+                }
+            }
+        })
+        .await
+    }
+
+    /// The key by which `rpc` is matched against the [`Interests`] of peers,
+    /// if it is a `Have` or `Want`.
+    fn interest_key(&self, rpc: &Rpc<Addr, Broadcast>) -> Option<Vec<u8>> {
+        match rpc {
+            Rpc::Gossip(Gossip::Have { val, .. }) | Rpc::Gossip(Gossip::Want { val, .. }) => {
+                self.storage.interest_key(val)
+            },
+            _ => None,
+        }
+    }
+
+    /// Tell `to`, or else all connected peers, which gossip we're interested
+    /// in.
+    ///
+    /// Only peers which support [`Capability::Interests`] are told.
+    async fn send_interests<'a, X>(&self, to: X)
+    where
+        X: Into<Option<&'a PeerId>>,
+    {
+        if !self.mparams.advertise_interests {
+            return;
+        }
+        let interests = match self.storage.interests().await {
+            Some(interests) => interests,
+            None => return,
+        };
+        let rpc: Rpc<Addr, Broadcast> = Membership::Interests(interests).into();
+        let to = to.into();
+
+        let mut connected_peers = self.connected_peers.lock().await;
+        futures::stream::iter(connected_peers.iter_mut().filter(|(peer_id, conn)| {
+            conn.supports(&Capability::Interests) && to.map(|to| to == *peer_id).unwrap_or(true)
+        }))
+        .for_each_concurrent(None, |(peer, out)| {
+            let rpc = rpc.clone();
+            async move {
+                tracing::trace!(msg = "Sending interests", peer = %peer);
+                if let Err(e) = out.sink().send(rpc).await {
+                    tracing::warn!(
+                        "{}: Failed to send interests to {}: {:?}",
+                        self.local_id,
+                        peer,
+                        e
+                    );
+                    out.failed()
                 }
             }
         })
@@ -887,8 +1306,9 @@ where
                 let rpc = rpc.clone();
                 async move {
                     tracing::trace!(msg= "Reply with", reply.rpc = ?rpc, reply.peer = %to);
-                    if let Err(e) = out.send(rpc).await {
+                    if let Err(e) = out.sink().send(rpc).await {
                         tracing::warn!("{}: Failed to reply to {}: {:?}", self.local_id, to, e);
+                        out.failed()
                     }
                 }
             })
@@ -941,3 +1361,218 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::{keys::SecretKey, net::connection::mock::MockStream};
+
+    type Node = Protocol<
+        Store,
+        u64,
+        PeerId,
+        <MockStream as connection::Stream>::Read,
+        <MockStream as connection::Stream>::Write,
+    >;
+
+    /// Stores every value exactly once.
+    ///
+    /// Values may be claimed by a peer, in which case only that peer may
+    /// announce them.
+    #[derive(Clone, Default)]
+    struct Store {
+        vals: Arc<std::sync::Mutex<HashSet<u64>>>,
+        claims: Arc<std::sync::Mutex<HashMap<u64, PeerId>>>,
+    }
+
+    impl Store {
+        fn insert(&self, val: u64) -> bool {
+            self.vals.lock().unwrap().insert(val)
+        }
+
+        fn has(&self, val: u64) -> bool {
+            self.vals.lock().unwrap().contains(&val)
+        }
+
+        fn claim(&self, val: u64, peer: PeerId) {
+            self.claims.lock().unwrap().insert(val, peer);
+        }
+    }
+
+    #[async_trait]
+    impl LocalStorage for Store {
+        type Update = u64;
+
+        async fn put(&self, _provider: &PeerId, has: u64) -> PutResult {
+            if self.insert(has) {
+                PutResult::Applied
+            } else {
+                PutResult::Stale
+            }
+        }
+
+        async fn ask(&self, want: u64) -> bool {
+            self.has(want)
+        }
+
+        fn may_announce(&self, origin: &PeerId, val: &u64) -> bool {
+            self.claims
+                .lock()
+                .unwrap()
+                .get(val)
+                .map(|peer| peer == origin)
+                .unwrap_or(true)
+        }
+    }
+
+    /// Set up a fully meshed network of `n` nodes. Along with the nodes, a
+    /// count of the `Have`s delivered between them is returned.
+    async fn mesh(n: usize, broadcast_tree: bool) -> (Vec<(Node, Store)>, Arc<AtomicUsize>) {
+        let haves = Arc::new(AtomicUsize::new(0));
+        let nodes = (0..n)
+            .map(|_| {
+                let key = SecretKey::new();
+                let peer_id = PeerId::from(&key);
+                let mut ad = PeerAdvertisement::new(peer_id.clone(), 0);
+                if !broadcast_tree {
+                    ad.capabilities.remove(&Capability::BroadcastTree);
+                }
+                let store = Store::default();
+                let node = Protocol::new(
+                    &peer_id,
+                    ad,
+                    key.into(),
+                    MembershipParams::default(),
+                    store.clone(),
+                    AddressBook::new(),
+                );
+                (node, store)
+            })
+            .collect::<Vec<_>>();
+
+        for (i, (a, _)) in nodes.iter().enumerate() {
+            for (b, _) in &nodes[i + 1..] {
+                connect(a, b, haves.clone())
+            }
+        }
+        // Let the `Neighbour` advertisements arrive
+        Delay::new(Duration::from_millis(500)).await;
+
+        (nodes, haves)
+    }
+
+    /// Connect `a` and `b` through a tap which counts the `Have`s passing.
+    fn connect(a: &Node, b: &Node, haves: Arc<AtomicUsize>) {
+        let (a_end, a_tap) = MockStream::pair(b.peer_id().clone(), a.peer_id().clone(), 4096);
+        let (b_tap, b_end) = MockStream::pair(b.peer_id().clone(), a.peer_id().clone(), 4096);
+        let (a_recv, a_send) = connection::Stream::split(a_tap);
+        let (b_recv, b_send) = connection::Stream::split(b_tap);
+        tokio::spawn(tap(a_recv, b_send, haves.clone()));
+        tokio::spawn(tap(b_recv, a_send, haves));
+
+        for (node, end) in vec![(a.clone(), a_end), (b.clone(), b_end)] {
+            let hello: Rpc<PeerId, u64> =
+                Membership::Neighbour(node.local_advertisement().clone()).into();
+            tokio::spawn(async move {
+                let _ = node
+                    .outgoing(Upgraded::new(end, upgrade::VERSION), hello)
+                    .await;
+            });
+        }
+    }
+
+    async fn tap<R, W>(recv: R, send: W, haves: Arc<AtomicUsize>)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut recv = FramedRead::new(recv, Codec::<PeerId, u64>::new());
+        let mut send = FramedWrite::new(send, Codec::<PeerId, u64>::new());
+        while let Some(Ok(rpc)) = recv.next().await {
+            if let Rpc::Gossip(Gossip::Have { .. }) = rpc {
+                haves.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            if send.send(rpc).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Announce `val` from the first node, and count the duplicate `Have`s
+    /// delivered until it reached every node.
+    async fn announce(nodes: &[(Node, Store)], haves: &AtomicUsize, val: u64) -> usize {
+        let before = haves.load(atomic::Ordering::SeqCst);
+
+        let (origin, store) = &nodes[0];
+        store.insert(val);
+        origin.announce(val).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !nodes.iter().all(|(_, store)| store.has(val)) {
+                Delay::new(Duration::from_millis(10)).await
+            }
+        })
+        .await
+        .expect("announcement did not reach all nodes");
+        // Let the remaining duplicates, and any `Prune`s, arrive
+        Delay::new(Duration::from_millis(500)).await;
+
+        let delivered = haves.load(atomic::Ordering::SeqCst) - before;
+        delivered - (nodes.len() - 1)
+    }
+
+    const NUM_NODES: usize = 5;
+    // Every node but the origin relays to everyone but its first sender
+    const FLOOD_DUPLICATES: usize = (NUM_NODES - 1) * (NUM_NODES - 2);
+
+    #[tokio::test]
+    async fn flood_without_broadcast_tree() {
+        let (nodes, haves) = mesh(NUM_NODES, false).await;
+
+        assert_eq!(announce(&nodes, &haves, 1).await, FLOOD_DUPLICATES);
+        assert_eq!(announce(&nodes, &haves, 2).await, FLOOD_DUPLICATES);
+    }
+
+    #[tokio::test]
+    async fn broadcast_tree_prunes_duplicates() {
+        let (nodes, haves) = mesh(NUM_NODES, true).await;
+
+        // All links start out eager, so the first announcement floods the
+        // network, pruning every link which delivered a duplicate ..
+        assert_eq!(announce(&nodes, &haves, 1).await, FLOOD_DUPLICATES);
+        // .. which leaves a spanning tree
+        assert_eq!(announce(&nodes, &haves, 2).await, 0);
+    }
+
+    #[tokio::test]
+    async fn drops_haves_on_behalf_of_other_peers() {
+        let (nodes, _) = mesh(2, true).await;
+        let (honest, honest_store) = &nodes[0];
+        let (forger, forger_store) = &nodes[1];
+
+        // The forger validly signs a `Have` of a value only the honest peer
+        // may announce
+        honest_store.claim(1, honest.peer_id().clone());
+        let mut events = honest.subscribe().await;
+        forger_store.insert(1);
+        forger.announce(1).await;
+
+        let penalised = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(evt) = events.next().await {
+                if let ProtocolEvent::Control(Control::Penalise(peer, misbehaviour)) = evt {
+                    return Some((peer, misbehaviour));
+                }
+            }
+            None
+        })
+        .await
+        .expect("forged Have was not penalised");
+        assert_eq!(
+            penalised,
+            Some((forger.peer_id().clone(), Misbehaviour::InvalidSignature))
+        );
+        assert!(!honest_store.has(1));
+    }
+}
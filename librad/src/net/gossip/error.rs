@@ -19,7 +19,7 @@ use std::io;
 
 use thiserror::Error;
 
-use crate::net::codec::CborCodecError;
+use crate::{net::codec::CborCodecError, signer::BoxedSignError};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -32,6 +32,9 @@ pub enum Error {
     #[error(transparent)]
     Cbor(#[from] CborCodecError),
 
+    #[error(transparent)]
+    Sign(#[from] BoxedSignError),
+
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -0,0 +1,202 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Compact summaries of what a peer is interested in.
+//!
+//! Peers exchange [`Interests`] so that gossip is only sent to peers which may
+//! care about it. A summary is a bloom filter over opaque keys (see
+//! [`crate::net::gossip::LocalStorage::interest_key`]): it may yield false
+//! positives, but never false negatives.
+
+use bit_vec::BitVec;
+use minicbor::{Decode, Encode};
+use multihash::Blake2b256;
+
+/// Filters smaller than this aren't worth the reduction in accuracy.
+const MIN_BITS: usize = 64;
+
+/// Upper bound on the size of a filter we accept from other peers, in bytes.
+const MAX_BYTES: usize = 1024 * 1024;
+
+/// Upper bound on the number of hash functions, beyond which a filter is
+/// rather pointless.
+const MAX_HASHES: u8 = 32;
+
+/// A bloom filter of the keys a peer is interested in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interests {
+    bits: BitVec,
+    hashes: u8,
+}
+
+impl Interests {
+    /// An empty filter, sized for `capacity` keys at a false positive rate of
+    /// `fp_rate`.
+    pub fn new(capacity: usize, fp_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let fp_rate = fp_rate.max(f64::EPSILON).min(1.0);
+        let ln2 = std::f64::consts::LN_2;
+
+        let bits = (-capacity * fp_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        // Round up to whole bytes, as this is how the filter is sent
+        let bits = ((bits.max(MIN_BITS) + 7) / 8 * 8).min(MAX_BYTES * 8);
+        let hashes = (bits as f64 / capacity * ln2).round() as u8;
+
+        Self {
+            bits: BitVec::from_elem(bits, false),
+            hashes: hashes.max(1).min(MAX_HASHES),
+        }
+    }
+
+    /// A filter containing all `keys`, at a false positive rate of `fp_rate`.
+    pub fn from_keys<I, K>(keys: I, fp_rate: f64) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let keys = keys.into_iter().collect::<Vec<_>>();
+        let mut this = Self::new(keys.len(), fp_rate);
+        for key in keys {
+            this.insert(key.as_ref())
+        }
+        this
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for i in self.indices(key) {
+            self.bits.set(i, true)
+        }
+    }
+
+    /// Whether `key` may be contained in the filter.
+    ///
+    /// `false` means it definitely isn't.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.indices(key).all(|i| self.bits.get(i).unwrap_or(false))
+    }
+
+    // The indices are derived from a cryptographic hash, so they're the same
+    // on every peer, and can't be gamed.
+    fn indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Blake2b256::digest(key);
+        let digest = digest.digest();
+        let mut h1 = [0; 8];
+        let mut h2 = [0; 8];
+        h1.copy_from_slice(&digest[..8]);
+        h2.copy_from_slice(&digest[8..16]);
+        let (h1, h2) = (u64::from_le_bytes(h1), u64::from_le_bytes(h2));
+
+        let len = self.bits.len() as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+impl Encode for Interests {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(2)?.bytes(&self.bits.to_bytes())?.u8(self.hashes)?;
+        Ok(())
+    }
+}
+
+impl<'de> Decode<'de> for Interests {
+    fn decode(d: &mut minicbor::Decoder<'de>) -> Result<Self, minicbor::decode::Error> {
+        if Some(2) != d.array()? {
+            return Err(minicbor::decode::Error::Message(
+                "expected array of length 2",
+            ));
+        }
+
+        let bytes = d.bytes()?;
+        if bytes.is_empty() || bytes.len() > MAX_BYTES {
+            return Err(minicbor::decode::Error::Message("invalid filter size"));
+        }
+        let hashes = d.u8()?;
+        if hashes == 0 || hashes > MAX_HASHES {
+            return Err(minicbor::decode::Error::Message(
+                "invalid number of hash functions",
+            ));
+        }
+
+        Ok(Self {
+            bits: BitVec::from_bytes(bytes),
+            hashes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use librad_test::roundtrip::*;
+
+    fn keys(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!("urn-{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let keys = keys(1000);
+        let interests = Interests::from_keys(&keys, 0.01);
+        assert!(keys.iter().all(|key| interests.may_contain(key)))
+    }
+
+    #[test]
+    fn few_false_positives() {
+        let interests = Interests::from_keys(keys(1000), 0.01);
+        let false_positives = (0..10_000)
+            .filter(|i| interests.may_contain(format!("other-{}", i).as_bytes()))
+            .count();
+        // Generous, so as to not be flaky
+        assert!(false_positives < 300, "{} false positives", false_positives)
+    }
+
+    #[test]
+    fn empty_contains_nothing() {
+        let interests = Interests::from_keys(Vec::<Vec<u8>>::new(), 0.01);
+        assert!(!interests.may_contain(b"urn-0"))
+    }
+
+    #[test]
+    fn roundtrip() {
+        cbor_roundtrip(Interests::from_keys(keys(100), 0.01))
+    }
+
+    fn encode_raw(bytes: &[u8], hashes: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        minicbor::Encoder::new(&mut buf)
+            .array(2)
+            .unwrap()
+            .bytes(bytes)
+            .unwrap()
+            .u8(hashes)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(minicbor::decode::<Interests>(&encode_raw(&[], 3)).is_err());
+        assert!(minicbor::decode::<Interests>(&encode_raw(&[0xff], 0)).is_err());
+        assert!(minicbor::decode::<Interests>(&encode_raw(&[0xff], 42)).is_err());
+        assert!(minicbor::decode::<Interests>(&encode_raw(&[0xff], 3)).is_ok());
+    }
+}
@@ -0,0 +1,685 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The active and passive views of the membership protocol.
+//!
+//! Both views are bounded. When they overflow, or when a peer is to be picked
+//! for shuffling or promotion, the choice is weighted by how healthy a peer
+//! appears to be: for how long we've been connected to it and how much it has
+//! told us, how recently we've seen it and whether we have heard from it
+//! directly, how often we failed to talk to it, and whether it misbehaved.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::{
+    seq::{IteratorRandom, SliceRandom},
+    Rng,
+};
+
+use crate::{
+    net::{
+        addrbook,
+        gossip::{
+            interest::Interests,
+            types::{Capability, PeerAdvertisement, PeerInfo},
+        },
+    },
+    peer::PeerId,
+};
+
+/// Uptime beyond this doesn't make a connection any healthier.
+const MAX_UPTIME: Duration = Duration::from_secs(60 * 60);
+
+/// Passive peers we failed to connect to this many times in a row are
+/// forgotten.
+const MAX_FAILURES: u32 = 5;
+
+/// How we learned about a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Provenance {
+    /// The peer info was relayed by another peer, eg. via `Shuffle`.
+    Relayed,
+    /// The peer told us about itself, via `Join` or `Neighbour`.
+    Direct,
+}
+
+pub(super) struct Connected<S> {
+    sink: S,
+    since: Instant,
+    recv: u64,
+    failures: u32,
+    misbehaviour: u32,
+    eager: bool,
+    capabilities: HashSet<Capability>,
+    interests: Option<Interests>,
+}
+
+impl<S> Connected<S> {
+    fn new(sink: S) -> Self {
+        Self {
+            sink,
+            since: Instant::now(),
+            recv: 0,
+            failures: 0,
+            misbehaviour: 0,
+            eager: true,
+            capabilities: HashSet::default(),
+            interests: None,
+        }
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Record that sending to the peer failed.
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1)
+    }
+
+    /// Record that the peer sent us something it shouldn't have, eg. a forged
+    /// message.
+    pub fn misbehaved(&mut self) {
+        self.misbehaviour = self.misbehaviour.saturating_add(1)
+    }
+
+    /// Whether gossip is pushed eagerly to the peer, ie. whether the peer is
+    /// part of our broadcast tree.
+    ///
+    /// New connections start out eager, and are moved back and forth by the
+    /// `Prune` and `Graft` messages of the broadcast protocol.
+    pub fn is_eager(&self) -> bool {
+        self.eager
+    }
+
+    pub fn set_eager(&mut self, eager: bool) {
+        self.eager = eager
+    }
+
+    /// The capabilities the peer advertised, if any.
+    pub fn capabilities(&self) -> &HashSet<Capability> {
+        &self.capabilities
+    }
+
+    pub fn supports(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Record capabilities of the peer, in addition to the ones already known.
+    pub fn add_capabilities<I: IntoIterator<Item = Capability>>(&mut self, capabilities: I) {
+        self.capabilities.extend(capabilities)
+    }
+
+    /// Whether the peer told us what it is interested in.
+    pub fn has_interests(&self) -> bool {
+        self.interests.is_some()
+    }
+
+    pub fn set_interests(&mut self, interests: Interests) {
+        self.interests = Some(interests)
+    }
+
+    /// Whether the peer may be interested in a value with the given
+    /// interest `key`.
+    ///
+    /// This is always the case if either the value or the peer don't specify
+    /// interests.
+    pub fn may_want(&self, key: Option<&[u8]>) -> bool {
+        match (key, &self.interests) {
+            (Some(key), Some(interests)) => interests.may_contain(key),
+            _ => true,
+        }
+    }
+
+    fn health(&self, now: Instant) -> f64 {
+        let uptime = now.saturating_duration_since(self.since).min(MAX_UPTIME);
+        let activity = (self.recv as f64).ln_1p();
+
+        let failures = f64::from(self.failures.saturating_add(1));
+        // Misbehaviour is intentional, and thus weighs heavier than failures
+        let misbehaviour = f64::from(self.misbehaviour.saturating_add(1)).powi(2);
+
+        (1.0 + uptime.as_secs_f64() / 60.0 + activity) / failures / misbehaviour
+    }
+}
+
+/// The currently connected-to peers, ie. the active view.
+///
+/// The number of peers is bounded -- when `insert`ing into an already full
+/// `ConnectedPeers`, an existing connection is ejected, and returned for
+/// upstream connection management. The choice is random, but weighted such
+/// that connections which are young, quiet, or failing are more likely to be
+/// ejected.
+pub(super) struct ConnectedPeers<S, R> {
+    max_peers: usize,
+    rng: R,
+    peers: HashMap<PeerId, Connected<S>>,
+}
+
+impl<S, R> ConnectedPeers<S, R>
+where
+    S: Unpin,
+    R: Rng,
+{
+    pub fn new(max_peers: usize, rng: R) -> Self {
+        Self {
+            max_peers,
+            rng,
+            peers: HashMap::default(),
+        }
+    }
+
+    pub fn insert(&mut self, peer_id: PeerId, sink: S) -> Option<(PeerId, S)> {
+        if let Some(existing) = self.peers.get_mut(&peer_id) {
+            let existing = std::mem::replace(existing, Connected::new(sink));
+            return Some((peer_id, existing.sink));
+        }
+
+        let ejected = if self.peers.len() + 1 > self.max_peers {
+            self.eject()
+        } else {
+            None
+        };
+        self.peers.insert(peer_id, Connected::new(sink));
+
+        ejected
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<(PeerId, S)> {
+        self.peers
+            .remove(peer_id)
+            .map(|conn| (peer_id.to_owned(), conn.sink))
+    }
+
+    /// Pick a connected peer at random, preferring healthy ones.
+    pub fn random(&mut self) -> Option<(&PeerId, &mut S)> {
+        let now = Instant::now();
+        let candidates = self
+            .peers
+            .iter()
+            .map(|(peer_id, conn)| (peer_id.clone(), conn.health(now)))
+            .collect::<Vec<_>>();
+        let chosen = candidates
+            .choose_weighted(&mut self.rng, |(_, health)| *health)
+            .ok()?
+            .0
+            .clone();
+
+        self.peers
+            .iter_mut()
+            .find(|(peer_id, _)| **peer_id == chosen)
+            .map(|(peer_id, conn)| (peer_id, &mut conn.sink))
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    pub fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut Connected<S>> {
+        self.peers.get_mut(peer_id)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&PeerId, &mut Connected<S>)> {
+        self.peers.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// The peers to send a value with the given interest `key` to.
+    ///
+    /// These are the peers which may be interested in the value (see
+    /// [`Connected::may_want`]), plus up to `fanout` randomly chosen others,
+    /// so that values can still reach peers whose interests have changed, or
+    /// which relay values to interested peers further away.
+    pub fn recipients(
+        &mut self,
+        key: Option<&[u8]>,
+        fanout: usize,
+        excluding: Option<&PeerId>,
+    ) -> HashSet<PeerId> {
+        let (interested, others): (Vec<_>, Vec<_>) = self
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| Some(*peer_id) != excluding)
+            .partition(|(_, conn)| conn.may_want(key));
+
+        interested
+            .into_iter()
+            .chain(others.into_iter().choose_multiple(&mut self.rng, fanout))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Record that we received a message from `peer_id`.
+    pub fn received(&mut self, peer_id: &PeerId) {
+        if let Some(conn) = self.peers.get_mut(peer_id) {
+            conn.recv = conn.recv.saturating_add(1)
+        }
+    }
+
+    fn eject(&mut self) -> Option<(PeerId, S)> {
+        let now = Instant::now();
+        let candidates = self
+            .peers
+            .iter()
+            .map(|(peer_id, conn)| (peer_id.clone(), conn.health(now).recip()))
+            .collect::<Vec<_>>();
+        let eject = candidates
+            .choose_weighted(&mut self.rng, |(_, weight)| *weight)
+            .ok()?
+            .0
+            .clone();
+
+        self.remove(&eject)
+    }
+}
+
+// Note that timestamps are wall-clock times, as they are persisted across
+// restarts (see `KnownPeers::entries`).
+#[derive(Clone)]
+struct Known<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    advertised: PeerAdvertisement<Addr>,
+    /// Most recently seen first.
+    addrs: VecDeque<(Addr, SystemTime)>,
+    provenance: Provenance,
+    last_seen: SystemTime,
+    last_success: Option<SystemTime>,
+    failures: u32,
+}
+
+impl<Addr> Known<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    fn see_addrs(&mut self, addrs: HashSet<Addr>, now: SystemTime, max_addrs: usize) {
+        self.addrs.retain(|(addr, _)| !addrs.contains(addr));
+        for addr in addrs {
+            self.addrs.push_front((addr, now))
+        }
+        self.addrs.truncate(max_addrs)
+    }
+
+    fn health(&self, now: SystemTime) -> f64 {
+        let provenance = match self.provenance {
+            Provenance::Direct => 2.0,
+            Provenance::Relayed => 1.0,
+        };
+        let age = now
+            .duration_since(self.last_seen)
+            .unwrap_or_default()
+            .as_secs_f64()
+            / 60.0;
+
+        provenance / (1.0 + age.ln_1p()) / f64::from(self.failures.saturating_add(1))
+    }
+
+    fn peer_info(&self, peer_id: &PeerId) -> PeerInfo<Addr> {
+        PeerInfo {
+            peer_id: peer_id.clone(),
+            advertised_info: self.advertised.clone(),
+            seen_addrs: self.addrs.iter().map(|(addr, _)| addr.clone()).collect(),
+        }
+    }
+}
+
+/// The peers we know about, ie. the passive view.
+///
+/// Keeps a bounded number of peers, each with a bounded number of the most
+/// recently seen addresses. When full, peers relayed by others are forgotten
+/// before the ones we've heard from directly, and failing or stale peers
+/// before healthy ones.
+#[derive(Clone, Default)]
+pub(super) struct KnownPeers<Addr, R>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+{
+    max_peers: usize,
+    max_addrs: usize,
+    peers: HashMap<PeerId, Known<Addr>>,
+    rng: R,
+}
+
+impl<Addr, R> KnownPeers<Addr, R>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+    R: Rng,
+{
+    pub fn new(max_peers: usize, max_addrs: usize, rng: R) -> Self {
+        Self {
+            max_peers,
+            max_addrs,
+            peers: HashMap::default(),
+            rng,
+        }
+    }
+
+    pub fn insert<I>(&mut self, peers: I, provenance: Provenance)
+    where
+        I: IntoIterator<Item = PeerInfo<Addr>>,
+    {
+        let now = SystemTime::now();
+        for info in peers {
+            match self.peers.entry(info.peer_id) {
+                Entry::Occupied(mut entry) => {
+                    let known = entry.get_mut();
+                    if provenance >= known.provenance {
+                        known.advertised = info.advertised_info;
+                        known.provenance = provenance;
+                    }
+                    if provenance == Provenance::Direct {
+                        known.last_seen = now;
+                        known.last_success = Some(now);
+                    }
+                    known.see_addrs(info.seen_addrs, now, self.max_addrs);
+                },
+                Entry::Vacant(entry) => {
+                    let mut known = Known {
+                        advertised: info.advertised_info,
+                        addrs: VecDeque::new(),
+                        provenance,
+                        last_seen: now,
+                        last_success: match provenance {
+                            Provenance::Direct => Some(now),
+                            Provenance::Relayed => None,
+                        },
+                        failures: 0,
+                    };
+                    known.see_addrs(info.seen_addrs, now, self.max_addrs);
+                    entry.insert(known);
+                },
+            }
+        }
+
+        self.evict()
+    }
+
+    /// Restore peers from persisted [`addrbook::Entry`]s.
+    ///
+    /// Peers we have been connected to before are trusted as if we heard from
+    /// them directly. Peers already known are left untouched.
+    pub fn restore<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = addrbook::Entry<Addr>>,
+    {
+        for entry in entries {
+            let last_seen = entry.last_seen();
+            let last_success = entry.last_success();
+            let info = entry.info;
+            if let Entry::Vacant(vacant) = self.peers.entry(info.peer_id) {
+                let mut known = Known {
+                    advertised: info.advertised_info,
+                    addrs: VecDeque::new(),
+                    provenance: if last_success.is_some() {
+                        Provenance::Direct
+                    } else {
+                        Provenance::Relayed
+                    },
+                    last_seen,
+                    last_success,
+                    failures: 0,
+                };
+                known.see_addrs(info.seen_addrs, last_seen, self.max_addrs);
+                vacant.insert(known);
+            }
+        }
+
+        self.evict()
+    }
+
+    /// All known peers, in a form suitable for persisting them.
+    pub fn entries(&self) -> Vec<addrbook::Entry<Addr>> {
+        self.peers
+            .iter()
+            .map(|(peer_id, known)| {
+                addrbook::Entry::new(
+                    known.peer_info(peer_id),
+                    known.last_seen,
+                    known.last_success,
+                )
+            })
+            .collect()
+    }
+
+    fn evict(&mut self) {
+        while self.peers.len() > self.max_peers {
+            let evict = self
+                .peers
+                .iter()
+                .min_by_key(|(_, known)| {
+                    (known.provenance, Reverse(known.failures), known.last_seen)
+                })
+                .map(|(peer_id, _)| peer_id.clone());
+            match evict {
+                Some(peer_id) => self.peers.remove(&peer_id),
+                None => break,
+            };
+        }
+    }
+
+    /// The most recent advertisement of `peer_id` we know of.
+    pub fn advertisement(&self, peer_id: &PeerId) -> Option<&PeerAdvertisement<Addr>> {
+        self.peers.get(peer_id).map(|known| &known.advertised)
+    }
+
+    /// Record that we successfully connected to `peer_id`.
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        if let Some(known) = self.peers.get_mut(peer_id) {
+            let now = SystemTime::now();
+            known.failures = 0;
+            known.last_seen = now;
+            known.last_success = Some(now);
+        }
+    }
+
+    /// Record that we failed to connect to `peer_id`, forgetting about it if
+    /// that happened too often.
+    pub fn failed(&mut self, peer_id: &PeerId) {
+        if let Some(known) = self.peers.get_mut(peer_id) {
+            known.failures = known.failures.saturating_add(1);
+            if known.failures >= MAX_FAILURES {
+                self.peers.remove(peer_id);
+            }
+        }
+    }
+
+    /// Pick a known peer at random, preferring healthy ones.
+    pub fn random(&mut self) -> Option<PeerInfo<Addr>> {
+        self.sample(1).pop()
+    }
+
+    /// Pick up to `n` distinct known peers at random, preferring healthy ones.
+    pub fn sample(&mut self, n: usize) -> Vec<PeerInfo<Addr>> {
+        let now = SystemTime::now();
+        let mut candidates = self
+            .peers
+            .iter()
+            .map(|(peer_id, known)| (peer_id, known, known.health(now)))
+            .collect::<Vec<_>>();
+
+        let mut sample = Vec::with_capacity(n.min(candidates.len()));
+        while sample.len() < n && !candidates.is_empty() {
+            let chosen = (0..candidates.len())
+                .collect::<Vec<_>>()
+                .choose_weighted(&mut self.rng, |i| candidates[*i].2)
+                .ok()
+                .copied();
+            match chosen {
+                Some(i) => {
+                    let (peer_id, known, _) = candidates.swap_remove(i);
+                    sample.push(known.peer_info(peer_id))
+                },
+                None => break,
+            }
+        }
+
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand_pcg::Pcg64Mcg;
+
+    use crate::keys::SecretKey;
+
+    fn peer_info(peer_id: &PeerId, addrs: &[u8]) -> PeerInfo<u8> {
+        PeerInfo {
+            peer_id: peer_id.clone(),
+            advertised_info: PeerAdvertisement::new(0, 0),
+            seen_addrs: addrs.iter().cloned().collect(),
+        }
+    }
+
+    fn peer() -> PeerId {
+        PeerId::from(SecretKey::new())
+    }
+
+    #[test]
+    fn eject_unhealthy_connections() {
+        let mut connected = ConnectedPeers::new(2, Pcg64Mcg::new(42));
+        let (healthy, failing) = (peer(), peer());
+        assert!(connected.insert(healthy.clone(), ()).is_none());
+        assert!(connected.insert(failing.clone(), ()).is_none());
+
+        // Make `healthy` a chatty connection, and `failing` a very unreliable
+        // one.
+        connected.get_mut(&healthy).unwrap().recv = 10_000;
+        connected.get_mut(&failing).unwrap().failures = 10_000;
+
+        let (ejected, ()) = connected.insert(peer(), ()).unwrap();
+        assert_eq!(ejected, failing);
+        assert!(connected.contains(&healthy));
+        assert_eq!(connected.len(), 2);
+    }
+
+    #[test]
+    fn recipients_by_interest() {
+        let mut connected = ConnectedPeers::new(10, Pcg64Mcg::new(42));
+        let (interested, uninterested, unknown) = (peer(), peer(), peer());
+        for peer_id in &[&interested, &uninterested, &unknown] {
+            connected.insert((*peer_id).clone(), ());
+        }
+        connected
+            .get_mut(&interested)
+            .unwrap()
+            .set_interests(Interests::from_keys(vec![b"urn"], 0.01));
+        connected
+            .get_mut(&uninterested)
+            .unwrap()
+            .set_interests(Interests::from_keys(vec![b"other"], 0.01));
+
+        assert_eq!(
+            connected.recipients(Some(&b"urn"[..]), 0, None),
+            vec![interested.clone(), unknown.clone()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            connected.recipients(Some(&b"urn"[..]), 0, Some(&unknown)),
+            vec![interested].into_iter().collect()
+        );
+        assert_eq!(connected.recipients(Some(&b"urn"[..]), 1, None).len(), 3);
+        assert_eq!(connected.recipients(None, 0, None).len(), 3);
+    }
+
+    #[test]
+    fn replace_existing_connection() {
+        let mut connected = ConnectedPeers::new(1, Pcg64Mcg::new(42));
+        let peer_id = peer();
+        assert!(connected.insert(peer_id.clone(), 1).is_none());
+        assert_eq!(connected.insert(peer_id.clone(), 2), Some((peer_id, 1)));
+        assert_eq!(connected.len(), 1);
+    }
+
+    #[test]
+    fn bounded_addrs() {
+        let mut known = KnownPeers::new(10, 2, Pcg64Mcg::new(42));
+        let peer_id = peer();
+        known.insert(vec![peer_info(&peer_id, &[1])], Provenance::Direct);
+        known.insert(vec![peer_info(&peer_id, &[2])], Provenance::Direct);
+        known.insert(vec![peer_info(&peer_id, &[3])], Provenance::Direct);
+
+        let info = known.random().unwrap();
+        assert_eq!(info.seen_addrs, vec![2, 3].into_iter().collect());
+    }
+
+    #[test]
+    fn relayed_peers_are_evicted_first() {
+        let mut known = KnownPeers::new(2, 2, Pcg64Mcg::new(42));
+        let (direct, relayed, newcomer) = (peer(), peer(), peer());
+        known.insert(vec![peer_info(&direct, &[1])], Provenance::Direct);
+        known.insert(vec![peer_info(&relayed, &[2])], Provenance::Relayed);
+        known.insert(vec![peer_info(&newcomer, &[3])], Provenance::Direct);
+
+        let mut sample = known
+            .sample(10)
+            .into_iter()
+            .map(|info| info.peer_id)
+            .collect::<Vec<_>>();
+        sample.sort();
+        let mut expected = vec![direct, newcomer];
+        expected.sort();
+        assert_eq!(sample, expected);
+    }
+
+    #[test]
+    fn restore_entries() {
+        let mut known = KnownPeers::new(10, 2, Pcg64Mcg::new(42));
+        let (connected, relayed) = (peer(), peer());
+        known.insert(vec![peer_info(&connected, &[1])], Provenance::Relayed);
+        known.insert(vec![peer_info(&relayed, &[2])], Provenance::Relayed);
+        known.connected(&connected);
+
+        let mut restored = KnownPeers::new(1, 2, Pcg64Mcg::new(42));
+        restored.restore(known.entries());
+
+        let info = restored.random().unwrap();
+        assert_eq!(info.peer_id, connected);
+        assert_eq!(info.seen_addrs, vec![1].into_iter().collect());
+    }
+
+    #[test]
+    fn forget_failing_peers() {
+        let mut known = KnownPeers::new(10, 2, Pcg64Mcg::new(42));
+        let peer_id = peer();
+        known.insert(vec![peer_info(&peer_id, &[1])], Provenance::Direct);
+
+        for _ in 0..MAX_FAILURES - 1 {
+            known.failed(&peer_id);
+        }
+        known.connected(&peer_id);
+        for _ in 0..MAX_FAILURES - 1 {
+            known.failed(&peer_id);
+        }
+        assert!(known.random().is_some());
+
+        known.failed(&peer_id);
+        assert!(known.random().is_none());
+    }
+}
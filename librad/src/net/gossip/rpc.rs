@@ -15,11 +15,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::hash::Hash;
+use std::{hash::Hash, io};
 
 use minicbor::{Decode, Encode};
 
-use crate::net::gossip::types::{PeerAdvertisement, PeerInfo};
+use crate::{
+    keys::Signature,
+    net::gossip::{
+        interest::Interests,
+        types::{MessageId, PeerAdvertisement, PeerInfo},
+    },
+    peer::PeerId,
+};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq, Encode, Decode)]
@@ -89,11 +96,29 @@ where
         #[n(0)]
         peers: Vec<PeerInfo<Addr>>,
     },
+
+    /// The sender only wishes to receive gossip it may be interested in.
+    ///
+    /// Only sent to peers which advertised [`Capability::Interests`].
+    ///
+    /// [`Capability::Interests`]: crate::net::gossip::Capability::Interests
+    #[n(5)]
+    Interests(#[n(0)] Interests),
 }
 
 /// Gossip messages are either announcements (`Have`), or queries (`Want`). The
 /// `origin` is the sender of the message -- this field is not modified if a
-/// message is relayed.
+/// message is relayed. The `ttl` is decremented on each hop, and the message
+/// is no longer relayed once it reaches zero.
+///
+/// `Have`s and `Want`s are signed by their `origin`, so relaying peers can't
+/// make claims on behalf of other peers (see [`Gossip::verify`]).
+///
+/// Announcements are disseminated along a broadcast tree: they are pushed
+/// eagerly to some peers, while others only receive an `IHave` with the
+/// [`MessageId`] of the announcement. Redundant tree edges are removed via
+/// `Prune`, and lazy peers are promoted into the tree via `Graft` if they
+/// announce a message we didn't receive in time.
 #[derive(Debug, Clone, PartialEq, Encode, Decode)]
 pub enum Gossip<Addr, Payload>
 where
@@ -106,6 +131,10 @@ where
         origin: PeerInfo<Addr>,
         #[n(1)]
         val: Payload,
+        #[n(2)]
+        ttl: usize,
+        #[n(3)]
+        signature: Signature,
     },
 
     #[n(1)]
@@ -115,5 +144,143 @@ where
         origin: PeerInfo<Addr>,
         #[n(1)]
         val: Payload,
+        #[n(2)]
+        ttl: usize,
+        #[n(3)]
+        signature: Signature,
     },
+
+    /// The sender has received the `Have` identified by `id`.
+    #[n(2)]
+    #[cbor(array)]
+    IHave {
+        #[n(0)]
+        id: MessageId,
+    },
+
+    /// The sender wishes to no longer receive `Have`s eagerly.
+    #[n(3)]
+    Prune,
+
+    /// The sender wishes to receive `Have`s eagerly, starting with the one
+    /// identified by `id`.
+    #[n(4)]
+    #[cbor(array)]
+    Graft {
+        #[n(0)]
+        id: MessageId,
+    },
+}
+
+/// Discriminates `Have`s from `Want`s in the signed portion of the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Have,
+    Want,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Have => "have",
+            Self::Want => "want",
+        }
+    }
+}
+
+/// The portion of a `Have` or `Want` covered by its signature.
+///
+/// This is the kind of message, the `PeerId` of its `origin`, and its payload.
+/// The `ttl` changes in transit, and the advertised info of the `origin` has no
+/// canonical encoding, so neither is signed.
+pub fn signed_payload<P: Encode>(
+    kind: Kind,
+    origin: &PeerId,
+    val: &P,
+) -> Result<Vec<u8>, minicbor::encode::Error<io::Error>> {
+    minicbor::to_vec(&(kind.as_str(), origin, val))
+}
+
+impl<Addr, Payload> Gossip<Addr, Payload>
+where
+    Addr: Clone + PartialEq + Eq + Hash,
+    Payload: Encode,
+{
+    /// Verify the signature of a `Have` or `Want` against its `origin`.
+    ///
+    /// Other messages are not signed, and always verify.
+    pub fn verify(&self) -> Result<bool, minicbor::encode::Error<io::Error>> {
+        let (kind, origin, val, signature) = match self {
+            Self::Have {
+                origin,
+                val,
+                signature,
+                ..
+            } => (Kind::Have, origin, val, signature),
+            Self::Want {
+                origin,
+                val,
+                signature,
+                ..
+            } => (Kind::Want, origin, val, signature),
+            _ => return Ok(true),
+        };
+
+        let payload = signed_payload(kind, &origin.peer_id, val)?;
+        Ok(origin.peer_id.as_public_key().verify(signature, &payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keys::SecretKey;
+
+    fn have(key: &SecretKey, val: u8) -> Gossip<u8, u8> {
+        let peer_id = PeerId::from(key);
+        let signature = key.sign(&signed_payload(Kind::Have, &peer_id, &val).unwrap());
+        Gossip::Have {
+            origin: PeerInfo {
+                peer_id,
+                advertised_info: PeerAdvertisement::new(0, 0),
+                seen_addrs: Default::default(),
+            },
+            val,
+            ttl: 1,
+            signature,
+        }
+    }
+
+    #[test]
+    fn verify_signed() {
+        assert!(have(&SecretKey::new(), 42).verify().unwrap())
+    }
+
+    #[test]
+    fn reject_forged_origin() {
+        let mut forged = have(&SecretKey::new(), 42);
+        if let Gossip::Have { origin, .. } = &mut forged {
+            origin.peer_id = PeerId::from(SecretKey::new())
+        }
+        assert!(!forged.verify().unwrap())
+    }
+
+    #[test]
+    fn reject_tampered_payload() {
+        let mut tampered = have(&SecretKey::new(), 42);
+        if let Gossip::Have { val, .. } = &mut tampered {
+            *val = 23
+        }
+        assert!(!tampered.verify().unwrap())
+    }
+
+    #[test]
+    fn ttl_is_not_signed() {
+        let mut relayed = have(&SecretKey::new(), 42);
+        if let Gossip::Have { ttl, .. } = &mut relayed {
+            *ttl = 0
+        }
+        assert!(relayed.verify().unwrap())
+    }
 }
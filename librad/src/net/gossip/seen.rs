@@ -0,0 +1,113 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::net::gossip::types::MessageId;
+
+/// Bounded set of recently seen messages.
+///
+/// Entries expire after `expiry`, and the oldest entries are evicted when
+/// `capacity` is exceeded.
+pub(super) struct Seen {
+    capacity: usize,
+    expiry: Duration,
+    seen: HashMap<MessageId, Instant>,
+    order: VecDeque<(MessageId, Instant)>,
+}
+
+impl Seen {
+    pub fn new(capacity: usize, expiry: Duration) -> Self {
+        Self {
+            capacity,
+            expiry,
+            seen: HashMap::default(),
+            order: VecDeque::default(),
+        }
+    }
+
+    /// Record that `id` was seen at `now`.
+    ///
+    /// Returns `false` if it was already seen, and hasn't expired yet.
+    pub fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        self.expire(now);
+        if self.seen.contains_key(&id) {
+            return false;
+        }
+
+        self.seen.insert(id.clone(), now);
+        self.order.push_back((id, now));
+        while self.order.len() > self.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, at)) = self.order.front() {
+            if now.saturating_duration_since(*at) < self.expiry {
+                break;
+            }
+            if let Some((id, _)) = self.order.pop_front() {
+                self.seen.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> MessageId {
+        MessageId::of(&n).unwrap()
+    }
+
+    #[test]
+    fn dedup() {
+        let now = Instant::now();
+        let mut seen = Seen::new(8, Duration::from_secs(60));
+        assert!(seen.insert(id(0), now));
+        assert!(!seen.insert(id(0), now));
+        assert!(seen.insert(id(1), now));
+    }
+
+    #[test]
+    fn expire() {
+        let now = Instant::now();
+        let mut seen = Seen::new(8, Duration::from_secs(60));
+        assert!(seen.insert(id(0), now));
+        assert!(!seen.insert(id(0), now + Duration::from_secs(59)));
+        assert!(seen.insert(id(0), now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn bounded() {
+        let now = Instant::now();
+        let mut seen = Seen::new(2, Duration::from_secs(60));
+        assert!(seen.insert(id(0), now));
+        assert!(seen.insert(id(1), now));
+        assert!(seen.insert(id(2), now));
+        assert!(seen.insert(id(0), now));
+    }
+}
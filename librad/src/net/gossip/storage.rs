@@ -15,7 +15,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::peer::PeerId;
+use crate::{net::gossip::interest::Interests, peer::PeerId};
 
 #[derive(Clone, Copy, Debug)]
 pub enum PutResult {
@@ -23,6 +23,7 @@ pub enum PutResult {
     Stale,
     Uninteresting,
     Error,
+    Invalid,
 }
 
 #[async_trait]
@@ -40,6 +41,10 @@ pub trait LocalStorage: Clone + Send + Sync {
     /// up-to-date, or it was not possible to fetch the actual state from
     /// the `provider`. In this case, the network is asked to retransmit
     /// [`Self::Update`], so we can eventually try again.
+    ///
+    /// [`PutResult::Invalid`] is like [`PutResult::Error`], except that the
+    /// failure was caused by the `provider`, eg. because the state fetched
+    /// from it failed verification. The `provider` is penalised for it.
     async fn put(&self, provider: &PeerId, has: Self::Update) -> PutResult;
 
     /// Ask the local storage if value `A` is available.
@@ -47,4 +52,30 @@ pub trait LocalStorage: Clone + Send + Sync {
     /// This is used to notify the asking peer that they may fetch value `A`
     /// from us.
     async fn ask(&self, want: Self::Update) -> bool;
+
+    /// The [`Interests`] of the local peer, which other peers use to decide
+    /// whether to send us a value.
+    ///
+    /// `None`, the default, means we are interested in everything.
+    async fn interests(&self) -> Option<Interests> {
+        None
+    }
+
+    /// The key by which `val` is looked up in the [`Interests`] of other peers.
+    ///
+    /// `None`, the default, means `val` is of interest to everyone.
+    fn interest_key(&self, _val: &Self::Update) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Whether `origin`, the peer which signed an announcement of `val`, may
+    /// announce it.
+    ///
+    /// `val` may make claims on behalf of peers other than `origin`, which
+    /// `origin`'s signature doesn't vouch for. Announcements for which this
+    /// returns `false` are dropped, and the peer which sent them is penalised.
+    /// The default accepts every announcement.
+    fn may_announce(&self, _origin: &PeerId, _val: &Self::Update) -> bool {
+        true
+    }
 }
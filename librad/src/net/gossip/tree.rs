@@ -0,0 +1,181 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Bookkeeping for the broadcast tree: which messages we have seen, and which
+//! ones lazy peers told us about but we haven't received yet.
+
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+
+use crate::{net::gossip::types::MessageId, peer::PeerId};
+
+/// What became of a received message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Status {
+    /// The message was handled, further copies are redundant.
+    Delivered,
+    /// The message was relayed, but applying it failed. Further copies may
+    /// still be useful, as we asked for a retransmission.
+    Pending,
+}
+
+/// Bounded cache of received messages.
+///
+/// Messages are kept around so they can be sent to peers `Graft`ing onto the
+/// broadcast tree. When the cache is full, the oldest message is forgotten.
+pub(super) struct Messages<M> {
+    capacity: usize,
+    received: HashMap<MessageId, (Status, M)>,
+    order: VecDeque<MessageId>,
+    missing: HashMap<MessageId, VecDeque<PeerId>>,
+}
+
+impl<M> Messages<M> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            received: HashMap::default(),
+            order: VecDeque::default(),
+            missing: HashMap::default(),
+        }
+    }
+
+    pub fn status(&self, id: &MessageId) -> Option<Status> {
+        self.received.get(id).map(|(status, _)| *status)
+    }
+
+    pub fn get(&self, id: &MessageId) -> Option<&M> {
+        self.received.get(id).map(|(_, msg)| msg)
+    }
+
+    /// Record that the message identified by `id` was received.
+    pub fn received(&mut self, id: MessageId, msg: M, status: Status) {
+        self.missing.remove(&id);
+        match self.received.entry(id) {
+            Entry::Occupied(mut entry) => {
+                entry.insert((status, msg));
+            },
+            Entry::Vacant(entry) => {
+                self.order.push_back(entry.key().clone());
+                entry.insert((status, msg));
+            },
+        }
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+    }
+
+    /// Record that `peer` announced the message identified by `id` via
+    /// `IHave`.
+    ///
+    /// Returns `true` if the message is now known to be missing, ie. it has
+    /// not been received, and this is the first announcement of it. The caller
+    /// should then wait for the message to arrive, and eventually `Graft` onto
+    /// an announcer (see [`Messages::next_announcer`]).
+    pub fn announced(&mut self, id: MessageId, peer: PeerId) -> bool {
+        if self.received.contains_key(&id) {
+            return false;
+        }
+
+        let full = self.missing.len() >= self.capacity;
+        match self.missing.entry(id) {
+            Entry::Occupied(mut entry) => {
+                let announcers = entry.get_mut();
+                if !announcers.contains(&peer) {
+                    announcers.push_back(peer)
+                }
+                false
+            },
+            Entry::Vacant(entry) => {
+                if full {
+                    return false;
+                }
+                entry.insert(vec![peer].into());
+                true
+            },
+        }
+    }
+
+    /// The next peer to `Graft` onto for a missing message.
+    ///
+    /// Returns `None`, and stops tracking the message, if it has been received
+    /// in the meantime, or all announcers have been tried.
+    pub fn next_announcer(&mut self, id: &MessageId) -> Option<PeerId> {
+        let next = self
+            .missing
+            .get_mut(id)
+            .and_then(|announcers| announcers.pop_front());
+        if next.is_none() {
+            self.missing.remove(id);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keys::SecretKey;
+
+    fn peer() -> PeerId {
+        PeerId::from(SecretKey::new())
+    }
+
+    fn id(n: u8) -> MessageId {
+        MessageId::of(&n).unwrap()
+    }
+
+    #[test]
+    fn bounded() {
+        let mut msgs = Messages::new(2);
+        msgs.received(id(0), 0, Status::Delivered);
+        msgs.received(id(1), 1, Status::Pending);
+        msgs.received(id(2), 2, Status::Delivered);
+
+        assert_eq!(msgs.status(&id(0)), None);
+        assert_eq!(msgs.status(&id(1)), Some(Status::Pending));
+        assert_eq!(msgs.get(&id(2)), Some(&2));
+    }
+
+    #[test]
+    fn graft_announcers_in_order() {
+        let mut msgs = Messages::<u8>::new(8);
+        let (first, second) = (peer(), peer());
+
+        assert!(msgs.announced(id(0), first.clone()));
+        assert!(!msgs.announced(id(0), second.clone()));
+        assert!(!msgs.announced(id(0), first.clone()));
+
+        assert_eq!(msgs.next_announcer(&id(0)), Some(first));
+        assert_eq!(msgs.next_announcer(&id(0)), Some(second));
+        assert_eq!(msgs.next_announcer(&id(0)), None);
+    }
+
+    #[test]
+    fn received_is_not_missing() {
+        let mut msgs = Messages::new(8);
+        let announcer = peer();
+
+        assert!(msgs.announced(id(0), announcer.clone()));
+        msgs.received(id(0), 0, Status::Delivered);
+        assert_eq!(msgs.next_announcer(&id(0)), None);
+        assert!(!msgs.announced(id(0), announcer));
+    }
+}
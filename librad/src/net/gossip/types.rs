@@ -15,17 +15,117 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::{collections::HashSet, hash::Hash};
+use std::{collections::HashSet, hash::Hash, io};
 
-use minicbor::{Decode, Encode};
+use minicbor::{data::Type, Decode, Encode};
 
-use crate::peer::PeerId;
+use crate::{hash, net::upgrade, peer::PeerId};
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Encode, Decode)]
-#[repr(u8)]
+/// A protocol version or optional feature supported by a peer.
+///
+/// Capabilities are advertised via [`PeerAdvertisement`], and should be
+/// checked before using a feature with a remote peer.
+///
+/// # Wire Encoding
+///
+/// Capabilities are encoded as unsigned integers, so that peers can decode
+/// (and ignore) capabilities they don't know about as
+/// [`Capability::Unknown`].
+///
+/// Nodes predating capability advertisements encode a capability as a
+/// 2-element array, the first element being the same integer. This form is
+/// still accepted when decoding.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum Capability {
-    #[n(0)]
-    Reserved = 0,
+    Reserved,
+    /// Speaks the given version of the stream upgrade protocol, see
+    /// [`upgrade::VERSION`].
+    Version(u8),
+    /// Understands the `IHave`, `Prune`, and `Graft` gossip messages.
+    BroadcastTree,
+    /// Answers point-to-point requests via the [`upgrade::Rpc`] stream
+    /// upgrade.
+    Rpc,
+    /// Understands the [`Membership::Interests`] message, and may not send us
+    /// gossip we aren't interested in.
+    ///
+    /// [`Membership::Interests`]: crate::net::gossip::Membership::Interests
+    Interests,
+    /// A capability not known to this build.
+    Unknown(u32),
+}
+
+/// Offset of the [`Capability::Version`]s in the wire encoding.
+const VERSION_OFFSET: u32 = 0x100;
+
+impl Capability {
+    /// The capabilities of this build.
+    pub fn supported() -> HashSet<Self> {
+        (upgrade::MIN_VERSION..=upgrade::VERSION)
+            .map(Self::Version)
+            .chain(vec![Self::BroadcastTree, Self::Rpc, Self::Interests])
+            .collect()
+    }
+}
+
+impl From<u32> for Capability {
+    fn from(n: u32) -> Self {
+        match n {
+            0 => Self::Reserved,
+            1 => Self::BroadcastTree,
+            2 => Self::Rpc,
+            3 => Self::Interests,
+            n if (VERSION_OFFSET..=VERSION_OFFSET + u32::from(u8::MAX)).contains(&n) => {
+                Self::Version((n - VERSION_OFFSET) as u8)
+            },
+            n => Self::Unknown(n),
+        }
+    }
+}
+
+impl From<Capability> for u32 {
+    fn from(cap: Capability) -> Self {
+        match cap {
+            Capability::Reserved => 0,
+            Capability::BroadcastTree => 1,
+            Capability::Rpc => 2,
+            Capability::Interests => 3,
+            Capability::Version(v) => VERSION_OFFSET + u32::from(v),
+            Capability::Unknown(n) => n,
+        }
+    }
+}
+
+impl Encode for Capability {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.u32(u32::from(*self))?;
+        Ok(())
+    }
+}
+
+impl<'de> Decode<'de> for Capability {
+    fn decode(d: &mut minicbor::Decoder<'de>) -> Result<Self, minicbor::decode::Error> {
+        if d.datatype()? != Type::Array {
+            return d.u32().map(Self::from);
+        }
+
+        // Legacy encoding: `[n, fields]`
+        match d.array()? {
+            Some(len) if len > 0 => {
+                let n = d.u32()?;
+                for _ in 1..len {
+                    d.skip()?
+                }
+                Ok(Self::from(n))
+            },
+            _ => Err(minicbor::decode::Error::Message(
+                "expected non-empty, definite array",
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Encode, Decode)]
@@ -42,31 +142,221 @@ where
     pub seen_addrs: HashSet<Addr>,
 }
 
-#[derive(Debug, Clone, PartialEq, Encode, Decode)]
-#[cbor(array)]
+/// What a peer tells others about itself.
+///
+/// # Wire Encoding
+///
+/// A CBOR array of:
+///
+/// 0. `listen_addr`
+/// 1. `listen_port`
+/// 2. capabilities understood by nodes predating capability advertisements
+///    (ie. none, as they only knew [`Capability::Reserved`])
+/// 3. `capabilities`
+///
+/// Element 3 is skipped by older nodes, and may be absent when receiving from
+/// them. Any further elements are ignored, so that fields can be added in a
+/// compatible way.
+#[derive(Debug, Clone, PartialEq)]
 pub struct PeerAdvertisement<Addr>
 where
     Addr: Clone + PartialEq + Eq + Hash,
 {
-    #[n(0)]
     pub listen_addr: Addr,
-
-    #[n(1)]
     pub listen_port: u16,
-
-    #[n(2)]
     pub capabilities: HashSet<Capability>,
 }
 
+impl<Addr> Encode for PeerAdvertisement<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash + Encode,
+{
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(4)?
+            .encode(&self.listen_addr)?
+            .u16(self.listen_port)?
+            .array(0)?
+            .encode(&self.capabilities)?;
+        Ok(())
+    }
+}
+
+impl<'de, Addr> Decode<'de> for PeerAdvertisement<Addr>
+where
+    Addr: Clone + PartialEq + Eq + Hash + Decode<'de>,
+{
+    fn decode(d: &mut minicbor::Decoder<'de>) -> Result<Self, minicbor::decode::Error> {
+        let len = match d.array()? {
+            Some(len) if len >= 3 => len,
+            _ => {
+                return Err(minicbor::decode::Error::Message(
+                    "expected definite array of at least 3 elements",
+                ))
+            },
+        };
+
+        let listen_addr = d.decode()?;
+        let listen_port = d.u16()?;
+        let mut capabilities: HashSet<Capability> = d.decode()?;
+        if len > 3 {
+            capabilities.extend(d.decode::<HashSet<Capability>>()?);
+        }
+        for _ in 4..len {
+            d.skip()?
+        }
+
+        Ok(Self {
+            listen_addr,
+            listen_port,
+            capabilities,
+        })
+    }
+}
+
 impl<Addr> PeerAdvertisement<Addr>
 where
     Addr: Clone + PartialEq + Eq + Hash,
 {
+    /// Advertise `listen_addr` and `listen_port`, along with the
+    /// [`Capability::supported`] by this build.
     pub fn new(listen_addr: Addr, listen_port: u16) -> Self {
         Self {
             listen_addr,
             listen_port,
-            capabilities: HashSet::default(),
+            capabilities: Capability::supported(),
+        }
+    }
+
+    pub fn supports(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/// Identifies a gossip payload.
+///
+/// The identifier is derived from the payload alone, so the same announcement
+/// received via different routes is recognised as a duplicate.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Encode, Decode)]
+#[cbor(array)]
+pub struct MessageId(#[n(0)] hash::Hash);
+
+impl MessageId {
+    pub fn of<P: Encode>(payload: &P) -> Result<Self, minicbor::encode::Error<io::Error>> {
+        minicbor::to_vec(payload).map(|bytes| Self(hash::Hash::hash(&bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use librad_test::roundtrip::*;
+
+    #[test]
+    fn roundtrip_capability() {
+        cbor_roundtrip(Capability::Reserved);
+        cbor_roundtrip(Capability::Version(upgrade::VERSION));
+        cbor_roundtrip(Capability::Version(u8::MAX));
+        cbor_roundtrip(Capability::BroadcastTree);
+        cbor_roundtrip(Capability::Rpc);
+        cbor_roundtrip(Capability::Interests);
+        cbor_roundtrip(Capability::Unknown(42));
+    }
+
+    /// [`Capability`] and [`PeerAdvertisement`] as encoded by nodes predating
+    /// capability advertisements.
+    mod baseline {
+        use super::*;
+
+        #[derive(Debug, Clone, Hash, PartialEq, Eq, Encode, Decode)]
+        #[repr(u8)]
+        pub enum Capability {
+            #[n(0)]
+            Reserved = 0,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Encode, Decode)]
+        #[cbor(array)]
+        pub struct PeerAdvertisement<Addr>
+        where
+            Addr: Clone + PartialEq + Eq + Hash,
+        {
+            #[n(0)]
+            pub listen_addr: Addr,
+
+            #[n(1)]
+            pub listen_port: u16,
+
+            #[n(2)]
+            pub capabilities: HashSet<Capability>,
         }
     }
+
+    #[test]
+    fn roundtrip_peer_advertisement() {
+        cbor_roundtrip(PeerAdvertisement::new("127.0.0.1".to_owned(), 12345))
+    }
+
+    #[test]
+    fn decode_baseline_advertisement() {
+        let baseline = baseline::PeerAdvertisement {
+            listen_addr: "127.0.0.1".to_owned(),
+            listen_port: 12345,
+            capabilities: vec![baseline::Capability::Reserved].into_iter().collect(),
+        };
+        let ad: PeerAdvertisement<String> =
+            minicbor::decode(&minicbor::to_vec(&baseline).unwrap()).unwrap();
+
+        assert_eq!(ad.listen_addr, baseline.listen_addr);
+        assert_eq!(ad.listen_port, baseline.listen_port);
+        assert_eq!(
+            ad.capabilities,
+            vec![Capability::Reserved].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn baseline_decodes_advertisement() {
+        let ad = PeerAdvertisement::new("127.0.0.1".to_owned(), 12345);
+        let baseline: baseline::PeerAdvertisement<String> =
+            minicbor::decode(&minicbor::to_vec(&ad).unwrap()).unwrap();
+
+        assert_eq!(baseline.listen_addr, ad.listen_addr);
+        assert_eq!(baseline.listen_port, ad.listen_port);
+        assert!(baseline.capabilities.is_empty());
+    }
+
+    #[test]
+    fn ignore_unknown_advertisement_fields() {
+        let mut buf = Vec::new();
+        minicbor::Encoder::new(&mut buf)
+            .array(5)
+            .unwrap()
+            .str("127.0.0.1")
+            .unwrap()
+            .u16(12345)
+            .unwrap()
+            .array(0)
+            .unwrap()
+            .encode(&Capability::supported())
+            .unwrap()
+            .str("from the future")
+            .unwrap();
+        let ad: PeerAdvertisement<String> = minicbor::decode(&buf).unwrap();
+
+        assert_eq!(ad, PeerAdvertisement::new("127.0.0.1".to_owned(), 12345))
+    }
+
+    #[test]
+    fn ignore_unknown_capabilities() {
+        let unknown = minicbor::to_vec(&vec![1u32, 4242]).unwrap();
+        let caps: Vec<Capability> = minicbor::decode(&unknown).unwrap();
+        assert_eq!(
+            caps,
+            vec![Capability::BroadcastTree, Capability::Unknown(4242)]
+        )
+    }
 }
@@ -16,44 +16,63 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
+    collections::HashSet,
     future::Future,
     io,
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use either::Either;
 use futures::{
     future::{self, BoxFuture, FutureExt},
-    stream::StreamExt,
+    lock::Mutex,
+    stream::{self, StreamExt},
 };
 use futures_timer::Delay;
 use thiserror::Error;
-use tokio::task::spawn_blocking;
+use tokio::task::{spawn_blocking, JoinError};
 use tracing_futures::Instrument as _;
 
 use crate::{
     git::{
         self,
-        p2p::{server::GitServer, transport::GitStreamFactory},
+        p2p::{
+            server::{GitServer, Pushed},
+            transport::GitStreamFactory,
+        },
+        refs::{self, Refs},
         storage,
     },
+    hash::Hash,
     internal::channel::Fanout,
     keys::{self, AsPKCS8},
     net::{
+        addrbook::{self, AddressBook},
         connection::LocalInfo,
         discovery::Discovery,
         gossip::{self, LocalStorage, PeerInfo, PutResult},
-        protocol::{Protocol, ProtocolEvent},
+        protocol::{self, Protocol, ProtocolEvent},
         quic::{self, Endpoint},
+        reputation::{self, Ban, Reputation},
+        rpc,
     },
     paths::Paths,
     peer::{Originates, PeerId},
-    signer::Signer,
+    signer::{BoxedSigner, Signer, SomeSigner},
     uri::{self, RadUrl, RadUrn},
 };
 
+pub mod providers;
+pub use providers::{Provider, Providers};
+
+pub mod queue;
+pub use queue::Priority;
+
+pub mod sync;
+pub use sync::{SyncEvent, SyncResult};
+
 pub mod types;
 pub use types::*;
 
@@ -69,6 +88,57 @@ pub enum PeerStorageError {
 
     #[error(transparent)]
     Pool(#[from] deadpool::managed::PoolError<git::storage::Error>),
+
+    #[error("too many fetches queued")]
+    QueueFull,
+
+    #[error("{0}")]
+    Fetch(Arc<PeerStorageError>),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl PeerStorageError {
+    fn is_no_such_urn(&self) -> bool {
+        match self {
+            Self::Store(git::storage::Error::NoSuchUrn(_)) => true,
+            Self::Fetch(e) => e.is_no_such_urn(),
+            _ => false,
+        }
+    }
+
+    /// Whether this error was caused by the data the remote peer sent us,
+    /// rather than by our own storage.
+    fn is_invalid_remote(&self) -> bool {
+        match self {
+            Self::Store(e) => matches!(
+                e,
+                git::storage::Error::Rejected { .. }
+                    | git::storage::Error::RootHashMismatch { .. }
+                    | git::storage::Error::IdentityRootMismatch { .. }
+                    | git::storage::Error::VerifyUser(_)
+                    | git::storage::Error::VerifyProject(_)
+                    | git::storage::Error::Refsig(refs::signed::Error::InvalidSignature(_))
+            ),
+            Self::Fetch(e) => e.is_invalid_remote(),
+            _ => false,
+        }
+    }
+
+    /// Whether a fetch failing with this error may succeed if retried.
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Pool(_) => true,
+            Self::Store(e) => matches!(
+                e,
+                git::storage::Error::Fetch(_)
+                    | git::storage::Error::Git(_)
+                    | git::storage::Error::Io(_)
+            ),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -83,6 +153,12 @@ pub enum BootstrapError {
     #[error(transparent)]
     Storage(#[from] git::storage::Error),
 
+    #[error(transparent)]
+    Reputation(#[from] reputation::Error),
+
+    #[error(transparent)]
+    AddressBook(#[from] addrbook::Error),
+
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -102,6 +178,18 @@ pub enum ApiError {
 
     #[error(transparent)]
     Pool(#[from] deadpool::managed::PoolError<git::storage::Error>),
+
+    #[error(transparent)]
+    Reputation(#[from] reputation::Error),
+
+    #[error(transparent)]
+    Protocol(#[from] protocol::Error),
+
+    #[error(transparent)]
+    SignedRefs(#[from] refs::signed::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
 }
 
 /// Upstream events.
@@ -111,6 +199,11 @@ pub enum ApiError {
 #[derive(Clone, Debug)]
 pub enum PeerEvent {
     GossipFetch(FetchInfo),
+    /// Progress of the background sync with connected peers, see
+    /// [`sync::Params`].
+    Sync(SyncEvent),
+    /// Transfer progress of a clone or fetch started via [`PeerApi`].
+    FetchProgress(storage::FetchProgress),
 }
 
 /// Event payload for a fetch triggered by [`LocalStorage::put`]
@@ -129,6 +222,10 @@ pub struct PeerConfig<Disco, Signer> {
     pub gossip_params: gossip::MembershipParams,
     pub disco: Disco,
     pub storage_config: StorageConfig,
+    pub sync_params: sync::Params,
+    /// Whether to accept pushes from peers we track, see
+    /// [`git::p2p::server::receive_pack`].
+    pub accept_pushes: bool,
 }
 
 impl<D, S> PeerConfig<D, S>
@@ -154,6 +251,12 @@ pub struct StorageConfig {
     ///
     /// Default: the number of physical cores available
     pub protocol_pool_size: usize,
+
+    /// Limits for fetches triggered by the network.
+    ///
+    /// Default: as many concurrent fetches as there are physical cores
+    /// available, see [`queue::Params`]
+    pub fetch_queue: queue::Params,
 }
 
 impl Default for StorageConfig {
@@ -161,6 +264,7 @@ impl Default for StorageConfig {
         Self {
             user_pool_size: num_cpus::get_physical(),
             protocol_pool_size: num_cpus::get_physical(),
+            fetch_queue: queue::Params::default(),
         }
     }
 }
@@ -174,6 +278,7 @@ pub struct PeerApi<S> {
     protocol: Protocol<PeerStorage<S>, Gossip>,
     storage: storage::Pool<S>,
     subscribers: Fanout<PeerEvent>,
+    providers: Arc<Mutex<Providers>>,
     paths: Paths,
 
     _git_transport_protocol_ref: Arc<Box<dyn GitStreamFactory>>,
@@ -200,7 +305,7 @@ where
         let storage = self.storage.get().await?;
         Ok(spawn_blocking(move || blocking(&storage))
             .await
-            .expect("blocking operation on storage panicked"))
+            .map_err(join_err)?)
     }
 
     pub fn peer_id(&self) -> &PeerId {
@@ -214,17 +319,66 @@ where
         async move { subscribers.subscribe().await }
     }
 
-    /// Query the network for providers of the given [`RadUrn`].
+    /// Clone the repo at `url`, reporting its progress as
+    /// [`PeerEvent::FetchProgress`].
+    ///
+    /// Once `cancel` is cancelled, the clone is aborted, and what was fetched
+    /// so far is deleted.
+    pub fn clone_repo(
+        &self,
+        url: RadUrl,
+        addr_hints: Vec<SocketAddr>,
+        cancel: storage::CancelToken,
+    ) -> impl Future<Output = Result<(), ApiError>> {
+        let storage = self.storage.clone();
+        let hooks = fetch_hooks(self.subscribers.clone(), cancel);
+        async move {
+            let git = storage.get().await?;
+            spawn_blocking(move || {
+                git.clone_repo_with(url, addr_hints, None, hooks)
+                    .map(|_| ())
+            })
+            .await
+            .map_err(join_err)??;
+            Ok(())
+        }
+    }
+
+    /// Fetch updates for the repo at `url`, reporting progress as
+    /// [`PeerEvent::FetchProgress`].
+    ///
+    /// Once `cancel` is cancelled, the fetch is aborted, and the remote
+    /// tracking refs are left as they were before.
+    pub fn fetch_repo(
+        &self,
+        url: RadUrl,
+        addr_hints: Vec<SocketAddr>,
+        cancel: storage::CancelToken,
+    ) -> impl Future<Output = Result<(), ApiError>> {
+        let storage = self.storage.clone();
+        let hooks = fetch_hooks(self.subscribers.clone(), cancel);
+        async move {
+            let git = storage.get().await?;
+            spawn_blocking(move || git.fetch_repo_with(url, addr_hints, hooks))
+                .await
+                .map_err(join_err)??;
+            Ok(())
+        }
+    }
+
+    /// Find providers of the given [`RadUrn`].
     ///
-    /// This is a convenience for the special case of issuing a gossip `Want`
-    /// message where we don't know a specific revision, nor an origin peer.
-    /// Consequently, any `Have` message with a matching `urn` should do for
-    /// attempting a clone, even if it isn't a direct response to our query.
+    /// Providers we already know about from the local [`Providers`] index are
+    /// yielded immediately, most reliable first. The network is then queried
+    /// for the rest, by issuing a gossip `Want` message where we don't know a
+    /// specific revision, nor an origin peer. Consequently, any `Have` message
+    /// for the same repository should do for attempting a clone, even if it
+    /// isn't a direct response to our query. Each provider is yielded at most
+    /// once.
     ///
     /// Note that there is no guarantee that a peer who claims to provide the
     /// [`RadUrn`] actually has it, nor that it is reachable using any of
-    /// the addresses contained in [`PeerInfo`]. The implementation may
-    /// change in the future to answer the query from a local cache first.
+    /// the addresses contained in [`PeerInfo`].
     ///
     /// The returned [`futures::Stream`] will be complete after the supplied
     /// `timeout` has elapsed, whether or not any responses have been yielded
@@ -238,11 +392,24 @@ where
     ) -> impl Future<Output = impl futures::Stream<Item = PeerInfo<IpAddr>>> {
         let span = tracing::trace_span!("PeerApi::providers", urn = %urn);
         let protocol = self.protocol.clone();
+        let providers = self.providers.clone();
         let target_urn = urn.clone();
 
         async move {
-            let providers = futures::stream::select(
-                futures::stream::once(
+            let cached = providers
+                .lock()
+                .await
+                .get(&urn, SystemTime::now())
+                .into_iter()
+                .map(|provider| provider.info)
+                .collect::<Vec<_>>();
+            let mut yielded = cached
+                .iter()
+                .map(|info| info.peer_id.clone())
+                .collect::<HashSet<_>>();
+
+            let queried = stream::select(
+                stream::once(
                     async move {
                         Delay::new(timeout).await;
                         Err("timed out")
@@ -257,14 +424,17 @@ where
                             ProtocolEvent::Gossip(gossip::Info::Has(gossip::Has {
                                 provider,
                                 val,
-                            })) if val.urn == urn => Some(provider),
+                            })) if val.urn.id == urn.id && val.urn.proto == urn.proto => {
+                                Some(provider)
+                            },
                             _ => None,
                         })
                     })
                     .map(Ok),
             )
             .take_while(|x| future::ready(x.is_ok()))
-            .map(Result::unwrap);
+            .map(Result::unwrap)
+            .filter(move |provider| future::ready(yielded.insert(provider.peer_id.clone())));
 
             protocol
                 .query(Gossip {
@@ -275,7 +445,86 @@ where
                 .instrument(span)
                 .await;
 
-            providers
+            stream::iter(cached).chain(queried)
+        }
+    }
+
+    /// The providers of the given [`RadUrn`] we know about, without querying
+    /// the network.
+    ///
+    /// See [`Providers::get`].
+    pub fn cached_providers(&self, urn: RadUrn) -> impl Future<Output = Vec<Provider>> {
+        let providers = self.providers.clone();
+        async move { providers.lock().await.get(&urn, SystemTime::now()) }
+    }
+
+    /// Ban `peer_id` for `duration`, or indefinitely if `None`.
+    ///
+    /// Banned peers are disconnected, and we refuse to talk to them until the
+    /// ban is lifted. Bans are persisted across restarts.
+    pub fn ban(
+        &self,
+        peer_id: PeerId,
+        duration: Option<Duration>,
+    ) -> impl Future<Output = Result<(), ApiError>> {
+        let protocol = self.protocol.clone();
+        async move { Ok(protocol.ban(&peer_id, duration).await?) }
+    }
+
+    /// Lift the ban of `peer_id`.
+    ///
+    /// Returns `true` if `peer_id` was banned.
+    pub fn unban(&self, peer_id: PeerId) -> impl Future<Output = Result<bool, ApiError>> {
+        let protocol = self.protocol.clone();
+        async move { Ok(protocol.unban(&peer_id).await?) }
+    }
+
+    /// The currently banned peers.
+    pub fn bans(&self) -> impl Future<Output = Vec<(PeerId, Ban)>> {
+        let protocol = self.protocol.clone();
+        async move { protocol.bans().await }
+    }
+
+    /// Measure the round-trip time of a request to the connected peer
+    /// `peer_id`.
+    pub fn ping(&self, peer_id: PeerId) -> impl Future<Output = Result<Duration, ApiError>> {
+        let protocol = self.protocol.clone();
+        async move { Ok(protocol.ping(&peer_id).await?) }
+    }
+
+    /// Ask the connected peer `peer_id` for its [`gossip::PeerAdvertisement`].
+    pub fn advertisement_of(
+        &self,
+        peer_id: PeerId,
+    ) -> impl Future<Output = Result<gossip::PeerAdvertisement<IpAddr>, ApiError>> {
+        let protocol = self.protocol.clone();
+        async move { Ok(protocol.advertisement_of(&peer_id).await?) }
+    }
+
+    /// Ask the connected peer `peer_id` which [`RadUrn`]s it replicates.
+    pub fn urns_of(&self, peer_id: PeerId) -> impl Future<Output = Result<Vec<RadUrn>, ApiError>> {
+        let protocol = self.protocol.clone();
+        async move { Ok(protocol.urns_of(&peer_id).await?) }
+    }
+
+    /// Ask the connected peer `peer_id` for its signed refs of `urn`.
+    ///
+    /// Returns `None` if `peer_id` doesn't have `urn`, and an error if the
+    /// signature of the refs is not valid.
+    pub fn signed_refs_of(
+        &self,
+        peer_id: PeerId,
+        urn: RadUrn,
+    ) -> impl Future<Output = Result<Option<Refs>, ApiError>> {
+        let protocol = self.protocol.clone();
+        async move {
+            match protocol.signed_refs_of(&peer_id, urn).await? {
+                None => Ok(None),
+                Some(json) => {
+                    let signed = refs::Signed::from_json(&json, &peer_id)?;
+                    Ok(Some(Refs::from(signed)))
+                },
+            }
         }
     }
 
@@ -307,6 +556,7 @@ pub struct Peer<S> {
     protocol: Protocol<PeerStorage<S>, Gossip>,
     run_loop: RunLoop,
     subscribers: Fanout<PeerEvent>,
+    providers: Arc<Mutex<Providers>>,
 
     // We cannot cast `Arc<Box<Protocol<A, B>>>` to `Arc<Box<dyn GitStreamFactory>>`
     // apparenty, so need to keep an `Arc` of the trait object here in order to
@@ -335,6 +585,7 @@ where
             storage: self.storage,
             protocol: self.protocol,
             subscribers: self.subscribers,
+            providers: self.providers,
             paths: self.paths,
 
             _git_transport_protocol_ref: self._git_transport_protocol_ref,
@@ -350,7 +601,7 @@ where
     {
         let peer_id = PeerId::from_signer(&config.signer);
 
-        let git = GitServer::new(&config.paths);
+        let git = GitServer::new(&config.paths).with_receive_pack(config.accept_pushes);
 
         let endpoint = Endpoint::bind(&config.signer, config.listen_addr)
             .await
@@ -360,6 +611,9 @@ where
             })?;
         let listen_addr = endpoint.local_addr()?;
 
+        let gossip_signer = BoxedSigner::from(SomeSigner {
+            signer: config.signer.clone(),
+        });
         let subscribers = Fanout::new();
         let user_storage = storage::Pool::new(
             storage::pool::Config::new(config.paths.clone(), config.signer.clone()),
@@ -370,17 +624,44 @@ where
                 storage::pool::Config::new(config.paths.clone(), config.signer),
                 config.storage_config.protocol_pool_size,
             ),
+            fetches: queue::FetchQueue::new(config.storage_config.fetch_queue),
             subscribers: subscribers.clone(),
         };
+        let sync_storage = peer_storage.clone();
 
+        let addr_book = AddressBook::load(addrbook::default_path(&config.paths))?;
         let gossip = gossip::Protocol::new(
             &peer_id,
             gossip::PeerAdvertisement::new(listen_addr.ip(), listen_addr.port()),
+            gossip_signer,
             config.gossip_params,
             peer_storage,
+            addr_book,
         );
 
-        let (protocol, run_loop) = Protocol::new(gossip, git, endpoint, config.disco.discover());
+        let reputation = Reputation::load(
+            config.paths.net_dir().join("bans.jsonl"),
+            reputation::Params::default(),
+            SystemTime::now(),
+        )?;
+
+        let mut disco = config.disco;
+        disco.local_peer(&peer_id, listen_addr);
+
+        let (protocol, run_loop) =
+            Protocol::new(gossip, git, endpoint, disco.discover(), reputation);
+
+        let providers = Arc::new(Mutex::new(Providers::default()));
+        let run_loop = {
+            let protocol_events = protocol.subscribe().await;
+            let peer_events = subscribers.subscribe().await;
+            let index = index_providers(providers.clone(), protocol_events, peer_events);
+            let sync = sync::run(config.sync_params, protocol.clone(), sync_storage);
+            let pushes = announce_pushes(protocol.clone(), protocol.subscribe().await);
+            future::select_all(vec![run_loop, index.boxed(), sync.boxed(), pushes.boxed()])
+                .map(|_| ())
+                .boxed()
+        };
         let _git_transport_protocol_ref =
             Arc::new(Box::new(protocol.clone()) as Box<dyn GitStreamFactory>);
         git::p2p::transport::register()
@@ -394,14 +675,108 @@ where
             protocol,
             run_loop,
             subscribers,
+            providers,
             _git_transport_protocol_ref,
         })
     }
 }
 
+/// Record providers in the [`Providers`] index as they announce [`RadUrn`]s,
+/// and as we fetch from them.
+///
+/// Nb. this must not hold on to a [`Protocol`], lest the run loop never
+/// terminates.
+async fn index_providers(
+    providers: Arc<Mutex<Providers>>,
+    protocol_events: impl futures::Stream<Item = ProtocolEvent<Gossip>> + Send,
+    peer_events: impl futures::Stream<Item = PeerEvent> + Send,
+) {
+    enum Event {
+        Announced(PeerInfo<IpAddr>, RadUrn),
+        Fetched(FetchInfo),
+        Prune,
+    }
+
+    let announced = protocol_events.filter_map(|evt| {
+        future::ready(match evt {
+            ProtocolEvent::Gossip(gossip::Info::Has(gossip::Has { provider, val })) => {
+                Some(Event::Announced(provider, val.urn))
+            },
+            _ => None,
+        })
+    });
+    let fetched = peer_events.filter_map(|evt| {
+        future::ready(match evt {
+            PeerEvent::GossipFetch(info) => Some(Event::Fetched(info)),
+            _ => None,
+        })
+    });
+    let prune = stream::unfold((), |()| async {
+        Delay::new(providers::Params::default().max_age).await;
+        Some((Event::Prune, ()))
+    });
+
+    stream::select(stream::select(announced, fetched), prune)
+        .for_each(|evt| {
+            let providers = providers.clone();
+            async move {
+                let mut providers = providers.lock().await;
+                let now = SystemTime::now();
+                match evt {
+                    Event::Announced(provider, urn) => providers.announced(&urn, provider, now),
+                    Event::Fetched(FetchInfo {
+                        provider,
+                        gossip,
+                        result,
+                    }) => providers.fetched(&gossip.urn, &provider, result, now),
+                    Event::Prune => providers.prune(now),
+                }
+            }
+        })
+        .await
+}
+
+/// Announce the branches other peers pushed to us.
+///
+/// We can't sign on behalf of the pusher, so the branches are announced as
+/// ours, under the pusher's remote. Other peers then fetch them from us.
+async fn announce_pushes<S>(
+    protocol: Protocol<PeerStorage<S>, Gossip>,
+    protocol_events: impl futures::Stream<Item = ProtocolEvent<Gossip>> + Send,
+) where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    protocol_events
+        .filter_map(|evt| {
+            future::ready(match evt {
+                ProtocolEvent::Pushed(pushed) => Some(pushed),
+                _ => None,
+            })
+        })
+        .for_each(|Pushed { peer, urn, heads }| {
+            let protocol = protocol.clone();
+            async move {
+                for (head, oid) in heads {
+                    let branch = head.strip_prefix("refs/").unwrap_or(&head);
+                    match uri::Path::parse(format!("refs/remotes/{}/{}", peer, branch)) {
+                        Ok(path) => {
+                            protocol
+                                .announce(Gossip::new(urn.id.clone(), path, Rev::Git(oid), None))
+                                .await
+                        },
+                        Err(e) => tracing::warn!(head = %head, "Invalid pushed branch: {}", e),
+                    }
+                }
+            }
+        })
+        .await
+}
+
 #[derive(Clone)]
 pub struct PeerStorage<S> {
     inner: storage::Pool<S>,
+    fetches: queue::FetchQueue<(Hash, PeerId), PeerStorageError>,
     subscribers: Fanout<PeerEvent>,
 }
 
@@ -410,32 +785,56 @@ where
     S: Signer + Clone,
     S::Error: keys::SignError,
 {
+    /// Fetch `urn` from `from`, unless we already have `head`.
+    ///
+    /// The fetch is run via the fetch queue, so concurrent requests to fetch
+    /// the same repository from the same peer are coalesced.
     async fn git_fetch<'a>(
         &'a self,
         from: &PeerId,
         urn: Either<RadUrn, Originates<RadUrn>>,
         head: impl Into<Option<git2::Oid>>,
+        priority: Priority,
     ) -> Result<(), PeerStorageError> {
-        let git = self.inner.get().await?;
-        let urn = urn_context(git.peer_id(), urn);
-        let head = head.into();
-        let from = from.clone();
-
-        spawn_blocking(move || {
-            if let Some(head) = head {
-                if git.has_commit(&urn, head)? {
+        let urn = {
+            let git = self.inner.get().await?;
+            let urn = urn_context(git.peer_id(), urn);
+            if let Some(head) = head.into() {
+                let has_commit = {
+                    let urn = urn.clone();
+                    spawn_blocking(move || git.has_commit(&urn, head))
+                        .await
+                        .map_err(join_err)??
+                };
+                if has_commit {
                     return Err(PeerStorageError::KnownObject(head));
                 }
             }
+            urn
+        };
 
-            let url = RadUrl {
-                authority: from,
-                urn,
-            };
-            git.fetch_repo(url, None).map_err(|e| e.into())
-        })
-        .await
-        .expect("`PeerStorage::git_fetch` panicked")
+        let key = (urn.id.clone(), from.clone());
+        let fetch = {
+            let pool = self.inner.clone();
+            let from = from.clone();
+            move || {
+                fetch_repo(
+                    pool.clone(),
+                    RadUrl {
+                        authority: from.clone(),
+                        urn: urn.clone(),
+                    },
+                )
+            }
+        };
+
+        self.fetches
+            .fetch(key, priority, fetch, PeerStorageError::is_transient)
+            .await
+            .map_err(|e| match e {
+                queue::Error::Full => PeerStorageError::QueueFull,
+                queue::Error::Fetch(e) => PeerStorageError::Fetch(e),
+            })
     }
 
     /// Determine if we have the given object locally
@@ -452,17 +851,69 @@ where
             Some(head) => git.has_commit(&urn, head).unwrap_or(false),
         })
         .await
-        .expect("`PeerStorage::git_has` panicked")
+        .unwrap_or_else(|e| {
+            tracing::error!(err = %e, "`PeerStorage::git_has` panicked");
+            false
+        })
+    }
+
+    /// Our copy of the signed refs of `peer`, if any.
+    async fn signed_refs_of(
+        &self,
+        urn: RadUrn,
+        peer: PeerId,
+    ) -> Result<Option<Refs>, PeerStorageError> {
+        let git = self.inner.get().await?;
+        spawn_blocking(move || match git.rad_signed_refs_of(&urn, peer) {
+            Ok(refs) => Ok(Some(refs)),
+            Err(storage::Error::Blob(git::ext::blob::Error::NotFound(_))) => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+        .await
+        .map_err(join_err)?
     }
 
     async fn is_tracked(&self, urn: RadUrn, peer: PeerId) -> Result<bool, PeerStorageError> {
         let git = self.inner.get().await?;
         Ok(spawn_blocking(move || git.is_tracked(&urn, &peer))
             .await
-            .expect("`PeerStorage::is_tracked` panicked")?)
+            .map_err(join_err)??)
     }
 }
 
+async fn fetch_repo<S>(pool: storage::Pool<S>, url: RadUrl) -> Result<(), PeerStorageError>
+where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    let git = pool.get().await?;
+    spawn_blocking(move || git.fetch_repo(url, None))
+        .await
+        .map_err(join_err)?
+        .map_err(PeerStorageError::from)
+}
+
+/// A blocking task failed to complete, because it panicked or the runtime is
+/// shutting down.
+fn join_err(e: JoinError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Hooks forwarding the progress of a fetch to `subscribers`.
+fn fetch_hooks(
+    subscribers: Fanout<PeerEvent>,
+    cancel: storage::CancelToken,
+) -> storage::FetchHooks {
+    storage::FetchHooks::default()
+        .with_cancel(cancel)
+        .on_progress(move |progress| {
+            // Called from the blocking fetch, and `emit` doesn't block for long
+            futures::executor::block_on(
+                subscribers.emit(PeerEvent::FetchProgress(progress.clone())),
+            )
+        })
+}
+
 /// If applicable, map the [`uri::Path`] of the given [`RadUrn`] to
 /// `refs/remotes/<origin>/<path>`
 fn urn_context(local_peer_id: &PeerId, urn: Either<RadUrn, Originates<RadUrn>>) -> RadUrn {
@@ -511,7 +962,7 @@ where
         match has.urn.proto {
             uri::Protocol::Git => {
                 let peer_id = has.origin.clone().unwrap_or_else(|| provider.clone());
-                let is_tracked = match self.is_tracked(has.urn.clone(), peer_id).await {
+                let is_tracked = match self.is_tracked(has.urn.clone(), peer_id.clone()).await {
                     Ok(b) => b,
                     Err(e) => {
                         tracing::error!(err = %e, "Git::Storage::is_tracked error");
@@ -521,6 +972,18 @@ where
                 let res = match has.rev {
                     // TODO: may need to fetch eagerly if we tracked while offline (#141)
                     Some(Rev::Git(head)) if is_tracked => {
+                        // Fetches from peers we track go first
+                        let provider_tracked = &peer_id == provider
+                            || self
+                                .is_tracked(has.urn.clone(), provider.clone())
+                                .await
+                                .unwrap_or(false);
+                        let priority = if provider_tracked {
+                            Priority::Tracked
+                        } else {
+                            Priority::Normal
+                        };
+
                         let res = {
                             let this = self.clone();
                             let provider = provider.clone();
@@ -532,7 +995,7 @@ where
                                 }),
                                 None => Either::Left(has.urn),
                             };
-                            this.git_fetch(&provider, urn, head).await
+                            this.git_fetch(&provider, urn, head, priority).await
                         };
 
                         match res {
@@ -551,9 +1014,20 @@ where
                             },
                             Err(e) => match e {
                                 PeerStorageError::KnownObject(_) => PutResult::Stale,
-                                PeerStorageError::Store(storage::Error::NoSuchUrn(_)) => {
+                                // We'll catch up when syncing
+                                PeerStorageError::QueueFull => {
+                                    tracing::warn!("Fetch queue is full, dropping update");
                                     PutResult::Uninteresting
                                 },
+                                e if e.is_no_such_urn() => PutResult::Uninteresting,
+                                e if e.is_invalid_remote() => {
+                                    tracing::warn!(
+                                        provider = %provider,
+                                        err = %e,
+                                        "Provider sent invalid data"
+                                    );
+                                    PutResult::Invalid
+                                },
                                 e => {
                                     tracing::error!(err = %e, "Fetch error");
                                     PutResult::Error
@@ -599,4 +1073,107 @@ where
             },
         }
     }
+
+    async fn interests(&self) -> Option<gossip::Interests> {
+        // This is asked for whenever we advertise our interests, so only list
+        // the namespaces instead of verifying every identity. If that fails,
+        // we're interested in everything.
+        let git = match self.inner.get().await {
+            Ok(git) => git,
+            Err(e) => {
+                tracing::error!(err = %e, "Storage pool error");
+                return None;
+            },
+        };
+        let namespaces = match spawn_blocking(move || git.namespaces()).await {
+            Ok(Ok(namespaces)) => namespaces,
+            Ok(Err(e)) => {
+                tracing::error!(err = %e, "Git::Storage::namespaces error");
+                return None;
+            },
+            Err(e) => {
+                tracing::error!(err = %e, "`PeerStorage::interests` panicked");
+                return None;
+            },
+        };
+        let keys = namespaces
+            .iter()
+            .filter_map(interest_key)
+            .collect::<Vec<_>>();
+        Some(gossip::Interests::from_keys(keys, INTEREST_FP_RATE))
+    }
+
+    fn interest_key(&self, val: &Self::Update) -> Option<Vec<u8>> {
+        interest_key(&val.urn.id)
+    }
+
+    fn may_announce(&self, origin: &PeerId, val: &Self::Update) -> bool {
+        val.may_be_announced_by(origin)
+    }
+}
+
+/// False positive rate of the [`gossip::Interests`] we advertise.
+const INTEREST_FP_RATE: f64 = 0.01;
+
+/// We're interested in all updates to a repository, regardless of the path of
+/// the [`RadUrn`], so the key is the namespace `id`.
+fn interest_key(id: &Hash) -> Option<Vec<u8>> {
+    minicbor::to_vec(id).ok()
+}
+
+#[async_trait]
+impl<S> rpc::LocalStorage for PeerStorage<S>
+where
+    S: Signer + Clone,
+    S::Error: keys::SignError,
+{
+    async fn urns(&self) -> Vec<RadUrn> {
+        let git = match self.inner.get().await {
+            Ok(git) => git,
+            Err(e) => {
+                tracing::error!(err = %e, "Storage pool error");
+                return vec![];
+            },
+        };
+
+        spawn_blocking(move || match git.all_identities() {
+            Ok(identities) => identities
+                .filter_map(|id| id.ok().map(|id| storage::rad_urn(&id.urn())))
+                .collect(),
+            Err(e) => {
+                tracing::error!(err = %e, "Git::Storage::all_identities error");
+                vec![]
+            },
+        })
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!(err = %e, "`PeerStorage::urns` panicked");
+            vec![]
+        })
+    }
+
+    async fn signed_refs(&self, urn: &RadUrn) -> Option<Vec<u8>> {
+        let git = match self.inner.get().await {
+            Ok(git) => git,
+            Err(e) => {
+                tracing::error!(err = %e, "Storage pool error");
+                return None;
+            },
+        };
+        let urn = urn.clone();
+
+        spawn_blocking(move || match git.rad_signed_refs_json(&urn) {
+            Ok(json) => Some(json),
+            Err(storage::Error::Blob(git::ext::blob::Error::NotFound(_))) => None,
+            Err(e) => {
+                tracing::error!(err = %e, "Git::Storage::rad_signed_refs_json error");
+                None
+            },
+        })
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!(err = %e, "`PeerStorage::signed_refs` panicked");
+            None
+        })
+    }
 }
@@ -0,0 +1,288 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Index of the peers which provide a [`RadUrn`].
+//!
+//! Providers are recorded when they announce a [`RadUrn`], and their
+//! reachability is tracked by recording whether fetching from them succeeded.
+//! The index is keyed by the repository a [`RadUrn`] refers to, ie. its path
+//! is ignored.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    net::gossip::{PeerInfo, PutResult},
+    peer::PeerId,
+    uri::{self, RadUrn},
+};
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Providers which haven't announced, nor been fetched from, for this long
+    /// are forgotten.
+    pub max_age: Duration,
+    /// Maximum number of providers to remember per [`RadUrn`].
+    pub max_providers: usize,
+    /// Providers we failed to fetch from this many times in a row are
+    /// forgotten.
+    pub max_failures: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(60 * 60),
+            max_providers: 32,
+            max_failures: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    pub info: PeerInfo<IpAddr>,
+    /// When the provider last announced the [`RadUrn`].
+    pub last_seen: SystemTime,
+    /// When we last fetched the [`RadUrn`] from the provider, if ever.
+    pub last_fetched: Option<SystemTime>,
+    /// Number of failed fetches from the provider since the last successful
+    /// one.
+    pub failures: u32,
+}
+
+impl Provider {
+    fn last_active(&self) -> SystemTime {
+        self.last_fetched
+            .map(|fetched| fetched.max(self.last_seen))
+            .unwrap_or(self.last_seen)
+    }
+}
+
+pub struct Providers {
+    params: Params,
+    index: HashMap<RadUrn, HashMap<PeerId, Provider>>,
+}
+
+impl Providers {
+    pub fn new(params: Params) -> Self {
+        Self {
+            params,
+            index: HashMap::new(),
+        }
+    }
+
+    /// Record that `provider` announced `urn`.
+    pub fn announced(&mut self, urn: &RadUrn, provider: PeerInfo<IpAddr>, now: SystemTime) {
+        let max_providers = self.params.max_providers;
+        let providers = self.index.entry(repo(urn)).or_default();
+        providers
+            .entry(provider.peer_id.clone())
+            .and_modify(|known| {
+                known.info = provider.clone();
+                known.last_seen = now;
+            })
+            .or_insert_with(|| Provider {
+                info: provider,
+                last_seen: now,
+                last_fetched: None,
+                failures: 0,
+            });
+
+        while providers.len() > max_providers {
+            let stalest = providers
+                .iter()
+                .min_by_key(|(_, known)| (Reverse(known.failures), known.last_active()))
+                .map(|(peer_id, _)| peer_id.clone());
+            match stalest {
+                Some(peer_id) => providers.remove(&peer_id),
+                None => break,
+            };
+        }
+    }
+
+    /// Record the `result` of fetching `urn` from `provider`.
+    ///
+    /// Only providers which are already known are updated.
+    pub fn fetched(&mut self, urn: &RadUrn, provider: &PeerId, result: PutResult, now: SystemTime) {
+        let repo = repo(urn);
+        let max_failures = self.params.max_failures;
+        let providers = match self.index.get_mut(&repo) {
+            Some(providers) => providers,
+            None => return,
+        };
+
+        match result {
+            PutResult::Applied | PutResult::Stale => {
+                if let Some(known) = providers.get_mut(provider) {
+                    known.last_fetched = Some(now);
+                    known.failures = 0;
+                }
+            },
+            PutResult::Error | PutResult::Invalid => {
+                let forget = providers
+                    .get_mut(provider)
+                    .map(|known| {
+                        known.failures = known.failures.saturating_add(1);
+                        known.failures >= max_failures
+                    })
+                    .unwrap_or(false);
+                if forget {
+                    providers.remove(provider);
+                }
+            },
+            PutResult::Uninteresting => {},
+        }
+
+        if providers.is_empty() {
+            self.index.remove(&repo);
+        }
+    }
+
+    /// The known providers of `urn`, most reliable and recently active first.
+    pub fn get(&self, urn: &RadUrn, now: SystemTime) -> Vec<Provider> {
+        let mut providers = self
+            .index
+            .get(&repo(urn))
+            .map(|providers| {
+                providers
+                    .values()
+                    .filter(|known| self.is_fresh(known, now))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        providers.sort_by_key(|known| (known.failures, Reverse(known.last_active())));
+        providers
+    }
+
+    /// Forget about providers which haven't been active for too long.
+    pub fn prune(&mut self, now: SystemTime) {
+        let max_age = self.params.max_age;
+        self.index = std::mem::take(&mut self.index)
+            .into_iter()
+            .filter_map(|(urn, providers)| {
+                let providers = providers
+                    .into_iter()
+                    .filter(|(_, known)| is_fresh(known, now, max_age))
+                    .collect::<HashMap<_, _>>();
+                if providers.is_empty() {
+                    None
+                } else {
+                    Some((urn, providers))
+                }
+            })
+            .collect();
+    }
+
+    fn is_fresh(&self, provider: &Provider, now: SystemTime) -> bool {
+        is_fresh(provider, now, self.params.max_age)
+    }
+}
+
+impl Default for Providers {
+    fn default() -> Self {
+        Self::new(Params::default())
+    }
+}
+
+fn is_fresh(provider: &Provider, now: SystemTime, max_age: Duration) -> bool {
+    now.duration_since(provider.last_active())
+        .map(|age| age < max_age)
+        .unwrap_or(true)
+}
+
+/// The [`RadUrn`] of the repository `urn` refers to.
+fn repo(urn: &RadUrn) -> RadUrn {
+    RadUrn::new(urn.id.clone(), urn.proto, uri::Path::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::{hash::Hash, keys::SecretKey, net::gossip::PeerAdvertisement};
+
+    fn urn(path: &str) -> RadUrn {
+        RadUrn::new(
+            Hash::hash(b"le project"),
+            uri::Protocol::Git,
+            uri::Path::parse(path).unwrap(),
+        )
+    }
+
+    fn provider() -> PeerInfo<IpAddr> {
+        PeerInfo {
+            peer_id: PeerId::from(SecretKey::new()),
+            advertised_info: PeerAdvertisement::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            seen_addrs: Default::default(),
+        }
+    }
+
+    #[test]
+    fn ignore_path() {
+        let mut providers = Providers::default();
+        let now = SystemTime::now();
+        let provider = provider();
+        providers.announced(&urn("refs/heads/master"), provider.clone(), now);
+
+        let found = providers.get(&urn(""), now);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].info, provider);
+    }
+
+    #[test]
+    fn prefer_reachable() {
+        let mut providers = Providers::default();
+        let now = SystemTime::now();
+        let (reachable, unreachable) = (provider(), provider());
+        providers.announced(&urn(""), unreachable.clone(), now);
+        providers.announced(&urn(""), reachable.clone(), now);
+        providers.fetched(&urn(""), &reachable.peer_id, PutResult::Applied, now);
+        providers.fetched(&urn(""), &unreachable.peer_id, PutResult::Error, now);
+
+        let found = providers
+            .get(&urn(""), now)
+            .into_iter()
+            .map(|known| known.info.peer_id)
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![reachable.peer_id, unreachable.peer_id.clone()]);
+
+        for _ in 1..Params::default().max_failures {
+            providers.fetched(&urn(""), &unreachable.peer_id, PutResult::Error, now);
+        }
+        assert_eq!(providers.get(&urn(""), now).len(), 1);
+    }
+
+    #[test]
+    fn expire() {
+        let mut providers = Providers::default();
+        let now = SystemTime::now();
+        providers.announced(&urn(""), provider(), now);
+
+        let later = now + Params::default().max_age;
+        assert!(providers.get(&urn(""), later).is_empty());
+        providers.prune(later);
+        assert!(providers.index.is_empty());
+    }
+}
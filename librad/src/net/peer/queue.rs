@@ -0,0 +1,505 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Queue for fetches triggered by the network.
+//!
+//! Requests for the same key (eg. the same repository from the same peer) are
+//! coalesced into a single fetch, the result of which is shared by all
+//! requesters. At most [`Params::max_concurrent`] fetches run at a time, the
+//! others wait in order of their [`Priority`]. Failed fetches are retried with
+//! exponential backoff.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, FutureExt, Shared},
+};
+use futures_timer::Delay;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    /// Maximum number of fetches to run concurrently.
+    pub max_concurrent: usize,
+    /// Maximum number of distinct fetches to keep, running or waiting. Further
+    /// requests are rejected.
+    pub max_queued: usize,
+    /// How often to retry a failed fetch.
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Doubled for every subsequent
+    /// one.
+    pub backoff: Duration,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            max_concurrent: num_cpus::get_physical(),
+            max_queued: 256,
+            max_retries: 2,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Fetches with higher priority are run first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Eg. catching up with peers in the background.
+    Background,
+    /// Fetching from a peer we don't track.
+    Normal,
+    /// Fetching from a peer we track.
+    Tracked,
+}
+
+#[derive(Debug)]
+pub(super) enum Error<E> {
+    /// Too many fetches are queued already.
+    Full,
+    /// The fetch failed, even after retrying.
+    Fetch(Arc<E>),
+}
+
+// Not derived, as `E` needn't be `Clone`
+impl<E> Clone for Error<E> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Full => Self::Full,
+            Self::Fetch(e) => Self::Fetch(e.clone()),
+        }
+    }
+}
+
+type Job<E> = Shared<BoxFuture<'static, Result<(), Error<E>>>>;
+
+struct Waiting<K> {
+    key: K,
+    priority: Priority,
+    seq: u64,
+    ready: oneshot::Sender<()>,
+}
+
+struct State<K, E> {
+    params: Params,
+    seq: u64,
+    running: usize,
+    jobs: HashMap<K, Job<E>>,
+    waiting: Vec<Waiting<K>>,
+}
+
+impl<K, E> State<K, E>
+where
+    K: PartialEq,
+{
+    /// Hand the slot of a finished fetch to the waiting one with the highest
+    /// priority, or free it if there is none.
+    fn release(&mut self) {
+        while !self.waiting.is_empty() {
+            let next = self
+                .waiting
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, waiting)| (waiting.priority, Reverse(waiting.seq)))
+                .map(|(i, _)| i)
+                .expect("`waiting` is not empty");
+            // The receiver may have given up already
+            if self.waiting.swap_remove(next).ready.send(()).is_ok() {
+                return;
+            }
+        }
+        self.running = self.running.saturating_sub(1)
+    }
+
+    fn raise(&mut self, key: &K, priority: Priority) {
+        for waiting in self
+            .waiting
+            .iter_mut()
+            .filter(|waiting| &waiting.key == key)
+        {
+            waiting.priority = waiting.priority.max(priority)
+        }
+    }
+}
+
+pub(super) struct FetchQueue<K, E> {
+    state: Arc<Mutex<State<K, E>>>,
+}
+
+// Not derived, as neither `K` nor `E` needs to be `Clone`
+impl<K, E> Clone for FetchQueue<K, E> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K, E> FetchQueue<K, E>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    pub fn new(params: Params) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                params,
+                seq: 0,
+                running: 0,
+                jobs: HashMap::new(),
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// Run `fetch` for `key`, or wait for the result of the fetch already
+    /// queued for `key`.
+    ///
+    /// If `fetch` fails, it is retried as long as `is_transient` holds for the
+    /// error.
+    pub async fn fetch<F, Fut, T>(
+        &self,
+        key: K,
+        priority: Priority,
+        fetch: F,
+        is_transient: T,
+    ) -> Result<(), Error<E>>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        T: Fn(&E) -> bool + Send + 'static,
+    {
+        let job = {
+            let mut state = self.state.lock().unwrap();
+            match state.jobs.get(&key) {
+                Some(job) => {
+                    let job = job.clone();
+                    state.raise(&key, priority);
+                    job
+                },
+                None if state.jobs.len() >= state.params.max_queued => return Err(Error::Full),
+                None => {
+                    let job = run(
+                        self.state.clone(),
+                        key.clone(),
+                        priority,
+                        fetch,
+                        is_transient,
+                    )
+                    .boxed()
+                    .shared();
+                    state.jobs.insert(key, job.clone());
+                    job
+                },
+            }
+        };
+
+        job.await
+    }
+}
+
+async fn run<K, E, F, Fut, T>(
+    state: Arc<Mutex<State<K, E>>>,
+    key: K,
+    priority: Priority,
+    fetch: F,
+    is_transient: T,
+) -> Result<(), Error<E>>
+where
+    K: Clone + Eq + Hash,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    T: Fn(&E) -> bool,
+{
+    // Forget about the job when done, or when all requesters gave up
+    let _job = JobGuard {
+        state: state.clone(),
+        key: key.clone(),
+    };
+    let Params {
+        max_retries,
+        backoff,
+        ..
+    } = state.lock().unwrap().params;
+
+    let mut retries = 0;
+    loop {
+        let res = {
+            let _slot = acquire(&state, &key, priority).await;
+            let fetch = fetch();
+            fetch.await
+        };
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if retries < max_retries && is_transient(&e) => {
+                Delay::new(backoff * 2u32.saturating_pow(retries)).await;
+                retries += 1;
+            },
+            Err(e) => return Err(Error::Fetch(Arc::new(e))),
+        }
+    }
+}
+
+/// Wait for a free slot to run a fetch in.
+async fn acquire<K, E>(state: &Arc<Mutex<State<K, E>>>, key: &K, priority: Priority) -> Slot<K, E>
+where
+    K: Clone + PartialEq,
+{
+    let (ready, seq) = {
+        let mut guard = state.lock().unwrap();
+        if guard.running < guard.params.max_concurrent {
+            guard.running += 1;
+            return Slot {
+                state: state.clone(),
+            };
+        }
+
+        let (tx, rx) = oneshot::channel();
+        guard.seq += 1;
+        let seq = guard.seq;
+        guard.waiting.push(Waiting {
+            key: key.clone(),
+            priority,
+            seq,
+            ready: tx,
+        });
+        (rx, seq)
+    };
+
+    let mut pending = Pending {
+        state: state.clone(),
+        seq,
+        granted: false,
+    };
+    // The sender is only dropped without sending if the queue is gone, in
+    // which case it doesn't matter whether we run.
+    let _ = ready.await;
+    pending.granted = true;
+
+    Slot {
+        state: state.clone(),
+    }
+}
+
+/// A running fetch. The slot is released when dropped.
+struct Slot<K, E>
+where
+    K: PartialEq,
+{
+    state: Arc<Mutex<State<K, E>>>,
+}
+
+impl<K, E> Drop for Slot<K, E>
+where
+    K: PartialEq,
+{
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release()
+    }
+}
+
+/// A fetch waiting for a slot.
+///
+/// If it gives up waiting, it is removed from the queue. If it was handed a
+/// slot in the meantime, the slot is released.
+struct Pending<K, E>
+where
+    K: PartialEq,
+{
+    state: Arc<Mutex<State<K, E>>>,
+    seq: u64,
+    granted: bool,
+}
+
+impl<K, E> Drop for Pending<K, E>
+where
+    K: PartialEq,
+{
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state
+            .waiting
+            .iter()
+            .position(|waiting| waiting.seq == self.seq)
+        {
+            Some(i) => {
+                state.waiting.swap_remove(i);
+            },
+            None => state.release(),
+        }
+    }
+}
+
+/// Removes a job from the queue when dropped.
+struct JobGuard<K, E>
+where
+    K: Eq + Hash,
+{
+    state: Arc<Mutex<State<K, E>>>,
+    key: K,
+}
+
+impl<K, E> Drop for JobGuard<K, E>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.state.lock().unwrap().jobs.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use assert_matches::assert_matches;
+    use futures::future;
+
+    fn params(max_concurrent: usize) -> Params {
+        Params {
+            max_concurrent,
+            max_queued: 8,
+            max_retries: 2,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_duplicates() {
+        let queue = FetchQueue::<&str, ()>::new(params(4));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetch = |fetches: Arc<AtomicUsize>| {
+            move || {
+                let fetches = fetches.clone();
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    Delay::new(Duration::from_millis(10)).await;
+                    Ok(())
+                }
+            }
+        };
+
+        let (a, b) = future::join(
+            queue.fetch("urn", Priority::Normal, fetch(fetches.clone()), |_| true),
+            queue.fetch("urn", Priority::Tracked, fetch(fetches.clone()), |_| true),
+        )
+        .await;
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Done, so fetched again
+        queue
+            .fetch("urn", Priority::Normal, fetch(fetches.clone()), |_| true)
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_first() {
+        let queue = FetchQueue::<u8, ()>::new(params(1));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let fetch = |n: u8| {
+            let order = order.clone();
+            move || {
+                let order = order.clone();
+                async move {
+                    Delay::new(Duration::from_millis(10)).await;
+                    order.lock().unwrap().push(n);
+                    Ok(())
+                }
+            }
+        };
+
+        // The first one occupies the only slot, so the others queue up
+        future::join_all(vec![
+            queue.fetch(0, Priority::Normal, fetch(0), |_| true).boxed(),
+            queue
+                .fetch(1, Priority::Background, fetch(1), |_| true)
+                .boxed(),
+            queue
+                .fetch(2, Priority::Tracked, fetch(2), |_| true)
+                .boxed(),
+        ])
+        .await;
+        assert_eq!(*order.lock().unwrap(), vec![0, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_errors() {
+        let queue = FetchQueue::<&str, bool>::new(params(1));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let fetch = |transient: bool| {
+            let attempts = attempts.clone();
+            move || {
+                let attempts = attempts.clone();
+                async move {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    Err(transient)
+                }
+            }
+        };
+
+        let res = queue
+            .fetch("urn", Priority::Normal, fetch(true), |transient| *transient)
+            .await;
+        assert_matches!(res, Err(Error::Fetch(_)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        attempts.store(0, Ordering::SeqCst);
+        let res = queue
+            .fetch("urn", Priority::Normal, fetch(false), |transient| {
+                *transient
+            })
+            .await;
+        assert_matches!(res, Err(Error::Fetch(_)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let queue = FetchQueue::<u8, ()>::new(Params {
+            max_queued: 1,
+            ..params(1)
+        });
+        let slow = || async {
+            Delay::new(Duration::from_millis(10)).await;
+            Ok(())
+        };
+
+        let (a, b) = future::join(
+            queue.fetch(0, Priority::Normal, slow, |_| true),
+            queue.fetch(1, Priority::Normal, slow, |_| true),
+        )
+        .await;
+        assert!(a.is_ok());
+        assert_matches!(b, Err(Error::Full));
+    }
+}
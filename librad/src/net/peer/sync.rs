@@ -0,0 +1,354 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Background syncing with connected peers.
+//!
+//! Replication via gossip is reactive: if we miss an announcement, eg. because
+//! we were offline, we won't catch up until the next one. To compensate, we
+//! periodically, and whenever a peer connects, ask connected peers which
+//! [`RadUrn`]s they have, and compare their signed refs of the ones we have,
+//! too, with what we last fetched from them. If they differ, we fetch.
+//!
+//! Only peers we track are compared, as we can't tell otherwise whether we're
+//! missing anything.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use either::Either;
+use futures::{
+    future::{self, FutureExt},
+    lock::Mutex,
+    stream::{self, BoxStream, StreamExt},
+};
+use futures_timer::Delay;
+use tracing_futures::Instrument as _;
+
+use crate::{
+    git::refs::{self, Refs},
+    keys,
+    net::{
+        gossip::Capability,
+        peer::{Gossip, PeerEvent, PeerStorage, Priority},
+        protocol::{Protocol, ProtocolEvent},
+        rpc,
+    },
+    peer::PeerId,
+    signer::Signer,
+    uri::RadUrn,
+};
+
+/// Upper bound on the number of newly connected peers waiting for
+/// [`Params::connect_delay`] to elapse.
+const MAX_PENDING_CONNECTS: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Whether to sync at all.
+    pub enabled: bool,
+    /// Interval in which to sync with all connected peers.
+    pub interval: Duration,
+    /// How long to wait after a peer connected before syncing with it, so it
+    /// has a chance to tell us about its capabilities.
+    pub connect_delay: Duration,
+    /// Don't sync with the same peer more often than this, eg. if it keeps
+    /// reconnecting.
+    pub min_peer_interval: Duration,
+    /// Maximum number of peers to sync with concurrently.
+    pub max_concurrent: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Duration::from_secs(5 * 60),
+            connect_delay: Duration::from_secs(3),
+            min_peer_interval: Duration::from_secs(60),
+            max_concurrent: 4,
+        }
+    }
+}
+
+/// Progress of syncing with a peer, see [`PeerEvent::Sync`].
+#[derive(Clone, Debug)]
+pub enum SyncEvent {
+    /// Started syncing with `peer`, which has `urns` of the [`RadUrn`]s we
+    /// have, too.
+    Started { peer: PeerId, urns: usize },
+    /// Compared `urn` with `peer`.
+    Synced {
+        peer: PeerId,
+        urn: RadUrn,
+        result: SyncResult,
+    },
+    /// Done syncing with `peer`.
+    Finished { peer: PeerId },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncResult {
+    /// The peer's signed refs are the same as the ones we have.
+    UpToDate,
+    /// The peer's signed refs were different, and we fetched from it.
+    Fetched,
+    /// We don't track the peer.
+    Untracked,
+    /// Either asking the peer, or fetching from it failed.
+    Error,
+}
+
+/// Remembers when we last synced with a peer.
+struct Schedule {
+    min_interval: Duration,
+    last_synced: HashMap<PeerId, Instant>,
+}
+
+impl Schedule {
+    fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_synced: HashMap::new(),
+        }
+    }
+
+    /// Whether to sync with `peer` at `now`. If so, it is assumed we do.
+    fn due(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let min_interval = self.min_interval;
+        // Forget about peers which wouldn't be skipped anyway
+        self.last_synced = std::mem::take(&mut self.last_synced)
+            .into_iter()
+            .filter(|(_, synced)| now.saturating_duration_since(*synced) < min_interval)
+            .collect();
+
+        if self.last_synced.contains_key(peer) {
+            false
+        } else {
+            self.last_synced.insert(peer.clone(), now);
+            true
+        }
+    }
+}
+
+/// Sync with peers as they connect, and every [`Params::interval`].
+///
+/// The returned future never completes, and is meant to be driven alongside
+/// the [`Protocol`]'s run loop.
+pub(super) async fn run<S>(
+    params: Params,
+    protocol: Protocol<PeerStorage<S>, Gossip>,
+    storage: PeerStorage<S>,
+) where
+    S: Signer + Clone + 'static,
+    S::Error: keys::SignError,
+{
+    if !params.enabled {
+        return future::pending::<()>().await;
+    }
+
+    let connect_delay = params.connect_delay;
+    let connected = protocol
+        .subscribe()
+        .await
+        .filter_map(|evt| {
+            future::ready(match evt {
+                ProtocolEvent::Connected(peer) => Some(peer),
+                _ => None,
+            })
+        })
+        .map(move |peer| {
+            async move {
+                Delay::new(connect_delay).await;
+                vec![peer]
+            }
+            .boxed()
+        });
+
+    let interval = params.interval;
+    let periodic = stream::unfold(protocol.clone(), move |protocol| async move {
+        Delay::new(interval).await;
+        let peers = protocol.connected_peers().await;
+        Some((peers, protocol))
+    })
+    .map(|peers| future::ready(peers).boxed());
+
+    let schedule = Arc::new(Mutex::new(Schedule::new(params.min_peer_interval)));
+    let peers: BoxStream<'static, PeerId> = stream::select(connected, periodic)
+        .buffer_unordered(MAX_PENDING_CONNECTS)
+        .flat_map(stream::iter)
+        .boxed();
+    peers
+        .for_each_concurrent(params.max_concurrent, |peer| {
+            let protocol = protocol.clone();
+            let storage = storage.clone();
+            let schedule = schedule.clone();
+            async move {
+                if schedule.lock().await.due(&peer, Instant::now()) {
+                    let span = tracing::info_span!("Peer::sync", peer = %peer);
+                    sync(&protocol, &storage, peer).instrument(span).await
+                }
+            }
+        })
+        .await
+}
+
+/// Sync the [`RadUrn`]s we have in common with `peer`.
+async fn sync<S>(
+    protocol: &Protocol<PeerStorage<S>, Gossip>,
+    storage: &PeerStorage<S>,
+    peer: PeerId,
+) where
+    S: Signer + Clone + 'static,
+    S::Error: keys::SignError,
+{
+    if !protocol
+        .capabilities(&peer)
+        .await
+        .contains(&Capability::Rpc)
+    {
+        tracing::debug!("Peer doesn't support rpc, not syncing");
+        return;
+    }
+
+    let theirs = match protocol.urns_of(&peer).await {
+        Ok(urns) => urns.into_iter().map(|urn| urn.id).collect::<HashSet<_>>(),
+        Err(e) => {
+            tracing::warn!(err = %e, "Failed to ask for urns");
+            return;
+        },
+    };
+    let common = rpc::LocalStorage::urns(storage)
+        .await
+        .into_iter()
+        .filter(|urn| theirs.contains(&urn.id))
+        .collect::<Vec<_>>();
+
+    storage
+        .subscribers
+        .emit(PeerEvent::Sync(SyncEvent::Started {
+            peer: peer.clone(),
+            urns: common.len(),
+        }))
+        .await;
+
+    for urn in common {
+        let result = sync_urn(protocol, storage, &peer, &urn).await;
+        tracing::debug!(urn = %urn, result = ?result, "Synced");
+        storage
+            .subscribers
+            .emit(PeerEvent::Sync(SyncEvent::Synced {
+                peer: peer.clone(),
+                urn,
+                result,
+            }))
+            .await
+    }
+
+    storage
+        .subscribers
+        .emit(PeerEvent::Sync(SyncEvent::Finished { peer }))
+        .await
+}
+
+async fn sync_urn<S>(
+    protocol: &Protocol<PeerStorage<S>, Gossip>,
+    storage: &PeerStorage<S>,
+    peer: &PeerId,
+    urn: &RadUrn,
+) -> SyncResult
+where
+    S: Signer + Clone + 'static,
+    S::Error: keys::SignError,
+{
+    match storage.is_tracked(urn.clone(), peer.clone()).await {
+        Ok(true) => {},
+        Ok(false) => return SyncResult::Untracked,
+        Err(e) => {
+            tracing::error!(err = %e, "Git::Storage::is_tracked error");
+            return SyncResult::Error;
+        },
+    }
+
+    let theirs = match protocol.signed_refs_of(peer, urn.clone()).await {
+        Ok(Some(json)) => match refs::Signed::from_json(&json, peer) {
+            Ok(signed) => Refs::from(signed),
+            Err(e) => {
+                tracing::warn!(err = %e, "Invalid signed refs");
+                return SyncResult::Error;
+            },
+        },
+        // They had it a moment ago
+        Ok(None) => return SyncResult::UpToDate,
+        Err(e) => {
+            tracing::warn!(err = %e, "Failed to ask for signed refs");
+            return SyncResult::Error;
+        },
+    };
+    let ours = match storage.signed_refs_of(urn.clone(), peer.clone()).await {
+        Ok(ours) => ours,
+        Err(e) => {
+            tracing::error!(err = %e, "Git::Storage::rad_signed_refs_of error");
+            return SyncResult::Error;
+        },
+    };
+
+    if ours.map(|ours| ours.heads) == Some(theirs.heads) {
+        return SyncResult::UpToDate;
+    }
+
+    match storage
+        .git_fetch(
+            peer,
+            Either::Left(urn.clone()),
+            None::<git2::Oid>,
+            Priority::Background,
+        )
+        .await
+    {
+        Ok(()) => SyncResult::Fetched,
+        Err(e) => {
+            tracing::warn!(err = %e, "Fetch error");
+            SyncResult::Error
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keys::SecretKey;
+
+    #[test]
+    fn schedule_skips_recently_synced() {
+        let min_interval = Duration::from_secs(60);
+        let mut schedule = Schedule::new(min_interval);
+        let (peer, other) = (
+            PeerId::from(SecretKey::new()),
+            PeerId::from(SecretKey::new()),
+        );
+        let now = Instant::now();
+
+        assert!(schedule.due(&peer, now));
+        assert!(!schedule.due(&peer, now + Duration::from_secs(1)));
+        assert!(schedule.due(&other, now + Duration::from_secs(1)));
+        assert!(schedule.due(&peer, now + min_interval));
+    }
+}
@@ -104,6 +104,9 @@ pub struct Gossip {
     ///
     /// If `Some`, this refers to the `PeerId`'s view of `urn` and `rev`. That
     /// is, it may map to `remotes/<PeerId>/<urn>`.
+    ///
+    /// The origin is not signed separately, so it must be the peer which
+    /// signed the announcement (see [`Gossip::may_be_announced_by`]).
     #[n(2)]
     pub origin: Option<PeerId>,
 }
@@ -121,6 +124,15 @@ impl Gossip {
 
         Self { urn, rev, origin }
     }
+
+    /// Whether `peer` may announce this update, ie. whether it is the
+    /// `origin`, if there is one.
+    pub fn may_be_announced_by(&self, peer: &PeerId) -> bool {
+        self.origin
+            .as_ref()
+            .map(|origin| origin == peer)
+            .unwrap_or(true)
+    }
 }
 
 #[cfg(test)]
@@ -151,4 +163,18 @@ mod tests {
 
         cbor_roundtrip(gossip)
     }
+
+    #[test]
+    fn test_gossip_may_only_be_announced_by_its_origin() {
+        let origin = PeerId::from(SecretKey::new());
+        let forger = PeerId::from(SecretKey::new());
+        let id = Hash::hash(b"cerveza coronita");
+
+        let gossip = Gossip::new(id.clone(), Path::new(), Rev::Git(*OID), origin.clone());
+        assert!(gossip.may_be_announced_by(&origin));
+        assert!(!gossip.may_be_announced_by(&forger));
+
+        let gossip = Gossip::new(id, Path::new(), Rev::Git(*OID), None);
+        assert!(gossip.may_be_announced_by(&forger));
+    }
 }
@@ -19,7 +19,7 @@
 
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     future::Future,
     io,
@@ -31,6 +31,7 @@ use std::{
         Arc,
     },
     task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
 
 use futures::{
@@ -44,7 +45,7 @@ use tracing_futures::Instrument;
 
 use crate::{
     git::p2p::{
-        server::GitServer,
+        server::{GitServer, Pushed},
         transport::{GitStream, GitStreamFactory},
     },
     internal::channel::Fanout,
@@ -53,9 +54,12 @@ use crate::{
         connection::{CloseReason, LocalInfo, RemoteInfo, Stream},
         gossip,
         quic,
+        reputation::{self, Ban, Misbehaviour, Reputation},
+        rpc,
         upgrade::{self, upgrade, with_upgraded, SomeUpgraded, UpgradeRequest, Upgraded},
     },
     peer::PeerId,
+    uri::RadUrn,
 };
 
 #[derive(Clone, Debug)]
@@ -65,6 +69,8 @@ pub enum ProtocolEvent<A> {
     Listening(SocketAddr),
     Gossip(gossip::Info<IpAddr, A>),
     Membership(gossip::MembershipInfo<IpAddr>),
+    /// A peer pushed to us, see [`GitServer::with_receive_pack`].
+    Pushed(Pushed),
 }
 
 /// Unification of the different inputs the run loop processes.
@@ -93,6 +99,9 @@ pub enum Error {
     #[error("no connection to {0}")]
     NoConnection(PeerId),
 
+    #[error("{0} is banned")]
+    Banned(PeerId),
+
     #[error(transparent)]
     Upgrade(#[from] upgrade::ErrorSource),
 
@@ -105,6 +114,9 @@ pub enum Error {
     #[error("error handling git upgrade")]
     Git(#[source] io::Error),
 
+    #[error("error handling rpc upgrade")]
+    Rpc(#[from] rpc::Error),
+
     #[error(transparent)]
     Quic(#[from] quic::Error),
 
@@ -112,6 +124,28 @@ pub enum Error {
     Io(#[from] io::Error),
 }
 
+impl Error {
+    /// The [`Misbehaviour`] of the remote peer indicated by this error, if
+    /// any.
+    fn misbehaviour(&self) -> Option<Misbehaviour> {
+        use gossip::error::Error as Gossip;
+        use upgrade::ErrorSource as Upgrade;
+
+        match self {
+            Self::Upgrade(Upgrade::Decode(_)) | Self::Upgrade(Upgrade::UnsupportedUpgrade(_)) => {
+                Some(Misbehaviour::Upgrade)
+            },
+            Self::Cbor(CborCodecError::Decode(_))
+            | Self::Gossip(Gossip::Cbor(CborCodecError::Decode(_)))
+            | Self::Rpc(rpc::Error::Cbor(CborCodecError::Decode(_))) => Some(Misbehaviour::Codec),
+            Self::Gossip(Gossip::StorageErrorRateLimitExceeded) => {
+                Some(Misbehaviour::StorageErrors)
+            },
+            _ => None,
+        }
+    }
+}
+
 pub type RunLoop = BoxFuture<'static, ()>;
 
 pub struct Protocol<S, A> {
@@ -121,6 +155,7 @@ pub struct Protocol<S, A> {
     endpoint: quic::Endpoint,
 
     connections: Arc<Mutex<HashMap<PeerId, quic::Connection>>>,
+    reputation: Arc<Mutex<Reputation>>,
     subscribers: Fanout<ProtocolEvent<A>>,
 
     ref_count: Arc<AtomicUsize>,
@@ -146,6 +181,7 @@ where
             git: self.git.clone(),
             endpoint: self.endpoint.clone(),
             connections: self.connections.clone(),
+            reputation: self.reputation.clone(),
             subscribers: self.subscribers.clone(),
             ref_count: self.ref_count.clone(),
         }
@@ -173,7 +209,7 @@ impl<S, A> Drop for Protocol<S, A> {
 
 impl<S, A> Protocol<S, A>
 where
-    S: gossip::LocalStorage<Update = A> + 'static,
+    S: gossip::LocalStorage<Update = A> + rpc::LocalStorage + 'static,
     for<'de> A: Encode + Decode<'de> + Clone + Debug + Send + Sync + 'static,
 {
     pub fn new<Disco>(
@@ -181,6 +217,7 @@ where
         git: GitServer,
         quic::BoundEndpoint { endpoint, incoming }: quic::BoundEndpoint<'static>,
         disco: Disco,
+        reputation: Reputation,
     ) -> (Self, RunLoop)
     where
         Disco: futures::stream::Stream<Item = (PeerId, Vec<SocketAddr>)> + Send + 'static,
@@ -190,6 +227,7 @@ where
             git,
             endpoint: endpoint.clone(),
             connections: Arc::new(Mutex::new(HashMap::default())),
+            reputation: Arc::new(Mutex::new(reputation)),
             subscribers: Fanout::new(),
             ref_count: Arc::new(AtomicUsize::new(0)),
         };
@@ -289,6 +327,82 @@ where
         self.connections.lock().await.len()
     }
 
+    /// The peers we currently have an active connection to.
+    pub async fn connected_peers(&self) -> Vec<PeerId> {
+        self.connections.lock().await.keys().cloned().collect()
+    }
+
+    /// The capabilities advertised by `peer_id`, as far as we know.
+    ///
+    /// Features not in this set should not be used with `peer_id`.
+    pub async fn capabilities(&self, peer_id: &PeerId) -> HashSet<gossip::Capability> {
+        self.gossip.capabilities(peer_id).await
+    }
+
+    /// Record that `peer_id` misbehaved.
+    ///
+    /// If this causes `peer_id` to be banned, it is disconnected.
+    pub async fn penalise(&self, peer_id: &PeerId, misbehaviour: Misbehaviour) {
+        tracing::info!(remote.id = %peer_id, misbehaviour = ?misbehaviour, "Penalising peer");
+
+        let banned =
+            self.reputation
+                .lock()
+                .await
+                .penalise(peer_id, misbehaviour, SystemTime::now());
+        match banned {
+            Ok(false) => {},
+            Ok(true) => {
+                tracing::warn!(remote.id = %peer_id, "Banned peer");
+                self.close_banned(peer_id).await
+            },
+            Err(e) => tracing::warn!("Failed to persist bans: {}", e),
+        }
+    }
+
+    /// Ban `peer_id` for `duration`, or indefinitely if `None`.
+    ///
+    /// If we're currently connected to `peer_id`, it is disconnected.
+    pub async fn ban(
+        &self,
+        peer_id: &PeerId,
+        duration: Option<Duration>,
+    ) -> Result<(), reputation::Error> {
+        self.reputation
+            .lock()
+            .await
+            .ban(peer_id.clone(), duration, SystemTime::now())?;
+        self.close_banned(peer_id).await;
+        Ok(())
+    }
+
+    /// Lift the ban of `peer_id`.
+    ///
+    /// Returns `true` if `peer_id` was banned.
+    pub async fn unban(&self, peer_id: &PeerId) -> Result<bool, reputation::Error> {
+        self.reputation
+            .lock()
+            .await
+            .unban(peer_id, SystemTime::now())
+    }
+
+    pub async fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.reputation
+            .lock()
+            .await
+            .is_banned(peer_id, SystemTime::now())
+    }
+
+    /// The currently banned peers.
+    pub async fn bans(&self) -> Vec<(PeerId, Ban)> {
+        self.reputation
+            .lock()
+            .await
+            .bans(SystemTime::now())
+            .map(|(peer_id, ban)| (peer_id.clone(), *ban))
+            .collect()
+    }
+
     /// Query the network for an update
     ///
     /// Answers from the network will be available as `ProtocolEvent::Gossip`
@@ -301,6 +415,61 @@ where
         self.gossip.query(want).await
     }
 
+    /// Send `request` to the connected peer `to`, and wait for its response.
+    pub async fn request(
+        &self,
+        to: &PeerId,
+        request: rpc::Request,
+    ) -> Result<rpc::Response, Error> {
+        if self.is_banned(to).await {
+            return Err(Error::Banned(to.clone()));
+        }
+
+        let stream = self.open_stream(to, upgrade::Rpc).await?;
+        let res = rpc::call(stream, request).await.map_err(Error::from);
+        self.penalise_on_err(to, res).await
+    }
+
+    /// Measure the round-trip time of a request to the connected peer `to`.
+    pub async fn ping(&self, to: &PeerId) -> Result<Duration, Error> {
+        let start = Instant::now();
+        match self.request(to, rpc::Request::Ping).await? {
+            rpc::Response::Pong => Ok(start.elapsed()),
+            _ => Err(rpc::Error::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Ask the connected peer `to` for its [`gossip::PeerAdvertisement`].
+    pub async fn advertisement_of(
+        &self,
+        to: &PeerId,
+    ) -> Result<gossip::PeerAdvertisement<IpAddr>, Error> {
+        match self.request(to, rpc::Request::Advertisement).await? {
+            rpc::Response::Advertisement(ad) => Ok(ad),
+            _ => Err(rpc::Error::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Ask the connected peer `to` which URNs it replicates.
+    pub async fn urns_of(&self, to: &PeerId) -> Result<Vec<RadUrn>, Error> {
+        match self.request(to, rpc::Request::Urns).await? {
+            rpc::Response::Urns(urns) => Ok(urns),
+            _ => Err(rpc::Error::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Ask the connected peer `to` for its signed refs of `urn`, in their
+    /// signed JSON form.
+    ///
+    /// Returns `None` if `to` doesn't have `urn`. Note that the signature is
+    /// not verified.
+    pub async fn signed_refs_of(&self, to: &PeerId, urn: RadUrn) -> Result<Option<Vec<u8>>, Error> {
+        match self.request(to, rpc::Request::SignedRefs { urn }).await? {
+            rpc::Response::SignedRefs(refs) => Ok(refs),
+            _ => Err(rpc::Error::UnexpectedResponse.into()),
+        }
+    }
+
     /// Open a QUIC stream which is upgraded to expect the git protocol
     ///
     /// If no connection to the given peer is currently active, `addr_hints`
@@ -315,6 +484,10 @@ where
     where
         Addrs: IntoIterator<Item = SocketAddr>,
     {
+        if self.is_banned(to).await {
+            return Err(Error::Banned(to.clone()));
+        }
+
         self.open_stream(to, upgrade::Git)
             .or_else(|e| async move {
                 match e {
@@ -325,8 +498,9 @@ where
                             .await
                             .ok_or_else(|| Error::NoConnection(to.clone()))?;
 
+                        let version = self.upgrade_version(to).await?;
                         let stream = conn.open_stream().await?;
-                        upgrade(stream, upgrade::Git)
+                        upgrade(stream, version, upgrade::Git)
                             .await
                             .map_err(|upgrade::Error { stream, source }| {
                                 stream.close(CloseReason::InvalidUpgrade);
@@ -355,7 +529,9 @@ where
                     "Run::Discovered",
                 );
 
-                if !self.connections.lock().await.contains_key(&peer) {
+                if self.is_banned(&peer).await {
+                    tracing::trace!(remote.id = %peer, "Ignoring banned peer");
+                } else if !self.connections.lock().await.contains_key(&peer) {
                     if let Some((conn, incoming)) = connect(&self.endpoint, &peer, addrs).await {
                         self.handle_connect(conn, incoming.boxed(), None).await;
                     }
@@ -379,6 +555,10 @@ where
                     gossip::Control::SendAdhoc { to, rpc } => {
                         tracing::trace!(remote.id = %to.peer_id, "Run::Rad(SendAdhoc)");
 
+                        if self.is_banned(&to.peer_id).await {
+                            return;
+                        }
+
                         let conn = match self.connections.lock().await.get(&to.peer_id) {
                             Some(conn) => Some(conn.clone()),
                             None => connect_peer_info(&self.endpoint, to)
@@ -405,10 +585,16 @@ where
                     gossip::Control::Connect { to } => {
                         tracing::trace!(remote.id = %to.peer_id, "Run::Rad(Connect)");
 
-                        if !self.connections.lock().await.contains_key(&to.peer_id) {
+                        if self.is_banned(&to.peer_id).await {
+                            self.gossip.connection_failed(&to.peer_id).await
+                        } else if !self.connections.lock().await.contains_key(&to.peer_id) {
+                            let peer_id = to.peer_id.clone();
                             let conn = connect_peer_info(&self.endpoint, to).await;
-                            if let Some((conn, incoming)) = conn {
-                                self.handle_connect(conn, incoming.boxed(), None).await
+                            match conn {
+                                Some((conn, incoming)) => {
+                                    self.handle_connect(conn, incoming.boxed(), None).await
+                                },
+                                None => self.gossip.connection_failed(&peer_id).await,
                             }
                         }
                     },
@@ -418,6 +604,12 @@ where
 
                         self.handle_disconnect(peer).await;
                     },
+
+                    gossip::Control::Penalise(peer, misbehaviour) => {
+                        tracing::trace!(peer.id = %peer, "Run::Rad(Penalise)");
+
+                        self.penalise(&peer, misbehaviour).await
+                    },
                 },
 
                 gossip::ProtocolEvent::Info(info) => {
@@ -470,6 +662,16 @@ where
         };
     }
 
+    async fn close_banned(&self, peer: &PeerId) {
+        if let Some(conn) = self.connections.lock().await.remove(peer) {
+            tracing::info!(msg = "Disconnecting banned peer", remote.addr = %conn.remote_addr());
+            conn.close(CloseReason::Banned);
+            self.subscribers
+                .emit(ProtocolEvent::Disconnecting(peer.clone()))
+                .await
+        }
+    }
+
     async fn handle_disconnect(&self, peer: PeerId) {
         if let Some(conn) = self.connections.lock().await.remove(&peer) {
             tracing::info!(msg = "Disconnecting", remote.addr = %conn.remote_addr());
@@ -490,6 +692,12 @@ where
         let remote_id = conn.remote_peer_id().clone();
         tracing::info!(remote.id = %remote_id, "New incoming connection");
 
+        if self.is_banned(&remote_id).await {
+            tracing::info!(remote.id = %remote_id, "Rejecting banned peer");
+            conn.close(CloseReason::Banned);
+            return Ok(());
+        }
+
         {
             self.connections
                 .lock()
@@ -518,7 +726,15 @@ where
         stream: quic::Stream,
         hello: impl Into<Option<gossip::Rpc<IpAddr, A>>>,
     ) -> Result<(), Error> {
-        match upgrade(stream, upgrade::Gossip).await {
+        let remote_id = stream.remote_peer_id().clone();
+        let version = match self.upgrade_version(&remote_id).await {
+            Ok(version) => version,
+            Err(e) => {
+                stream.close(CloseReason::InvalidUpgrade);
+                return Err(e);
+            },
+        };
+        let res = match upgrade(stream, version, upgrade::Gossip).await {
             Err(upgrade::Error { stream, source }) => {
                 stream.close(CloseReason::InvalidUpgrade);
                 Err(Error::from(source))
@@ -529,11 +745,13 @@ where
                 .outgoing(upgraded, hello)
                 .await
                 .map_err(|e| e.into()),
-        }
+        };
+        self.penalise_on_err(&remote_id, res).await
     }
 
     async fn incoming(&self, stream: quic::Stream) -> Result<(), Error> {
-        match with_upgraded(stream).await {
+        let remote_id = stream.remote_peer_id().clone();
+        let res = match with_upgraded(stream).await {
             Err(upgrade::Error { stream, source }) => {
                 stream.close(CloseReason::InvalidUpgrade);
                 Err(Error::from(source))
@@ -544,25 +762,67 @@ where
                     self.gossip.incoming(upgraded).await.map_err(Error::Gossip)
                 },
 
-                SomeUpgraded::Git(upgraded) => self
+                SomeUpgraded::Git(upgraded) => match self
                     .git
-                    .invoke_service(upgraded.into_stream().split())
+                    .invoke_service(&remote_id, upgraded.into_stream().split())
                     .await
-                    .map_err(Error::Git),
+                {
+                    Ok(Some(pushed)) => {
+                        self.subscribers.emit(ProtocolEvent::Pushed(pushed)).await;
+                        Ok(())
+                    },
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(Error::Git(e)),
+                },
+
+                SomeUpgraded::Rpc(upgraded) => rpc::serve(
+                    upgraded,
+                    self.gossip.storage(),
+                    self.gossip.local_advertisement(),
+                )
+                .await
+                .map_err(Error::Rpc),
             },
+        };
+        self.penalise_on_err(&remote_id, res).await
+    }
+
+    async fn penalise_on_err<T>(
+        &self,
+        peer_id: &PeerId,
+        res: Result<T, Error>,
+    ) -> Result<T, Error> {
+        if let Some(misbehaviour) = res.as_ref().err().and_then(Error::misbehaviour) {
+            self.penalise(peer_id, misbehaviour).await
         }
+        res
+    }
+
+    /// The version of the upgrade protocol to speak to `peer_id`, as per the
+    /// versions it advertised.
+    async fn upgrade_version(&self, peer_id: &PeerId) -> Result<u8, Error> {
+        let versions = self
+            .capabilities(peer_id)
+            .await
+            .into_iter()
+            .filter_map(|cap| match cap {
+                gossip::Capability::Version(version) => Some(version),
+                _ => None,
+            });
+        upgrade::negotiate_version(versions).map_err(Error::from)
     }
 
     async fn open_stream<U>(&self, to: &PeerId, up: U) -> Result<Upgraded<U, quic::Stream>, Error>
     where
         U: Into<UpgradeRequest>,
     {
+        let version = self.upgrade_version(to).await?;
         let stream = match self.connections.lock().await.get(to) {
             Some(conn) => conn.open_stream().await.map_err(Error::from),
             None => Err(Error::NoConnection(to.clone())),
         }?;
 
-        upgrade(stream, up)
+        upgrade(stream, version, up)
             .await
             .map_err(|upgrade::Error { stream, source }| {
                 stream.close(CloseReason::InvalidUpgrade);
@@ -574,7 +834,7 @@ where
 #[async_trait]
 impl<S, A> GitStreamFactory for Protocol<S, A>
 where
-    S: gossip::LocalStorage<Update = A> + 'static,
+    S: gossip::LocalStorage<Update = A> + rpc::LocalStorage + 'static,
     for<'de> A: Encode + Decode<'de> + Clone + Debug + Send + Sync + 'static,
 {
     async fn open_stream(
@@ -0,0 +1,513 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Tracking of misbehaving peers.
+//!
+//! Every time a peer misbehaves, it accrues penalty points, which are forgiven
+//! again over time. Once the points reach a threshold, the peer is banned for
+//! a while. Peers can also be banned manually, possibly indefinitely.
+//!
+//! Bans are persisted, such that they survive restarts. Penalty points are
+//! not. Rather than rewriting all bans whenever one changes, changes are
+//! appended to a journal, which is compacted once it grew to hold
+//! considerably more records than there are bans.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    mem,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::peer::PeerId;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Ways in which a peer can misbehave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// Sent data we could not decode.
+    Codec,
+    /// Sent an invalid or unsupported stream upgrade.
+    Upgrade,
+    /// Provided data which failed verification when we fetched from it.
+    Fetch,
+    /// Relayed a message with an invalid signature, or making claims its
+    /// signature doesn't vouch for.
+    InvalidSignature,
+    /// Caused too many storage errors in a row.
+    StorageErrors,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> u32 {
+        match self {
+            Self::Codec => 20,
+            Self::Upgrade => 10,
+            Self::Fetch => 5,
+            Self::InvalidSignature => 50,
+            Self::StorageErrors => 25,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Number of penalty points at which a peer is banned.
+    pub threshold: u32,
+    /// One penalty point is forgiven per this interval.
+    pub recovery_interval: Duration,
+    /// How long to ban peers which reached the `threshold`.
+    pub ban_duration: Duration,
+    /// Maximum number of peers to keep penalty points for. If exceeded, the
+    /// points of the peer which misbehaved least recently are forgotten.
+    pub max_scores: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            threshold: 100,
+            recovery_interval: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(60 * 60),
+            max_scores: 1024,
+        }
+    }
+}
+
+/// A ban of a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    /// The ban is lifted at this point in time, or never if `None`.
+    pub until: Option<SystemTime>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.until.map(|until| until > now).unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    points: u32,
+    updated: SystemTime,
+}
+
+impl Score {
+    fn points(&self, now: SystemTime, recovery_interval: Duration) -> u32 {
+        let elapsed = now.duration_since(self.updated).unwrap_or_default();
+        let forgiven = elapsed.as_secs() / recovery_interval.as_secs().max(1);
+        self.points.saturating_sub(forgiven as u32)
+    }
+}
+
+/// A change to the bans, as recorded in the journal.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    peer: PeerId,
+    /// The new ban, or `None` if the ban was lifted.
+    ban: Option<Ban>,
+}
+
+/// The journal is not compacted while it holds fewer records than this.
+const MIN_JOURNAL: usize = 64;
+
+pub struct Reputation {
+    params: Params,
+    path: Option<PathBuf>,
+    scores: HashMap<PeerId, Score>,
+    bans: BTreeMap<PeerId, Ban>,
+    /// The number of records in the journal at `path`.
+    journal: usize,
+}
+
+impl Reputation {
+    /// A [`Reputation`] which does not persist bans.
+    pub fn new(params: Params) -> Self {
+        Self {
+            params,
+            path: None,
+            scores: HashMap::new(),
+            bans: BTreeMap::new(),
+            journal: 0,
+        }
+    }
+
+    /// A [`Reputation`] which persists bans to the journal at `path`.
+    ///
+    /// If the journal exists, bans are loaded from it, and it is compacted if
+    /// it contains expired or lifted bans.
+    pub fn load(path: PathBuf, params: Params, now: SystemTime) -> Result<Self, Error> {
+        let mut bans = BTreeMap::new();
+        let mut journal = 0;
+        match fs::read(&path) {
+            Ok(lines) => {
+                for line in lines.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                    let Record { peer, ban } = serde_json::from_slice(line)?;
+                    match ban {
+                        Some(ban) => bans.insert(peer, ban),
+                        None => bans.remove(&peer),
+                    };
+                    journal += 1;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut this = Self {
+            params,
+            path: Some(path),
+            scores: HashMap::new(),
+            bans,
+            journal,
+        };
+        this.prune(now);
+        if this.journal > this.bans.len() {
+            this.compact(now)?;
+        }
+
+        Ok(this)
+    }
+
+    /// Record that `peer` misbehaved.
+    ///
+    /// Returns `true` if `peer` got banned as a consequence.
+    pub fn penalise(
+        &mut self,
+        peer: &PeerId,
+        misbehaviour: Misbehaviour,
+        now: SystemTime,
+    ) -> Result<bool, Error> {
+        self.prune(now);
+
+        let recovery_interval = self.params.recovery_interval;
+        let points = self
+            .scores
+            .get(peer)
+            .map(|score| score.points(now, recovery_interval))
+            .unwrap_or(0)
+            .saturating_add(misbehaviour.penalty());
+
+        if points >= self.params.threshold {
+            self.scores.remove(peer);
+            self.ban(peer.clone(), Some(self.params.ban_duration), now)?;
+            Ok(true)
+        } else {
+            if !self.scores.contains_key(peer) && self.scores.len() >= self.params.max_scores {
+                let oldest = self
+                    .scores
+                    .iter()
+                    .min_by_key(|(_, score)| score.updated)
+                    .map(|(peer, _)| peer.clone());
+                if let Some(oldest) = oldest {
+                    self.scores.remove(&oldest);
+                }
+            }
+            self.scores.insert(
+                peer.clone(),
+                Score {
+                    points,
+                    updated: now,
+                },
+            );
+            Ok(false)
+        }
+    }
+
+    /// The current penalty points of `peer`.
+    pub fn points(&self, peer: &PeerId, now: SystemTime) -> u32 {
+        self.scores
+            .get(peer)
+            .map(|score| score.points(now, self.params.recovery_interval))
+            .unwrap_or(0)
+    }
+
+    /// Ban `peer` for `duration`, or indefinitely if `None`.
+    pub fn ban(
+        &mut self,
+        peer: PeerId,
+        duration: Option<Duration>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        self.prune(now);
+
+        let ban = Ban {
+            until: duration.map(|duration| now + duration),
+        };
+        self.bans.insert(peer.clone(), ban);
+        self.record(
+            Record {
+                peer,
+                ban: Some(ban),
+            },
+            now,
+        )
+    }
+
+    /// Lift the ban of `peer`, if any.
+    ///
+    /// Returns `true` if `peer` was banned.
+    pub fn unban(&mut self, peer: &PeerId, now: SystemTime) -> Result<bool, Error> {
+        self.scores.remove(peer);
+        match self.bans.remove(peer) {
+            None => Ok(false),
+            Some(ban) => {
+                self.record(
+                    Record {
+                        peer: peer.clone(),
+                        ban: None,
+                    },
+                    now,
+                )?;
+                Ok(ban.is_active(now))
+            },
+        }
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, now: SystemTime) -> bool {
+        self.bans
+            .get(peer)
+            .map(|ban| ban.is_active(now))
+            .unwrap_or(false)
+    }
+
+    /// The currently active bans.
+    pub fn bans(&self, now: SystemTime) -> impl Iterator<Item = (&PeerId, &Ban)> {
+        self.bans.iter().filter(move |(_, ban)| ban.is_active(now))
+    }
+
+    /// Forget expired bans, and the scores of peers which were forgiven
+    /// completely.
+    fn prune(&mut self, now: SystemTime) {
+        let recovery_interval = self.params.recovery_interval;
+        self.bans = mem::take(&mut self.bans)
+            .into_iter()
+            .filter(|(_, ban)| ban.is_active(now))
+            .collect();
+        self.scores
+            .retain(|_, score| score.points(now, recovery_interval) > 0);
+    }
+
+    /// Append `record` to the journal, if we have a `path`.
+    ///
+    /// If the journal grew to twice the number of bans, it is compacted
+    /// instead.
+    fn record(&mut self, record: Record, now: SystemTime) -> Result<(), Error> {
+        if self.journal >= (2 * self.bans.len()).max(MIN_JOURNAL) {
+            return self.compact(now);
+        }
+
+        if let Some(path) = &self.path {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(&line)?;
+            self.journal += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the journal to hold only the active bans, if we have a `path`.
+    fn compact(&mut self, now: SystemTime) -> Result<(), Error> {
+        self.prune(now);
+
+        if let Some(path) = &self.path {
+            let mut lines = Vec::new();
+            for (peer, ban) in &self.bans {
+                serde_json::to_writer(
+                    &mut lines,
+                    &Record {
+                        peer: peer.clone(),
+                        ban: Some(*ban),
+                    },
+                )?;
+                lines.push(b'\n');
+            }
+
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, lines)?;
+            fs::rename(tmp, path)?;
+            self.journal = self.bans.len();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::keys::SecretKey;
+
+    fn peer() -> PeerId {
+        PeerId::from(SecretKey::new())
+    }
+
+    #[test]
+    fn ban_at_threshold() {
+        let mut rep = Reputation::new(Params::default());
+        let (now, peer) = (SystemTime::now(), peer());
+
+        assert!(!rep
+            .penalise(&peer, Misbehaviour::InvalidSignature, now)
+            .unwrap());
+        assert!(!rep.is_banned(&peer, now));
+        assert!(rep
+            .penalise(&peer, Misbehaviour::InvalidSignature, now)
+            .unwrap());
+        assert!(rep.is_banned(&peer, now));
+
+        let lifted = now + Params::default().ban_duration;
+        assert!(!rep.is_banned(&peer, lifted));
+    }
+
+    #[test]
+    fn forgive_over_time() {
+        let params = Params::default();
+        let mut rep = Reputation::new(params.clone());
+        let (now, peer) = (SystemTime::now(), peer());
+
+        rep.penalise(&peer, Misbehaviour::InvalidSignature, now)
+            .unwrap();
+        assert_eq!(rep.points(&peer, now), 50);
+        assert_eq!(rep.points(&peer, now + params.recovery_interval * 10), 40);
+
+        let later = now + params.recovery_interval * 50;
+        assert!(!rep
+            .penalise(&peer, Misbehaviour::InvalidSignature, later)
+            .unwrap());
+        assert!(!rep.is_banned(&peer, later));
+    }
+
+    #[test]
+    fn unban() {
+        let mut rep = Reputation::new(Params::default());
+        let (now, peer) = (SystemTime::now(), peer());
+
+        rep.ban(peer.clone(), None, now).unwrap();
+        assert!(rep.is_banned(&peer, now + Duration::from_secs(60 * 60 * 24 * 365)));
+        assert!(rep.unban(&peer, now).unwrap());
+        assert!(!rep.is_banned(&peer, now));
+        assert!(!rep.unban(&peer, now).unwrap());
+    }
+
+    #[test]
+    fn persist_bans() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("bans.jsonl");
+        let (now, banned, lifted) = (SystemTime::now(), peer(), peer());
+
+        {
+            let mut rep = Reputation::load(path.clone(), Params::default(), now).unwrap();
+            rep.ban(banned.clone(), None, now).unwrap();
+            rep.ban(lifted.clone(), None, now).unwrap();
+            rep.unban(&lifted, now).unwrap();
+        }
+
+        let rep = Reputation::load(path, Params::default(), now).unwrap();
+        assert!(rep.is_banned(&banned, now));
+        assert!(!rep.is_banned(&lifted, now));
+        assert_eq!(rep.journal, 1);
+    }
+
+    #[test]
+    fn compact_journal() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("bans.jsonl");
+        let params = Params::default();
+        let (now, banned) = (SystemTime::now(), peer());
+
+        let mut rep = Reputation::load(path.clone(), params.clone(), now).unwrap();
+        rep.ban(banned.clone(), None, now).unwrap();
+        for _ in 0..MIN_JOURNAL * 2 {
+            rep.ban(peer(), Some(params.ban_duration), now).unwrap();
+        }
+        let lifted = now + params.ban_duration;
+        for _ in 0..MIN_JOURNAL {
+            rep.ban(peer(), Some(params.ban_duration), lifted).unwrap();
+        }
+
+        // The expired bans were dropped when the journal was compacted
+        let records = fs::read(&path)
+            .unwrap()
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .count();
+        assert!(records <= MIN_JOURNAL * 2);
+
+        let rep = Reputation::load(path, params, lifted).unwrap();
+        assert!(rep.is_banned(&banned, lifted));
+        assert_eq!(rep.bans(lifted).count(), MIN_JOURNAL + 1);
+    }
+
+    #[test]
+    fn bounded_scores() {
+        let params = Params {
+            max_scores: 2,
+            ..Params::default()
+        };
+        let mut rep = Reputation::new(params.clone());
+        let now = SystemTime::now();
+        let peers = vec![peer(), peer(), peer()];
+
+        for (i, peer) in peers.iter().enumerate() {
+            let at = now + params.recovery_interval * i as u32;
+            rep.penalise(peer, Misbehaviour::Codec, at).unwrap();
+        }
+
+        let at = now + params.recovery_interval * 2;
+        assert_eq!(rep.scores.len(), 2);
+        assert_eq!(rep.points(&peers[0], at), 0);
+        assert_eq!(rep.points(&peers[2], at), 20);
+    }
+
+    #[test]
+    fn prune_expired() {
+        let params = Params::default();
+        let mut rep = Reputation::new(params.clone());
+        let (now, banned, penalised) = (SystemTime::now(), peer(), peer());
+
+        rep.ban(banned, Some(params.ban_duration), now).unwrap();
+        rep.penalise(&penalised, Misbehaviour::Fetch, now).unwrap();
+
+        let later = now + params.ban_duration;
+        rep.penalise(&peer(), Misbehaviour::Fetch, later).unwrap();
+        assert!(rep.bans.is_empty());
+        assert_eq!(rep.scores.len(), 1);
+    }
+}
@@ -0,0 +1,256 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Point-to-point request/response RPC.
+//!
+//! Unlike gossip, which floods the network, requests are sent to a single,
+//! connected peer over a QUIC stream upgraded to [`upgrade::Rpc`]. The
+//! initiator sends [`Request`]s, to each of which the receiver replies with
+//! exactly one [`Response`], in order.
+//!
+//! [`upgrade::Rpc`]: crate::net::upgrade::Rpc
+
+use std::{net::IpAddr, time::Duration};
+
+use futures::{
+    future::{self, Either},
+    io::{AsyncRead, AsyncWrite},
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use futures_codec::Framed;
+use futures_timer::Delay;
+use minicbor::{Decode, Encode};
+use thiserror::Error;
+
+use crate::{
+    net::{
+        codec::{CborCodec, CborCodecError},
+        gossip::PeerAdvertisement,
+    },
+    uri::RadUrn,
+};
+
+/// How long to wait for a [`Response`].
+// NOTE: Answering a request may involve reading from storage, so this should
+// be generous.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("timed out waiting for a response")]
+    Timeout,
+
+    #[error("stream closed before receiving a response")]
+    NoResponse,
+
+    #[error("unexpected response")]
+    UnexpectedResponse,
+
+    #[error(transparent)]
+    Cbor(#[from] CborCodecError),
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum Request {
+    /// Elicit a [`Response::Pong`], eg. to measure latency.
+    #[n(0)]
+    Ping,
+
+    /// Ask for the receiver's [`PeerAdvertisement`].
+    #[n(1)]
+    Advertisement,
+
+    /// Ask for the URNs the receiver replicates.
+    #[n(2)]
+    Urns,
+
+    /// Ask for the receiver's signed refs of `urn`.
+    #[n(3)]
+    #[cbor(array)]
+    SignedRefs {
+        #[n(0)]
+        urn: RadUrn,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum Response {
+    #[n(0)]
+    Pong,
+
+    #[n(1)]
+    Advertisement(#[n(0)] PeerAdvertisement<IpAddr>),
+
+    #[n(2)]
+    Urns(#[n(0)] Vec<RadUrn>),
+
+    /// The signed refs in their signed JSON form, or `None` if the receiver
+    /// doesn't have the requested URN.
+    ///
+    /// The JSON is passed along verbatim, so the initiator can verify the
+    /// signature (see [`crate::git::refs::Signed::from_json`]).
+    #[n(3)]
+    SignedRefs(#[n(0)] Option<Vec<u8>>),
+}
+
+/// Access to the local state needed for answering [`Request`]s.
+#[async_trait]
+pub trait LocalStorage: Clone + Send + Sync {
+    /// The URNs we replicate.
+    async fn urns(&self) -> Vec<RadUrn>;
+
+    /// Our signed refs of `urn` in their signed JSON form, if we have `urn`.
+    async fn signed_refs(&self, urn: &RadUrn) -> Option<Vec<u8>>;
+}
+
+type ClientCodec = CborCodec<Request, Response>;
+type ServerCodec = CborCodec<Response, Request>;
+
+/// Send `request` over `stream`, and wait for the [`Response`].
+pub(super) async fn call<S>(stream: S, request: Request) -> Result<Response, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, ClientCodec::new());
+    let response = {
+        let call = async {
+            framed.send(request).await?;
+            match framed.next().await {
+                None => Err(Error::NoResponse),
+                Some(response) => Ok(response?),
+            }
+        };
+        futures::pin_mut!(call);
+
+        match future::select(call, Delay::new(RESPONSE_TIMEOUT)).await {
+            Either::Left((response, _)) => response,
+            Either::Right(((), _)) => Err(Error::Timeout),
+        }
+    };
+    let _ = framed.close().await;
+
+    response
+}
+
+/// Answer [`Request`]s received over `stream`, until it is closed.
+pub(super) async fn serve<S, Storage>(
+    stream: S,
+    storage: &Storage,
+    local_ad: &PeerAdvertisement<IpAddr>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Storage: LocalStorage,
+{
+    let mut framed = Framed::new(stream, ServerCodec::new());
+    while let Some(request) = framed.next().await {
+        let response = match request? {
+            Request::Ping => Response::Pong,
+            Request::Advertisement => Response::Advertisement(local_ad.clone()),
+            Request::Urns => Response::Urns(storage.urns().await),
+            Request::SignedRefs { urn } => Response::SignedRefs(storage.signed_refs(&urn).await),
+        };
+        framed.send(response).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        hash::Hash,
+        keys::SecretKey,
+        net::connection::mock::MockStream,
+        peer::PeerId,
+        uri::{Path, Protocol},
+    };
+    use librad_test::roundtrip::*;
+
+    lazy_static! {
+        static ref URN: RadUrn = RadUrn::new(Hash::hash(b"geheim"), Protocol::Git, Path::empty());
+    }
+
+    #[derive(Clone)]
+    struct Storage;
+
+    #[async_trait]
+    impl LocalStorage for Storage {
+        async fn urns(&self) -> Vec<RadUrn> {
+            vec![URN.clone()]
+        }
+
+        async fn signed_refs(&self, urn: &RadUrn) -> Option<Vec<u8>> {
+            if urn == &*URN {
+                Some(b"{}".to_vec())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_request() {
+        cbor_roundtrip(Request::Ping);
+        cbor_roundtrip(Request::Advertisement);
+        cbor_roundtrip(Request::Urns);
+        cbor_roundtrip(Request::SignedRefs { urn: URN.clone() });
+    }
+
+    #[test]
+    fn roundtrip_response() {
+        cbor_roundtrip(Response::Pong);
+        cbor_roundtrip(Response::Advertisement(PeerAdvertisement::new(
+            Ipv4Addr::LOCALHOST.into(),
+            12345,
+        )));
+        cbor_roundtrip(Response::Urns(vec![URN.clone()]));
+        cbor_roundtrip(Response::SignedRefs(Some(b"{}".to_vec())));
+        cbor_roundtrip(Response::SignedRefs(None));
+    }
+
+    #[async_test]
+    async fn call_and_serve() {
+        let local_ad = PeerAdvertisement::new(Ipv4Addr::LOCALHOST.into(), 12345);
+        let initiator = PeerId::from(SecretKey::new());
+        let receiver = PeerId::from(SecretKey::new());
+
+        for (request, expected) in vec![
+            (Request::Ping, Response::Pong),
+            (
+                Request::Advertisement,
+                Response::Advertisement(local_ad.clone()),
+            ),
+            (Request::Urns, Response::Urns(vec![URN.clone()])),
+            (
+                Request::SignedRefs { urn: URN.clone() },
+                Response::SignedRefs(Some(b"{}".to_vec())),
+            ),
+        ] {
+            let (client, server) = MockStream::pair(initiator.clone(), receiver.clone(), 512);
+            let (response, served) =
+                futures::join!(call(client, request), serve(server, &Storage, &local_ad));
+            assert_eq!(response.unwrap(), expected);
+            assert!(served.is_ok());
+        }
+    }
+}
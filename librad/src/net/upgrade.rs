@@ -44,6 +44,50 @@ use crate::git::p2p::transport::GitStream;
 // nodes time out a lot.
 const RECV_UPGRADE_TIMEOUT: Duration = Duration::from_secs(23);
 
+/// The version of the upgrade protocol spoken by this build.
+///
+/// This is also the major version of the protocols negotiated via
+/// [`UpgradeRequest`], and is advertised to other peers (see
+/// [`crate::net::gossip::Capability::Version`]).
+///
+/// Version `1` signs gossip `Have`s and `Want`s, which version `0` peers can't
+/// decode.
+pub const VERSION: u8 = 1;
+
+/// The oldest version of the upgrade protocol this build still understands.
+///
+/// We no longer accept unsigned gossip, so this excludes version `0`.
+pub const MIN_VERSION: u8 = 1;
+
+/// Pick the version of the upgrade protocol to speak to a peer which
+/// advertised the `remote` versions (see
+/// [`crate::net::gossip::Capability::Version`]).
+///
+/// This is the highest version supported by both sides. A peer which didn't
+/// advertise any versions -- because it predates version advertisements, or
+/// because we haven't heard from it yet -- is assumed to speak
+/// [`MIN_VERSION`].
+///
+/// # Errors
+///
+/// If the peer advertised versions, but none of them is in the range
+/// [`MIN_VERSION`]`..=`[`VERSION`].
+pub fn negotiate_version<I>(remote: I) -> Result<u8, ErrorSource>
+where
+    I: IntoIterator<Item = u8>,
+{
+    let remote = remote.into_iter().collect::<Vec<_>>();
+    match remote.iter().max() {
+        None => Ok(MIN_VERSION),
+        Some(max) => remote
+            .iter()
+            .copied()
+            .filter(|v| (MIN_VERSION..=VERSION).contains(v))
+            .max()
+            .ok_or(ErrorSource::UnsupportedVersion(*max)),
+    }
+}
+
 /// Length in bytes of the CBOR encoding of [`UpgradeRequest`].
 ///
 /// We use this to allocate only a fixed-size buffer, and not deal with
@@ -57,6 +101,9 @@ pub struct Gossip;
 #[derive(Debug)]
 pub struct Git;
 
+#[derive(Debug)]
+pub struct Rpc;
+
 /// Signal the (sub-) protocol about to be sent over a given QUIC stream.
 ///
 /// This is only valid as the first message sent by the initiator of a fresh
@@ -67,16 +114,23 @@ pub struct Git;
 /// # Wire Encoding
 ///
 /// The message is encoded as a 2-element CBOR array, where the first element is
-/// the (major) version tag (currently `0` (zero)). The second element is of
+/// the (major) version tag (currently `1` (one)). The second element is of
 /// CBOR major type 0 (unsigned integer), with the value being the `u8`
 /// discriminator of the enum. This allows _compatible_ changes to
 /// [`UpgradeRequest`] (ie. both ends can handle the absence of a variant), as
 /// well as _incompatible_ evolution by incrementing the version tag.
+///
+/// A receiver rejects requests with a version tag outside of
+/// [`MIN_VERSION`]`..=`[`VERSION`], as well as discriminators it doesn't know
+/// about. Peers advertise the versions they support, from which the initiator
+/// picks the version to speak using [`negotiate_version`]. The receiver makes
+/// the version chosen available via [`Upgraded::version`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum UpgradeRequest {
     Gossip = 0,
     Git = 1,
+    Rpc = 2,
 }
 
 impl Into<UpgradeRequest> for Gossip {
@@ -91,33 +145,55 @@ impl Into<UpgradeRequest> for Git {
     }
 }
 
+impl Into<UpgradeRequest> for Rpc {
+    fn into(self) -> UpgradeRequest {
+        UpgradeRequest::Rpc
+    }
+}
+
 impl minicbor::Encode for UpgradeRequest {
     fn encode<W: minicbor::encode::Write>(
         &self,
         e: &mut minicbor::Encoder<W>,
     ) -> Result<(), minicbor::encode::Error<W::Error>> {
-        e.array(2)?.u8(0)?.u8(*self as u8)?.end()?;
+        e.array(2)?.u8(VERSION)?.u8(*self as u8)?.end()?;
         Ok(())
     }
 }
 
-impl<'de> minicbor::Decode<'de> for UpgradeRequest {
-    fn decode(d: &mut minicbor::Decoder<'de>) -> Result<Self, minicbor::decode::Error> {
+impl UpgradeRequest {
+    /// Decode the version tag and discriminator, without interpreting them.
+    fn decode_raw(d: &mut minicbor::Decoder) -> Result<(u8, u8), minicbor::decode::Error> {
         if Some(2) != d.array()? {
             return Err(minicbor::decode::Error::Message("expected 2-element array"));
         }
 
-        match d.u8()? {
-            0 => match d.u8()? {
-                0 => Ok(Self::Gossip),
-                1 => Ok(Self::Git),
-                n => Err(minicbor::decode::Error::UnknownVariant(n as u32)),
-            },
-            n => Err(minicbor::decode::Error::UnknownVariant(n as u32)),
+        Ok((d.u8()?, d.u8()?))
+    }
+
+    fn from_raw(version: u8, discriminator: u8) -> Result<Self, ErrorSource> {
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(ErrorSource::UnsupportedVersion(version));
+        }
+
+        match discriminator {
+            0 => Ok(Self::Gossip),
+            1 => Ok(Self::Git),
+            2 => Ok(Self::Rpc),
+            n => Err(ErrorSource::UnsupportedUpgrade(n)),
         }
     }
 }
 
+impl<'de> minicbor::Decode<'de> for UpgradeRequest {
+    fn decode(d: &mut minicbor::Decoder<'de>) -> Result<Self, minicbor::decode::Error> {
+        let (version, discriminator) = Self::decode_raw(d)?;
+        Self::from_raw(version, discriminator).or(Err(minicbor::decode::Error::Message(
+            "unsupported upgrade request",
+        )))
+    }
+}
+
 #[derive(Error)]
 #[error("stream upgrade failed")]
 pub struct Error<S> {
@@ -136,6 +212,16 @@ pub enum ErrorSource {
     #[error("timed out")]
     Timeout,
 
+    #[error(
+        "unsupported protocol version {0}, expected {}..={}",
+        MIN_VERSION,
+        VERSION
+    )]
+    UnsupportedVersion(u8),
+
+    #[error("unsupported upgrade {0}")]
+    UnsupportedUpgrade(u8),
+
     #[error(transparent)]
     Encode(#[from] minicbor::encode::Error<io::Error>),
 
@@ -149,17 +235,24 @@ pub enum ErrorSource {
 #[derive(Debug)]
 pub struct Upgraded<U, S> {
     stream: S,
+    version: u8,
     _marker: PhantomData<U>,
 }
 
 impl<U, S> Upgraded<U, S> {
-    pub fn new(stream: S) -> Self {
+    pub fn new(stream: S, version: u8) -> Self {
         Self {
             stream,
+            version,
             _marker: PhantomData,
         }
     }
 
+    /// The version of the upgrade protocol both ends agreed on.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn into_stream(self) -> S {
         self.stream
     }
@@ -170,6 +263,7 @@ impl<U, S> Upgraded<U, S> {
     {
         Upgraded {
             stream: f(self.stream),
+            version: self.version,
             _marker: PhantomData,
         }
     }
@@ -221,6 +315,7 @@ impl<S> GitStream for Upgraded<Git, S> where S: AsyncRead + AsyncWrite + Unpin +
 pub enum SomeUpgraded<S> {
     Gossip(Upgraded<Gossip, S>),
     Git(Upgraded<Git, S>),
+    Rpc(Upgraded<Rpc, S>),
 }
 
 impl<S> SomeUpgraded<S> {
@@ -231,23 +326,40 @@ impl<S> SomeUpgraded<S> {
         match self {
             Self::Gossip(up) => SomeUpgraded::Gossip(up.map(f)),
             Self::Git(up) => SomeUpgraded::Git(up.map(f)),
+            Self::Rpc(up) => SomeUpgraded::Rpc(up.map(f)),
         }
     }
 }
 
-pub async fn upgrade<U, S>(mut stream: S, upgrade: U) -> Result<Upgraded<U, S>, Error<S>>
+/// Request `upgrade` of `stream`, speaking `version` of the upgrade protocol.
+///
+/// `version` should be obtained from [`negotiate_version`].
+pub async fn upgrade<U, S>(
+    mut stream: S,
+    version: u8,
+    upgrade: U,
+) -> Result<Upgraded<U, S>, Error<S>>
 where
     U: Into<UpgradeRequest>,
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
     let send = async {
-        let cbor = minicbor::to_vec(&upgrade.into())?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(ErrorSource::UnsupportedVersion(version));
+        }
+
+        let mut cbor = Vec::with_capacity(UPGRADE_REQUEST_ENCODING_LEN);
+        minicbor::Encoder::new(&mut cbor)
+            .array(2)?
+            .u8(version)?
+            .u8(upgrade.into() as u8)?
+            .end()?;
         Ok(stream.write_all(&cbor).await?)
     };
 
     match send.await {
         Err(source) => Err(Error { stream, source }),
-        Ok(()) => Ok(Upgraded::new(stream)),
+        Ok(()) => Ok(Upgraded::new(stream, version)),
     }
 }
 
@@ -273,7 +385,9 @@ where
                 .await?;
         }
 
-        Ok(minicbor::decode(&buf)?)
+        let (version, discriminator) =
+            UpgradeRequest::decode_raw(&mut minicbor::Decoder::new(&buf))?;
+        UpgradeRequest::from_raw(version, discriminator).map(|req| (version, req))
     };
 
     match recv.await {
@@ -281,10 +395,11 @@ where
             stream: incoming,
             source,
         }),
-        Ok(req) => {
+        Ok((version, req)) => {
             let upgrade = match req {
-                UpgradeRequest::Gossip => SomeUpgraded::Gossip(Upgraded::new(incoming)),
-                UpgradeRequest::Git => SomeUpgraded::Git(Upgraded::new(incoming)),
+                UpgradeRequest::Gossip => SomeUpgraded::Gossip(Upgraded::new(incoming, version)),
+                UpgradeRequest::Git => SomeUpgraded::Git(Upgraded::new(incoming, version)),
+                UpgradeRequest::Rpc => SomeUpgraded::Rpc(Upgraded::new(incoming, version)),
             };
 
             Ok(upgrade)
@@ -317,7 +432,7 @@ mod tests {
     ) -> Result<SomeUpgraded<()>, Error<MockStream>> {
         let (initiator, receiver) = MockStream::pair(INITIATOR.clone(), RECEIVER.clone(), 512);
         try_join!(
-            async { upgrade(initiator, req).await.map_err(Error::from) },
+            async { upgrade(initiator, VERSION, req).await.map_err(Error::from) },
             async {
                 with_upgraded(receiver)
                     .await
@@ -337,9 +452,117 @@ mod tests {
         assert_matches!(test_upgrade(Gossip).await, Ok(SomeUpgraded::Gossip(_)))
     }
 
+    #[async_test]
+    async fn upgrade_rpc() {
+        assert_matches!(test_upgrade(Rpc).await, Ok(SomeUpgraded::Rpc(_)))
+    }
+
+    #[async_test]
+    async fn reject_unsupported_version() {
+        let (mut initiator, receiver) = MockStream::pair(INITIATOR.clone(), RECEIVER.clone(), 512);
+        let (sent, received) = futures::join!(
+            async {
+                let mut buf = Vec::new();
+                minicbor::Encoder::new(&mut buf)
+                    .array(2)?
+                    .u8(VERSION + 1)?
+                    .u8(UpgradeRequest::Gossip as u8)?
+                    .end()?;
+                initiator.write_all(&buf).await.map_err(ErrorSource::from)
+            },
+            with_upgraded(receiver)
+        );
+
+        assert!(sent.is_ok());
+        assert_matches!(
+            received.map(|upgrade| upgrade.map(|_| ())),
+            Err(Error {
+                source: ErrorSource::UnsupportedVersion(v),
+                ..
+            }) if v == VERSION + 1
+        )
+    }
+
+    #[async_test]
+    async fn reject_unsigned_gossip_version() {
+        let (mut initiator, receiver) = MockStream::pair(INITIATOR.clone(), RECEIVER.clone(), 512);
+        let (sent, received) = futures::join!(
+            async {
+                let mut buf = Vec::new();
+                minicbor::Encoder::new(&mut buf)
+                    .array(2)?
+                    .u8(0)?
+                    .u8(UpgradeRequest::Gossip as u8)?
+                    .end()?;
+                initiator.write_all(&buf).await.map_err(ErrorSource::from)
+            },
+            with_upgraded(receiver)
+        );
+
+        assert!(sent.is_ok());
+        assert_matches!(
+            received.map(|upgrade| upgrade.map(|_| ())),
+            Err(Error {
+                source: ErrorSource::UnsupportedVersion(0),
+                ..
+            })
+        )
+    }
+
+    #[async_test]
+    async fn receiver_learns_version() {
+        let (initiator, receiver) = MockStream::pair(INITIATOR.clone(), RECEIVER.clone(), 512);
+        let (sent, received) = futures::join!(
+            upgrade(initiator, MIN_VERSION, Gossip),
+            with_upgraded(receiver)
+        );
+
+        assert_eq!(sent.unwrap().version(), MIN_VERSION);
+        assert_matches!(
+            received.map(|upgrade| upgrade.map(|_| ())),
+            Ok(SomeUpgraded::Gossip(upgraded)) if upgraded.version() == MIN_VERSION
+        )
+    }
+
+    #[async_test]
+    async fn refuse_to_send_unsupported_version() {
+        let (initiator, _receiver) = MockStream::pair(INITIATOR.clone(), RECEIVER.clone(), 512);
+        assert_matches!(
+            upgrade(initiator, VERSION + 1, Gossip)
+                .await
+                .map(|upgraded| upgraded.map(|_| ())),
+            Err(Error {
+                source: ErrorSource::UnsupportedVersion(v),
+                ..
+            }) if v == VERSION + 1
+        )
+    }
+
+    #[test]
+    fn negotiate_highest_common_version() {
+        assert_eq!(
+            negotiate_version(vec![MIN_VERSION, VERSION, VERSION + 1]).unwrap(),
+            VERSION
+        )
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_min_version() {
+        assert_eq!(negotiate_version(None).unwrap(), MIN_VERSION)
+    }
+
+    #[test]
+    fn negotiate_no_common_version() {
+        assert_matches!(
+            negotiate_version(Some(VERSION + 1)),
+            Err(ErrorSource::UnsupportedVersion(v)) if v == VERSION + 1
+        )
+    }
+
     #[test]
     fn rountrip_upgrade_request() {
         cbor_roundtrip(UpgradeRequest::Gossip);
-        cbor_roundtrip(UpgradeRequest::Git)
+        cbor_roundtrip(UpgradeRequest::Git);
+        cbor_roundtrip(UpgradeRequest::Rpc)
     }
 }
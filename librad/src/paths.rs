@@ -30,6 +30,7 @@ pub struct Paths {
     keys_dir: PathBuf,
     git_dir: PathBuf,
     git_includes_dir: PathBuf,
+    net_dir: PathBuf,
 }
 
 impl Paths {
@@ -48,6 +49,7 @@ impl Paths {
             keys_dir: config_dir.join("keys"),
             git_dir: data_dir.join("git"),
             git_includes_dir: config_dir.join("git-includes"),
+            net_dir: data_dir.join("net"),
         }
         .init()
     }
@@ -61,6 +63,7 @@ impl Paths {
             keys_dir: root.join("keys"),
             git_dir: root.join("git"),
             git_includes_dir: root.join("git-includes"),
+            net_dir: root.join("net"),
         }
         .init()
     }
@@ -84,6 +87,11 @@ impl Paths {
         &self.git_includes_dir
     }
 
+    /// State of the networking layer, eg. the list of banned peers.
+    pub fn net_dir(&self) -> &Path {
+        &self.net_dir
+    }
+
     pub fn all_dirs(&self) -> HashMap<&str, &Path> {
         // Nb. this pattern match is here to keep the map consistent with the
         // struct fields
@@ -91,12 +99,14 @@ impl Paths {
             keys_dir,
             git_dir,
             git_includes_dir,
+            net_dir,
         } = self;
 
         [
             ("keys_dir", keys_dir.as_path()),
             ("git_dir", git_dir.as_path()),
             ("git_includes_dir", git_includes_dir.as_path()),
+            ("net_dir", net_dir.as_path()),
         ]
         .iter()
         .cloned()
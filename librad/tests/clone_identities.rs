@@ -0,0 +1,325 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+#![feature(async_closure)]
+
+#[macro_use]
+extern crate assert_matches;
+
+use std::collections::BTreeSet;
+
+use librad::{
+    git::{
+        storage::{self, rad_urn},
+        types::NamespacedRef,
+    },
+    identities::{
+        git::{User, Verifying},
+        payload::{self, UserPayload},
+    },
+};
+
+use librad_test::{
+    logging,
+    rad::{
+        identities::{create_test_project, create_test_user},
+        testnet,
+    },
+};
+
+#[tokio::test]
+async fn can_clone_user() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let alice = peer1
+            .with_storage(move |storage| create_test_user(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        peer2
+            .with_storage(move |storage| {
+                let cloned = storage
+                    .clone_user(&alice.urn(), peer1_id, Some(peer1_addr))
+                    .unwrap();
+                assert_eq!(cloned.content_id, alice.content_id);
+                assert_eq!(
+                    storage.user(&alice.urn()).unwrap().content_id,
+                    alice.content_id
+                );
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn can_clone_project() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        peer2
+            .with_storage(move |storage| {
+                let cloned = storage
+                    .clone_project(&radicle.project.urn(), peer1_id, Some(peer1_addr))
+                    .unwrap();
+                assert_eq!(cloned.content_id, radicle.project.content_id);
+
+                // The owner is adopted along with the project
+                assert_eq!(
+                    storage.user(&radicle.owner.urn()).unwrap().content_id,
+                    radicle.owner.content_id
+                );
+                assert_eq!(
+                    storage.certifiers(&radicle.urn()).unwrap(),
+                    Some(radicle.owner.urn())
+                        .into_iter()
+                        .collect::<BTreeSet<_>>()
+                );
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn cannot_clone_twice() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let alice = peer1
+            .with_storage(move |storage| create_test_user(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        peer2
+            .with_storage(move |storage| {
+                storage
+                    .clone_user(&alice.urn(), peer1_id.clone(), Some(peer1_addr))
+                    .unwrap();
+                assert_matches!(
+                    storage.clone_user(&alice.urn(), peer1_id, Some(peer1_addr)),
+                    Err(storage::Error::AlreadyExists(_))
+                );
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rejected_clone_is_rolled_back() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let alice = peer1
+            .with_storage(move |storage| create_test_user(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        peer2
+            .with_storage(move |storage| {
+                // `alice` is not a project, so verification fails after the
+                // namespace was fetched
+                assert!(storage
+                    .clone_project(&alice.urn(), peer1_id.clone(), Some(peer1_addr))
+                    .is_err());
+                assert!(!storage.has_urn(&rad_urn(&alice.urn())).unwrap());
+
+                // .. which doesn't prevent us from cloning it properly
+                storage
+                    .clone_user(&alice.urn(), peer1_id, Some(peer1_addr))
+                    .unwrap();
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn fetch_user_fast_forwards() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let alice = {
+            let key = peer1_key;
+            peer1
+                .with_storage(move |storage| create_test_user(storage, &key))
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        {
+            let alice = alice.clone();
+            let peer1_id = peer1_id.clone();
+            peer2
+                .with_storage(move |storage| {
+                    storage
+                        .clone_user(&alice.urn(), peer1_id, Some(peer1_addr))
+                        .unwrap();
+                })
+                .await
+                .unwrap();
+        }
+
+        // Rename `alice` on peer1
+        let updated = {
+            let alice = alice.clone();
+            let git_dir = peer1.paths().git_dir().to_path_buf();
+            peer1
+                .with_storage(move |storage| {
+                    let payload: UserPayload = payload::User {
+                        name: "alice-liddell".into(),
+                    }
+                    .into();
+                    let updated = storage
+                        .identities::<User>()
+                        .update(
+                            Verifying::from(alice.clone().into_inner())
+                                .signed()
+                                .unwrap(),
+                            payload,
+                            None,
+                            &peer1_key,
+                        )
+                        .unwrap();
+
+                    let repo = git2::Repository::open(git_dir).unwrap();
+                    repo.reference(
+                        &NamespacedRef::rad_id(rad_urn(&alice.urn()).id).to_string(),
+                        *updated.content_id,
+                        true,
+                        "updated alice",
+                    )
+                    .unwrap();
+
+                    updated
+                })
+                .await
+                .unwrap()
+        };
+
+        peer2
+            .with_storage(move |storage| {
+                let fetched = storage
+                    .fetch_user(&alice.urn(), peer1_id, Some(peer1_addr))
+                    .unwrap();
+                assert_eq!(fetched.content_id, updated.content_id);
+                assert_eq!(
+                    storage.user(&alice.urn()).unwrap().content_id,
+                    updated.content_id
+                );
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn fetch_project_without_changes() {
+    logging::init();
+
+    const NUM_PEERS: usize = 2;
+
+    let peers = testnet::setup(NUM_PEERS).await.unwrap();
+    testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
+        let (peer1, peer1_key) = apis.pop().unwrap();
+        let (peer2, _) = apis.pop().unwrap();
+
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let peer1_id = peer1.peer_id().clone();
+        let peer1_addr = peer1.listen_addr();
+        peer2
+            .with_storage(move |storage| {
+                let urn = radicle.project.urn();
+                storage
+                    .clone_project(&urn, peer1_id.clone(), Some(peer1_addr))
+                    .unwrap();
+                let fetched = storage
+                    .fetch_project(&urn, peer1_id, Some(peer1_addr))
+                    .unwrap();
+                assert_eq!(fetched.content_id, radicle.project.content_id);
+                assert_eq!(
+                    storage.project(&urn).unwrap().content_id,
+                    radicle.project.content_id
+                );
+            })
+            .await
+            .unwrap();
+    })
+    .await;
+}
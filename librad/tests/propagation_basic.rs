@@ -17,7 +17,7 @@
 
 #![feature(async_closure)]
 
-use std::{marker::PhantomData, time::Duration};
+use std::{collections::BTreeSet, marker::PhantomData, time::Duration};
 
 use futures::{future, stream::StreamExt};
 use tempfile::tempdir;
@@ -29,7 +29,6 @@ use librad::{
         storage,
         types::{remote::Remote, FlatRef, Force, NamespacedRef},
     },
-    meta::{entity::Signatory, project::ProjectInfo},
     net::peer::{FetchInfo, Gossip, PeerEvent, Rev},
     signer::SomeSigner,
     uri::{self, RadUrn},
@@ -38,10 +37,7 @@ use librad::{
 use librad_test::{
     git::initial_commit,
     logging,
-    rad::{
-        entity::{Alice, Radicle},
-        testnet,
-    },
+    rad::{identities::create_test_project, testnet},
 };
 
 #[tokio::test]
@@ -55,35 +51,17 @@ async fn can_clone() {
         let (peer1, peer1_key) = apis.pop().unwrap();
         let (peer2, _) = apis.pop().unwrap();
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
-        let radicle_urn = radicle.urn();
-
-        peer1
-            .with_storage(move |storage| {
-                storage.create_repo(&alice).unwrap();
-                storage.create_repo(&radicle).unwrap();
-            })
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
             .await
+            .unwrap()
             .unwrap();
+        let radicle_urn = radicle.urn();
+
         peer2
             .with_storage(move |storage| {
                 storage
-                    .clone_repo::<ProjectInfo, _>(
+                    .clone_repo(
                         radicle_urn.clone().into_rad_url(peer1.peer_id().clone()),
                         None,
                     )
@@ -108,35 +86,17 @@ async fn can_clone_disconnected() {
         let (peer1, peer1_key) = apis.pop().unwrap();
         let (peer2, _) = apis.pop().unwrap();
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
-        let radicle_urn = radicle.urn();
-
-        peer1
-            .with_storage(move |storage| {
-                storage.create_repo(&alice).unwrap();
-                storage.create_repo(&radicle).unwrap();
-            })
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
             .await
+            .unwrap()
             .unwrap();
+        let radicle_urn = radicle.urn();
+
         peer2
             .with_storage(move |storage| {
                 storage
-                    .clone_repo::<ProjectInfo, _>(
+                    .clone_repo(
                         radicle_urn.clone().into_rad_url(peer1.peer_id().clone()),
                         Some(peer1.listen_addr()),
                     )
@@ -161,46 +121,22 @@ async fn fetches_on_gossip_notify() {
         let (peer1, peer1_key) = apis.pop().unwrap();
         let (peer2, _) = apis.pop().unwrap();
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
         let peer2_events = peer2.subscribe().await;
-        let urn = radicle.urn();
-        let alice_name = alice.name();
 
         // Create project on peer1, and clone from peer2
-        {
-            let alice = alice.clone();
-            let radicle = radicle.clone();
-            peer1
-                .with_storage(move |storage| {
-                    storage.create_repo(&alice).unwrap();
-                    storage.create_repo(&radicle).unwrap();
-                })
-                .await
-                .unwrap();
-        }
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
+        let urn = radicle.urn();
+        let alice_name = radicle.owner.doc.payload.subject.name.to_string();
 
         {
             let radicle_at_peer1 = radicle.urn().into_rad_url(peer1.peer_id().clone());
             peer2
                 .with_storage(move |storage| {
-                    storage
-                        .clone_repo::<ProjectInfo, _>(radicle_at_peer1, None)
-                        .unwrap();
+                    storage.clone_repo(radicle_at_peer1, None).unwrap();
                 })
                 .await
                 .unwrap();
@@ -265,6 +201,7 @@ async fn fetches_on_gossip_notify() {
                         PeerEvent::GossipFetch(FetchInfo { provider, .. }) => {
                             future::ready(provider == peer1_id)
                         },
+                        _ => future::ready(false),
                     })
                     .map(|_| ())
                     .next(),
@@ -297,7 +234,7 @@ async fn fetches_on_gossip_notify() {
 }
 
 #[tokio::test]
-async fn all_metadata_returns_only_local_projects() {
+async fn all_identities_returns_only_local_identities() {
     logging::init();
 
     const NUM_PEERS: usize = 3;
@@ -308,49 +245,38 @@ async fn all_metadata_returns_only_local_projects() {
         let (peer2, _) = apis.pop().unwrap();
         let (peer3, _) = apis.pop().unwrap();
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
         let radicle_at_peer1 = radicle.urn().into_rad_url(peer1.peer_id().clone());
         let radicle_at_peer2 = radicle.urn().into_rad_url(peer2.peer_id().clone());
 
-        peer1
-            .with_storage(move |storage| {
-                storage.create_repo(&alice).unwrap();
-                storage.create_repo(&radicle).unwrap();
-            })
-            .await
-            .unwrap();
         peer2
             .with_storage(move |storage| {
-                storage
-                    .clone_repo::<ProjectInfo, _>(radicle_at_peer1, None)
-                    .unwrap();
+                storage.clone_repo(radicle_at_peer1, None).unwrap();
             })
             .await
             .unwrap();
-        let all_metadata_acc_to_peer3 = peer3
+        let all_identities_acc_to_peer3 = peer3
             .with_storage(move |storage| {
-                storage.clone_repo::<ProjectInfo, _>(radicle_at_peer2, None)?;
-                Ok::<_, storage::Error>(storage.all_metadata()?.collect::<Vec<_>>())
+                storage.clone_repo(radicle_at_peer2, None)?;
+                storage
+                    .all_identities()?
+                    .map(|id| id.map(|id| id.urn()))
+                    .collect::<Result<BTreeSet<_>, storage::Error>>()
             })
             .await
             .unwrap()
             .unwrap();
-        assert_eq!(1, all_metadata_acc_to_peer3.len());
+        // The project, and its maintainer adopted along with it
+        assert_eq!(
+            vec![radicle.owner.urn(), radicle.project.urn()]
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+            all_identities_acc_to_peer3
+        );
     })
     .await;
 }
@@ -369,30 +295,12 @@ async fn ask_and_clone() {
 
     testnet::run_on_testnet(peers, NUM_PEERS, async move |mut apis| {
         let (peer1, peer1_key) = apis.pop().unwrap();
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
         let repo_urn = peer1
-            .with_storage(move |storage| {
-                storage.create_repo(&alice).unwrap();
-                storage.create_repo(&radicle).unwrap().urn
-            })
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
             .await
-            .unwrap();
+            .unwrap()
+            .unwrap()
+            .urn();
 
         let (peer2, _) = apis.pop().unwrap();
         let res = peer2
@@ -424,7 +332,7 @@ async fn ask_and_clone() {
             let url = repo_urn.clone().into_rad_url(peer_id);
             peer2
                 .with_storage(move |storage| {
-                    storage.clone_repo::<ProjectInfo, _>(url, None).unwrap();
+                    storage.clone_repo(url, None).unwrap();
                 })
                 .await
                 .unwrap();
@@ -465,33 +373,14 @@ async fn menage_a_troi() {
 
         let (peer3, _) = apis.pop().unwrap();
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
-        let urn = radicle.urn();
-        let default_branch = radicle.default_branch().to_string();
-        let alice_name = alice.name().to_string();
-
-        peer1
-            .with_storage(move |storage| {
-                storage.create_repo(&alice).unwrap();
-                storage.create_repo(&radicle).unwrap();
-            })
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
             .await
+            .unwrap()
             .unwrap();
+        let urn = radicle.urn();
+        let default_branch = "master";
+        let alice_name = radicle.owner.doc.payload.subject.name.to_string();
 
         let tmp = tempfile::tempdir().unwrap();
         let settings = librad::git::local::transport::Settings {
@@ -527,9 +416,7 @@ async fn menage_a_troi() {
             let url = urn.clone().into_rad_url(peer1_id.clone());
             peer2
                 .with_storage(move |storage| {
-                    storage
-                        .clone_repo::<ProjectInfo, _>(url, Some(peer1_addr))
-                        .unwrap();
+                    storage.clone_repo(url, Some(peer1_addr)).unwrap();
                     storage.has_ref(&head).unwrap()
                 })
                 .await
@@ -540,9 +427,7 @@ async fn menage_a_troi() {
             let url = urn.clone().into_rad_url(peer2_id);
             peer3
                 .with_storage(move |storage| {
-                    storage
-                        .clone_repo::<ProjectInfo, _>(url, Some(peer2_addr))
-                        .unwrap();
+                    storage.clone_repo(url, Some(peer2_addr)).unwrap();
                     storage.has_ref(&head).unwrap()
                 })
                 .await
@@ -31,7 +31,6 @@ use librad::{
         local::{transport, url::LocalUrl},
         types::{remote::Remote, FlatRef, Force, NamespacedRef},
     },
-    meta::{entity::Signatory, project::ProjectInfo},
     net::peer::{FetchInfo, Gossip, PeerEvent, Rev},
     peer::PeerId,
     signer::SomeSigner,
@@ -41,10 +40,7 @@ use librad::{
 use librad_test::{
     git::initial_commit,
     logging,
-    rad::{
-        entity::{Alice, Radicle},
-        testnet,
-    },
+    rad::{identities::create_test_project, testnet},
 };
 
 /// This integration test is to ensure that we can setup a working copy that can
@@ -83,41 +79,18 @@ async fn can_fetch() {
         };
         librad::git::local::transport::register(global_settings);
 
-        let mut alice = Alice::new(peer1_key.public());
-        let mut radicle = Radicle::new(&alice);
-        {
-            let resolves_to_alice = alice.clone();
-            alice
-                .sign(&peer1_key, &Signatory::OwnedKey, &resolves_to_alice)
-                .unwrap();
-            radicle
-                .sign(
-                    &peer1_key,
-                    &Signatory::User(alice.urn()),
-                    &resolves_to_alice,
-                )
-                .unwrap();
-        }
-
+        let radicle = peer1
+            .with_storage(move |storage| create_test_project(storage, &peer1_key))
+            .await
+            .unwrap()
+            .unwrap();
         let radicle_urn = radicle.urn();
 
-        {
-            let alice = alice.clone();
-            let radicle = radicle.clone();
-            peer1
-                .with_storage(move |storage| {
-                    storage.create_repo(&alice).unwrap();
-                    storage.create_repo(&radicle).unwrap();
-                })
-                .await
-                .unwrap();
-        }
-
         let tracked_users = {
             let url = radicle_urn.clone().into_rad_url(peer1.peer_id().clone());
             peer2
                 .with_storage(move |storage| {
-                    storage.clone_repo::<ProjectInfo, _>(url, None).unwrap();
+                    storage.clone_repo(url, None).unwrap();
                     storage
                         .tracked(&radicle_urn)
                         .unwrap()
@@ -142,7 +115,11 @@ async fn can_fetch() {
         let heads = NamespacedRef::heads(radicle.urn().id, Some(peer1.peer_id().clone()));
         let remotes: FlatRef<String, _> = FlatRef::heads(
             PhantomData,
-            Some(format!("{}@{}", alice.name(), peer1.peer_id())),
+            Some(format!(
+                "{}@{}",
+                radicle.owner.doc.payload.subject.name,
+                peer1.peer_id()
+            )),
         );
 
         let remote = Remote::rad_remote(url, Some(remotes.refspec(heads, Force::True).into_dyn()));
@@ -221,6 +198,7 @@ where
                 PeerEvent::GossipFetch(FetchInfo { provider, .. }) => {
                     future::ready(provider == remote)
                 },
+                _ => future::ready(false),
             })
             .map(|_| ())
             .next(),
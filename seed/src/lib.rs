@@ -19,7 +19,7 @@
 #[macro_use]
 extern crate async_trait;
 
-use std::{collections::HashSet, io, net::SocketAddr, path::PathBuf, time::Duration, vec};
+use std::{collections::HashSet, io, net::SocketAddr, path::PathBuf, time::Duration};
 
 use futures::stream::StreamExt;
 use thiserror::Error;
@@ -29,8 +29,8 @@ use radicle_keystore::sign::ed25519;
 use librad::{
     git,
     keys,
-    meta::project,
     net::{
+        addrbook,
         discovery,
         gossip,
         gossip::types::PeerInfo,
@@ -53,6 +53,9 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error(transparent)]
+    AddressBook(#[from] addrbook::Error),
+
     #[error(transparent)]
     Bootstrap(#[from] peer::BootstrapError),
 
@@ -165,10 +168,16 @@ impl Node {
         } else {
             paths::Paths::new()?
         };
-        let gossip_params = Default::default();
-        let seeds: Vec<(PeerId, SocketAddr)> = vec![];
-        let disco = discovery::Static::new(seeds);
+        // Seeds replicate everything, so there's no point in only being sent
+        // gossip we're interested in
+        let gossip_params = gossip::MembershipParams {
+            advertise_interests: false,
+            ..Default::default()
+        };
+        // Rejoin the network via the peers we knew before the last restart
+        let disco = discovery::Persisted::load(addrbook::default_path(&paths))?;
         let storage_config = Default::default();
+        let sync_params = Default::default();
         let config = PeerConfig {
             signer: self.config.signer,
             paths,
@@ -176,6 +185,8 @@ impl Node {
             gossip_params,
             disco,
             storage_config,
+            sync_params,
+            accept_pushes: false,
         };
 
         let peer = config.try_into_peer().await?;
@@ -231,7 +242,7 @@ impl Node {
             let urn = urn.clone();
             api.with_storage(move |storage| {
                 storage
-                    .clone_repo::<project::ProjectInfo, _>(url, addr_hints)
+                    .clone_repo(url, addr_hints)
                     .and_then(|_| storage.track(&urn, &peer_id))
             })
         }
@@ -0,0 +1,565 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Building blocks for reconciling issues which were edited concurrently by
+//! different peers.
+//!
+//! Every type implementing [`Merge`] does so in a way that is commutative,
+//! associative and idempotent: no matter in which order, or how often, peers
+//! exchange their states, they end up with the same result.
+//!
+//! * Sets (labels, assignees, reactions) are [`ORSet`]s, where a removal only
+//!   affects the additions it has observed.
+//! * Single values (the content of a comment) are [`LwwRegister`]s, where the
+//!   edit with the greatest [`Stamp`] wins.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+};
+
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use librad::{
+    internal::canonical::{Cjson, CjsonError},
+    peer::PeerId,
+};
+
+use crate::clock::{Clock, RadClock};
+
+/// Types whose values can be merged with concurrently modified copies.
+pub trait Merge {
+    /// Merge `other` into `self`.
+    fn merge(&mut self, other: Self);
+}
+
+/// The point in time at which a peer made a change.
+///
+/// Stamps are ordered by their [`RadClock`], and by the [`PeerId`] of the
+/// peer which made the change if the clocks happen to be equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    time: RadClock,
+    peer: PeerId,
+}
+
+impl Stamp {
+    /// Create a `Stamp` for a change made by `peer` right now.
+    pub fn new(peer: PeerId) -> Self {
+        Self::new_with_timestamp(peer, RadClock::current_time())
+    }
+
+    /// Create a `Stamp` with a supplied `timestamp`.
+    pub fn new_with_timestamp(peer: PeerId, time: RadClock) -> Self {
+        Self { time, peer }
+    }
+
+    /// Get a reference to the time of the change.
+    pub fn time(&self) -> &RadClock {
+        &self.time
+    }
+
+    /// Get a reference to the peer which made the change.
+    pub fn peer(&self) -> &PeerId {
+        &self.peer
+    }
+}
+
+impl PartialOrd for Stamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Stamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.time
+            .cmp_time(&other.time)
+            .then_with(|| self.peer.cmp(&other.peer))
+    }
+}
+
+/// A last-writer-wins register.
+///
+/// The initial value carries no [`Stamp`], so any edit supersedes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: T,
+    stamp: Option<Stamp>,
+}
+
+impl<T> LwwRegister<T> {
+    /// Create a register holding the initial `value`.
+    pub fn new(value: T) -> Self {
+        Self { value, stamp: None }
+    }
+
+    /// Get a reference to the current value.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Get a reference to the [`Stamp`] of the last edit, if any.
+    pub fn stamp(&self) -> Option<&Stamp> {
+        self.stamp.as_ref()
+    }
+
+    /// Set the register to `value`, unless it already holds a more recent
+    /// edit.
+    ///
+    /// Returns `true` if the value was set.
+    pub fn set(&mut self, value: T, stamp: Stamp) -> bool {
+        if self.stamp.as_ref() < Some(&stamp) {
+            self.value = value;
+            self.stamp = Some(stamp);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<T: Ord> Merge for LwwRegister<T> {
+    fn merge(&mut self, other: Self) {
+        let ours = (&self.stamp, &self.value);
+        let theirs = (&other.stamp, &other.value);
+        if ours < theirs {
+            *self = other
+        }
+    }
+}
+
+/// An observed-remove set.
+///
+/// Every addition of an element is tagged with a [`Stamp`]. Removing an
+/// element removes the tags observed so far, so that a concurrent addition of
+/// the same element survives the merge.
+///
+/// Elements are serialised in the order of their canonical JSON
+/// representation, so that equal sets serialise to the same bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ORSet<T: Eq + Hash> {
+    entries: HashMap<T, Entry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Entry {
+    adds: BTreeSet<Stamp>,
+    removes: BTreeSet<Stamp>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        self.adds.iter().any(|tag| !self.removes.contains(tag))
+    }
+}
+
+impl<T: Eq + Hash> ORSet<T> {
+    /// Create an empty set.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Add `value` to the set, tagging the addition with `tag`.
+    ///
+    /// Returns `true` if the value was not present before.
+    pub fn insert(&mut self, value: T, tag: Stamp) -> bool {
+        let entry = self.entries.entry(value).or_default();
+        let was_live = entry.is_live();
+        entry.adds.insert(tag);
+        !was_live
+    }
+
+    /// Remove `value` from the set.
+    ///
+    /// Returns `true` if the value was present before.
+    pub fn remove(&mut self, value: &T) -> bool {
+        match self.entries.get_mut(value) {
+            Some(entry) if entry.is_live() => {
+                let observed = entry.adds.clone();
+                entry.removes.extend(observed);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Remove the additions of `value` tagged with one of `observed`.
+    ///
+    /// Unlike [`ORSet::remove`], this can be applied to any copy of the set,
+    /// leaving additions alone which were not observed when the removal was
+    /// made (see [`ORSet::observed`]).
+    ///
+    /// Returns `true` if the value was present before, and is no longer.
+    pub fn remove_observed(&mut self, value: T, observed: &BTreeSet<Stamp>) -> bool {
+        let entry = self.entries.entry(value).or_default();
+        let was_live = entry.is_live();
+        entry.removes.extend(observed.iter().cloned());
+        was_live && !entry.is_live()
+    }
+
+    /// Get the tags of the additions of `value` which were not removed.
+    pub fn observed(&self, value: &T) -> BTreeSet<Stamp> {
+        self.entries
+            .get(value)
+            .map(|entry| entry.adds.difference(&entry.removes).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Check if `value` is in the set.
+    pub fn contains(&self, value: &T) -> bool {
+        self.entries
+            .get(value)
+            .map(|entry| entry.is_live())
+            .unwrap_or(false)
+    }
+
+    /// Iterate over the elements in the set.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &T> + 'a {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.is_live())
+            .map(|(value, _)| value)
+    }
+
+    /// The number of elements in the set.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Check if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+impl<T: Eq + Hash> Merge for ORSet<T> {
+    fn merge(&mut self, other: Self) {
+        for (value, theirs) in other.entries {
+            let ours = self.entries.entry(value).or_default();
+            ours.adds.extend(theirs.adds);
+            ours.removes.extend(theirs.removes);
+        }
+    }
+}
+
+impl<T: Eq + Hash + Serialize> Serialize for ORSet<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut entries = self
+            .entries
+            .iter()
+            .map(|(value, entry)| Ok((Cjson(value).canonical_form()?, value, entry)))
+            .collect::<Result<Vec<_>, CjsonError>>()
+            .map_err(<S::Error as ser::Error>::custom)?;
+        entries.sort_by(|(a_key, _, a), (b_key, _, b)| a_key.cmp(b_key).then(a.cmp(b)));
+        serializer.collect_seq(entries.into_iter().map(|(_, value, entry)| (value, entry)))
+    }
+}
+
+impl<'de, T: Eq + Hash + Deserialize<'de>> Deserialize<'de> for ORSet<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<(T, Entry)>::deserialize(deserializer)?;
+        Ok(Self {
+            entries: entries.into_iter().collect(),
+        })
+    }
+}
+
+/// An item of a [`crate::Thread`] which can be merged with its concurrently
+/// modified copies.
+///
+/// Items are identified by their [`Node::id`], and siblings are ordered by
+/// their creation time, and then by their id.
+pub trait Node: Merge {
+    /// The type identifying an item.
+    type Id: Ord;
+
+    /// Get a reference to the identifier of the item.
+    fn id(&self) -> &Self::Id;
+
+    /// Get a reference to the creation time of the item.
+    fn created_at(&self) -> &RadClock;
+
+    /// Compare the positions of two items in a [`crate::Thread`].
+    fn cmp_position(&self, other: &Self) -> Ordering {
+        self.created_at()
+            .cmp_time(other.created_at())
+            .then_with(|| self.id().cmp(other.id()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librad::keys::SecretKey;
+    use proptest::prelude::*;
+
+    use crate::{Comment, Finger, Issue, Label, Reaction, ReplyTo, Thread, Title};
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(u8, usize),
+        Remove(u8),
+    }
+
+    fn peers() -> Vec<PeerId> {
+        (0..3).map(|_| PeerId::from(SecretKey::new())).collect()
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0u8..4, 0usize..3).prop_map(|(x, peer)| Op::Insert(x, peer)),
+            (0u8..4).prop_map(Op::Remove),
+        ]
+    }
+
+    fn apply(set: &mut ORSet<u8>, ops: &[Op], peers: &[PeerId]) {
+        for op in ops {
+            match op {
+                Op::Insert(x, peer) => {
+                    set.insert(*x, Stamp::new(peers[*peer].clone()));
+                },
+                Op::Remove(x) => {
+                    set.remove(x);
+                },
+            }
+        }
+    }
+
+    fn merged<T: Merge + Clone>(a: &T, b: &T) -> T {
+        let mut result = a.clone();
+        result.merge(b.clone());
+        result
+    }
+
+    proptest! {
+        #[test]
+        fn orset_merge_commutes(
+            base in prop::collection::vec(op(), 0..8),
+            xs in prop::collection::vec(op(), 0..8),
+            ys in prop::collection::vec(op(), 0..8),
+        ) {
+            let peers = peers();
+            let mut a = ORSet::new();
+            apply(&mut a, &base, &peers);
+            let mut b = a.clone();
+            apply(&mut a, &xs, &peers);
+            apply(&mut b, &ys, &peers);
+
+            prop_assert_eq!(merged(&a, &b), merged(&b, &a));
+        }
+
+        #[test]
+        fn orset_merge_is_associative_and_idempotent(
+            xs in prop::collection::vec(op(), 0..8),
+            ys in prop::collection::vec(op(), 0..8),
+            zs in prop::collection::vec(op(), 0..8),
+        ) {
+            let peers = peers();
+            let (mut a, mut b, mut c) = (ORSet::new(), ORSet::new(), ORSet::new());
+            apply(&mut a, &xs, &peers);
+            apply(&mut b, &ys, &peers);
+            apply(&mut c, &zs, &peers);
+
+            prop_assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)));
+            prop_assert_eq!(merged(&a, &a), a);
+        }
+
+        #[test]
+        fn lww_merge_commutes(xs in prop::collection::vec((0u8..4, 0usize..3), 0..8)) {
+            let peers = peers();
+            let mut registers = vec![LwwRegister::new(0u8), LwwRegister::new(0u8)];
+            for (i, (x, peer)) in xs.into_iter().enumerate() {
+                registers[i % 2].set(x, Stamp::new(peers[peer].clone()));
+            }
+
+            prop_assert_eq!(
+                merged(&registers[0], &registers[1]),
+                merged(&registers[1], &registers[0])
+            );
+        }
+    }
+
+    type TestIssue = Issue<u64, u64, String>;
+
+    #[derive(Debug, Clone)]
+    enum IssueOp {
+        Label(bool, u8),
+        Assign(bool, u8),
+        React(bool, u8),
+        Reply(u8),
+        Edit(u8, u8),
+        Delete(u8),
+    }
+
+    fn issue_op() -> impl Strategy<Value = IssueOp> {
+        prop_oneof![
+            (any::<bool>(), 0u8..4).prop_map(|(add, x)| IssueOp::Label(add, x)),
+            (any::<bool>(), 0u8..4).prop_map(|(add, x)| IssueOp::Assign(add, x)),
+            (any::<bool>(), 0u8..4).prop_map(|(add, x)| IssueOp::React(add, x)),
+            any::<u8>().prop_map(IssueOp::Reply),
+            (any::<u8>(), 0u8..4).prop_map(|(i, x)| IssueOp::Edit(i, x)),
+            any::<u8>().prop_map(IssueOp::Delete),
+        ]
+    }
+
+    // Navigate to the `i`th item of the main thread (modulo its length), if
+    // there are any.
+    fn navigate_main(thread: &mut Thread<Comment<u64, String>>, i: u8) -> bool {
+        thread.navigate_to_root();
+        let len = thread.expand().len() - 1;
+        len > 0 && thread.navigate_to(Finger::Main(i as usize % len)).is_ok()
+    }
+
+    fn apply_issue(issue: &mut TestIssue, ops: &[IssueOp], peer: &PeerId, next_id: &mut u64) {
+        for op in ops {
+            match op {
+                IssueOp::Label(add, x) => {
+                    let label = Label::new(x.to_string());
+                    if *add {
+                        issue.meta_mut().add_label(label, peer);
+                    } else {
+                        issue.meta_mut().remove_label(&label);
+                    }
+                },
+                IssueOp::Assign(add, x) => {
+                    if *add {
+                        issue.meta_mut().add_assignee(x.to_string(), peer);
+                    } else {
+                        issue.meta_mut().remove_assignee(&x.to_string());
+                    }
+                },
+                IssueOp::React(add, x) => {
+                    let thread = issue.thread_mut();
+                    thread.navigate_to_root();
+                    let comment = thread.view_mut().unwrap().get_mut();
+                    let reaction = Reaction::new(String::from("kim"), x.to_string());
+                    if *add {
+                        comment.react(reaction, peer);
+                    } else {
+                        comment.unreact(&reaction);
+                    }
+                },
+                IssueOp::Reply(i) => {
+                    *next_id += 1;
+                    let comment = Comment::new(*next_id, String::from("finto"), i.to_string());
+                    let thread = issue.thread_mut();
+                    if i % 2 == 0 && navigate_main(thread, *i) {
+                        thread.reply(comment, ReplyTo::Thread);
+                    } else {
+                        thread.navigate_to_root();
+                        thread.reply(comment, ReplyTo::Main);
+                    }
+                },
+                IssueOp::Edit(i, x) => {
+                    let thread = issue.thread_mut();
+                    if navigate_main(thread, *i) {
+                        thread.edit(|comment| {
+                            comment.edit(x.to_string(), peer);
+                        });
+                    }
+                },
+                IssueOp::Delete(i) => {
+                    let thread = issue.thread_mut();
+                    if navigate_main(thread, *i) {
+                        thread.delete().unwrap();
+                    }
+                },
+            }
+        }
+    }
+
+    fn assert_same(a: &TestIssue, b: &TestIssue) -> Result<(), TestCaseError> {
+        let (mut a, mut b) = (a.thread().clone(), b.thread().clone());
+        a.navigate_to_root();
+        b.navigate_to_root();
+        prop_assert_eq!(a.view(), b.view());
+        prop_assert_eq!(&a, &b);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn issue_merge_commutes(
+            base in prop::collection::vec(issue_op(), 0..8),
+            xs in prop::collection::vec(issue_op(), 0..8),
+            ys in prop::collection::vec(issue_op(), 0..8),
+        ) {
+            let peers = peers();
+            let mut a = Issue::new(
+                0,
+                0,
+                String::from("Monadic"),
+                Title::from("Buggy Boeuf"),
+                String::from("We have bugs in our boeuf"),
+            );
+            apply_issue(&mut a, &base, &peers[0], &mut 0);
+            let mut b = a.clone();
+            apply_issue(&mut a, &xs, &peers[1], &mut 100);
+            apply_issue(&mut b, &ys, &peers[2], &mut 200);
+
+            let (ab, ba) = (merged(&a, &b), merged(&b, &a));
+            assert_same(&ab, &ba)?;
+            prop_assert_eq!(ab.meta(), ba.meta());
+            assert_same(&merged(&ab, &a), &ab)?;
+        }
+    }
+
+    #[test]
+    fn concurrent_add_survives_remove() {
+        let peers = peers();
+        let mut a = ORSet::new();
+        a.insert("bug", Stamp::new(peers[0].clone()));
+        let mut b = a.clone();
+
+        a.remove(&"bug");
+        b.insert("bug", Stamp::new(peers[1].clone()));
+
+        assert!(merged(&a, &b).contains(&"bug"));
+        assert!(merged(&b, &a).contains(&"bug"));
+    }
+
+    #[test]
+    fn orset_serialisation_is_deterministic() {
+        let peer = PeerId::from(SecretKey::new());
+        let stamp = Stamp::new(peer);
+
+        let mut forwards = ORSet::new();
+        let mut backwards = ORSet::new();
+        for x in 0u8..32 {
+            forwards.insert(x, stamp.clone());
+            backwards.insert(31 - x, stamp.clone());
+        }
+        forwards.remove(&7);
+        backwards.remove(&7);
+
+        assert_eq!(
+            serde_json::to_string(&forwards).unwrap(),
+            serde_json::to_string(&backwards).unwrap()
+        );
+    }
+}
@@ -0,0 +1,563 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Git-backed storage of [`Issue`]s.
+//!
+//! Issues live in the namespace of the project they belong to, ie. in the
+//! same repository as `librad::git::storage::Storage` (which can be opened
+//! via `librad::paths::Paths::git_dir`):
+//!
+//! * `refs/namespaces/<project>/refs/issues/<issue>` for our own issues
+//! * `refs/namespaces/<project>/refs/remotes/<peer>/issues/<issue>` for the
+//!   issues of tracked peers, which are fetched alongside the project's code
+//!
+//! An issue is stored as the history of the [`SignedOp`]s made to it: every
+//! operation is recorded as a commit on top of the previous one. The tree of
+//! each commit holds a single blob, `op`, containing the canonical JSON
+//! representation of the operation.
+//!
+//! Operations are verified against the delegations of their authors when an
+//! issue is loaded, so that tampered or forged operations replicated from
+//! other peers are never [`replay`]ed.
+//!
+//! [`Issue`]: crate::Issue
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    hash::Hash,
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use librad::{
+    git::{
+        ext::{is_not_found_err, FirstParent, References, Start},
+        types::{Multiple, Namespace, NamespacedRef, Single},
+    },
+    internal::canonical::{Cjson, CjsonError},
+    keys::PublicKey,
+    peer::PeerId,
+    uri::RadUrn,
+};
+
+use crate::{
+    op::{self, replay, Delegations, SignedOp},
+    SomeIssue,
+};
+
+/// The name of the blob holding the operation in an issue commit's tree.
+const BLOB_NAME: &str = "op";
+
+/// The operations of an issue, alongside the commits they were read from.
+type CommitOps<Id, Cid, User> = Vec<(git2::Oid, SignedOp<Id, Cid, User>)>;
+
+/// Errors which may occur when reading or writing issues.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The issue commit doesn't contain the `op` blob.
+    #[error("no op blob found in commit {0}")]
+    MissingBlob(git2::Oid),
+
+    /// The operation recorded in the commit failed verification.
+    #[error("rejected operation in commit {commit}")]
+    Rejected {
+        /// The commit holding the operation.
+        commit: git2::Oid,
+        /// The reason for rejecting the operation.
+        #[source]
+        reason: op::Error,
+    },
+
+    /// The operations could not be replayed.
+    #[error(transparent)]
+    Op(#[from] op::Error),
+
+    /// The operation could not be serialised.
+    #[error(transparent)]
+    Cjson(#[from] CjsonError),
+
+    /// The operation could not be deserialised.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// An error occurred in the underlying git repository.
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+}
+
+/// Read and write access to the [`SomeIssue`]s of a project.
+pub struct Issues<'a> {
+    repo: &'a git2::Repository,
+    namespace: Namespace,
+    maintainers: BTreeSet<PublicKey>,
+}
+
+impl<'a> Issues<'a> {
+    /// Access the issues of the project `urn`, stored in `repo`.
+    ///
+    /// `maintainers` are the keys of the project's delegations, which may
+    /// moderate its issues (see [`replay`]).
+    pub fn new(repo: &'a git2::Repository, urn: &RadUrn, maintainers: BTreeSet<PublicKey>) -> Self {
+        Self {
+            repo,
+            namespace: urn.id.clone(),
+            maintainers,
+        }
+    }
+
+    /// Record `op` as the latest operation on the issue `id`, returning the
+    /// new commit.
+    ///
+    /// The operation is stored under our own `refs/issues/<id>`. It is not
+    /// verified, so it is up to the caller to only record operations they
+    /// signed themselves, or which passed [`SignedOp::verify`].
+    pub fn record<Id, Cid, User>(
+        &self,
+        id: &Id,
+        op: &SignedOp<Id, Cid, User>,
+        message: &str,
+    ) -> Result<git2::Oid, Error>
+    where
+        Id: Display + Serialize,
+        Cid: Serialize,
+        User: Serialize,
+    {
+        let reference = self.reference(id, None);
+        let parent = match reference.find(self.repo) {
+            Ok(r) => Some(r.peel_to_commit()?),
+            Err(e) if is_not_found_err(&e) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let blob = self.repo.blob(&Cjson(op).canonical_form()?)?;
+        let tree = {
+            let mut builder = self.repo.treebuilder(None)?;
+            builder.insert(BLOB_NAME, blob, 0o100_644)?;
+            let oid = builder.write()?;
+            self.repo.find_tree(oid)
+        }?;
+        let author = self
+            .repo
+            .signature()
+            .or_else(|_| git2::Signature::now("radicle", "radicle@localhost"))?;
+
+        let oid = self.repo.commit(
+            Some(&reference.to_string()),
+            &author,
+            &author,
+            message,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )?;
+
+        Ok(oid)
+    }
+
+    /// Load the operations on issue `id`, either our own or as seen by `peer`,
+    /// without verifying them.
+    ///
+    /// The operations are returned newest first.
+    pub fn ops<Id, Cid, User>(
+        &self,
+        id: &Id,
+        peer: Option<PeerId>,
+    ) -> Result<Vec<SignedOp<Id, Cid, User>>, Error>
+    where
+        Id: Display + DeserializeOwned,
+        Cid: DeserializeOwned,
+        User: DeserializeOwned,
+    {
+        match self.reference(id, peer).find(self.repo) {
+            Ok(r) => Ok(self
+                .ops_from(r.peel_to_commit()?.id())?
+                .into_iter()
+                .map(|(_, op)| op)
+                .collect()),
+            Err(e) if is_not_found_err(&e) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load the issue `id`, either our own or as seen by `peer`.
+    ///
+    /// Returns `None` if there is no such issue.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Rejected`] if any of the operations on the issue
+    /// is not signed by a key its author delegates to.
+    pub fn get<Id, Cid, User, D>(
+        &self,
+        id: &Id,
+        peer: Option<PeerId>,
+        delegations: &D,
+    ) -> Result<Option<SomeIssue<Id, Cid, User>>, Error>
+    where
+        Id: Clone + Display + Serialize + DeserializeOwned,
+        Cid: Clone + Ord + Serialize + DeserializeOwned,
+        User: Clone + Eq + Hash + Serialize + DeserializeOwned,
+        D: Delegations<User>,
+    {
+        match self.reference(id, peer).find(self.repo) {
+            Ok(r) => self
+                .issue_from(r.peel_to_commit()?.id(), delegations)
+                .map(Some),
+            Err(e) if is_not_found_err(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load the issue `id` from the operations made to it by ourselves and
+    /// all tracked peers.
+    ///
+    /// Operations which fail verification are left out, rather than failing
+    /// the whole issue on account of a single misbehaving peer.
+    pub fn merged<Id, Cid, User, D>(
+        &self,
+        id: &Id,
+        delegations: &D,
+    ) -> Result<Option<SomeIssue<Id, Cid, User>>, Error>
+    where
+        Id: Clone + Display + Serialize + DeserializeOwned,
+        Cid: Clone + Ord + Serialize + DeserializeOwned,
+        User: Clone + Eq + Hash + Serialize + DeserializeOwned,
+        D: Delegations<User>,
+    {
+        let mut peers = vec![None];
+        peers.extend(self.remotes()?.into_iter().map(Some));
+
+        let mut ops = HashMap::new();
+        for peer in peers {
+            for op in self.ops(id, peer)? {
+                if op.verify(delegations).is_ok() {
+                    ops.insert(Cjson(&op).canonical_form()?, op);
+                }
+            }
+        }
+
+        if ops.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(replay(
+                ops.into_iter().map(|(_, op)| op),
+                &self.maintainers,
+            )?))
+        }
+    }
+
+    /// Load all issues of the project, either our own or as seen by `peer`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Rejected`] if any of the operations on any of the
+    /// issues is not signed by a key its author delegates to.
+    pub fn list<Id, Cid, User, D>(
+        &self,
+        peer: Option<PeerId>,
+        delegations: &D,
+    ) -> Result<Vec<SomeIssue<Id, Cid, User>>, Error>
+    where
+        Id: Clone + Serialize + DeserializeOwned,
+        Cid: Clone + Ord + Serialize + DeserializeOwned,
+        User: Clone + Eq + Hash + Serialize + DeserializeOwned,
+        D: Delegations<User>,
+    {
+        let refs: NamespacedRef<Multiple> = NamespacedRef::issues(self.namespace.clone(), peer);
+        refs.references(self.repo)?
+            .peeled()
+            .map(|(_, oid)| self.issue_from(oid, delegations))
+            .collect()
+    }
+
+    /// The remote peers we have fetched issues of.
+    pub fn remotes(&self) -> Result<Vec<PeerId>, Error> {
+        let prefix = format!("refs/namespaces/{}/refs/remotes/", self.namespace);
+        let glob = format!("{}*/issues/*", prefix);
+
+        let mut peers = References::from_globs(self.repo, &[glob])?
+            .peeled()
+            .filter_map(|(name, _)| {
+                name.strip_prefix(&prefix)
+                    .and_then(|suffix| suffix.split('/').next())
+                    .and_then(|peer| peer.parse::<PeerId>().ok())
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.to_string());
+        peers.dedup();
+
+        Ok(peers)
+    }
+
+    fn reference<Id: Display>(&self, id: &Id, peer: Option<PeerId>) -> NamespacedRef<Single> {
+        NamespacedRef::issue(self.namespace.clone(), peer, &id.to_string())
+    }
+
+    fn issue_from<Id, Cid, User, D>(
+        &self,
+        head: git2::Oid,
+        delegations: &D,
+    ) -> Result<SomeIssue<Id, Cid, User>, Error>
+    where
+        Id: Clone + Serialize + DeserializeOwned,
+        Cid: Clone + Ord + Serialize + DeserializeOwned,
+        User: Clone + Eq + Hash + Serialize + DeserializeOwned,
+        D: Delegations<User>,
+    {
+        let ops = self.ops_from(head)?;
+        for (commit, op) in &ops {
+            op.verify(delegations).map_err(|reason| Error::Rejected {
+                commit: *commit,
+                reason,
+            })?;
+        }
+
+        Ok(replay(
+            ops.into_iter().map(|(_, op)| op),
+            &self.maintainers,
+        )?)
+    }
+
+    fn ops_from<Id, Cid, User>(&self, head: git2::Oid) -> Result<CommitOps<Id, Cid, User>, Error>
+    where
+        Id: DeserializeOwned,
+        Cid: DeserializeOwned,
+        User: DeserializeOwned,
+    {
+        FirstParent::new(self.repo, Start::Oid(head))?
+            .into_iter()
+            .map(|commit| {
+                let commit = commit?;
+                Ok((commit, self.op_at(commit)?))
+            })
+            .collect()
+    }
+
+    fn op_at<Id, Cid, User>(&self, commit: git2::Oid) -> Result<SignedOp<Id, Cid, User>, Error>
+    where
+        Id: DeserializeOwned,
+        Cid: DeserializeOwned,
+        User: DeserializeOwned,
+    {
+        let tree = self.repo.find_commit(commit)?.tree()?;
+        let entry = tree
+            .get_path(Path::new(BLOB_NAME))
+            .map_err(|_| Error::MissingBlob(commit))?;
+        let blob = entry.to_object(self.repo)?.peel_to_blob()?;
+
+        Ok(serde_json::from_slice(blob.content())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use librad::{hash::Hash as RadHash, keys::SecretKey, uri};
+
+    use crate::{
+        op::Op,
+        test::{delegations, maintainers},
+        Finger,
+        Label,
+        Title,
+    };
+
+    type TestIssue = SomeIssue<u64, u64, String>;
+    type TestOp = SignedOp<u64, u64, String>;
+
+    fn urn() -> RadUrn {
+        RadUrn::new(
+            RadHash::hash(b"project"),
+            uri::Protocol::Git,
+            uri::Path::empty(),
+        )
+    }
+
+    fn open(id: u64, key: &SecretKey) -> TestOp {
+        SignedOp::sign(
+            Op::Open {
+                id,
+                comment: 0,
+                title: Title::from("Buggy Boeuf"),
+                content: String::from("We have bugs in our boeuf"),
+            },
+            String::from("monadic"),
+            key,
+        )
+        .unwrap()
+    }
+
+    fn op(op: Op<u64, u64, String>, author: &str, key: &SecretKey) -> TestOp {
+        SignedOp::sign(op, author.to_string(), key).unwrap()
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), Error> {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path())?;
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+        let issues = Issues::new(&repo, &urn(), maintainers(&monadic));
+        let delegations = delegations(&[("monadic", &monadic), ("finto", &finto)]);
+
+        let first = issues.record(&1, &open(1, &monadic), "Create issue")?;
+        let reply = op(
+            Op::Reply {
+                to: None,
+                comment: 1,
+                content: String::from("Where?"),
+            },
+            "finto",
+            &finto,
+        );
+        let second = issues.record(&1, &reply, "Reply")?;
+        let label = op(
+            Op::Label {
+                label: Label::new(String::from("bug")),
+            },
+            "monadic",
+            &monadic,
+        );
+        issues.record(&1, &label, "Label")?;
+        issues.record(&1, &op(Op::Close, "monadic", &monadic), "Close")?;
+        assert_eq!(repo.find_commit(second)?.parent_id(0)?, first);
+
+        let ops: Vec<TestOp> = issues.ops(&1, None)?;
+        assert_eq!(ops.len(), 4);
+        assert_eq!(ops[2], reply);
+
+        let loaded: TestIssue = issues
+            .get(&1, None, &delegations)?
+            .expect("issue not found");
+        assert!(!loaded.is_open());
+        assert_eq!(loaded.issue().title(), &Title::from("Buggy Boeuf"));
+        assert!(loaded
+            .issue()
+            .meta()
+            .labels()
+            .contains(&Label::new(String::from("bug"))));
+        assert_eq!(
+            loaded.issue().thread().find(|c| c.identifier() == &1),
+            Some(Finger::Main(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_undelegated() -> Result<(), Error> {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path())?;
+        let monadic = SecretKey::new();
+        let mallory = SecretKey::new();
+        let issues = Issues::new(&repo, &urn(), maintainers(&monadic));
+        let delegations = delegations(&[("monadic", &monadic)]);
+
+        issues.record(&1, &open(1, &monadic), "Create issue")?;
+        issues.record(&1, &op(Op::Close, "monadic", &mallory), "Close")?;
+
+        let loaded: Result<Option<TestIssue>, Error> = issues.get(&1, None, &delegations);
+        assert!(matches!(
+            loaded,
+            Err(Error::Rejected {
+                reason: op::Error::NotDelegated(_),
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merged_skips_rejected() -> Result<(), Error> {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path())?;
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+        let mallory = SecretKey::new();
+        let issues = Issues::new(&repo, &urn(), maintainers(&monadic));
+        let delegations = delegations(&[("monadic", &monadic), ("finto", &finto)]);
+
+        let head = issues.record(&1, &open(1, &monadic), "Create issue")?;
+
+        // Pretend we fetched the issue from two peers, one of which is lying
+        // about `finto`'s reaction.
+        for (key, reaction) in &[(&finto, "heart"), (&mallory, "thumbsdown")] {
+            let peer = PeerId::from(**key);
+            let reaction = op(
+                Op::React {
+                    comment: 0,
+                    reaction: reaction.to_string(),
+                },
+                "finto",
+                key,
+            );
+            let blob = repo.blob(&Cjson(&reaction).canonical_form()?)?;
+            let mut builder = repo.treebuilder(None)?;
+            builder.insert(BLOB_NAME, blob, 0o100_644)?;
+            let tree = repo.find_tree(builder.write()?)?;
+            let author = git2::Signature::now("radicle", "radicle@localhost")?;
+            repo.commit(
+                Some(&issues.reference(&1, Some(peer)).to_string()),
+                &author,
+                &author,
+                "React",
+                &tree,
+                &[&repo.find_commit(head)?],
+            )?;
+        }
+
+        let loaded: TestIssue = issues.merged(&1, &delegations)?.expect("issue not found");
+        let reactions = loaded.issue().thread().view().unwrap().get().reactions();
+        assert_eq!(reactions.get("heart"), Some(&vec![String::from("finto")]));
+        assert_eq!(reactions.get("thumbsdown"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_per_urn() -> Result<(), Error> {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path())?;
+        let key = SecretKey::new();
+        let issues = Issues::new(&repo, &urn(), maintainers(&key));
+        let delegations = delegations(&[("monadic", &key)]);
+
+        issues.record(&1, &open(1, &key), "Create issue")?;
+        issues.record(&2, &open(2, &key), "Create issue")?;
+
+        let other = RadUrn::new(
+            RadHash::hash(b"other"),
+            uri::Protocol::Git,
+            uri::Path::empty(),
+        );
+        Issues::new(&repo, &other, maintainers(&key)).record(&3, &open(3, &key), "Create issue")?;
+
+        let mut ids = issues
+            .list::<u64, u64, String, _>(None, &delegations)?
+            .iter()
+            .map(|issue| *issue.issue().identifier())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        Ok(())
+    }
+}
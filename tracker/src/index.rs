@@ -0,0 +1,597 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! An in-memory index over the issues of a project.
+//!
+//! The [`Index`] is populated with [`SomeIssue`]s, eg. as loaded via
+//! [`crate::git::Issues::list`], and answers [`Query`]s over them.
+//!
+//! # Examples
+//!
+//! ```
+//! use radicle_tracker::{
+//!     index::{Index, Query, Sort, State},
+//!     Issue,
+//!     SomeIssue,
+//!     Title,
+//! };
+//!
+//! let index = vec![
+//!     Issue::new(1, 0, "monadic", Title::from("Buggy Boeuf"), String::from("Bugs!")),
+//!     Issue::new(2, 0, "finto", Title::from("Tasty Boeuf"), String::from("Yum!")),
+//! ]
+//! .into_iter()
+//! .map(SomeIssue::from)
+//! .collect::<Index<_, _, _>>();
+//!
+//! let page = index.query(&Query::new().state(State::Open).text("bugs"));
+//! assert_eq!(page.total, 1);
+//! assert_eq!(page.issues[0].issue().title(), &Title::from("Buggy Boeuf"));
+//!
+//! let page = index.query(&Query::new().sort(Sort::Title).limit(1));
+//! assert_eq!(page.total, 2);
+//! assert_eq!(page.issues[0].issue().title(), &Title::from("Tasty Boeuf"));
+//! ```
+
+use std::{cmp::Ordering, collections::BTreeMap, hash::Hash, iter::FromIterator};
+
+use crate::{
+    clock::{Clock, Elapsed, RadClock, Sign},
+    Label,
+    SomeIssue,
+};
+
+/// The state of an issue, see [`SomeIssue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    /// The issue is open.
+    Open,
+    /// The issue was closed.
+    Closed,
+}
+
+/// The key to sort the results of a [`Query`] by.
+///
+/// Issues with equal keys are ordered by their identifier, so that the order
+/// of results is stable across pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sort {
+    /// Sort by the creation time of the issue.
+    Created,
+    /// Sort by the time of the last activity on the issue.
+    Activity,
+    /// Sort by the title of the issue.
+    Title,
+    /// Sort by the identifier of the issue.
+    Identifier,
+}
+
+/// The direction to sort the results of a [`Query`] in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Order {
+    /// Smallest first, eg. oldest first.
+    Ascending,
+    /// Largest first, eg. newest first.
+    Descending,
+}
+
+/// A query over an [`Index`].
+///
+/// All filters given must match for an issue to be part of the result. By
+/// default, all issues match, newest first.
+#[derive(Debug, Clone)]
+pub struct Query<User> {
+    state: Option<State>,
+    labels: Vec<Label>,
+    assignee: Option<User>,
+    author: Option<User>,
+    created_after: Option<RadClock>,
+    created_before: Option<RadClock>,
+    active_after: Option<RadClock>,
+    active_before: Option<RadClock>,
+    active_within: Option<(RadClock, Elapsed)>,
+    terms: Vec<String>,
+    sort: Sort,
+    order: Order,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl<User> Default for Query<User> {
+    fn default() -> Self {
+        Self {
+            state: None,
+            labels: vec![],
+            assignee: None,
+            author: None,
+            created_after: None,
+            created_before: None,
+            active_after: None,
+            active_before: None,
+            active_within: None,
+            terms: vec![],
+            sort: Sort::Created,
+            order: Order::Descending,
+            offset: 0,
+            limit: None,
+        }
+    }
+}
+
+impl<User> Query<User> {
+    /// A query matching all issues, newest first.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match issues in `state`.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Only match issues labelled with `label`. May be given multiple times,
+    /// in which case issues must carry all labels.
+    pub fn label(mut self, label: Label) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    /// Only match issues `user` is assigned to.
+    pub fn assignee(mut self, user: User) -> Self {
+        self.assignee = Some(user);
+        self
+    }
+
+    /// Only match issues opened by `user`.
+    pub fn author(mut self, user: User) -> Self {
+        self.author = Some(user);
+        self
+    }
+
+    /// Only match issues created at or after `time`.
+    pub fn created_after(mut self, time: RadClock) -> Self {
+        self.created_after = Some(time);
+        self
+    }
+
+    /// Only match issues created at or before `time`.
+    pub fn created_before(mut self, time: RadClock) -> Self {
+        self.created_before = Some(time);
+        self
+    }
+
+    /// Only match issues with activity at or after `time`.
+    pub fn active_after(mut self, time: RadClock) -> Self {
+        self.active_after = Some(time);
+        self
+    }
+
+    /// Only match issues without activity after `time`.
+    pub fn active_before(mut self, time: RadClock) -> Self {
+        self.active_before = Some(time);
+        self
+    }
+
+    /// Only match issues with activity within `elapsed` before `now`, eg. "in
+    /// the last 2 weeks".
+    pub fn active_within(mut self, now: RadClock, elapsed: Elapsed) -> Self {
+        self.active_within = Some((now, elapsed));
+        self
+    }
+
+    /// Only match issues containing all words of `text` in their title or
+    /// the content of any of their live comments, ignoring case.
+    pub fn text(mut self, text: &str) -> Self {
+        self.terms
+            .extend(text.split_whitespace().map(str::to_lowercase));
+        self
+    }
+
+    /// Sort the results by `sort`.
+    pub fn sort(mut self, sort: Sort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Sort the results in `order`.
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Skip the first `offset` results.
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Return at most `limit` results.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// A page of results of a [`Query`].
+#[derive(Debug)]
+pub struct Page<'a, Id, Cid, User: Eq + Hash> {
+    /// The total number of issues matching the query, regardless of
+    /// pagination.
+    pub total: usize,
+    /// The issues on this page.
+    pub issues: Vec<&'a SomeIssue<Id, Cid, User>>,
+}
+
+struct Entry<Id, Cid, User: Eq + Hash> {
+    issue: SomeIssue<Id, Cid, User>,
+    last_activity: RadClock,
+    text: String,
+}
+
+impl<Id, Cid, User: Eq + Hash> Entry<Id, Cid, User> {
+    fn new(issue: SomeIssue<Id, Cid, User>) -> Self {
+        let (last_activity, text) = {
+            let inner = issue.issue();
+            let mut last_activity = *inner.timestamp();
+            let mut text = inner.title().to_lowercase();
+
+            for comment in inner.thread().iter() {
+                let comment = comment.get();
+                let times = Some(comment.timestamp())
+                    .into_iter()
+                    .chain(comment.edited().map(|stamp| stamp.time()));
+                for time in times {
+                    if time.cmp_time(&last_activity) == Ordering::Greater {
+                        last_activity = *time;
+                    }
+                }
+            }
+            for comment in inner.thread().iter().filter_map(|c| c.live()) {
+                text.push('\n');
+                text.push_str(&comment.content().to_lowercase());
+            }
+
+            (last_activity, text)
+        };
+
+        Self {
+            issue,
+            last_activity,
+            text,
+        }
+    }
+
+    fn state(&self) -> State {
+        if self.issue.is_open() {
+            State::Open
+        } else {
+            State::Closed
+        }
+    }
+
+    fn matches(&self, query: &Query<User>) -> bool {
+        let issue = self.issue.issue();
+        let at_or_after =
+            |time: &RadClock, bound: &RadClock| time.cmp_time(bound) != Ordering::Less;
+        let at_or_before =
+            |time: &RadClock, bound: &RadClock| time.cmp_time(bound) != Ordering::Greater;
+
+        query.state.map_or(true, |state| self.state() == state)
+            && query
+                .labels
+                .iter()
+                .all(|label| issue.meta().labels().contains(label))
+            && query
+                .assignee
+                .as_ref()
+                .map_or(true, |user| issue.meta().assignees().contains(user))
+            && query
+                .author
+                .as_ref()
+                .map_or(true, |user| issue.author() == user)
+            && query
+                .created_after
+                .as_ref()
+                .map_or(true, |bound| at_or_after(issue.timestamp(), bound))
+            && query
+                .created_before
+                .as_ref()
+                .map_or(true, |bound| at_or_before(issue.timestamp(), bound))
+            && query
+                .active_after
+                .as_ref()
+                .map_or(true, |bound| at_or_after(&self.last_activity, bound))
+            && query
+                .active_before
+                .as_ref()
+                .map_or(true, |bound| at_or_before(&self.last_activity, bound))
+            && query.active_within.as_ref().map_or(true, |(now, elapsed)| {
+                let since = now.diff_since(&self.last_activity);
+                since.sign() == Sign::Minus || since <= elapsed.seconds()
+            })
+            && query.terms.iter().all(|term| self.text.contains(term))
+    }
+}
+
+/// An index over the issues of a project, keyed by their identifiers.
+pub struct Index<Id, Cid, User: Eq + Hash> {
+    entries: BTreeMap<Id, Entry<Id, Cid, User>>,
+}
+
+impl<Id: Ord, Cid, User: Eq + Hash> Default for Index<Id, Cid, User> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<Id: Ord + Clone, Cid, User: Eq + Hash> Index<Id, Cid, User> {
+    /// Create an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `issue` to the index, replacing and returning a previous version
+    /// of it, if any.
+    pub fn insert(&mut self, issue: SomeIssue<Id, Cid, User>) -> Option<SomeIssue<Id, Cid, User>> {
+        let id = issue.issue().identifier().clone();
+        self.entries
+            .insert(id, Entry::new(issue))
+            .map(|entry| entry.issue)
+    }
+
+    /// Remove the issue `id` from the index.
+    pub fn remove(&mut self, id: &Id) -> Option<SomeIssue<Id, Cid, User>> {
+        self.entries.remove(id).map(|entry| entry.issue)
+    }
+
+    /// Get the issue `id`.
+    pub fn get(&self, id: &Id) -> Option<&SomeIssue<Id, Cid, User>> {
+        self.entries.get(id).map(|entry| &entry.issue)
+    }
+
+    /// Get the time of the last activity on the issue `id`, ie. the latest
+    /// time a comment was made or edited.
+    pub fn last_activity(&self, id: &Id) -> Option<&RadClock> {
+        self.entries.get(id).map(|entry| &entry.last_activity)
+    }
+
+    /// The number of issues in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over all issues, ordered by their identifiers.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &SomeIssue<Id, Cid, User>> + 'a {
+        self.entries.values().map(|entry| &entry.issue)
+    }
+
+    /// Run `query` against the index.
+    pub fn query(&self, query: &Query<User>) -> Page<'_, Id, Cid, User> {
+        let mut matches = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.matches(query))
+            .collect::<Vec<_>>();
+
+        // `matches` is ordered by identifier, and the sort is stable
+        matches.sort_by(|(_, a), (_, b)| {
+            let ordering = match query.sort {
+                Sort::Created => a
+                    .issue
+                    .issue()
+                    .timestamp()
+                    .cmp_time(b.issue.issue().timestamp()),
+                Sort::Activity => a.last_activity.cmp_time(&b.last_activity),
+                Sort::Title => a.issue.issue().title().cmp(b.issue.issue().title()),
+                Sort::Identifier => Ordering::Equal,
+            };
+            match query.order {
+                Order::Ascending => ordering,
+                Order::Descending => ordering.reverse(),
+            }
+        });
+        if query.sort == Sort::Identifier && query.order == Order::Descending {
+            matches.reverse();
+        }
+
+        let total = matches.len();
+        let issues = matches
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(_, entry)| &entry.issue)
+            .collect();
+
+        Page { total, issues }
+    }
+}
+
+impl<Id: Ord + Clone, Cid, User: Eq + Hash> FromIterator<SomeIssue<Id, Cid, User>>
+    for Index<Id, Cid, User>
+{
+    fn from_iter<I: IntoIterator<Item = SomeIssue<Id, Cid, User>>>(iter: I) -> Self {
+        let mut index = Self::new();
+        for issue in iter {
+            index.insert(issue);
+        }
+        index
+    }
+}
+
+impl<Id: Ord + Clone, Cid, User: Eq + Hash> Extend<SomeIssue<Id, Cid, User>>
+    for Index<Id, Cid, User>
+{
+    fn extend<I: IntoIterator<Item = SomeIssue<Id, Cid, User>>>(&mut self, iter: I) {
+        for issue in iter {
+            self.insert(issue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use librad::{keys::SecretKey, peer::PeerId};
+
+    use crate::{clock::TimeDiff, Comment, Issue, ReplyTo, Title};
+
+    type TestIssue = SomeIssue<u64, u64, String>;
+
+    fn at(secs: u64) -> RadClock {
+        RadClock::from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn issue(id: u64, author: &str, title: &str, created: u64) -> Issue<u64, u64, String> {
+        Issue::new_with_timestamp(
+            id,
+            0,
+            author.to_string(),
+            Title::from(title),
+            format!("Body of {}", title),
+            at(created),
+        )
+    }
+
+    fn index() -> Index<u64, u64, String> {
+        let peer = PeerId::from(SecretKey::new());
+
+        let mut bug = issue(1, "monadic", "Buggy Boeuf", 100);
+        bug.meta_mut()
+            .add_label(Label::new(String::from("bug")), &peer);
+        bug.meta_mut().add_assignee(String::from("finto"), &peer);
+        bug.thread_mut().reply(
+            Comment::new_with_timestamp(
+                1,
+                String::from("finto"),
+                String::from("Found the caterpillar"),
+                at(1_000),
+            ),
+            ReplyTo::Main,
+        );
+
+        let tasty = issue(2, "finto", "Tasty Boeuf", 200);
+        let closed = issue(3, "monadic", "Stale Boeuf", 300).close();
+
+        vec![
+            TestIssue::from(bug),
+            TestIssue::from(tasty),
+            TestIssue::from(closed),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn ids(page: Page<u64, u64, String>) -> Vec<u64> {
+        page.issues
+            .iter()
+            .map(|issue| *issue.issue().identifier())
+            .collect()
+    }
+
+    #[test]
+    fn filter_by_state() {
+        let index = index();
+        assert_eq!(
+            ids(index.query(&Query::new().state(State::Open))),
+            vec![2, 1]
+        );
+        assert_eq!(
+            ids(index.query(&Query::new().state(State::Closed))),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn filter_by_meta() {
+        let index = index();
+        let bug = Label::new(String::from("bug"));
+
+        assert_eq!(ids(index.query(&Query::new().label(bug))), vec![1]);
+        assert_eq!(
+            ids(index.query(&Query::new().assignee(String::from("finto")))),
+            vec![1]
+        );
+        assert_eq!(
+            ids(index.query(&Query::new().author(String::from("monadic")))),
+            vec![3, 1]
+        );
+    }
+
+    #[test]
+    fn filter_by_time() {
+        let index = index();
+
+        assert_eq!(
+            ids(index.query(&Query::new().created_after(at(200)))),
+            vec![3, 2]
+        );
+        assert_eq!(
+            ids(index.query(&Query::new().created_before(at(200)))),
+            vec![2, 1]
+        );
+        assert_eq!(
+            ids(index.query(&Query::new().active_after(at(500)))),
+            vec![1]
+        );
+        assert_eq!(
+            ids(index.query(
+                &Query::new().active_within(at(1_200), Elapsed::Minutes(TimeDiff::from(5)))
+            )),
+            vec![1]
+        );
+        assert_eq!(index.last_activity(&1), Some(&at(1_000)));
+    }
+
+    #[test]
+    fn full_text() {
+        let index = index();
+
+        assert_eq!(ids(index.query(&Query::new().text("BOEUF"))), vec![3, 2, 1]);
+        assert_eq!(ids(index.query(&Query::new().text("caterpillar"))), vec![1]);
+        assert_eq!(
+            ids(index.query(&Query::new().text("tasty caterpillar"))),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn sort_and_paginate() {
+        let index = index();
+
+        let query = Query::new().sort(Sort::Activity).order(Order::Descending);
+        assert_eq!(ids(index.query(&query)), vec![1, 3, 2]);
+
+        let query = Query::new().sort(Sort::Title).order(Order::Ascending);
+        let page = index.query(&query.offset(1).limit(1));
+        assert_eq!(page.total, 3);
+        assert_eq!(ids(page), vec![3]);
+
+        let query = Query::new().sort(Sort::Identifier).order(Order::Descending);
+        assert_eq!(ids(index.query(&query.offset(2))), vec![1]);
+    }
+}
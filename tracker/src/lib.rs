@@ -27,13 +27,25 @@
 //! allowing us to label for organisation, react for emotions, and assign to
 //! users to help responsibility.
 //!
+//! Peers may change the same issue concurrently. Their copies can be
+//! reconciled using [`crdt::Merge`], which all parts of an issue implement.
+//! When issues are shared, every change is made as an [`op::Op`] signed by its
+//! author, and issues are rebuilt by replaying the verified operations.
+//!
+//! Code review happens on a [`Patch`], which carries a review thread and
+//! metadata just like an issue.
+//!
 //! ```
 //! # use std::error::Error;
 //! #
 //! # fn main() -> Result<(), Box<dyn Error>> {
+//! use librad::{keys::SecretKey, peer::PeerId};
 //! use radicle_tracker::{Comment, Issue, Metadata, ReplyTo, Reaction, Title, Thread};
 //! use std::str::FromStr;
 //!
+//! // The peer on whose behalf we're making changes
+//! let peer = PeerId::from(SecretKey::new());
+//!
 //! // Setting up some way of giving out "global" identifiers for
 //! // issues and comments.
 //! let mut global_issue_id = 0;
@@ -87,7 +99,7 @@
 //!
 //! // And we react to this comment with surprise!
 //! let current_comment = thread.view_mut()?.get_mut();
-//! current_comment.react(Reaction::new(String::from("massi"), String::from("surprise")));
+//! current_comment.react(Reaction::new(String::from("massi"), String::from("surprise")), &peer);
 //! #
 //! #     Ok(())
 //! # }
@@ -97,17 +109,74 @@
 #![feature(vec_remove_item)]
 use std::hash::Hash;
 
+use serde::{Deserialize, Serialize};
+
 mod thread;
 pub use thread::{DataState, Error as ThreadError, Finger, Replies, ReplyTo, Thread};
 
 mod metadata;
 pub use metadata::*;
 
+pub mod crdt;
+use crdt::Merge;
+
+pub mod git;
+
+pub mod index;
+
+pub mod op;
+
+pub mod patch;
+pub use patch::Patch;
+
+#[cfg(test)]
+mod test;
+
 use clock::{Clock, RadClock};
 
+/// Either an open [`Issue`] or a [`ClosedIssue`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", content = "issue", rename_all = "lowercase")]
+pub enum SomeIssue<Id, Cid, User: Eq + Hash> {
+    /// The issue is open.
+    Open(Issue<Id, Cid, User>),
+    /// The issue was closed.
+    Closed(ClosedIssue<Id, Cid, User>),
+}
+
+impl<Id, Cid, User: Eq + Hash> SomeIssue<Id, Cid, User> {
+    /// Get a reference to the underlying [`Issue`], regardless of its state.
+    pub fn issue(&self) -> &Issue<Id, Cid, User> {
+        match self {
+            Self::Open(issue) => issue,
+            Self::Closed(closed) => closed.issue(),
+        }
+    }
+
+    /// Check if the issue is open.
+    pub fn is_open(&self) -> bool {
+        match self {
+            Self::Open(_) => true,
+            Self::Closed(_) => false,
+        }
+    }
+}
+
+impl<Id, Cid, User: Eq + Hash> From<Issue<Id, Cid, User>> for SomeIssue<Id, Cid, User> {
+    fn from(issue: Issue<Id, Cid, User>) -> Self {
+        Self::Open(issue)
+    }
+}
+
+impl<Id, Cid, User: Eq + Hash> From<ClosedIssue<Id, Cid, User>> for SomeIssue<Id, Cid, User> {
+    fn from(issue: ClosedIssue<Id, Cid, User>) -> Self {
+        Self::Closed(issue)
+    }
+}
+
 /// An [`Issue`] that has been closed. The underlying issue cannot be mutated,
 /// and can we can only access the reference of this issue..
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClosedIssue<Id, Cid, User: Eq + Hash>(Issue<Id, Cid, User>);
 
 impl<Id, Cid, User: Eq + Hash> ClosedIssue<Id, Cid, User> {
@@ -131,7 +200,7 @@ impl<Id, Cid, User: Eq + Hash> ClosedIssue<Id, Cid, User> {
 ///
 /// It also contains [`Metadata`] for which we would like to keep track of and
 /// enhance the experience of the conversation.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Issue<Id, Cid, User: Eq + Hash> {
     identifier: Id,
     author: User,
@@ -181,11 +250,21 @@ impl<Id, Cid, User: Eq + Hash> Issue<Id, Cid, User> {
         ClosedIssue(self)
     }
 
+    /// Get a reference to the identifier of this issue.
+    pub fn identifier(&self) -> &Id {
+        &self.identifier
+    }
+
     /// Get a reference to the author (`User`) of this issue.
     pub fn author(&self) -> &User {
         &self.author
     }
 
+    /// Get a reference to the creation time of this issue.
+    pub fn timestamp(&self) -> &RadClock {
+        &self.timestamp
+    }
+
     /// Get a reference to the [`Title`] of this issue.
     pub fn title(&self) -> &Title {
         &self.title
@@ -211,3 +290,15 @@ impl<Id, Cid, User: Eq + Hash> Issue<Id, Cid, User> {
         &mut self.meta
     }
 }
+
+impl<Id, Cid, User> Merge for Issue<Id, Cid, User>
+where
+    Cid: Clone + Ord,
+    User: Clone + Eq + Hash,
+{
+    /// Merge the [`Thread`] and [`Metadata`] of another copy of this issue.
+    fn merge(&mut self, other: Self) {
+        self.thread.merge(other.thread);
+        self.meta.merge(other.meta);
+    }
+}
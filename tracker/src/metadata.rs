@@ -15,20 +15,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::{
-    collections::{HashMap, HashSet},
-    convert::Infallible,
-    hash::Hash,
-    str::FromStr,
-};
+use std::{collections::HashMap, convert::Infallible, hash::Hash, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use librad::peer::PeerId;
+
+use crate::crdt::{LwwRegister, Merge, Node, ORSet, Stamp};
 
 pub mod clock;
 use clock::{Clock, RadClock};
 
 /// The metadata that is related to an issue.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Metadata<User: Eq + Hash> {
-    labels: HashSet<Label>,
+    labels: ORSet<Label>,
     assignees: Assignees<User>,
 }
 
@@ -37,19 +38,20 @@ impl<User: Eq + Hash> Metadata<User> {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Metadata {
-            labels: HashSet::new(),
+            labels: ORSet::new(),
             assignees: Assignees::new(),
         }
     }
 
     /// Get a reference to the [`Label`]s in the metadata.
-    pub fn labels(&self) -> &HashSet<Label> {
+    pub fn labels(&self) -> &ORSet<Label> {
         &self.labels
     }
 
-    /// Add a [`Label`] to the set of labels in the metadata.
-    pub fn add_label(&mut self, label: Label) -> bool {
-        self.labels.insert(label)
+    /// Add a [`Label`] to the set of labels in the metadata, on behalf of
+    /// `peer`.
+    pub fn add_label(&mut self, label: Label, peer: &PeerId) -> bool {
+        self.labels.insert(label, Stamp::new(peer.clone()))
     }
 
     /// Remove a [`Label`] from the set of labels in the metadata.
@@ -62,9 +64,10 @@ impl<User: Eq + Hash> Metadata<User> {
         &self.assignees
     }
 
-    /// Add a `User` to the set of [`Assignees`] in the metadata.
-    pub fn add_assignee(&mut self, assignee: User) -> bool {
-        self.assignees.add(assignee)
+    /// Add a `User` to the set of [`Assignees`] in the metadata, on behalf of
+    /// `peer`.
+    pub fn add_assignee(&mut self, assignee: User, peer: &PeerId) -> bool {
+        self.assignees.add(assignee, peer)
     }
 
     /// Remove a `User` from the set of [`Assignees`] in the metadata.
@@ -73,8 +76,25 @@ impl<User: Eq + Hash> Metadata<User> {
     }
 }
 
+impl<User: Eq + Hash> Metadata<User> {
+    pub(crate) fn labels_mut(&mut self) -> &mut ORSet<Label> {
+        &mut self.labels
+    }
+
+    pub(crate) fn assignees_mut(&mut self) -> &mut ORSet<User> {
+        &mut self.assignees.0
+    }
+}
+
+impl<User: Eq + Hash> Merge for Metadata<User> {
+    fn merge(&mut self, other: Self) {
+        self.labels.merge(other.labels);
+        self.assignees.merge(other.assignees);
+    }
+}
+
 /// The title of an issue.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Title(String);
 
 impl Title {
@@ -110,12 +130,12 @@ impl FromStr for Title {
 /// [`Comment::content`] of the comment, and its [`Comment::reactions`].
 ///
 /// It has a unique identifier (of type `Id`) chosen by the implementor.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Comment<Id, User: Eq + Hash> {
     identifier: Id,
     author: User,
-    content: String,
-    reactions: HashSet<Reaction<User>>,
+    content: LwwRegister<String>,
+    reactions: ORSet<Reaction<User>>,
     timestamp: RadClock,
 }
 
@@ -136,12 +156,22 @@ impl<Cid, User: Eq + Hash> Comment<Cid, User> {
         Comment {
             identifier,
             author,
-            content,
-            reactions: HashSet::new(),
+            content: LwwRegister::new(content),
+            reactions: ORSet::new(),
             timestamp,
         }
     }
 
+    /// Get a reference to the identifier of this comment.
+    pub fn identifier(&self) -> &Cid {
+        &self.identifier
+    }
+
+    /// Get a reference to the creation time of this comment.
+    pub fn timestamp(&self) -> &RadClock {
+        &self.timestamp
+    }
+
     /// Get a reference to to the author of this comment.
     pub fn author(&self) -> &User {
         &self.author
@@ -149,14 +179,27 @@ impl<Cid, User: Eq + Hash> Comment<Cid, User> {
 
     /// Get a reference to to the content of this comment.
     pub fn content(&self) -> &String {
-        &self.content
+        self.content.get()
     }
 
-    /// Add a new reaction to the set of reactions on the comment.
+    /// Get the [`Stamp`] of the last edit of this comment, if it was edited.
+    pub fn edited(&self) -> Option<&Stamp> {
+        self.content.stamp()
+    }
+
+    /// Replace the content of this comment, on behalf of `peer`.
+    ///
+    /// Concurrent edits are resolved in favour of the most recent one.
+    pub fn edit(&mut self, content: String, peer: &PeerId) -> bool {
+        self.content.set(content, Stamp::new(peer.clone()))
+    }
+
+    /// Add a new reaction to the set of reactions on the comment, on behalf
+    /// of `peer`.
     /// Returns `true` if the reaction was new.
     /// Returns `false` if the reaction already existed.
-    pub fn react(&mut self, reaction: Reaction<User>) -> bool {
-        self.reactions.insert(reaction)
+    pub fn react(&mut self, reaction: Reaction<User>, peer: &PeerId) -> bool {
+        self.reactions.insert(reaction, Stamp::new(peer.clone()))
     }
 
     /// Add a new reaction to the set of reactions on the comment.
@@ -172,18 +215,52 @@ impl<Cid, User: Eq + Hash> Comment<Cid, User> {
         User: Clone,
     {
         let mut reaction_map = HashMap::new();
-        for reaction in self.reactions.clone().into_iter() {
+        for reaction in self.reactions.iter() {
             reaction_map
                 .entry(reaction.value.clone())
                 .and_modify(|users: &mut Vec<User>| users.push(reaction.user.clone()))
-                .or_insert_with(|| vec![reaction.user]);
+                .or_insert_with(|| vec![reaction.user.clone()]);
         }
         reaction_map
     }
 }
 
+impl<Cid, User: Eq + Hash> Comment<Cid, User> {
+    pub(crate) fn content_mut(&mut self) -> &mut LwwRegister<String> {
+        &mut self.content
+    }
+
+    pub(crate) fn reaction_set(&self) -> &ORSet<Reaction<User>> {
+        &self.reactions
+    }
+
+    pub(crate) fn reactions_mut(&mut self) -> &mut ORSet<Reaction<User>> {
+        &mut self.reactions
+    }
+}
+
+impl<Cid, User: Eq + Hash> Merge for Comment<Cid, User> {
+    /// Merge the edits and reactions of another copy of this comment.
+    fn merge(&mut self, other: Self) {
+        self.content.merge(other.content);
+        self.reactions.merge(other.reactions);
+    }
+}
+
+impl<Cid: Ord, User: Eq + Hash> Node for Comment<Cid, User> {
+    type Id = Cid;
+
+    fn id(&self) -> &Self::Id {
+        &self.identifier
+    }
+
+    fn created_at(&self) -> &RadClock {
+        &self.timestamp
+    }
+}
+
 /// A custom label that can be added to an issue.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Label(String);
 
 impl Label {
@@ -210,21 +287,21 @@ impl FromStr for Label {
 }
 
 /// A collection of users that represent the assigned users of the issue.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Assignees<User: Eq + Hash>(HashSet<User>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assignees<User: Eq + Hash>(ORSet<User>);
 
 impl<User: Eq + Hash> Assignees<User> {
     /// Create an empty set of assignees.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Assignees(HashSet::new())
+        Assignees(ORSet::new())
     }
 
-    /// Add a user to the set of assignees.
+    /// Add a user to the set of assignees, on behalf of `peer`.
     /// If the set did not have this value present, `true` is returned.
     /// If the set did have this value present, `false` is returned.
-    pub fn add(&mut self, user: User) -> bool {
-        self.0.insert(user)
+    pub fn add(&mut self, user: User, peer: &PeerId) -> bool {
+        self.0.insert(user, Stamp::new(peer.clone()))
     }
 
     /// Remove the user from the set of assignees.
@@ -240,8 +317,14 @@ impl<User: Eq + Hash> Assignees<User> {
     }
 }
 
+impl<User: Eq + Hash> Merge for Assignees<User> {
+    fn merge(&mut self, other: Self) {
+        self.0.merge(other.0)
+    }
+}
+
 impl<User: Eq + Hash> std::ops::Deref for Assignees<User> {
-    type Target = HashSet<User>;
+    type Target = ORSet<User>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -249,7 +332,7 @@ impl<User: Eq + Hash> std::ops::Deref for Assignees<User> {
 }
 
 /// A reaction is the pair of a user and a free-form reaction.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Reaction<User> {
     user: User,
     value: String,
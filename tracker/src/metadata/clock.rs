@@ -43,6 +43,7 @@
 //! # }
 //! ```
 use std::{
+    cmp::Ordering,
     fmt,
     ops::{Div, Mul, Neg, Sub},
     time::{SystemTime, UNIX_EPOCH},
@@ -50,6 +51,7 @@ use std::{
 
 use num_bigint::BigInt;
 pub use num_bigint::Sign;
+use serde::{Deserialize, Serialize};
 
 // Rough calculations for the number of seconds in some larger unit
 const SECONDS_IN_MINUTE: u64 = 60;
@@ -158,6 +160,21 @@ pub enum Elapsed {
     Years(TimeDiff),
 }
 
+impl Elapsed {
+    /// The (rough) number of seconds this `Elapsed` spans.
+    pub fn seconds(&self) -> TimeDiff {
+        let (diff, unit) = match self {
+            Elapsed::Minutes(t) => (t, SECONDS_IN_MINUTE),
+            Elapsed::Hours(t) => (t, SECONDS_IN_HOUR),
+            Elapsed::Days(t) => (t, SECONDS_IN_DAY),
+            Elapsed::Weeks(t) => (t, SECONDS_IN_WEEK),
+            Elapsed::Months(t) => (t, SECONDS_IN_MONTH),
+            Elapsed::Years(t) => (t, SECONDS_IN_YEAR),
+        };
+        diff.clone() * TimeDiff::from(unit)
+    }
+}
+
 impl std::fmt::Display for Elapsed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let reference_point = |diff: &TimeDiff| match diff.sign() {
@@ -215,8 +232,9 @@ pub trait Clock {
 ///
 /// **NB**: `RadClock` does not implement [`PartialOrd`] nor [`Ord`] since the
 /// use of these time types is imprecise and used for _displaying_ purposes
-/// only (via [`TimeDiff`] and [`Elapsed`].
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// only (via [`TimeDiff`] and [`Elapsed`]. The only exception is resolving
+/// conflicting edits deterministically, see [`crate::crdt::Stamp`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct RadClock(SystemTime);
 
 impl Clock for RadClock {
@@ -229,11 +247,21 @@ impl Clock for RadClock {
     }
 }
 
+impl From<SystemTime> for RadClock {
+    fn from(time: SystemTime) -> Self {
+        RadClock(time)
+    }
+}
+
 impl RadClock {
     /// Calculate the [`Elapsed`] time for two `RadClock`s.
     pub fn elapsed(&self, other: &Self) -> Elapsed {
         elapsed(self, other)
     }
+
+    pub(crate) fn cmp_time(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
 }
 
 fn elapsed<C>(clock: &C, other: &C) -> Elapsed
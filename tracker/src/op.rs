@@ -0,0 +1,885 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Signed operations on issues.
+//!
+//! Issues which are shared between peers are not exchanged as snapshots, but
+//! as the set of [`Op`]s which created them. Each operation is signed by a key
+//! of its author, so that it can be attributed to a `User` and verified by
+//! any peer replicating it.
+//!
+//! The state of an issue is obtained by [`replay`]ing its (verified)
+//! operations. Replaying is deterministic: it only depends on the set of
+//! operations, not on the order in which they were received.
+//!
+//! Removals carry the additions their author observed, so that a label,
+//! assignee, or reaction added concurrently is not removed along with them.
+//! Moderating an issue, that is closing, reopening, labelling, or assigning
+//! it, is reserved for the maintainers of the project.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    error,
+    hash::Hash,
+};
+
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use librad::{
+    git::storage::{self, Storage},
+    identities::{git::Revision, urn::Urn},
+    internal::canonical::{Cjson, CjsonError},
+    keys::{self, PublicKey, Signature},
+    peer::PeerId,
+    signer::Signer,
+};
+
+use crate::{
+    crdt::{LwwRegister, Stamp},
+    Comment,
+    DataState,
+    Finger,
+    Issue,
+    Label,
+    Reaction,
+    ReplyTo,
+    SomeIssue,
+    Thread,
+    Title,
+};
+
+/// Errors which may occur when signing, verifying, or replaying operations.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The signature doesn't match the operation.
+    #[error("invalid signature by {0}")]
+    InvalidSignature(PublicKey),
+
+    /// The operation was stamped by a peer other than the one which signed
+    /// it.
+    #[error("operation stamped by {stamped}, but signed by {signed}")]
+    ForeignStamp {
+        /// The peer the operation claims to be made by.
+        stamped: PeerId,
+        /// The peer which actually signed the operation.
+        signed: PeerId,
+    },
+
+    /// The signing key is not one of the author's delegations.
+    #[error("key {0} is not a delegation of the operation's author")]
+    NotDelegated(PublicKey),
+
+    /// The delegations of the author could not be resolved.
+    #[error("failed to resolve the delegations of the operation's author")]
+    Delegations(#[source] Box<dyn error::Error + Send + Sync + 'static>),
+
+    /// None of the operations opens the issue.
+    #[error("no operation opening the issue")]
+    MissingOpen,
+
+    /// The operation could not be signed.
+    #[error(transparent)]
+    Sign(Box<dyn error::Error + Send + Sync + 'static>),
+
+    /// The operation could not be serialised.
+    #[error(transparent)]
+    Cjson(#[from] CjsonError),
+}
+
+/// A change to an issue.
+///
+/// Operations referring to a comment are identified by the comment's `Cid`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Op<Id, Cid, User> {
+    /// Open a new issue, with `comment` as the first comment of its thread.
+    Open {
+        /// The identifier of the new issue.
+        id: Id,
+        /// The identifier of the first comment.
+        comment: Cid,
+        /// The title of the issue.
+        title: Title,
+        /// The content of the first comment.
+        content: String,
+    },
+    /// Reply to the main thread if `to` is `None`, or to the thread of the
+    /// comment `to` otherwise.
+    Reply {
+        /// The comment being replied to, if any.
+        to: Option<Cid>,
+        /// The identifier of the new comment.
+        comment: Cid,
+        /// The content of the new comment.
+        content: String,
+    },
+    /// Replace the content of one of the author's comments.
+    Edit {
+        /// The comment being edited.
+        comment: Cid,
+        /// The new content.
+        content: String,
+    },
+    /// Delete one of the author's comments.
+    Delete {
+        /// The comment being deleted.
+        comment: Cid,
+    },
+    /// React to a comment.
+    React {
+        /// The comment being reacted to.
+        comment: Cid,
+        /// The reaction.
+        reaction: String,
+    },
+    /// Take back a reaction to a comment.
+    Unreact {
+        /// The comment which was reacted to.
+        comment: Cid,
+        /// The reaction.
+        reaction: String,
+        /// The stamps of the reactions being taken back.
+        observed: BTreeSet<Stamp>,
+    },
+    /// Add a [`Label`] to the issue.
+    Label {
+        /// The label.
+        label: Label,
+    },
+    /// Remove a [`Label`] from the issue.
+    Unlabel {
+        /// The label.
+        label: Label,
+        /// The stamps of the additions of the label being removed.
+        observed: BTreeSet<Stamp>,
+    },
+    /// Assign a `User` to the issue.
+    Assign {
+        /// The assignee.
+        user: User,
+    },
+    /// Unassign a `User` from the issue.
+    Unassign {
+        /// The assignee.
+        user: User,
+        /// The stamps of the assignments being removed.
+        observed: BTreeSet<Stamp>,
+    },
+    /// Close the issue.
+    Close,
+    /// Reopen the issue.
+    Reopen,
+}
+
+impl<Id, Cid, User> Op<Id, Cid, User>
+where
+    Cid: Eq,
+    User: Eq + Hash,
+{
+    /// Remove `label` from `issue`, as far as we have seen it added.
+    pub fn unlabel(issue: &Issue<Id, Cid, User>, label: Label) -> Self {
+        let observed = issue.meta().labels().observed(&label);
+        Op::Unlabel { label, observed }
+    }
+
+    /// Unassign `user` from `issue`, as far as we have seen them assigned.
+    pub fn unassign(issue: &Issue<Id, Cid, User>, user: User) -> Self {
+        let observed = issue.meta().assignees().observed(&user);
+        Op::Unassign { user, observed }
+    }
+
+    /// Take back the `reaction` of `author` to `comment` of `issue`.
+    ///
+    /// Returns `None` if there is no such comment.
+    pub fn unreact(
+        issue: &Issue<Id, Cid, User>,
+        comment: Cid,
+        author: User,
+        reaction: String,
+    ) -> Option<Self> {
+        let observed = issue
+            .thread()
+            .iter()
+            .map(DataState::get)
+            .find(|candidate| candidate.identifier() == &comment)?
+            .reaction_set()
+            .observed(&Reaction::new(author, reaction.clone()));
+        Some(Op::Unreact {
+            comment,
+            reaction,
+            observed,
+        })
+    }
+}
+
+/// An [`Op`] made by `author`, signed by one of the author's keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedOp<Id, Cid, User> {
+    op: Op<Id, Cid, User>,
+    author: User,
+    stamp: Stamp,
+    key: PublicKey,
+    signature: Signature,
+}
+
+/// The part of a [`SignedOp`] covered by its signature.
+#[derive(Serialize)]
+struct Payload<'a, Id, Cid, User> {
+    op: &'a Op<Id, Cid, User>,
+    author: &'a User,
+    stamp: &'a Stamp,
+}
+
+impl<Id, Cid, User> SignedOp<Id, Cid, User>
+where
+    Id: Serialize,
+    Cid: Serialize,
+    User: Serialize,
+{
+    /// Sign `op` on behalf of `author`, stamping it with the current time.
+    ///
+    /// The key of `signer` must be one of the delegations of `author` for the
+    /// operation to pass [`SignedOp::verify`].
+    pub fn sign<S>(op: Op<Id, Cid, User>, author: User, signer: &S) -> Result<Self, Error>
+    where
+        S: Signer,
+        S::Error: keys::SignError,
+    {
+        let key: PublicKey = signer.public_key().into();
+        let stamp = Stamp::new(PeerId::from(key.clone()));
+        let payload = Cjson(Payload {
+            op: &op,
+            author: &author,
+            stamp: &stamp,
+        })
+        .canonical_form()?;
+        let signature = block_on(signer.sign(&payload)).map_err(|e| Error::Sign(Box::new(e)))?;
+
+        Ok(Self {
+            op,
+            author,
+            stamp,
+            key,
+            signature: signature.into(),
+        })
+    }
+
+    /// Verify that this operation was signed by a key `author` delegates to.
+    pub fn verify<D>(&self, delegations: &D) -> Result<(), Error>
+    where
+        D: Delegations<User>,
+    {
+        let payload = Cjson(Payload {
+            op: &self.op,
+            author: &self.author,
+            stamp: &self.stamp,
+        })
+        .canonical_form()?;
+        if !self.signature.verify(&payload, &self.key) {
+            return Err(Error::InvalidSignature(self.key.clone()));
+        }
+
+        let signed = PeerId::from(self.key.clone());
+        if self.stamp.peer() != &signed {
+            return Err(Error::ForeignStamp {
+                stamped: self.stamp.peer().clone(),
+                signed,
+            });
+        }
+
+        let keys = delegations
+            .delegations(&self.author)
+            .map_err(|e| Error::Delegations(Box::new(e)))?;
+        if !keys.contains(&self.key) {
+            return Err(Error::NotDelegated(self.key.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl<Id, Cid, User> SignedOp<Id, Cid, User> {
+    /// Get a reference to the operation.
+    pub fn op(&self) -> &Op<Id, Cid, User> {
+        &self.op
+    }
+
+    /// Get a reference to the author of the operation.
+    pub fn author(&self) -> &User {
+        &self.author
+    }
+
+    /// Get a reference to the [`Stamp`] of the operation.
+    pub fn stamp(&self) -> &Stamp {
+        &self.stamp
+    }
+
+    /// Get a reference to the key which signed the operation.
+    pub fn key(&self) -> &PublicKey {
+        &self.key
+    }
+}
+
+/// Resolution of the keys a `User` delegates to.
+pub trait Delegations<User> {
+    /// The error which may occur when resolving the delegations.
+    type Error: error::Error + Send + Sync + 'static;
+
+    /// The keys which may sign operations on behalf of `user`.
+    fn delegations(&self, user: &User) -> Result<BTreeSet<PublicKey>, Self::Error>;
+}
+
+/// Users are identified by the [`Urn`] of their verified identity.
+impl<S: Clone> Delegations<Urn<Revision>> for Storage<S> {
+    type Error = storage::Error;
+
+    fn delegations(&self, user: &Urn<Revision>) -> Result<BTreeSet<PublicKey>, Self::Error> {
+        let user = self.user(user)?;
+        Ok((&user.doc.delegations).into_iter().cloned().collect())
+    }
+}
+
+/// The `User` is not present in a static map of delegations.
+#[derive(Debug, Error)]
+#[error("unknown user")]
+pub struct UnknownUser;
+
+/// A static map of delegations, mostly useful for testing.
+impl<User: Eq + Hash> Delegations<User> for HashMap<User, BTreeSet<PublicKey>> {
+    type Error = UnknownUser;
+
+    fn delegations(&self, user: &User) -> Result<BTreeSet<PublicKey>, Self::Error> {
+        self.get(user).cloned().ok_or(UnknownUser)
+    }
+}
+
+/// The outcome of applying an operation to an issue.
+enum Applied {
+    /// The operation was applied.
+    Yes,
+    /// The operation refers to a comment we haven't seen (yet).
+    Later,
+    /// The operation is not applicable, eg. because it edits somebody else's
+    /// comment.
+    No,
+}
+
+/// Build an issue from a set of operations.
+///
+/// The operations are applied in the order of their [`Stamp`]s. Operations
+/// referring to a comment are applied once that comment exists, so skewed
+/// clocks don't cause replies to get lost. Operations which are not
+/// applicable, like edits of another author's comment, are ignored.
+///
+/// Labels and assignees may only be changed by operations signed by one of
+/// the `maintainers`' keys. The issue may be closed or reopened by its author,
+/// or by a maintainer. Other moderation operations are ignored.
+///
+/// Note that this does not verify the operations, see [`SignedOp::verify`].
+///
+/// # Errors
+///
+/// Fails with [`Error::MissingOpen`] if none of the operations opens the
+/// issue. If there are multiple, the earliest one wins.
+pub fn replay<Id, Cid, User, I>(
+    ops: I,
+    maintainers: &BTreeSet<PublicKey>,
+) -> Result<SomeIssue<Id, Cid, User>, Error>
+where
+    Id: Clone + Serialize,
+    Cid: Clone + Ord + Serialize,
+    User: Clone + Eq + Hash + Serialize,
+    I: IntoIterator<Item = SignedOp<Id, Cid, User>>,
+{
+    let mut ops = ops
+        .into_iter()
+        .map(|op| Ok((Cjson(&op).canonical_form()?, op)))
+        .collect::<Result<Vec<_>, CjsonError>>()?;
+    ops.sort_by(|(a_bytes, a), (b_bytes, b)| a.stamp.cmp(&b.stamp).then(a_bytes.cmp(b_bytes)));
+    ops.dedup_by(|(a, _), (b, _)| a == b);
+    let mut ops = ops.into_iter().map(|(_, op)| op).collect::<Vec<_>>();
+
+    let open = ops
+        .iter()
+        .position(|op| matches!(op.op, Op::Open { .. }))
+        .ok_or(Error::MissingOpen)?;
+    let first = ops.remove(open);
+    let mut issue = match first.op {
+        Op::Open {
+            id,
+            comment,
+            title,
+            content,
+        } => Issue::new_with_timestamp(
+            id,
+            comment,
+            first.author,
+            title,
+            content,
+            *first.stamp.time(),
+        ),
+        _ => unreachable!(),
+    };
+    let mut is_open = LwwRegister::new(true);
+
+    loop {
+        let pending = ops.len();
+        ops.retain(
+            |op| match apply(&mut issue, &mut is_open, maintainers, op) {
+                Applied::Later => true,
+                Applied::Yes | Applied::No => false,
+            },
+        );
+
+        if ops.is_empty() || ops.len() == pending {
+            break;
+        }
+    }
+    issue.thread_mut().navigate_to_root();
+
+    Ok(if *is_open.get() {
+        SomeIssue::Open(issue)
+    } else {
+        SomeIssue::Closed(issue.close())
+    })
+}
+
+fn apply<Id, Cid, User>(
+    issue: &mut Issue<Id, Cid, User>,
+    is_open: &mut LwwRegister<bool>,
+    maintainers: &BTreeSet<PublicKey>,
+    signed: &SignedOp<Id, Cid, User>,
+) -> Applied
+where
+    Cid: Clone + Ord,
+    User: Clone + Eq + Hash,
+{
+    let SignedOp {
+        op,
+        author,
+        stamp,
+        key,
+        ..
+    } = signed;
+    let is_maintainer = maintainers.contains(key);
+
+    match op {
+        Op::Open { .. } => Applied::No,
+
+        Op::Reply {
+            to,
+            comment,
+            content,
+        } => {
+            if find(issue, comment).is_some() {
+                return Applied::No;
+            }
+
+            let reply_to = match to {
+                None => ReplyTo::Main,
+                Some(to) => match find(issue, to) {
+                    None => return Applied::Later,
+                    Some(Finger::Root) => ReplyTo::Main,
+                    Some(finger) => {
+                        let thread = issue.thread_mut();
+                        if thread.navigate_to(finger).is_err() {
+                            return Applied::No;
+                        }
+                        ReplyTo::Thread
+                    },
+                },
+            };
+            issue.thread_mut().reply(
+                Comment::new_with_timestamp(
+                    comment.clone(),
+                    author.clone(),
+                    content.clone(),
+                    *stamp.time(),
+                ),
+                reply_to,
+            );
+            issue.thread_mut().navigate_to_root();
+
+            Applied::Yes
+        },
+
+        Op::Edit { comment, content } => with_comment(issue, comment, |thread| {
+            let target = thread.view_mut().expect("navigated to comment").get_mut();
+            if target.author() != author {
+                return Applied::No;
+            }
+            target.content_mut().set(content.clone(), stamp.clone());
+            Applied::Yes
+        }),
+
+        Op::Delete { comment } => with_comment(issue, comment, |thread| {
+            let target = thread.view().expect("navigated to comment").get();
+            if target.author() != author {
+                return Applied::No;
+            }
+            match thread.delete() {
+                Ok(()) => Applied::Yes,
+                Err(_) => Applied::No,
+            }
+        }),
+
+        Op::React { comment, reaction } => with_comment(issue, comment, |thread| {
+            let target = thread.view_mut().expect("navigated to comment").get_mut();
+            let reaction = Reaction::new(author.clone(), reaction.clone());
+            target.reactions_mut().insert(reaction, stamp.clone());
+            Applied::Yes
+        }),
+
+        Op::Unreact {
+            comment,
+            reaction,
+            observed,
+        } => with_comment(issue, comment, |thread| {
+            let target = thread.view_mut().expect("navigated to comment").get_mut();
+            let reaction = Reaction::new(author.clone(), reaction.clone());
+            target.reactions_mut().remove_observed(reaction, observed);
+            Applied::Yes
+        }),
+
+        Op::Label { .. } | Op::Unlabel { .. } | Op::Assign { .. } | Op::Unassign { .. }
+            if !is_maintainer =>
+        {
+            Applied::No
+        },
+
+        Op::Close | Op::Reopen if !is_maintainer && author != issue.author() => Applied::No,
+
+        Op::Label { label } => {
+            issue
+                .meta_mut()
+                .labels_mut()
+                .insert(label.clone(), stamp.clone());
+            Applied::Yes
+        },
+
+        Op::Unlabel { label, observed } => {
+            issue
+                .meta_mut()
+                .labels_mut()
+                .remove_observed(label.clone(), observed);
+            Applied::Yes
+        },
+
+        Op::Assign { user } => {
+            issue
+                .meta_mut()
+                .assignees_mut()
+                .insert(user.clone(), stamp.clone());
+            Applied::Yes
+        },
+
+        Op::Unassign { user, observed } => {
+            issue
+                .meta_mut()
+                .assignees_mut()
+                .remove_observed(user.clone(), observed);
+            Applied::Yes
+        },
+
+        Op::Close => {
+            is_open.set(false, stamp.clone());
+            Applied::Yes
+        },
+
+        Op::Reopen => {
+            is_open.set(true, stamp.clone());
+            Applied::Yes
+        },
+    }
+}
+
+fn find<Id, Cid, User>(issue: &Issue<Id, Cid, User>, comment: &Cid) -> Option<Finger>
+where
+    Cid: Eq,
+    User: Eq + Hash,
+{
+    issue
+        .thread()
+        .find(|candidate| candidate.identifier() == comment)
+}
+
+/// Navigate to `comment` and apply `f`, or defer if we haven't seen it yet.
+fn with_comment<Id, Cid, User, F>(issue: &mut Issue<Id, Cid, User>, comment: &Cid, f: F) -> Applied
+where
+    Cid: Eq,
+    User: Eq + Hash,
+    F: FnOnce(&mut Thread<Comment<Cid, User>>) -> Applied,
+{
+    let finger = match find(issue, comment) {
+        None => return Applied::Later,
+        Some(finger) => finger,
+    };
+
+    let thread = issue.thread_mut();
+    let applied = match thread.navigate_to(finger) {
+        Ok(()) => f(thread),
+        Err(_) => Applied::No,
+    };
+    thread.navigate_to_root();
+
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use librad::keys::SecretKey;
+
+    use crate::test::{delegations, maintainers};
+
+    type TestOp = SignedOp<u64, u64, String>;
+
+    fn sign(op: Op<u64, u64, String>, author: &str, key: &SecretKey) -> TestOp {
+        SignedOp::sign(op, author.to_string(), key).unwrap()
+    }
+
+    fn open(author: &str, key: &SecretKey) -> TestOp {
+        sign(
+            Op::Open {
+                id: 1,
+                comment: 0,
+                title: Title::from("Buggy Boeuf"),
+                content: String::from("We have bugs in our boeuf"),
+            },
+            author,
+            key,
+        )
+    }
+
+    #[test]
+    fn verify_signed() {
+        let key = SecretKey::new();
+        let op = open("monadic", &key);
+
+        assert!(op.verify(&delegations(&[("monadic", &key)])).is_ok());
+    }
+
+    #[test]
+    fn reject_undelegated_key() {
+        let key = SecretKey::new();
+        let other = SecretKey::new();
+        let op = open("monadic", &key);
+
+        assert!(matches!(
+            op.verify(&delegations(&[("monadic", &other)])),
+            Err(Error::NotDelegated(_))
+        ));
+        assert!(matches!(
+            op.verify(&delegations(&[("finto", &key)])),
+            Err(Error::Delegations(_))
+        ));
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let key = SecretKey::new();
+        let delegations = delegations(&[("monadic", &key), ("finto", &key)]);
+
+        let mut op = open("monadic", &key);
+        op.author = String::from("finto");
+        assert!(matches!(
+            op.verify(&delegations),
+            Err(Error::InvalidSignature(_))
+        ));
+
+        let mut op = open("monadic", &key);
+        op.stamp = Stamp::new(PeerId::from(SecretKey::new()));
+        assert!(matches!(
+            op.verify(&delegations),
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn replay_is_order_independent() {
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+
+        let ops = vec![
+            open("monadic", &monadic),
+            sign(
+                Op::Reply {
+                    to: None,
+                    comment: 1,
+                    content: String::from("Where?"),
+                },
+                "finto",
+                &finto,
+            ),
+            sign(
+                Op::Reply {
+                    to: Some(1),
+                    comment: 2,
+                    content: String::from("Everywhere"),
+                },
+                "monadic",
+                &monadic,
+            ),
+            sign(
+                Op::React {
+                    comment: 2,
+                    reaction: String::from("surprise"),
+                },
+                "finto",
+                &finto,
+            ),
+            sign(
+                Op::Label {
+                    label: Label::new(String::from("bug")),
+                },
+                "monadic",
+                &monadic,
+            ),
+            sign(Op::Close, "monadic", &monadic),
+        ];
+
+        let maintainers = maintainers(&monadic);
+        let forwards = replay(ops.clone(), &maintainers).unwrap();
+        let backwards = replay(ops.into_iter().rev(), &maintainers).unwrap();
+
+        assert!(!forwards.is_open());
+        assert_eq!(forwards.issue().thread(), backwards.issue().thread());
+        assert_eq!(forwards.issue().meta(), backwards.issue().meta());
+
+        let mut thread = forwards.issue().thread().clone();
+        thread
+            .navigate_to(Finger::Thread { main: 0, reply: 1 })
+            .unwrap();
+        let reply = thread.view().unwrap().get();
+        assert_eq!(reply.content(), &String::from("Everywhere"));
+        assert_eq!(
+            reply.reactions().get("surprise"),
+            Some(&vec![String::from("finto")])
+        );
+    }
+
+    #[test]
+    fn only_authors_edit_their_comments() {
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+
+        let ops = vec![
+            open("monadic", &monadic),
+            sign(
+                Op::Edit {
+                    comment: 0,
+                    content: String::from("No bugs here"),
+                },
+                "finto",
+                &finto,
+            ),
+        ];
+
+        let issue = replay(ops, &maintainers(&monadic)).unwrap();
+        assert_eq!(
+            issue.issue().thread().view().unwrap().get().content(),
+            &String::from("We have bugs in our boeuf")
+        );
+    }
+
+    #[test]
+    fn unlabel_keeps_unobserved_labels() {
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+        let bug = Label::new(String::from("bug"));
+        let label =
+            |author: &str, key: &SecretKey| sign(Op::Label { label: bug.clone() }, author, key);
+
+        let open = open("monadic", &monadic);
+        let labelled = label("monadic", &monadic);
+        let mut maintainers = maintainers(&monadic);
+        maintainers.insert(finto.public());
+
+        // `monadic` removes the label, unaware that `finto` added it again
+        let seen = replay(vec![open.clone(), labelled.clone()], &maintainers).unwrap();
+        let unlabel = sign(Op::unlabel(seen.issue(), bug.clone()), "monadic", &monadic);
+        let relabelled = label("finto", &finto);
+
+        let issue = replay(
+            vec![open.clone(), labelled.clone(), unlabel.clone()],
+            &maintainers,
+        )
+        .unwrap();
+        assert!(!issue.issue().meta().labels().contains(&bug));
+
+        let issue = replay(vec![open, labelled, relabelled, unlabel], &maintainers).unwrap();
+        assert!(issue.issue().meta().labels().contains(&bug));
+    }
+
+    #[test]
+    fn only_maintainers_moderate() {
+        let monadic = SecretKey::new();
+        let finto = SecretKey::new();
+        let maintainers = maintainers(&monadic);
+
+        let ops = vec![
+            open("finto", &finto),
+            sign(
+                Op::Label {
+                    label: Label::new(String::from("wontfix")),
+                },
+                "finto",
+                &finto,
+            ),
+            sign(
+                Op::Assign {
+                    user: String::from("finto"),
+                },
+                "finto",
+                &finto,
+            ),
+        ];
+        let issue = replay(ops.clone(), &maintainers).unwrap();
+        assert!(issue.issue().meta().labels().is_empty());
+        assert!(issue.issue().meta().assignees().is_empty());
+
+        // The author may close their own issue, others may not
+        let mut closed_by_author = ops.clone();
+        closed_by_author.push(sign(Op::Close, "finto", &finto));
+        assert!(!replay(closed_by_author, &maintainers).unwrap().is_open());
+
+        let mallory = SecretKey::new();
+        let mut closed_by_other = ops.clone();
+        closed_by_other.push(sign(Op::Close, "mallory", &mallory));
+        assert!(replay(closed_by_other, &maintainers).unwrap().is_open());
+
+        let mut closed_by_maintainer = ops;
+        closed_by_maintainer.push(sign(Op::Close, "monadic", &monadic));
+        assert!(!replay(closed_by_maintainer, &maintainers)
+            .unwrap()
+            .is_open());
+    }
+
+    #[test]
+    fn replay_needs_open() {
+        let key = SecretKey::new();
+        let ops = vec![sign(Op::Close, "monadic", &key)];
+
+        assert!(matches!(
+            replay(ops, &maintainers(&key)),
+            Err(Error::MissingOpen)
+        ));
+    }
+}
@@ -0,0 +1,742 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A [`Patch`] is a proposal to merge changes into a project, and the review
+//! that forms around it.
+//!
+//! The changes are captured as a series of [`Revision`]s, each of which
+//! refers to a `base` commit in the project, and a `head` commit published by
+//! a peer under `refs/remotes/<peer>/heads/<branch>` of the project. Every time
+//! the author pushes new changes, a new revision is added, so that reviews
+//! remain attached to the changes they were made for.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::error::Error;
+//! #
+//! # fn main() -> Result<(), Box<dyn Error>> {
+//! use librad::{hash::Hash, keys::SecretKey, peer::PeerId, uri::{self, RadUrn}};
+//! use radicle_tracker::{
+//!     patch::{Anchor, Patch, Source, Status, Verdict},
+//!     Comment,
+//!     ReplyTo,
+//!     Title,
+//! };
+//!
+//! let project = RadUrn::new(Hash::hash(b"boeuf"), uri::Protocol::Git, uri::Path::empty());
+//! let peer = PeerId::from(SecretKey::new());
+//! let base = git2::Oid::from_str("f41a1eb1a5e4d4bd1e4e4a5ff5a1a5ff5a1a5ff5")?;
+//! let head = git2::Oid::from_str("e11e8a0b0ad54ecc4b9c0b0ad54ecc4b9c0b0ad5")?;
+//!
+//! let mut patch = Patch::new(
+//!     0,
+//!     0,
+//!     String::from("finto"),
+//!     Title::from("Debug the boeuf"),
+//!     String::from("This should get rid of the bugs"),
+//!     project,
+//!     base,
+//!     head,
+//!     Source::new(peer, "debug"),
+//! );
+//!
+//! // Comment on a line of the changes
+//! patch.reply(
+//!     Comment::new(1, String::from("monadic"), String::from("Is this a caterpillar?"))
+//!         .anchored(Anchor::new(0, "src/boeuf.rs", 42)),
+//!     ReplyTo::Main,
+//! )?;
+//!
+//! patch.review(String::from("monadic"), Verdict::Approve)?;
+//! patch.merge(0)?;
+//! assert!(matches!(patch.status(), Status::Merged { revision: 0, .. }));
+//! #
+//! #     Ok(())
+//! # }
+//! ```
+
+use std::{convert::TryFrom, hash::Hash, ops::Deref};
+
+use nonempty::NonEmpty;
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use librad::{
+    git::{
+        ext::Oid,
+        types::{NamespacedRef, Single},
+    },
+    peer::PeerId,
+    uri::RadUrn,
+};
+
+use crate::{
+    clock::{Clock, RadClock},
+    Comment,
+    Metadata,
+    ReplyTo,
+    Thread,
+    Title,
+};
+
+/// Errors which may occur when updating a [`Patch`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    /// The patch was already merged or closed.
+    #[error("the patch is not open")]
+    NotOpen,
+
+    /// The patch is open already.
+    #[error("the patch is open")]
+    AlreadyOpen,
+
+    /// There is no revision with the given index.
+    #[error("there is no revision {0}")]
+    NoSuchRevision(usize),
+
+    /// An attempt was made to build a [`Patch`] without any revisions.
+    #[error("a patch must have at least one revision")]
+    NoRevisions,
+}
+
+/// The branch of a peer a revision of a [`Patch`] was published on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Source {
+    peer: PeerId,
+    branch: String,
+}
+
+impl Source {
+    /// The branch `branch` of `peer`, ie. `refs/remotes/<peer>/heads/<branch>`.
+    pub fn new(peer: PeerId, branch: &str) -> Self {
+        Self {
+            peer,
+            branch: branch.to_string(),
+        }
+    }
+
+    /// Get a reference to the peer which published the revision.
+    pub fn peer(&self) -> &PeerId {
+        &self.peer
+    }
+
+    /// Get a reference to the name of the branch.
+    pub fn branch(&self) -> &String {
+        &self.branch
+    }
+
+    /// The reference of the branch in the namespace of `project`.
+    pub fn reference(&self, project: &RadUrn) -> NamespacedRef<Single> {
+        NamespacedRef::head(project.id.clone(), self.peer.clone(), &self.branch)
+    }
+}
+
+/// The verdict of a [`Review`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// The reviewer approves of the changes.
+    Approve,
+    /// The reviewer rejects the changes.
+    Reject,
+}
+
+/// The review of a [`Revision`] by a `User`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Review<User> {
+    reviewer: User,
+    verdict: Verdict,
+    timestamp: RadClock,
+}
+
+impl<User> Review<User> {
+    /// Get a reference to the reviewer.
+    pub fn reviewer(&self) -> &User {
+        &self.reviewer
+    }
+
+    /// Get the verdict of the review.
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
+    /// Get a reference to the time of the review.
+    pub fn timestamp(&self) -> &RadClock {
+        &self.timestamp
+    }
+}
+
+/// A version of the changes proposed by a [`Patch`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision<User> {
+    base: Oid,
+    head: Oid,
+    source: Source,
+    reviews: Vec<Review<User>>,
+    timestamp: RadClock,
+}
+
+impl<User: Eq> Revision<User> {
+    fn new(base: git2::Oid, head: git2::Oid, source: Source) -> Self {
+        Self {
+            base: Oid(base),
+            head: Oid(head),
+            source,
+            reviews: vec![],
+            timestamp: RadClock::current_time(),
+        }
+    }
+
+    /// Get the commit the changes are based on.
+    pub fn base(&self) -> git2::Oid {
+        self.base.0
+    }
+
+    /// Get the commit containing the changes.
+    pub fn head(&self) -> git2::Oid {
+        self.head.0
+    }
+
+    /// Get a reference to the branch the changes were published on.
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// Get the reviews of this revision, at most one per reviewer.
+    pub fn reviews(&self) -> &[Review<User>] {
+        &self.reviews
+    }
+
+    /// Get the reviewers approving of this revision.
+    pub fn approvals(&self) -> impl Iterator<Item = &User> {
+        self.reviewers(Verdict::Approve)
+    }
+
+    /// Get the reviewers rejecting this revision.
+    pub fn rejections(&self) -> impl Iterator<Item = &User> {
+        self.reviewers(Verdict::Reject)
+    }
+
+    /// Get a reference to the time this revision was proposed.
+    pub fn timestamp(&self) -> &RadClock {
+        &self.timestamp
+    }
+
+    fn reviewers(&self, verdict: Verdict) -> impl Iterator<Item = &User> {
+        self.reviews
+            .iter()
+            .filter(move |review| review.verdict == verdict)
+            .map(|review| &review.reviewer)
+    }
+
+    fn review(&mut self, reviewer: User, verdict: Verdict) {
+        self.reviews.retain(|review| review.reviewer != reviewer);
+        self.reviews.push(Review {
+            reviewer,
+            verdict,
+            timestamp: RadClock::current_time(),
+        })
+    }
+}
+
+/// The revisions of a [`Patch`], oldest first. There is always at least one.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<Revision<User>>")]
+struct Revisions<User>(NonEmpty<Revision<User>>);
+
+impl<User: Serialize> Serialize for Revisions<User> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<User> TryFrom<Vec<Revision<User>>> for Revisions<User> {
+    type Error = Error;
+
+    fn try_from(revisions: Vec<Revision<User>>) -> Result<Self, Self::Error> {
+        let mut revisions = revisions.into_iter();
+        let first = revisions.next().ok_or(Error::NoRevisions)?;
+        Ok(Revisions(NonEmpty::from((first, revisions.collect()))))
+    }
+}
+
+/// The position in the changes of a [`Revision`] an inline comment refers
+/// to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Anchor {
+    revision: usize,
+    path: String,
+    line: u32,
+}
+
+impl Anchor {
+    /// Line `line` of the file at `path` in revision `revision` of the patch.
+    pub fn new(revision: usize, path: &str, line: u32) -> Self {
+        Self {
+            revision,
+            path: path.to_string(),
+            line,
+        }
+    }
+
+    /// Get the index of the revision.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Get a reference to the path of the file.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Get the line number.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+}
+
+/// A [`Comment`] made during a review, which may be anchored to a line of the
+/// changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReviewComment<Cid, User: Eq + Hash> {
+    comment: Comment<Cid, User>,
+    anchor: Option<Anchor>,
+}
+
+impl<Cid, User: Eq + Hash> Comment<Cid, User> {
+    /// Anchor this comment to a line of the changes of a [`Patch`].
+    pub fn anchored(self, anchor: Anchor) -> ReviewComment<Cid, User> {
+        ReviewComment {
+            comment: self,
+            anchor: Some(anchor),
+        }
+    }
+}
+
+impl<Cid, User: Eq + Hash> ReviewComment<Cid, User> {
+    /// Get a reference to the position this comment is anchored to, if it is
+    /// an inline comment.
+    pub fn anchor(&self) -> Option<&Anchor> {
+        self.anchor.as_ref()
+    }
+
+    /// Get a mutable reference to the underlying [`Comment`].
+    pub fn comment_mut(&mut self) -> &mut Comment<Cid, User> {
+        &mut self.comment
+    }
+}
+
+impl<Cid, User: Eq + Hash> From<Comment<Cid, User>> for ReviewComment<Cid, User> {
+    fn from(comment: Comment<Cid, User>) -> Self {
+        Self {
+            comment,
+            anchor: None,
+        }
+    }
+}
+
+impl<Cid, User: Eq + Hash> Deref for ReviewComment<Cid, User> {
+    type Target = Comment<Cid, User>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.comment
+    }
+}
+
+/// The status of a [`Patch`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Status {
+    /// The patch is under review.
+    Open,
+    /// The patch was merged.
+    Merged {
+        /// The index of the revision which was merged.
+        revision: usize,
+        /// The time the patch was merged.
+        timestamp: RadClock,
+    },
+    /// The patch was closed without being merged.
+    Closed,
+}
+
+/// A `Patch` is a proposal by its [`Patch::author`] to merge changes into a
+/// project.
+///
+/// Like an [`crate::Issue`], it is kicked off by providing a [`Title`] and an
+/// initial [`Comment`] that starts the review [`Thread`], and carries
+/// [`Metadata`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Patch<Id, Cid, User: Eq + Hash> {
+    identifier: Id,
+    author: User,
+    title: Title,
+    project: RadUrn,
+    revisions: Revisions<User>,
+    thread: Thread<ReviewComment<Cid, User>>,
+    meta: Metadata<User>,
+    status: Status,
+    timestamp: RadClock,
+}
+
+impl<Id, Cid, User: Eq + Hash> Patch<Id, Cid, User> {
+    /// Create a new `Patch`, proposing to merge `head` into `base` of
+    /// `project`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        identifier: Id,
+        comment_id: Cid,
+        author: User,
+        title: Title,
+        content: String,
+        project: RadUrn,
+        base: git2::Oid,
+        head: git2::Oid,
+        source: Source,
+    ) -> Self
+    where
+        User: Clone,
+    {
+        let timestamp = RadClock::current_time();
+        let comment = Comment::new_with_timestamp(comment_id, author.clone(), content, timestamp);
+
+        Patch {
+            identifier,
+            author,
+            title,
+            project,
+            revisions: Revisions(NonEmpty::new(Revision::new(base, head, source))),
+            thread: Thread::new(ReviewComment::from(comment)),
+            meta: Metadata::new(),
+            status: Status::Open,
+            timestamp,
+        }
+    }
+
+    /// Get a reference to the identifier of this patch.
+    pub fn identifier(&self) -> &Id {
+        &self.identifier
+    }
+
+    /// Get a reference to the author (`User`) of this patch.
+    pub fn author(&self) -> &User {
+        &self.author
+    }
+
+    /// Get a reference to the [`Title`] of this patch.
+    pub fn title(&self) -> &Title {
+        &self.title
+    }
+
+    /// Get a reference to the project this patch is proposed to.
+    pub fn project(&self) -> &RadUrn {
+        &self.project
+    }
+
+    /// Get a reference to the creation time of this patch.
+    pub fn timestamp(&self) -> &RadClock {
+        &self.timestamp
+    }
+
+    /// Get the revisions of this patch, oldest first.
+    pub fn revisions(&self) -> impl Iterator<Item = &Revision<User>> {
+        self.revisions.0.iter()
+    }
+
+    /// Get the revision with index `index`, if it exists.
+    pub fn revision(&self, index: usize) -> Option<&Revision<User>> {
+        self.revisions.0.get(index)
+    }
+
+    /// Get the latest revision of this patch.
+    pub fn latest(&self) -> &Revision<User> {
+        self.revisions.0.last()
+    }
+
+    /// Get a reference to the review [`Thread`] of this patch.
+    pub fn thread(&self) -> &Thread<ReviewComment<Cid, User>> {
+        &self.thread
+    }
+
+    /// Get a mutable reference to the review [`Thread`] of this patch.
+    ///
+    /// Comments should be added via [`Patch::reply`], which checks their
+    /// [`Anchor`].
+    pub fn thread_mut(&mut self) -> &mut Thread<ReviewComment<Cid, User>> {
+        &mut self.thread
+    }
+
+    /// Add `comment` to the review [`Thread`], see [`Thread::reply`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NoSuchRevision`] if `comment` is anchored to a
+    /// revision which doesn't exist.
+    pub fn reply(
+        &mut self,
+        comment: ReviewComment<Cid, User>,
+        reply_to: ReplyTo,
+    ) -> Result<(), Error> {
+        if let Some(anchor) = comment.anchor() {
+            if self.revision(anchor.revision).is_none() {
+                return Err(Error::NoSuchRevision(anchor.revision));
+            }
+        }
+        self.thread.reply(comment, reply_to);
+        Ok(())
+    }
+
+    /// Get a reference to the [`Metadata`] of this patch.
+    pub fn meta(&self) -> &Metadata<User> {
+        &self.meta
+    }
+
+    /// Get a mutable reference to the [`Metadata`] of this patch.
+    pub fn meta_mut(&mut self) -> &mut Metadata<User> {
+        &mut self.meta
+    }
+
+    /// Get a reference to the [`Status`] of this patch.
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Propose a new revision of the changes, returning its index.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NotOpen`] if the patch was merged or closed.
+    pub fn update(
+        &mut self,
+        base: git2::Oid,
+        head: git2::Oid,
+        source: Source,
+    ) -> Result<usize, Error> {
+        self.ensure_open()?;
+        self.revisions.0.push(Revision::new(base, head, source));
+        Ok(self.revisions.0.len() - 1)
+    }
+
+    /// Review the latest revision on behalf of `reviewer`, replacing their
+    /// previous review of it, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NotOpen`] if the patch was merged or closed.
+    pub fn review(&mut self, reviewer: User, verdict: Verdict) -> Result<(), Error> {
+        self.ensure_open()?;
+        self.revisions.0.last_mut().review(reviewer, verdict);
+        Ok(())
+    }
+
+    /// Mark the patch as merged at revision `revision`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NotOpen`] if the patch was merged or closed, and
+    /// with [`Error::NoSuchRevision`] if there is no such revision.
+    pub fn merge(&mut self, revision: usize) -> Result<(), Error> {
+        self.ensure_open()?;
+        if self.revision(revision).is_none() {
+            return Err(Error::NoSuchRevision(revision));
+        }
+        self.status = Status::Merged {
+            revision,
+            timestamp: RadClock::current_time(),
+        };
+        Ok(())
+    }
+
+    /// Close the patch without merging it.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::NotOpen`] if the patch was merged or closed.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.ensure_open()?;
+        self.status = Status::Closed;
+        Ok(())
+    }
+
+    /// Reopen a closed patch.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::AlreadyOpen`] if the patch is open, and with
+    /// [`Error::NotOpen`] if it was merged.
+    pub fn reopen(&mut self) -> Result<(), Error> {
+        match self.status {
+            Status::Open => Err(Error::AlreadyOpen),
+            Status::Merged { .. } => Err(Error::NotOpen),
+            Status::Closed => {
+                self.status = Status::Open;
+                Ok(())
+            },
+        }
+    }
+
+    fn ensure_open(&self) -> Result<(), Error> {
+        match self.status {
+            Status::Open => Ok(()),
+            _ => Err(Error::NotOpen),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use librad::{hash::Hash as RadHash, keys::SecretKey, uri};
+
+    use crate::Finger;
+
+    fn oid(s: &str) -> git2::Oid {
+        git2::Oid::hash_object(git2::ObjectType::Blob, s.as_bytes()).unwrap()
+    }
+
+    fn patch() -> Patch<u64, u64, String> {
+        Patch::new(
+            0,
+            0,
+            String::from("finto"),
+            Title::from("Debug the boeuf"),
+            String::from("This should get rid of the bugs"),
+            RadUrn::new(
+                RadHash::hash(b"boeuf"),
+                uri::Protocol::Git,
+                uri::Path::empty(),
+            ),
+            oid("base"),
+            oid("head"),
+            Source::new(PeerId::from(SecretKey::new()), "debug"),
+        )
+    }
+
+    #[test]
+    fn source_reference() {
+        let patch = patch();
+        let source = patch.latest().source();
+
+        assert_eq!(
+            source.reference(patch.project()).to_string(),
+            format!(
+                "refs/namespaces/{}/refs/remotes/{}/heads/debug",
+                patch.project().id,
+                source.peer()
+            )
+        );
+    }
+
+    #[test]
+    fn reviews_are_per_revision() -> Result<(), Error> {
+        let mut patch = patch();
+        let source = patch.latest().source().clone();
+
+        patch.review(String::from("monadic"), Verdict::Reject)?;
+        patch.review(String::from("monadic"), Verdict::Approve)?;
+        patch.review(String::from("kim"), Verdict::Reject)?;
+        assert_eq!(
+            patch.latest().approvals().collect::<Vec<_>>(),
+            vec!["monadic"]
+        );
+        assert_eq!(patch.latest().rejections().collect::<Vec<_>>(), vec!["kim"]);
+
+        let revision = patch.update(oid("base"), oid("head v2"), source)?;
+        assert_eq!(revision, 1);
+        assert_eq!(patch.latest().head(), oid("head v2"));
+        assert!(patch.latest().reviews().is_empty());
+        assert_eq!(patch.revision(0).unwrap().reviews().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn inline_comments() -> Result<(), Error> {
+        let mut patch = patch();
+        patch.reply(
+            Comment::new(1, String::from("monadic"), String::from("Typo")).anchored(Anchor::new(
+                0,
+                "README.md",
+                1,
+            )),
+            ReplyTo::Main,
+        )?;
+        patch.reply(
+            Comment::new(2, String::from("finto"), String::from("Fixed")).into(),
+            ReplyTo::Thread,
+        )?;
+
+        let inline = patch
+            .thread()
+            .iter()
+            .filter_map(|comment| comment.get().anchor())
+            .collect::<Vec<_>>();
+        assert_eq!(inline, vec![&Anchor::new(0, "README.md", 1)]);
+        assert_eq!(
+            patch.thread().find(|comment| comment.identifier() == &2),
+            Some(Finger::Thread { main: 0, reply: 1 })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn anchor_to_missing_revision() {
+        let mut patch = patch();
+        assert_eq!(
+            patch.reply(
+                Comment::new(1, String::from("monadic"), String::from("Typo"))
+                    .anchored(Anchor::new(1, "README.md", 1)),
+                ReplyTo::Main,
+            ),
+            Err(Error::NoSuchRevision(1))
+        );
+        assert!(patch
+            .thread()
+            .iter()
+            .all(|comment| comment.get().anchor().is_none()));
+    }
+
+    #[test]
+    fn reject_no_revisions() {
+        let mut json = serde_json::to_value(patch()).unwrap();
+        json["revisions"] = serde_json::json!([]);
+        assert!(serde_json::from_value::<Patch<u64, u64, String>>(json).is_err());
+    }
+
+    #[test]
+    fn status_transitions() {
+        let mut patch = patch();
+
+        assert_eq!(patch.reopen(), Err(Error::AlreadyOpen));
+        assert_eq!(patch.close(), Ok(()));
+        assert_eq!(patch.merge(0), Err(Error::NotOpen));
+        assert_eq!(patch.reopen(), Ok(()));
+        assert_eq!(patch.merge(1), Err(Error::NoSuchRevision(1)));
+        assert_eq!(patch.merge(0), Ok(()));
+        assert_eq!(
+            patch.review(String::from("kim"), Verdict::Approve),
+            Err(Error::NotOpen)
+        );
+        assert_eq!(patch.reopen(), Err(Error::NotOpen));
+    }
+}
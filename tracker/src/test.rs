@@ -0,0 +1,33 @@
+// This file is part of radicle-link
+// <https://github.com/radicle-dev/radicle-link>
+//
+// Copyright (C) 2019-2020 The Radicle Team <dev@radicle.xyz>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3 or
+// later as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeSet, HashMap};
+
+use librad::keys::{PublicKey, SecretKey};
+
+/// Delegate each of `users` to the accompanying key.
+pub(crate) fn delegations(users: &[(&str, &SecretKey)]) -> HashMap<String, BTreeSet<PublicKey>> {
+    users
+        .iter()
+        .map(|(user, key)| (user.to_string(), maintainers(key)))
+        .collect()
+}
+
+/// The set of maintainer keys consisting of just `key`.
+pub(crate) fn maintainers(key: &SecretKey) -> BTreeSet<PublicKey> {
+    vec![key.public()].into_iter().collect()
+}
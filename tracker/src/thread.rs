@@ -15,13 +15,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use std::convert::TryFrom;
+
+use crate::crdt::{Merge, Node};
+
 use nonempty::NonEmpty;
+use serde::{Deserialize, Serialize, Serializer};
 use thiserror::Error;
 
 /// The "liveness" status of some data.
 ///
+/// Deletion is final: there is no way to bring `Dead` data back to life, so
+/// when merging, a deletion made by any peer wins over concurrent edits (see
+/// the [`Merge`] instance). The edits are still merged into the dead value.
+///
 /// TODO: we may want to consider `Modified`.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum DataState<A> {
     /// The data has been created.
     Live(A),
@@ -71,6 +80,25 @@ impl<A> DataState<A> {
     }
 }
 
+impl<A: Merge + Clone> Merge for DataState<A> {
+    /// Merge the values, where deletion always wins.
+    ///
+    /// `Live` and `Dead` form a two-state lattice in which `Dead` is the top,
+    /// which keeps the merge commutative, associative and idempotent. Deleted
+    /// data can't be revived, so there is no concurrent "undelete" this could
+    /// lose.
+    fn merge(&mut self, other: Self) {
+        let (other, other_dead) = match other {
+            Self::Live(a) => (a, false),
+            Self::Dead(a) => (a, true),
+        };
+        self.get_mut().merge(other);
+        if other_dead {
+            self.kill()
+        }
+    }
+}
+
 /// Errors can occur when navigating around a thread or when attempting to
 /// delete the root item of a thread.
 #[derive(Error, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -83,6 +111,9 @@ pub enum Error {
     /// the pointer is already at the root item.
     #[error("tried to move to previous item in the main thread, but we are at the first")]
     PreviousOnRoot,
+    /// An attempt was made to build [`Replies`] from an empty collection.
+    #[error("replies must contain at least one item")]
+    EmptyReplies,
     ///
     #[error("an attempt was made to move to {attempt}, but this is out of bounds where the bounds are {main:?}, {reply:?}.")]
     OutOfBounds {
@@ -99,9 +130,29 @@ pub enum Error {
 ///
 /// `Replies` are deliberately opaque as they should mostly be interacted with
 /// via [`Thread`].
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "Vec<DataState<A>>")]
 pub struct Replies<A>(NonEmpty<DataState<A>>);
 
+impl<A: Serialize> Serialize for Replies<A> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<A> TryFrom<Vec<DataState<A>>> for Replies<A> {
+    type Error = Error;
+
+    fn try_from(replies: Vec<DataState<A>>) -> Result<Self, Self::Error> {
+        let mut replies = replies.into_iter();
+        let first = replies.next().ok_or(Error::EmptyReplies)?;
+        Ok(Replies(NonEmpty::from((first, replies.collect()))))
+    }
+}
+
 impl<A> Replies<A> {
     fn new(a: A) -> Self {
         Replies(NonEmpty::new(DataState::Live(a)))
@@ -143,6 +194,40 @@ impl<A> Replies<A> {
     }
 }
 
+impl<A: Node + Clone> Merge for Replies<A> {
+    /// Merge the replies of another copy of this sub-thread, where the first
+    /// item is assumed to be the same.
+    fn merge(&mut self, other: Self) {
+        let mut other = other.0.iter().cloned();
+        if let Some(first) = other.next() {
+            self.first_mut().merge(first);
+        }
+
+        let mut replies = self.0.iter().skip(1).cloned().collect::<Vec<_>>();
+        merge_nodes(&mut replies, other, |node| node.get());
+
+        let first = self.first().clone();
+        self.0 = NonEmpty::from((first, replies));
+    }
+}
+
+/// Merge `theirs` into `ours`, matching items by their [`Node::id`] and
+/// ordering them by [`Node::cmp_position`].
+fn merge_nodes<T, A, F>(ours: &mut Vec<T>, theirs: impl Iterator<Item = T>, node: F)
+where
+    T: Merge,
+    A: Node,
+    F: Fn(&T) -> &A,
+{
+    for item in theirs {
+        match ours.iter_mut().find(|x| node(x).id() == node(&item).id()) {
+            Some(x) => x.merge(item),
+            None => ours.push(item),
+        }
+    }
+    ours.sort_by(|x, y| node(x).cmp_position(node(y)));
+}
+
 /// A structure for pointing into a [`Thread`].
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Finger {
@@ -179,13 +264,20 @@ impl std::fmt::Display for Finger {
 // This point to the main thread, and the first item in that thread.
 const ROOT_FINGER: Finger = Finger::Root;
 
+fn root_finger() -> Finger {
+    ROOT_FINGER
+}
+
 /// A `Thread` is the root item followed by a series of non-empty replies to the
 /// root item. For each item in reply to the root item there may be 0 or more
 /// replies.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Thread<A> {
     // A finger points into the `main_thread` structure. It allows us to efficiently look at the
     // current item, and gives us a way to move around the data structure as if reading a thread.
+    //
+    // The finger is a view onto the data, so it is not persisted.
+    #[serde(skip, default = "root_finger")]
     finger: Finger,
 
     // root and main_thread make up the actual data of the data structure.
@@ -199,6 +291,26 @@ impl<A: PartialEq> PartialEq for Thread<A> {
     }
 }
 
+impl<A: Node + Clone> Merge for Thread<A> {
+    /// Merge another copy of this thread.
+    ///
+    /// Replies are matched by their [`Node::id`], and ordered by their creation
+    /// time, so that replies made concurrently end up in the same order on
+    /// every peer. Replies always come after the item they reply to.
+    ///
+    /// Since the positions of items may change, the thread is navigated back
+    /// to its root.
+    fn merge(&mut self, other: Self) {
+        self.root.merge(other.root);
+        merge_nodes(
+            &mut self.main_thread,
+            other.main_thread.into_iter(),
+            |replies| replies.first().get(),
+        );
+        self.finger = ROOT_FINGER;
+    }
+}
+
 /// `ReplyTo` tells the navigation and reply functions whether they should take
 /// action on the "main thread" or on a "reply thread".
 ///
@@ -390,6 +502,74 @@ impl<A> Thread<A> {
         self.finger = ROOT_FINGER;
     }
 
+    /// Iterate over all items of the thread, starting at the root, followed by
+    /// each item of the main thread and its replies.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use radicle_tracker::{DataState, ReplyTo, Thread};
+    ///
+    /// let mut thread = Thread::new(String::from("Discussing rose trees"));
+    /// thread.reply(String::from("I love rose trees!"), ReplyTo::Main);
+    /// thread.reply(String::from("I love rose bushes!"), ReplyTo::Thread);
+    /// thread.reply(String::from("What should we use them for?"), ReplyTo::Main);
+    ///
+    /// assert_eq!(
+    ///     thread.iter().map(DataState::get).collect::<Vec<_>>(),
+    ///     vec![
+    ///         "Discussing rose trees",
+    ///         "I love rose trees!",
+    ///         "I love rose bushes!",
+    ///         "What should we use them for?",
+    ///     ]
+    /// );
+    /// ```
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &DataState<A>> + 'a {
+        std::iter::once(&self.root).chain(self.main_thread.iter().flat_map(Replies::iter))
+    }
+
+    /// Find the first item in the thread which satisfies the predicate `f`,
+    /// and get the [`Finger`] pointing to it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use radicle_tracker::{Finger, ReplyTo, Thread};
+    ///
+    /// let mut thread = Thread::new(String::from("Discussing rose trees"));
+    /// thread.reply(String::from("I love rose trees!"), ReplyTo::Main);
+    /// thread.reply(String::from("I love rose bushes!"), ReplyTo::Thread);
+    ///
+    /// assert_eq!(
+    ///     thread.find(|item| item.contains("bushes")),
+    ///     Some(Finger::Thread { main: 0, reply: 1 })
+    /// );
+    /// assert_eq!(thread.find(|item| item.contains("tulips")), None);
+    /// ```
+    pub fn find<F>(&self, f: F) -> Option<Finger>
+    where
+        F: Fn(&A) -> bool,
+    {
+        if f(self.root.get()) {
+            return Some(Finger::Root);
+        }
+
+        for (main, replies) in self.main_thread.iter().enumerate() {
+            for (reply, node) in replies.iter().enumerate() {
+                if f(node.get()) {
+                    return Some(if reply == 0 {
+                        Finger::Main(main)
+                    } else {
+                        Finger::Thread { main, reply }
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
     /// Absolute navigation to a position in the `Thread` using a [`Finger`].
     ///
     /// * [`Error::OutOfBounds`] - If the navigation to the next item in the